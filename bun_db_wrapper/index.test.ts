@@ -21,6 +21,14 @@ const BUN_GATEWAY_HEALTH_URL = `http://localhost:${BUN_GATEWAY_PORT}/`;
 const SERVER_START_DELAY_MS = 5000;
 const API_TIMEOUT_MS = 15000;
 
+// Object documents come back with the `_rev` the server keeps on them; the
+// revision itself is covered by the "Revisions" tests.
+function withoutRev(value: any): any {
+    if (value === null || typeof value !== "object" || Array.isArray(value)) return value;
+    const { _rev, ...rest } = value;
+    return rest;
+}
+
 // --- Test Setup & Teardown ---
 let rustServerProcess: Subprocess | null = null;
 let bunGatewayProcess: Subprocess | null = null;
@@ -266,7 +274,18 @@ describe("Basic CRUD & Edge Cases", () => {
         expect(response.status).toBe(200);
         response = await apiRequest("/get", "POST", { key: testKey });
         expect(response.status).toBe(200);
-        expect(await response.json()).toEqual(testValue);
+        expect(withoutRev(await response.json())).toEqual(testValue);
+    });
+
+    it("should send ETag and Last-Modified without adding fields to the document", async () => {
+        await apiRequest("/set", "POST", { key: testKey, value: testValue });
+        const response = await apiRequest("/get", "POST", { key: testKey });
+        expect(response.status).toBe(200);
+        expect(response.headers.get("ETag")).toBeTruthy();
+        const lastModified = response.headers.get("Last-Modified");
+        expect(lastModified).toBeTruthy();
+        expect(Math.abs(Date.parse(lastModified!) - Date.now())).toBeLessThan(60_000);
+        expect(await response.json()).toEqual({ ...testValue, _rev: 1 });
     });
 
      it("should return 404 for a non-existent key", async () => {
@@ -281,7 +300,7 @@ describe("Basic CRUD & Edge Cases", () => {
         expect(response.status).toBe(200);
         response = await apiRequest("/get", "POST", { key: testKey });
         expect(response.status).toBe(200);
-        expect(await response.json()).toEqual(updatedValue);
+        expect(withoutRev(await response.json())).toEqual(updatedValue);
     });
 
     it("should delete a value", async () => {
@@ -335,7 +354,7 @@ describe("Basic CRUD & Edge Cases", () => {
         expect(response.status).toBe(200);
         response = await apiRequest("/get", "POST", { key: "emptyObjectKey" });
         expect(response.status).toBe(200);
-        expect(await response.json()).toEqual({});
+        expect(withoutRev(await response.json())).toEqual({});
     });
 
      it("should handle empty array value", async () => {
@@ -400,7 +419,71 @@ describe("Complex Data Structures", () => {
         response = await apiRequest("/get", "POST", { key: complexKey });
         expect(response.status).toBe(200);
         const retrievedValue = await response.json();
-        expect(retrievedValue).toEqual(complexValue);
+        expect(withoutRev(retrievedValue)).toEqual(complexValue);
+    });
+});
+
+
+describe("Revisions", () => {
+    const revKey = "revisionKey";
+    const importedKey = "revisionImportKey";
+
+    beforeEach(async () => {
+        await apiRequest("/delete", "POST", { key: revKey }).catch(()=>{});
+        await apiRequest("/delete", "POST", { key: importedKey }).catch(()=>{});
+        await new Promise(resolve => setTimeout(resolve, 20));
+    });
+
+    it("should stamp _rev 1 on a new object and count up on each write", async () => {
+        await apiRequest("/set", "POST", { key: revKey, value: { n: 1 } });
+        let response = await apiRequest("/get", "POST", { key: revKey });
+        expect(await response.json()).toEqual({ n: 1, _rev: 1 });
+
+        await apiRequest("/set", "POST", { key: revKey, value: { n: 2 } });
+        response = await apiRequest("/get", "POST", { key: revKey });
+        expect(await response.json()).toEqual({ n: 2, _rev: 2 });
+    });
+
+    it("should stamp _rev on an empty object", async () => {
+        await apiRequest("/set", "POST", { key: revKey, value: {} });
+        const response = await apiRequest("/get", "POST", { key: revKey });
+        expect(await response.json()).toEqual({ _rev: 1 });
+    });
+
+    it("should leave non-object values without _rev", async () => {
+        await apiRequest("/set", "POST", { key: revKey, value: [1, 2] });
+        const response = await apiRequest("/get", "POST", { key: revKey });
+        expect(await response.json()).toEqual([1, 2]);
+    });
+
+    it("should accept a write carrying the stored _rev and reject a stale one with 409", async () => {
+        await apiRequest("/set", "POST", { key: revKey, value: { n: 1 } });
+        let response = await apiRequest("/set", "POST", { key: revKey, value: { n: 2, _rev: 1 } });
+        expect(response.status).toBe(200);
+
+        response = await apiRequest("/set", "POST", { key: revKey, value: { n: 3, _rev: 1 } });
+        expect(response.status).toBe(409);
+        response = await apiRequest("/get", "POST", { key: revKey });
+        expect(await response.json()).toEqual({ n: 2, _rev: 2 });
+    });
+
+    it("should only create the key when _rev is 0", async () => {
+        let response = await apiRequest("/set", "POST", { key: revKey, value: { n: 1, _rev: 0 } });
+        expect(response.status).toBe(200);
+        response = await apiRequest("/set", "POST", { key: revKey, value: { n: 2, _rev: 0 } });
+        expect(response.status).toBe(409);
+    });
+
+    it("should stamp _rev on imported objects, continuing from the stored revision", async () => {
+        let response = await apiRequest("/import", "POST", [{ key: importedKey, value: { n: 1, _rev: 7 } }]);
+        expect(response.status).toBe(201);
+        response = await apiRequest("/get", "POST", { key: importedKey });
+        expect(await response.json()).toEqual({ n: 1, _rev: 1 });
+
+        response = await apiRequest("/import", "POST", [{ key: importedKey, value: { n: 2 } }]);
+        expect(response.status).toBe(201);
+        response = await apiRequest("/get", "POST", { key: importedKey });
+        expect(await response.json()).toEqual({ n: 2, _rev: 2 });
     });
 });
 
@@ -440,7 +523,7 @@ describe("Import/Export", () => {
         const response = await apiRequest("/export", "GET");
         expect(response.status).toBe(200);
         const exportedJsonString: string = await response.json();
-        const exportedJson = JSON.parse(exportedJsonString);
+        const exportedJson = JSON.parse(exportedJsonString).map((item: any) => ({ ...item, value: withoutRev(item.value) }));
         console.log("Exported JSON:", JSON.stringify(exportedJson)); // Add logging
         console.log("Expected Export Data:", JSON.stringify(exportData)); // Add logging
         expect(exportedJson).toBeInstanceOf(Array);
@@ -476,7 +559,7 @@ describe("Import/Export", () => {
         const exportResponse = await apiRequest("/export", "GET");
         expect(exportResponse.status).toBe(200);
         const exportedJsonString = await exportResponse.json();
-        const exportedJson = JSON.parse(exportedJsonString);
+        const exportedJson = JSON.parse(exportedJsonString).map((item: any) => ({ ...item, value: withoutRev(item.value) }));
         console.log("Exported JSON:", JSON.stringify(exportedJson)); // Add logging
 
         // Map both arrays to a common type
@@ -1,29 +1,62 @@
 use serde::{Serialize, Deserialize, de::Error as SerdeError};
 use serde_json::{Value, json, Map};
-use sled::{Db, IVec, Batch, transaction::{TransactionError, UnabortableTransactionError, ConflictableTransactionError, TransactionalTree}};
-use std::collections::{HashMap, HashSet};
+use sled::{Db, IVec, Batch, transaction::{TransactionError, UnabortableTransactionError, ConflictableTransactionError, TransactionalTree, Transactional}};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::sync::OnceLock;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use thiserror::Error;
-use tracing::{error, debug, warn};
-use geo::{Coord, Point, Rect, prelude::*};
-use geohash::{encode, neighbors as geohash_neighbors, Neighbors}; // Removed decode_bbox
+use tracing::{debug, warn};
+use geo::{Coord, Point, Rect, LineString, Polygon, Geometry, GeometryCollection, Closest, Densify, Distance, Haversine, Geodesic, prelude::*};
+use geohash::{encode, decode_bbox};
 use std::convert::TryInto;
 use std::cmp::Ordering;
-use hex;
 use lazy_static::lazy_static;
+use rstar::{RTree, PointDistance, primitives::GeomWithData};
 use regex::Regex;
+use rand::seq::IteratorRandom;
+use chrono::DateTime;
 // Removed TypeId
 use std::ops::Bound;
-use std::hash::{Hash, Hasher};
 // Removed Arc
 // Removed FromIterator
 
-pub const GEO_SORTED_INDEX_PREFIX: &str = "__geo_sorted__";
+pub const GEO_SORTED_INDEX_TREE: &str = "__geo_sorted__";
 pub const GEOHASH_PRECISION: usize = 9;
 pub const CAS_RETRY_LIMIT: u32 = 10;
+// Field holding an object document's revision, incremented on every write.
+pub const REVISION_FIELD: &str = "_rev";
+// Field of an object document holding its own expiry (RFC3339 or Unix seconds).
+pub const EXPIRES_AT_FIELD: &str = "_expires_at";
+pub const DEFAULT_IMPORT_CHUNK_SIZE: usize = 1000;
+// Documents between calls of a `Progress` callback.
+pub const PROGRESS_INTERVAL: usize = 1000;
 pub const DEFAULT_DB_PATH: &str = "database_data_server";
 pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:3000";
-pub const FIELD_INDEX_PREFIX: &str = "__field_index__";
-pub const FIELD_SORTED_INDEX_PREFIX: &str = "__field_sorted__";
+pub const FIELD_INDEX_TREE: &str = "__field_index__";
+pub const FIELD_SORTED_INDEX_TREE: &str = "__field_sorted__";
+pub const UNIQUE_INDEX_TREE: &str = "__unique_index__";
+pub const TTL_INDEX_TREE: &str = "__ttl_index__";
+// When each document was last written, in Unix milliseconds, keyed like the
+// documents; see `modified_at`.
+pub const MODIFIED_AT_TREE: &str = "__modified_at__";
+pub const META_TREE: &str = "__meta__";
+pub const DB_CONFIG_KEY: &str = "__db_config__";
+// Meta key prefix of the per-tree index format versions, followed by the tree
+// name.
+pub const INDEX_FORMAT_KEY: &str = "__index_format__";
+
+// Secondary indexes are kept in their own trees so the default tree holds
+// documents only. Older databases stored the hash, sorted and geo entries in
+// the default tree under these names as key prefixes; see
+// `migrate_legacy_layout`.
+const INDEX_TREES: [&str; 5] = [FIELD_INDEX_TREE, FIELD_SORTED_INDEX_TREE, GEO_SORTED_INDEX_TREE, UNIQUE_INDEX_TREE, TTL_INDEX_TREE];
+// Layout version of each tree in `INDEX_TREES`. Bump a tree's version whenever
+// the layout of its entries changes (e.g. `encode_sorted_value` for the sorted
+// tree, the geohash key for the geo tree): when the database is opened, trees
+// stamped with another version are cleared and rebuilt from the documents,
+// while the other trees are kept.
+const INDEX_TREE_VERSIONS: [u32; 5] = [1, 1, 1, 1, 1];
 
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -69,6 +102,24 @@ pub enum DbError {
     TransactionOperationFailed(String),
     #[error("Invalid Field Index Key format: {0}")] // Added
     InvalidFieldIndexKey(String),
+    #[error("Unique constraint violated: {0}")]
+    UniqueViolation(String),
+    #[error("Invalid index expression: {0}")]
+    InvalidExpression(String),
+    #[error("Compare-and-set conflict: {0}")]
+    CasConflict(String),
+    #[error("Revision conflict: {0}")]
+    RevisionConflict(String),
+    #[error("Patch test failed: {0}")]
+    PatchTestFailed(String),
+    #[error("Key already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Collection not found: {0}")]
+    CollectionNotFound(String),
+    #[error("Webhook not found: {0}")]
+    WebhookNotFound(String),
 }
 
 impl From<TransactionError<DbError>> for DbError {
@@ -91,14 +142,253 @@ impl From<UnabortableTransactionError> for DbError {
 
 pub type DbResult<T> = Result<T, DbError>;
 
-#[derive(Debug, Clone, Default)]
+// Called by the `_with_progress` variants of long operations with the number
+// of documents processed so far and, when known up front, the total.
+pub type Progress<'a> = &'a dyn Fn(usize, Option<usize>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct DbConfig {
     pub hash_indexed_fields: HashSet<String>,
     pub sorted_indexed_fields: HashSet<String>,
     pub geo_indexed_fields: HashSet<String>,
+    pub unique_fields: HashSet<String>,
+    // Timestamp field -> seconds after which the document expires.
+    pub ttl_fields: HashMap<String, u64>,
+    // Geo field -> geohash precision of its index; GEOHASH_PRECISION if unset.
+    pub geohash_precision: HashMap<String, usize>,
+    // Indexes still being backfilled in the background; writes maintain them
+    // but queries do not use them until the build completes.
+    pub building_indexes: HashSet<(String, IndexKind)>,
+    // Hash-indexed fields whose index omits documents missing the field.
+    pub sparse_fields: HashSet<String>,
+    // Sorted-indexed field -> collation of its string values; byte order if unset.
+    pub collations: HashMap<String, Collation>,
+    // Fields holding RFC3339 timestamps, which their sorted index orders as
+    // instants. Strings of other fields order as text, however they look.
+    pub datetime_fields: HashSet<String>,
+    // Names of the collections; see `create_collection`.
+    pub collections: BTreeSet<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// How strings are ordered in a sorted index. Options combine, e.g. a
+// case-insensitive numeric collation sorts "File2" before "file10".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Collation {
+    pub case_insensitive: bool,
+    // Runs of digits compare by numeric value ("a2" < "a10").
+    pub numeric: bool,
+    // Latin letters with diacritics sort with their base letter ("é" with "e",
+    // "ß" as "ss"), approximating European locale ordering.
+    pub locale: bool,
+}
+
+impl DbConfig {
+    pub fn geohash_precision_for(&self, field_path: &str) -> usize {
+        self.geohash_precision.get(field_path).copied().unwrap_or(GEOHASH_PRECISION)
+    }
+
+    pub fn indexed_fields(&self, kind: IndexKind) -> &HashSet<String> {
+        index_fields(self, kind)
+    }
+
+    // Whether the index is declared, even if it is still being built.
+    pub fn has_index(&self, field_path: &str, kind: IndexKind) -> bool {
+        index_fields(self, kind).contains(field_path)
+    }
+
+    // Whether queries can rely on the field's index of this kind.
+    pub fn is_index_ready(&self, field_path: &str, kind: IndexKind) -> bool {
+        index_fields(self, kind).contains(field_path) && !self.building_indexes.contains(&(field_path.to_string(), kind))
+    }
+}
+
+// Opens a database that lives only in memory and is gone once dropped, for
+// tests and ephemeral caches, with no path to pick or clean up. Compression
+// is left off: only the server builds sled with it.
+pub fn open_temporary() -> DbResult<Db> {
+    Ok(sled::Config::default().temporary(true).open()?)
+}
+
+// Loads the index configuration persisted in the database, or the default if none was saved.
+// Databases written with an older index layout are migrated first.
+pub fn load_config(db: &Db) -> DbResult<DbConfig> {
+    migrate_legacy_layout(db)?;
+    let meta = db.open_tree(META_TREE)?;
+    let config = match meta.get(DB_CONFIG_KEY.as_bytes())? {
+        Some(ivec) => serde_json::from_slice(&ivec)?,
+        None => DbConfig::default(),
+    };
+
+    migrate_index_formats(db, &config)?;
+    Ok(config)
+}
+
+fn index_format_key(tree_name: &str) -> String {
+    format!("{}:{}", INDEX_FORMAT_KEY, tree_name)
+}
+
+fn read_format_version(meta: &sled::Tree, key: &str) -> DbResult<Option<u32>> {
+    meta.get(key.as_bytes())?
+        .map(|ivec| -> DbResult<u32> { Ok(u32::from_be_bytes(ivec.as_ref().try_into()?)) })
+        .transpose()
+}
+
+// Rebuilds the index trees whose stored format version differs from the one
+// this build writes, then stamps every tree with its current version. On a
+// fresh database no tree is stamped yet and the rebuild finds no documents.
+fn migrate_index_formats(db: &Db, config: &DbConfig) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    let mut stale = HashSet::new();
+    for (tree_name, version) in INDEX_TREES.into_iter().zip(INDEX_TREE_VERSIONS) {
+        let stored_version = read_format_version(&meta, &index_format_key(tree_name))?;
+        if stored_version != Some(version) {
+            warn!(tree = tree_name, stored_version = ?stored_version, version = version, "Index format changed, rebuilding index tree");
+            stale.insert(tree_name);
+        }
+    }
+
+    if !stale.is_empty() {
+        for tree_name in &stale {
+            db.open_tree(tree_name)?.clear()?;
+        }
+        populate_index(db, &stale_trees_config(config, &stale))?;
+        for (tree_name, version) in INDEX_TREES.into_iter().zip(INDEX_TREE_VERSIONS) {
+            meta.insert(index_format_key(tree_name).as_bytes(), &version.to_be_bytes())?;
+        }
+        db.flush()?;
+    }
+    Ok(())
+}
+
+// The part of the configuration whose entries live in the given index trees.
+fn stale_trees_config(config: &DbConfig, stale: &HashSet<&str>) -> DbConfig {
+    let mut trees_config = config.clone();
+    for kind in [IndexKind::Hash, IndexKind::Sorted, IndexKind::Geo, IndexKind::Unique] {
+        if !stale.contains(index_tree_name(kind)) {
+            index_fields_mut(&mut trees_config, kind).clear();
+        }
+    }
+    if !stale.contains(TTL_INDEX_TREE) {
+        trees_config.ttl_fields.clear();
+    }
+    trees_config
+}
+
+pub fn save_config(db: &Db, config: &DbConfig) -> DbResult<()> {
+    db.open_tree(META_TREE)?.insert(DB_CONFIG_KEY.as_bytes(), serde_json::to_vec(config)?)?;
+    db.flush()?;
+    Ok(())
+}
+
+// Meta key recording that the default tree was cleared of the old layout, so
+// documents later stored under keys that look like it are left alone.
+const LEGACY_LAYOUT_MIGRATED_KEY: &str = "__legacy_layout_migrated__";
+const LEGACY_INDEX_PREFIXES: [&str; 3] = [FIELD_INDEX_TREE, FIELD_SORTED_INDEX_TREE, GEO_SORTED_INDEX_TREE];
+
+// Databases from before the index trees kept index entries in the default
+// tree, as "<prefix><field>:<value>:<key>". Those entries are dropped (the
+// format version check rebuilds them) and the configuration is moved to the
+// meta tree, once per database.
+fn migrate_legacy_layout(db: &Db) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    if meta.contains_key(LEGACY_LAYOUT_MIGRATED_KEY.as_bytes())? {
+        return Ok(());
+    }
+    for prefix in LEGACY_INDEX_PREFIXES {
+        let mut batch = Batch::default();
+        let mut removed = 0;
+        for key_result in db.scan_prefix(prefix.as_bytes()).keys() {
+            let key = key_result?;
+            if key[prefix.len()..].iter().filter(|&&b| b == b':').count() >= 2 {
+                batch.remove(key);
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            db.apply_batch(batch)?;
+            warn!(prefix = prefix, removed = removed, "Dropped legacy index entries from the default tree");
+        }
+    }
+    if let Some(config) = db.remove(DB_CONFIG_KEY.as_bytes())? {
+        meta.insert(DB_CONFIG_KEY.as_bytes(), config)?;
+    }
+    meta.insert(LEGACY_LAYOUT_MIGRATED_KEY.as_bytes(), &[])?;
+    Ok(())
+}
+
+// Transactional views of the document tree, every index tree, the
+// modification times, the changelog and the meta tree.
+struct TxTrees<'a> {
+    docs: &'a TransactionalTree,
+    hash: &'a TransactionalTree,
+    sorted: &'a TransactionalTree,
+    geo: &'a TransactionalTree,
+    unique: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    modified: &'a TransactionalTree,
+    changelog: &'a TransactionalTree,
+    meta: &'a TransactionalTree,
+}
+
+// Runs `f` in one transaction spanning documents, their indexes and the
+// changelog entries of their changes.
+fn transaction<F, A>(db: &Db, f: F) -> DbResult<A>
+where
+    F: Fn(&TxTrees) -> Result<A, ConflictableTransactionError<DbError>>,
+{
+    let [hash, sorted, geo, unique, ttl] = INDEX_TREES.map(|name| db.open_tree(name));
+    let (hash, sorted, geo, unique, ttl) = (hash?, sorted?, geo?, unique?, ttl?);
+    let [modified, changelog, meta] = [MODIFIED_AT_TREE, CHANGELOG_TREE, META_TREE].map(|name| db.open_tree(name));
+    let (modified, changelog, meta) = (modified?, changelog?, meta?);
+    let trees = (&**db, &hash, &sorted, &geo, &unique, &ttl, &modified, &changelog, &meta);
+    let result = trees.transaction(|(docs, hash, sorted, geo, unique, ttl, modified, changelog, meta)| {
+        f(&TxTrees { docs, hash, sorted, geo, unique, ttl, modified, changelog, meta })
+    })?;
+    Ok(result)
+}
+
+// The clock installed with `set_clock`, if any.
+static CLOCK: OnceLock<fn() -> u64> = OnceLock::new();
+
+// Installs the clock writes and expiries are timed by, returning Unix
+// milliseconds, in place of the system clock. Only the first call takes
+// effect. wasm32-unknown-unknown has no system clock (reading it panics), so
+// the WASM bindings install `Date.now()`.
+pub fn set_clock(clock: fn() -> u64) {
+    let _ = CLOCK.set(clock);
+}
+
+pub fn unix_now_millis() -> u64 {
+    match CLOCK.get() {
+        Some(clock) => clock(),
+        None => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0),
+    }
+}
+
+pub fn unix_now_secs() -> u64 {
+    unix_now_millis() / 1000
+}
+
+// Records that the document under `key` was written now.
+fn touch_document(tx: &TxTrees, key: &str) -> DbResult<()> {
+    tx.modified.insert(key.as_bytes(), &unix_now_millis().to_be_bytes())?;
+    Ok(())
+}
+
+// When the document under `key` was last written, in Unix milliseconds.
+// None for missing documents.
+pub fn modified_at(db: &Db, key: &str) -> DbResult<Option<u64>> {
+    let Some(ivec) = db.open_tree(MODIFIED_AT_TREE)?.get(key.as_bytes())? else { return Ok(None) };
+    let bytes: [u8; 8] = ivec.as_ref().try_into()?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct GeoPoint {
     pub lat: f64,
     pub lon: f64,
@@ -112,56 +402,269 @@ impl From<GeoPoint> for Coord<f64> {
     fn from(gp: GeoPoint) -> Self { Coord { x: gp.lon, y: gp.lat } }
 }
 
-fn get_geo_sorted_index_key(field_path: &str, geohash: &str, key: &str) -> String {
-    format!("{}{}:{}:{}", GEO_SORTED_INDEX_PREFIX, field_path, geohash, key)
+// Index keys are sequences of components. Within a component 0x00 is escaped
+// as 0x00 0xFF, and every component ends with 0x00 0x01, so components may
+// contain any byte (including ':') and keys sort component by component.
+fn push_escaped(buf: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        buf.push(b);
+        if b == 0x00 {
+            buf.push(0xFF);
+        }
+    }
 }
 
-fn get_geo_sorted_index_prefix_for_hash(field_path: &str, geohash: &str) -> String {
-    format!("{}{}:{}:", GEO_SORTED_INDEX_PREFIX, field_path, geohash)
+fn index_key(components: &[&[u8]]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for component in components {
+        push_escaped(&mut buf, component);
+        buf.extend_from_slice(&[0x00, 0x01]);
+    }
+    buf
 }
 
-fn get_geo_sorted_index_prefix_for_field(field_path: &str) -> String {
-    format!("{}{}:", GEO_SORTED_INDEX_PREFIX, field_path)
+fn split_index_key(key: &[u8]) -> DbResult<Vec<Vec<u8>>> {
+    let invalid = || DbError::InvalidFieldIndexKey(String::from_utf8_lossy(key).into_owned());
+    let mut components = Vec::new();
+    let mut current = Vec::new();
+    let mut bytes = key.iter();
+    while let Some(&b) = bytes.next() {
+        if b != 0x00 {
+            current.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(0xFF) => current.push(0x00),
+            Some(0x01) => components.push(std::mem::take(&mut current)),
+            _ => return Err(invalid()),
+        }
+    }
+    if !current.is_empty() {
+        return Err(invalid());
+    }
+    Ok(components)
 }
 
+// Reads the value and primary key of a `field, value, primary key` entry.
+fn parse_index_entry(key: &[u8]) -> DbResult<(Vec<u8>, String)> {
+    match <[Vec<u8>; 3]>::try_from(split_index_key(key)?) {
+        Ok([_, value, primary_key]) => Ok((value, String::from_utf8(primary_key)?)),
+        Err(_) => Err(DbError::InvalidFieldIndexKey(String::from_utf8_lossy(key).into_owned())),
+    }
+}
 
-// Modified: Include primary_key
-fn get_field_index_key(field_path: &str, value: &str, primary_key: &str) -> String {
-    format!("{}{}:{}:{}", FIELD_INDEX_PREFIX, field_path, value, primary_key)
+// Smallest key greater than every key starting with `prefix`.
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bound = prefix.to_vec();
+    while let Some(last) = bound.pop() {
+        if last < 0xFF {
+            bound.push(last + 1);
+            return Some(bound);
+        }
+    }
+    None
 }
 
-// Added: Prefix for scanning hash index
-fn get_field_index_prefix(field_path: &str, value: &str) -> String {
-    format!("{}{}:{}:", FIELD_INDEX_PREFIX, field_path, value)
+fn get_geo_sorted_index_key(field_path: &str, geohash: &str, key: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes(), geohash.as_bytes(), key.as_bytes()])
 }
 
-fn get_field_sorted_index_key(field_path: &str, encoded_value: &[u8], key: &str) -> String {
-    format!("{}{}:{}:{}", FIELD_SORTED_INDEX_PREFIX, field_path, hex::encode(encoded_value), key)
+// Matches every entry whose geohash starts with `geohash`.
+fn get_geo_sorted_index_prefix_for_hash(field_path: &str, geohash: &str) -> Vec<u8> {
+    let mut prefix = index_key(&[field_path.as_bytes()]);
+    push_escaped(&mut prefix, geohash.as_bytes());
+    prefix
 }
 
-fn get_field_sorted_index_prefix(field_path: &str) -> String {
-    format!("{}{}:", FIELD_SORTED_INDEX_PREFIX, field_path)
+fn get_field_index_key(field_path: &str, value: &str, primary_key: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes(), value.as_bytes(), primary_key.as_bytes()])
 }
 
-fn encode_sorted_value(value: &Value) -> DbResult<Vec<u8>> {
-    let mut buf = Vec::new();
-    match value {
-        Value::Number(num) => {
-            if let Some(i) = num.as_i64() {
-                buf.push(0x01);
-                buf.extend_from_slice(&i.to_be_bytes());
-            } else if let Some(u) = num.as_u64() {
-                buf.push(0x02);
-                buf.extend_from_slice(&u.to_be_bytes());
-            } else if let Some(f) = num.as_f64() {
-                buf.push(0x03);
-                buf.extend_from_slice(&f.to_be_bytes());
+fn get_field_index_prefix(field_path: &str, value: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes(), value.as_bytes()])
+}
+
+// Value components of hash index entries that record a field's presence
+// rather than a value. None of these bytes occurs in UTF-8, so no value
+// collides. The unordered marker flags documents whose field holds something
+// other than one number, string or bool (a null, an array, an object or
+// several values), which a negated comparison cannot be answered for from
+// the sorted index.
+const HASH_UNORDERED_MARKER: &[u8] = &[0xFD];
+const HASH_PRESENT_MARKER: &[u8] = &[0xFE];
+const HASH_MISSING_MARKER: &[u8] = &[0xFF];
+
+fn is_presence_marker(value: &[u8]) -> bool {
+    value == HASH_UNORDERED_MARKER || value == HASH_PRESENT_MARKER || value == HASH_MISSING_MARKER
+}
+
+fn get_unique_index_key(field_path: &str, value: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes(), value.as_bytes()])
+}
+
+// Expiry is big-endian so entries sort by expiry time.
+fn get_ttl_index_key(expires_at: u64, primary_key: &str) -> Vec<u8> {
+    index_key(&[&expires_at.to_be_bytes(), primary_key.as_bytes()])
+}
+
+fn get_field_sorted_index_key(field_path: &str, encoded_value: &[u8], key: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes(), encoded_value, key.as_bytes()])
+}
+
+fn get_field_sorted_index_prefix(field_path: &str) -> Vec<u8> {
+    index_key(&[field_path.as_bytes()])
+}
+
+// Maps an f64 to bits whose unsigned order matches the numeric order: the sign
+// bit of positive values is flipped and every bit of negative values.
+fn ordered_f64_bits(f: f64) -> u64 {
+    let bits = f.to_bits();
+    if bits >> 63 == 0 { bits ^ (1 << 63) } else { !bits }
+}
+
+fn f64_from_ordered_bits(bits: u64) -> f64 {
+    f64::from_bits(if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits })
+}
+
+// Numbers of every representation share one type byte so they interleave in
+// numeric order: the value as an order-preserving f64, then for integral
+// values the exact integer (so large integers that round to the same f64 still
+// order), then whether the number was written as an integer (0) or a float (1).
+fn encode_sorted_number(num: &serde_json::Number, buf: &mut Vec<u8>) -> DbResult<()> {
+    let (float, exact, is_float) = if let Some(i) = num.as_i64() {
+        (i as f64, Some(i as i128), 0)
+    } else if let Some(u) = num.as_u64() {
+        (u as f64, Some(u as i128), 0)
+    } else if let Some(f) = num.as_f64() {
+        let exact = (f.fract() == 0.0 && f.abs() < i128::MAX as f64).then_some(f as i128);
+        (f, exact, 1)
+    } else {
+        return Err(DbError::Serde(serde_json::Error::custom("Unsupported number type")));
+    };
+    buf.push(0x01);
+    // -0.0 orders equal to 0.0
+    let float = if float == 0.0 { 0.0 } else { float };
+    buf.extend_from_slice(&ordered_f64_bits(float).to_be_bytes());
+    if let Some(exact) = exact {
+        buf.extend_from_slice(&((exact as u128) ^ (1 << 127)).to_be_bytes());
+    }
+    buf.push(is_float);
+    Ok(())
+}
+
+// Length of the prefix of an encoded sorted value that determines its order.
+// What follows only preserves the original representation: a number's
+// integer/float flag, or the string of a datetime or collated string.
+fn sorted_order_len(encoded: &[u8]) -> usize {
+    match encoded.first() {
+        Some(0x01) => encoded.len() - 1,
+        Some(0x06) => 9.min(encoded.len()),
+        // The collation key ends at its 0x00 separator.
+        Some(0x07) => encoded.iter().position(|b| *b == 0).map_or(encoded.len(), |i| i + 1),
+        _ => encoded.len(),
+    }
+}
+
+fn fold_accent(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    })
+}
+
+// Bytes whose order is the collation's order of the string. They never
+// contain 0x00, which separates them from the original string in the index.
+fn collation_key(s: &str, collation: &Collation) -> Vec<u8> {
+    let mut folded = String::with_capacity(s.len());
+    for c in s.chars() {
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        match fold_accent(lower).filter(|_| collation.locale) {
+            Some(base) if c != lower && !collation.case_insensitive => folded.push_str(&base.to_uppercase()),
+            Some(base) => folded.push_str(base),
+            None if collation.case_insensitive => folded.extend(c.to_lowercase()),
+            None => folded.push(c),
+        }
+    }
+
+    let mut key = Vec::with_capacity(folded.len());
+    let mut chars = folded.chars().peekable();
+    while let Some(c) = chars.next() {
+        if collation.numeric && c.is_ascii_digit() {
+            // A digit run becomes '0', its length without leading zeros and
+            // its digits, so longer numbers sort later. Lengths below 254 take
+            // one byte (plus one, to avoid 0x00); longer ones 0xFF, then the
+            // length's own digit count (plus one) and its decimal digits.
+            let mut digits = String::new();
+            if c != '0' {
+                digits.push(c);
+            }
+            while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                if !(digits.is_empty() && d == '0') {
+                    digits.push(d);
+                }
+            }
+            key.push(b'0');
+            if digits.len() < 254 {
+                key.push(digits.len() as u8 + 1);
             } else {
-                return Err(DbError::Serde(serde_json::Error::custom("Unsupported number type")));
+                let length = digits.len().to_string();
+                key.push(0xFF);
+                key.push(length.len() as u8 + 1);
+                key.extend_from_slice(length.as_bytes());
             }
+            key.extend_from_slice(digits.as_bytes());
+        } else if c == '\0' {
+            key.push(0x01);
+        } else {
+            let mut utf8 = [0; 4];
+            key.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
         }
+    }
+    key
+}
+
+// Timestamps only encode as instants (0x06) for a datetime field; other
+// strings, and unparseable ones there, encode as text.
+fn encode_sorted_value(value: &Value, collation: Option<&Collation>, datetime: bool) -> DbResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    match value {
+        Value::Number(num) => encode_sorted_number(num, &mut buf)?,
         Value::String(s) => {
-            buf.push(0x04);
+            let micros = if datetime { parse_datetime_micros(s) } else { None };
+            if let Some(micros) = micros {
+                // Sign bit flipped so instants before 1970 order before later ones
+                buf.push(0x06);
+                buf.extend_from_slice(&((micros as u64) ^ (1 << 63)).to_be_bytes());
+            } else if let Some(collation) = collation {
+                // The collation key, then the original string
+                buf.push(0x07);
+                buf.extend_from_slice(&collation_key(s, collation));
+                buf.push(0x00);
+            } else {
+                buf.push(0x04);
+            }
             buf.extend_from_slice(s.as_bytes());
         }
         Value::Bool(b) => {
@@ -179,19 +682,19 @@ fn decode_sorted_value(encoded: &[u8]) -> DbResult<Value> {
     }
     match encoded[0] {
         0x01 => {
-            if encoded.len() < 9 { return Err(DbError::Serde(serde_json::Error::custom("Invalid i64 encoding length"))); }
-            let num = i64::from_be_bytes(encoded[1..9].try_into()?);
-            Ok(Value::Number(num.into()))
-        }
-        0x02 => {
-            if encoded.len() < 9 { return Err(DbError::Serde(serde_json::Error::custom("Invalid u64 encoding length"))); }
-            let num = u64::from_be_bytes(encoded[1..9].try_into()?);
-            Ok(Value::Number(num.into()))
-        }
-        0x03 => {
-            if encoded.len() < 9 { return Err(DbError::Serde(serde_json::Error::custom("Invalid f64 encoding length"))); }
-            let num = f64::from_be_bytes(encoded[1..9].try_into()?);
-            Ok(Value::Number(serde_json::Number::from_f64(num).ok_or_else(|| DbError::Serde(serde_json::Error::custom("Invalid f64")))?) )
+            if encoded.len() != 10 && encoded.len() != 26 { return Err(DbError::Serde(serde_json::Error::custom("Invalid number encoding length"))); }
+            let float = f64_from_ordered_bits(u64::from_be_bytes(encoded[1..9].try_into()?));
+            let is_float = encoded[encoded.len() - 1] != 0;
+            if encoded.len() == 26 && !is_float {
+                let exact = (u128::from_be_bytes(encoded[9..25].try_into()?) ^ (1 << 127)) as i128;
+                if let Ok(i) = i64::try_from(exact) {
+                    return Ok(Value::Number(i.into()));
+                }
+                if let Ok(u) = u64::try_from(exact) {
+                    return Ok(Value::Number(u.into()));
+                }
+            }
+            Ok(Value::Number(serde_json::Number::from_f64(float).ok_or_else(|| DbError::Serde(serde_json::Error::custom("Invalid f64")))?))
         }
         0x04 => {
             let s = String::from_utf8(encoded[1..].to_vec())?;
@@ -201,6 +704,16 @@ fn decode_sorted_value(encoded: &[u8]) -> DbResult<Value> {
             if encoded.len() < 2 { return Err(DbError::Serde(serde_json::Error::custom("Invalid bool encoding length"))); }
             Ok(Value::Bool(encoded[1] != 0))
         }
+        0x07 => {
+            let start = sorted_order_len(encoded);
+            Ok(Value::String(String::from_utf8(encoded[start..].to_vec())?))
+        }
+        0x06 => {
+            // The original string follows the 8-byte instant
+            if encoded.len() < 9 { return Err(DbError::Serde(serde_json::Error::custom("Invalid datetime encoding length"))); }
+            let s = String::from_utf8(encoded[9..].to_vec())?;
+            Ok(Value::String(s))
+        }
         _ => Err(DbError::Serde(serde_json::Error::custom("Unknown type byte"))),
     }
 }
@@ -227,7 +740,42 @@ fn parse_value(value_str: &str) -> DbResult<Value> {
     }
 }
 
-fn compare_values(v1: &Value, v2: &Value) -> Option<Ordering> {
+// Parses an RFC3339 timestamp into microseconds since the Unix epoch.
+fn parse_datetime_micros(s: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.timestamp_micros())
+}
+
+// Reads a TTL timestamp (RFC3339 string or Unix seconds) as Unix seconds.
+fn timestamp_secs(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => parse_datetime_micros(s).map(|micros| micros.div_euclid(1_000_000).max(0) as u64),
+        Value::Number(n) => n.as_f64().filter(|secs| secs.is_finite()).map(|secs| secs.max(0.0) as u64),
+        _ => None,
+    }
+}
+
+// Reads a number or a numeric string as f64.
+fn coerce_numeric(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+// Like compare_values, but compares a number and a numeric string numerically.
+fn compare_values_coerced(v1: &Value, v2: &Value, datetime: bool) -> Option<Ordering> {
+    match (v1, v2) {
+        (Value::Number(_), Value::String(_)) | (Value::String(_), Value::Number(_)) => {
+            coerce_numeric(v1)?.partial_cmp(&coerce_numeric(v2)?)
+        }
+        _ => compare_values(v1, v2, datetime),
+    }
+}
+
+// With `datetime`, strings compare as instants, and not at all unless both
+// are timestamps, as in the sorted index of a datetime field.
+fn compare_values(v1: &Value, v2: &Value, datetime: bool) -> Option<Ordering> {
     match (v1, v2) {
         (Value::Number(n1), Value::Number(n2)) => {
             if let (Some(f1), Some(f2)) = (n1.as_f64(), n2.as_f64()) {
@@ -236,26 +784,126 @@ fn compare_values(v1: &Value, v2: &Value) -> Option<Ordering> {
                 None
             }
         }
+        (Value::String(s1), Value::String(s2)) if datetime => Some(parse_datetime_micros(s1)?.cmp(&parse_datetime_micros(s2)?)),
         (Value::String(s1), Value::String(s2)) => s1.partial_cmp(s2),
         (Value::Bool(b1), Value::Bool(b2)) => b1.partial_cmp(b2),
         (Value::Null, Value::Null) => Some(Ordering::Equal),
-        _ => {
-            if std::mem::discriminant(v1) != std::mem::discriminant(v2) {
-                 None
-            } else {
-                 None
+        _ => None,
+    }
+}
+
+// Matches a concrete document path against an index path where a `*`
+// segment stands for any array element or object field (e.g. `items.*.sku`).
+fn path_matches_pattern(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('*') {
+        return pattern == path;
+    }
+    let mut pattern_parts = pattern.split('.');
+    let mut path_parts = path.split('.');
+    loop {
+        match (pattern_parts.next(), path_parts.next()) {
+            (None, None) => return true,
+            (Some(p), Some(s)) if p == "*" || p == s => continue,
+            _ => return false,
+        }
+    }
+}
+
+// Indexed fields can be scoped to a collection of keys: "users:*/email"
+// indexes the `email` field of documents whose key starts with "users:" only.
+// The scope is part of the index name, so collections don't share index
+// entries or unique constraints.
+const INDEX_SCOPE_SEPARATOR: &str = "*/";
+
+// The field path of an indexed field, without its key scope.
+fn index_field_path(field: &str) -> &str {
+    field.split_once(INDEX_SCOPE_SEPARATOR).map_or(field, |(_, path)| path)
+}
+
+// The field path of an indexed field, if the key is inside its scope.
+fn scoped_field_path<'a>(field: &'a str, key: &str) -> Option<&'a str> {
+    match field.split_once(INDEX_SCOPE_SEPARATOR) {
+        Some((prefix, path)) => key.starts_with(prefix).then_some(path),
+        None => Some(field),
+    }
+}
+
+// Returns the configured index paths that a concrete document path of the key is indexed under.
+fn matching_index_paths<'a>(fields: &'a HashSet<String>, key: &str, path: &str) -> Vec<&'a String> {
+    fields.iter()
+        .filter(|field| scoped_field_path(field, key).is_some_and(|pattern| path_matches_pattern(pattern, path)))
+        .collect()
+}
+
+// Earliest expiry among the TTL fields matching the path, if the value is a timestamp.
+fn ttl_expiry(config: &DbConfig, key: &str, path: &str, value: &Value) -> Option<u64> {
+    let ttl = config.ttl_fields.iter()
+        .filter(|(field, _)| scoped_field_path(field, key).is_some_and(|pattern| path_matches_pattern(pattern, path)))
+        .map(|(_, ttl)| *ttl)
+        .min()?;
+    timestamp_secs(value).map(|secs| secs.saturating_add(ttl))
+}
+
+// One secondary index entry derived from a document.
+#[derive(PartialEq, Eq, Hash)]
+struct IndexEntry {
+    tree: &'static str,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl TxTrees<'_> {
+    fn index_tree(&self, tree_name: &str) -> &TransactionalTree {
+        match tree_name {
+            FIELD_INDEX_TREE => self.hash,
+            FIELD_SORTED_INDEX_TREE => self.sorted,
+            GEO_SORTED_INDEX_TREE => self.geo,
+            UNIQUE_INDEX_TREE => self.unique,
+            _ => self.ttl,
+        }
+    }
+}
+
+// Collects every index entry of a document.
+fn collect_index_entries(key: &str, value: &Value, config: &DbConfig, entries: &mut Vec<IndexEntry>) -> DbResult<()> {
+    collect_value_entries(key, "", value, config, entries)?;
+    collect_computed_entries(key, value, config, entries);
+    collect_presence_entries(key, value, config, entries);
+    // A document's own expiry shares the TTL index with the TTL fields.
+    if let Some(expires_at) = value.get(EXPIRES_AT_FIELD).and_then(timestamp_secs) {
+        entries.push(IndexEntry { tree: TTL_INDEX_TREE, key: get_ttl_index_key(expires_at, key), value: vec![] });
+    }
+    Ok(())
+}
+
+// Entries of indexes defined over expressions, keyed by the expression text.
+// Documents for which the expression has no scalar value are not indexed.
+fn collect_computed_entries(key: &str, value: &Value, config: &DbConfig, entries: &mut Vec<IndexEntry>) {
+    // Each expression is evaluated once, whatever the indexes and scopes using it.
+    let expressions: HashSet<&str> = config.hash_indexed_fields.iter()
+        .chain(&config.sorted_indexed_fields)
+        .chain(&config.unique_fields)
+        .filter_map(|field| scoped_field_path(field, key))
+        .collect();
+    for expression in expressions {
+        let Some(expr) = parse_index_expr(expression).ok().flatten() else { continue };
+        match expr.evaluate(value) {
+            Some(computed) if !computed.is_object() && !computed.is_array() => {
+                collect_scalar_entries(key, expression, &computed, false, config, entries);
             }
+            _ => {}
         }
     }
 }
 
-fn index_value_recursive(
-    tx_db: &TransactionalTree,
+// Collects every index entry the configuration derives from the value at
+// `current_path` of the document stored under `key`.
+fn collect_value_entries(
     key: &str, // primary key
     current_path: &str,
     value: &Value,
     config: &DbConfig,
-    batch: &mut Batch,
+    entries: &mut Vec<IndexEntry>,
 ) -> DbResult<()> {
     match value {
         Value::Object(map) => {
@@ -266,185 +914,867 @@ fn index_value_recursive(
                     format!("{}.{}", current_path, field_name)
                 };
 
-                if config.geo_indexed_fields.contains(&new_path) {
-                    if let Ok(geo_point) = serde_json::from_value::<GeoPoint>(field_value.clone()) {
-                        index_geospatial_field(tx_db, key, &new_path, &geo_point)?;
+                for geo_path in matching_index_paths(&config.geo_indexed_fields, key, &new_path) {
+                    if let Some(geometry) = parse_geometry(field_value) {
+                        for cell in geometry_cells(&geometry, config.geohash_precision_for(geo_path))? {
+                            entries.push(IndexEntry { tree: GEO_SORTED_INDEX_TREE, key: get_geo_sorted_index_key(geo_path, &cell, key), value: vec![] });
+                        }
                     } else if !field_value.is_null() {
-                         warn!(key=key, path=%new_path, "Field configured for geo indexing is not a valid GeoPoint or null");
+                         warn!(key=key, path=%new_path, "Field configured for geo indexing is not a valid GeoPoint, geometry or null");
                     }
                 }
 
-                index_value_recursive(tx_db, key, &new_path, field_value, config, batch)?;
+                collect_value_entries(key, &new_path, field_value, config, entries)?;
             }
         }
         Value::Array(arr) => {
             for (index, elem) in arr.iter().enumerate() {
                 let index_path = format!("{}.{}", current_path, index); // Path to the element itself
-                index_value_recursive(tx_db, key, &index_path, elem, config, batch)?;
+                collect_value_entries(key, &index_path, elem, config, entries)?;
 
                 // Index primitive values within the array against the array's path
-                if config.hash_indexed_fields.contains(current_path) {
-                     if !elem.is_object() && !elem.is_array() { // Only index primitives directly
-                         let elem_str = elem.to_string().trim_matches('"').to_string();
-                         // Modified: Use new key format, insert empty value
-                         let index_key = get_field_index_key(current_path, &elem_str, key);
-                         batch.insert(index_key.as_bytes(), vec![]);
-                     }
-                }
-                 // Index sortable primitive values within the array against the array's path
-                 if config.sorted_indexed_fields.contains(current_path) {
-                     if let Ok(encoded) = encode_sorted_value(elem) {
-                         let sorted_index_key = get_field_sorted_index_key(current_path, &encoded, key);
-                         batch.insert(sorted_index_key.as_bytes(), vec![]);
-                     }
-                 }
-            }
-        }
-        _ => { // Primitive value
-            if config.hash_indexed_fields.contains(current_path) {
-                let value_str = value.to_string().trim_matches('"').to_string();
-                // Modified: Use new key format, insert empty value
-                let index_key = get_field_index_key(current_path, &value_str, key);
-                batch.insert(index_key.as_bytes(), vec![]);
-            }
-            if config.sorted_indexed_fields.contains(current_path) {
-                if let Ok(encoded) = encode_sorted_value(value) {
-                    let sorted_index_key = get_field_sorted_index_key(current_path, &encoded, key);
-                    batch.insert(sorted_index_key.as_bytes(), vec![]);
+                if !elem.is_object() && !elem.is_array() {
+                    collect_scalar_entries(key, current_path, elem, true, config, entries);
                 }
             }
         }
+        _ => collect_scalar_entries(key, current_path, value, false, config, entries),
     }
     Ok(())
 }
 
-fn remove_indices_recursive(
-    tx_db: &TransactionalTree,
-    key: &str, // primary key
-    current_path: &str,
-    value: &Value,
-    config: &DbConfig,
-    batch: &mut Batch,
-) -> DbResult<()> {
-     match value {
-        Value::Object(map) => {
-            for (field_name, field_value) in map {
-                let new_path = if current_path.is_empty() {
-                    field_name.clone()
-                } else {
-                    format!("{}.{}", current_path, field_name)
-                };
-
-                if config.geo_indexed_fields.contains(&new_path) {
-                    if let Ok(geo_point) = serde_json::from_value::<GeoPoint>(field_value.clone()) {
-                         remove_geospatial_index(tx_db, key, &new_path, &geo_point)?;
-                    }
-                }
-
-                remove_indices_recursive(tx_db, key, &new_path, field_value, config, batch)?;
-            }
+// Gives hash-indexed fields without value entries a presence marker, so Exists
+// is answered from the index alone: documents missing the field get the
+// missing marker unless the index is sparse, and fields holding nothing
+// indexable (e.g. an empty array) get the present marker. Fields not holding
+// a single orderable scalar also get the unordered marker.
+fn collect_presence_entries(key: &str, value: &Value, config: &DbConfig, entries: &mut Vec<IndexEntry>) {
+    for field_path in &config.hash_indexed_fields {
+        let Some(path) = scoped_field_path(field_path, key) else { continue };
+        let field_prefix = index_key(&[field_path.as_bytes()]);
+        let value_entries = entries.iter().filter(|entry| entry.tree == FIELD_INDEX_TREE && entry.key.starts_with(&field_prefix)).count();
+        let present = field_present(value, path);
+        if (value_entries > 0 || present) && !holds_one_scalar(value, path, value_entries) {
+            entries.push(IndexEntry { tree: FIELD_INDEX_TREE, key: index_key(&[field_path.as_bytes(), HASH_UNORDERED_MARKER, key.as_bytes()]), value: vec![] });
         }
-        Value::Array(arr) => {
-            for (index, elem) in arr.iter().enumerate() {
-                let index_path = format!("{}.{}", current_path, index);
-                remove_indices_recursive(tx_db, key, &index_path, elem, config, batch)?;
-
-                 if config.hash_indexed_fields.contains(current_path) {
-                     if !elem.is_object() && !elem.is_array() {
-                         let elem_str = elem.to_string().trim_matches('"').to_string();
-                         // Modified: Use new key format for removal
-                         let index_key = get_field_index_key(current_path, &elem_str, key);
-                         batch.remove(index_key.as_bytes());
-                     }
-                 }
-                 if config.sorted_indexed_fields.contains(current_path) {
-                     if let Ok(encoded) = encode_sorted_value(elem) {
-                         let sorted_index_key = get_field_sorted_index_key(current_path, &encoded, key);
-                         batch.remove(sorted_index_key.as_bytes());
-                     }
-                 }
-            }
+        if value_entries > 0 {
+            continue;
         }
-        _ => { // Primitive value
-            if config.hash_indexed_fields.contains(current_path) {
-                let value_str = value.to_string().trim_matches('"').to_string();
-                // Modified: Use new key format for removal
-                let index_key = get_field_index_key(current_path, &value_str, key);
-                batch.remove(index_key.as_bytes());
-            }
-            if config.sorted_indexed_fields.contains(current_path) {
-                if let Ok(encoded) = encode_sorted_value(value) {
-                    let sorted_index_key = get_field_sorted_index_key(current_path, &encoded, key);
-                    batch.remove(sorted_index_key.as_bytes());
+        let marker = if present {
+            HASH_PRESENT_MARKER
+        } else if config.sparse_fields.contains(field_path) {
+            continue;
+        } else {
+            HASH_MISSING_MARKER
+        };
+        entries.push(IndexEntry { tree: FIELD_INDEX_TREE, key: index_key(&[field_path.as_bytes(), marker, key.as_bytes()]), value: vec![] });
+    }
+}
+
+// Whether the field is a single number, string or bool. Arrays and nested
+// array paths yield an entry per element, so the entry count must agree.
+fn holds_one_scalar(doc: &Value, field_path: &str, value_entries: usize) -> bool {
+    let values = match parse_index_expr(field_path).ok().flatten() {
+        Some(expr) => expr.evaluate(doc).into_iter().collect(),
+        None => get_values_by_path(doc, field_path).into_iter().cloned().collect::<Vec<_>>(),
+    };
+    value_entries == 1 && matches!(values.as_slice(), [Value::Number(_) | Value::String(_) | Value::Bool(_)])
+}
+
+fn field_present(doc: &Value, field_path: &str) -> bool {
+    if let Some(expr) = parse_index_expr(field_path).ok().flatten() {
+        return expr.evaluate(doc).is_some();
+    }
+    !get_values_by_path(doc, field_path).is_empty()
+}
+
+const INDEX_EXPR_FUNCTIONS: [&str; 4] = ["lower", "upper", "len", "abs"];
+
+// A value computed from a document, e.g. `lower(email)`, `len(tags)` or
+// `price * quantity`. Fields, indexes and queries refer to it by its text.
+// Binary operators must be surrounded by spaces, as `*` is also the path
+// wildcard and `-` may appear in field names.
+#[derive(Debug, Clone, PartialEq)]
+enum IndexExpr {
+    Path(String),
+    Number(serde_json::Number),
+    Call(String, Box<IndexExpr>),
+    Binary(char, Box<IndexExpr>, Box<IndexExpr>),
+}
+
+impl IndexExpr {
+    // None when a path is missing or the operands don't fit the operation.
+    fn evaluate(&self, doc: &Value) -> Option<Value> {
+        match self {
+            IndexExpr::Path(path) => get_value_by_path(doc, path).cloned(),
+            IndexExpr::Number(num) => Some(Value::Number(num.clone())),
+            IndexExpr::Call(function, arg) => match (function.as_str(), arg.evaluate(doc)?) {
+                ("lower", Value::String(s)) => Some(Value::String(s.to_lowercase())),
+                ("upper", Value::String(s)) => Some(Value::String(s.to_uppercase())),
+                ("len", Value::String(s)) => Some(Value::from(s.chars().count())),
+                ("len", Value::Array(arr)) => Some(Value::from(arr.len())),
+                ("len", Value::Object(map)) => Some(Value::from(map.len())),
+                ("abs", Value::Number(num)) => match num.as_i64() {
+                    Some(i) => i.checked_abs().map(Value::from),
+                    None => num.as_f64().and_then(|f| serde_json::Number::from_f64(f.abs())).map(Value::Number),
+                },
+                _ => None,
+            },
+            IndexExpr::Binary(op, lhs, rhs) => {
+                let (Value::Number(a), Value::Number(b)) = (lhs.evaluate(doc)?, rhs.evaluate(doc)?) else {
+                    return None;
+                };
+                // Integers stay exact unless they overflow or are divided
+                let exact = match (a.as_i64(), b.as_i64()) {
+                    (Some(x), Some(y)) => match op {
+                        '+' => x.checked_add(y),
+                        '-' => x.checked_sub(y),
+                        '*' => x.checked_mul(y),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(result) = exact {
+                    return Some(Value::from(result));
                 }
+                let (x, y) = (a.as_f64()?, b.as_f64()?);
+                let result = match op {
+                    '+' => x + y,
+                    '-' => x - y,
+                    '*' => x * y,
+                    _ if y == 0.0 => return None,
+                    _ => x / y,
+                };
+                serde_json::Number::from_f64(result).map(Value::Number)
             }
         }
     }
-    Ok(())
 }
 
+// Parses `text` as an index expression, or returns None if it is a plain
+// field path (no call and no space-separated operator).
+fn parse_index_expr(text: &str) -> DbResult<Option<IndexExpr>> {
+    let is_operator = |token: &str| matches!(token, "+" | "-" | "*" | "/");
+    if !text.contains('(') && !text.split_whitespace().any(is_operator) {
+        return Ok(None);
+    }
 
-fn set_key_internal(tx_db: &TransactionalTree, key: &str, value: &Value, config: &DbConfig) -> DbResult<()> { // Take value by reference
-    let serialized_value = serde_json::to_vec(value)?;
-    let key_bytes = key.as_bytes();
-    let mut removal_batch = Batch::default();
-    let mut creation_batch = Batch::default();
-
-    if let Some(old_ivec) = tx_db.get(key_bytes)? {
-        if let Ok(old_val) = serde_json::from_slice::<Value>(&old_ivec) {
-             remove_indices_recursive(tx_db, key, "", &old_val, config, &mut removal_batch)?;
+    let mut tokens = Vec::new();
+    for word in text.split_whitespace() {
+        let mut start = 0;
+        for (i, c) in word.char_indices() {
+            if c == '(' || c == ')' {
+                if start < i {
+                    tokens.push(&word[start..i]);
+                }
+                tokens.push(&word[i..i + 1]);
+                start = i + 1;
+            }
+        }
+        if start < word.len() {
+            tokens.push(&word[start..]);
         }
     }
 
-    tx_db.apply_batch(&removal_batch)?;
-    tx_db.insert(key_bytes, serialized_value.clone())?;
-    index_value_recursive(tx_db, key, "", value, config, &mut creation_batch)?; // Pass reference
-    tx_db.apply_batch(&creation_batch)?;
-    Ok(())
+    let mut pos = 0;
+    let expr = parse_expr_sum(&tokens, &mut pos)?;
+    match tokens.get(pos) {
+        None => Ok(Some(expr)),
+        Some(token) => Err(DbError::InvalidExpression(format!("Unexpected '{}' in '{}'", token, text))),
+    }
 }
 
-pub fn set_key(db: &Db, key: &str, value: Value, config: &DbConfig) -> DbResult<()> {
-    db.transaction(|tx_db| {
-        // Clone value here as it's moved into the closure
-        set_key_internal(tx_db, key, &value, config).map_err(ConflictableTransactionError::Abort)
-    })?;
-    Ok(())
+fn parse_expr_sum(tokens: &[&str], pos: &mut usize) -> DbResult<IndexExpr> {
+    let mut expr = parse_expr_product(tokens, pos)?;
+    while let Some(op @ ("+" | "-")) = tokens.get(*pos).copied() {
+        *pos += 1;
+        expr = IndexExpr::Binary(op.chars().next().unwrap_or('+'), Box::new(expr), Box::new(parse_expr_product(tokens, pos)?));
+    }
+    Ok(expr)
 }
 
-// Modified: Make fields public
-#[derive(Deserialize, Debug)]
-pub struct BatchSetItem {
-    pub key: String,
-    pub value: Value,
+fn parse_expr_product(tokens: &[&str], pos: &mut usize) -> DbResult<IndexExpr> {
+    let mut expr = parse_expr_operand(tokens, pos)?;
+    while let Some(op @ ("*" | "/")) = tokens.get(*pos).copied() {
+        *pos += 1;
+        expr = IndexExpr::Binary(op.chars().next().unwrap_or('*'), Box::new(expr), Box::new(parse_expr_operand(tokens, pos)?));
+    }
+    Ok(expr)
 }
 
-pub fn batch_set(db: &Db, items: &[BatchSetItem], config: &DbConfig) -> DbResult<()> { // Take slice
-     db.transaction(|tx_db| {
-         for item in items { // Iterate over slice
-             set_key_internal(tx_db, &item.key, &item.value, config) // Pass references
-                 .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Batch set failed for key '{}': {}", item.key, e))))?;
-         }
-         Ok(())
-     })?;
-     Ok(())
+fn parse_expr_operand(tokens: &[&str], pos: &mut usize) -> DbResult<IndexExpr> {
+    let token = *tokens.get(*pos).ok_or_else(|| DbError::InvalidExpression("Unexpected end of expression".to_string()))?;
+    *pos += 1;
+    let inner = |pos: &mut usize| -> DbResult<IndexExpr> {
+        let expr = parse_expr_sum(tokens, pos)?;
+        if tokens.get(*pos) != Some(&")") {
+            return Err(DbError::InvalidExpression("Missing ')'".to_string()));
+        }
+        *pos += 1;
+        Ok(expr)
+    };
+    match token {
+        "(" => inner(pos),
+        ")" | "+" | "-" | "*" | "/" => Err(DbError::InvalidExpression(format!("Unexpected '{}'", token))),
+        _ if tokens.get(*pos) == Some(&"(") => {
+            if !INDEX_EXPR_FUNCTIONS.contains(&token) {
+                return Err(DbError::InvalidExpression(format!("Unknown function '{}'", token)));
+            }
+            *pos += 1;
+            Ok(IndexExpr::Call(token.to_string(), Box::new(inner(pos)?)))
+        }
+        _ => match serde_json::from_str::<serde_json::Number>(token) {
+            Ok(num) => Ok(IndexExpr::Number(num)),
+            Err(_) => Ok(IndexExpr::Path(token.to_string())),
+        },
+    }
 }
 
-fn delete_key_internal(tx_db: &TransactionalTree, key: &str, config: &DbConfig) -> DbResult<()> {
-    let key_bytes = key.as_bytes();
-    if let Some(ivec) = tx_db.get(key_bytes)? {
-        let mut removal_batch = Batch::default();
-        if let Ok(val) = serde_json::from_slice::<Value>(&ivec) {
-             remove_indices_recursive(tx_db, key, "", &val, config, &mut removal_batch)?;
-        }
-        removal_batch.remove(key_bytes);
-        tx_db.apply_batch(&removal_batch)?;
+// The value at a field path, or the value of an index expression.
+fn field_value<'a>(doc: &'a Value, field_path: &str) -> Option<Cow<'a, Value>> {
+    match parse_index_expr(field_path).ok().flatten() {
+        Some(expr) => expr.evaluate(doc).map(Cow::Owned),
+        None => get_value_by_path(doc, field_path).map(Cow::Borrowed),
     }
-    Ok(())
+}
+
+// The form of a value in the hash and unique indexes. Numbers, and strings
+// holding a JSON number, are written in one form per numeric value so that
+// 1, 1.0 and "1" share an entry.
+fn hash_index_value(value: &Value) -> String {
+    canonical_number(value).unwrap_or_else(|| value.to_string().trim_matches('"').to_string())
+}
+
+fn canonical_number(value: &Value) -> Option<String> {
+    let number = match value {
+        Value::Number(n) => n.clone(),
+        Value::String(s) => serde_json::from_str::<serde_json::Number>(s).ok()?,
+        _ => return None,
+    };
+    Some(match (number.as_i64(), number.as_u64(), number.as_f64()) {
+        (Some(i), _, _) => i.to_string(),
+        (_, Some(u), _) => u.to_string(),
+        // Also matches -0.0, which would otherwise be written "-0".
+        (_, _, Some(0.0)) => "0".to_string(),
+        // f64's Display writes integral values without a fraction ("1", not "1.0").
+        (_, _, Some(f)) => f.to_string(),
+        _ => number.to_string(),
+    })
+}
+
+// Sorted index entries of array elements carry this value, so the entry of
+// `[5]` is not read back as the scalar `5`.
+const SORTED_ARRAY_ELEMENT: &[u8] = &[1];
+
+fn collect_scalar_entries(key: &str, path: &str, value: &Value, in_array: bool, config: &DbConfig, entries: &mut Vec<IndexEntry>) {
+    let value_str = hash_index_value(value);
+    for index_path in matching_index_paths(&config.hash_indexed_fields, key, path) {
+        entries.push(IndexEntry { tree: FIELD_INDEX_TREE, key: get_field_index_key(index_path, &value_str, key), value: vec![] });
+    }
+    // Nulls are never considered duplicates.
+    if !value.is_null() {
+        for unique_path in matching_index_paths(&config.unique_fields, key, path) {
+            entries.push(IndexEntry { tree: UNIQUE_INDEX_TREE, key: get_unique_index_key(unique_path, &value_str), value: key.as_bytes().to_vec() });
+        }
+    }
+    if let Some(expires_at) = ttl_expiry(config, key, path, value) {
+        entries.push(IndexEntry { tree: TTL_INDEX_TREE, key: get_ttl_index_key(expires_at, key), value: vec![] });
+    }
+    for index_path in matching_index_paths(&config.sorted_indexed_fields, key, path) {
+        if let Ok(encoded) = encode_sorted_value(value, config.collations.get(index_path), config.datetime_fields.contains(index_path)) {
+            let entry_value = if in_array { SORTED_ARRAY_ELEMENT.to_vec() } else { vec![] };
+            entries.push(IndexEntry { tree: FIELD_SORTED_INDEX_TREE, key: get_field_sorted_index_key(index_path, &encoded, key), value: entry_value });
+        }
+    }
+}
+
+// Writes the document's index entries, aborting if a unique value is already
+// owned by another document.
+fn index_document(tx: &TxTrees, key: &str, value: &Value, config: &DbConfig) -> DbResult<()> {
+    let mut entries = Vec::new();
+    collect_index_entries(key, value, config, &mut entries)?;
+    insert_index_entries(tx, &entries)
+}
+
+fn insert_index_entries<'a>(tx: &TxTrees, entries: impl IntoIterator<Item = &'a IndexEntry>) -> DbResult<()> {
+    for entry in entries {
+        if entry.tree == UNIQUE_INDEX_TREE {
+            if let Some(owner) = tx.unique.get(entry.key.as_slice())? {
+                if owner != entry.value {
+                    return Err(unique_violation(&entry.key, &owner));
+                }
+            }
+        }
+        tx.index_tree(entry.tree).insert(entry.key.as_slice(), entry.value.as_slice())?;
+    }
+    Ok(())
+}
+
+fn unique_violation(unique_key: &[u8], owner: &[u8]) -> DbError {
+    let components = split_index_key(unique_key).unwrap_or_default();
+    let component = |i: usize| components.get(i).map(|c| String::from_utf8_lossy(c).into_owned()).unwrap_or_default();
+    DbError::UniqueViolation(format!(
+        "value '{}' of field '{}' is already used by key '{}'",
+        component(1), component(0), String::from_utf8_lossy(owner)
+    ))
+}
+
+// Removes the document's index entries. Unique entries are only removed while
+// the document still owns them.
+fn unindex_document(tx: &TxTrees, key: &str, value: &Value, config: &DbConfig) -> DbResult<()> {
+    let mut entries = Vec::new();
+    collect_index_entries(key, value, config, &mut entries)?;
+    remove_index_entries(tx, &entries)
+}
+
+fn remove_index_entries<'a>(tx: &TxTrees, entries: impl IntoIterator<Item = &'a IndexEntry>) -> DbResult<()> {
+    for entry in entries {
+        if entry.tree == UNIQUE_INDEX_TREE && tx.unique.get(entry.key.as_slice())?.is_some_and(|owner| owner != entry.value) {
+            continue;
+        }
+        tx.index_tree(entry.tree).remove(entry.key.as_slice())?;
+    }
+    Ok(())
+}
+
+// Moves the index from the old to the new version of a document, touching
+// only the entries that differ, so paths the change left alone keep their
+// entries as they are.
+fn reindex_document(tx: &TxTrees, key: &str, old_value: &Value, new_value: &Value, config: &DbConfig) -> DbResult<()> {
+    let (mut old_entries, mut new_entries) = (Vec::new(), Vec::new());
+    collect_index_entries(key, old_value, config, &mut old_entries)?;
+    collect_index_entries(key, new_value, config, &mut new_entries)?;
+    let old_set: HashSet<&IndexEntry> = old_entries.iter().collect();
+    let new_set: HashSet<&IndexEntry> = new_entries.iter().collect();
+    remove_index_entries(tx, old_set.difference(&new_set).copied())?;
+    insert_index_entries(tx, new_set.difference(&old_set).copied())
+}
+
+// Stamps an object document with the revision after the stored one (0 when
+// there is none). A `_rev` sent by the caller must equal the stored revision,
+// so a write based on a stale read fails; `_rev: 0` only creates the key.
+fn with_next_revision<'a>(key: &str, value: &'a Value, old_value: Option<&Value>) -> DbResult<Cow<'a, Value>> {
+    let Value::Object(map) = value else { return Ok(Cow::Borrowed(value)) };
+    let stored = old_value.and_then(|old| old.get(REVISION_FIELD)).and_then(Value::as_u64).unwrap_or(0);
+    if let Some(expected) = map.get(REVISION_FIELD) {
+        if expected.as_u64() != Some(stored) {
+            return Err(DbError::RevisionConflict(format!("key '{}' is at revision {}, not {}", key, stored, expected)));
+        }
+    }
+    let mut map = map.clone();
+    map.insert(REVISION_FIELD.to_string(), json!(stored + 1));
+    Ok(Cow::Owned(Value::Object(map)))
+}
+
+fn set_key_internal(tx: &TxTrees, key: &str, value: &Value, config: &DbConfig) -> DbResult<()> { // Take value by reference
+    let old_value = match tx.docs.get(key.as_bytes())? {
+        Some(old_ivec) => serde_json::from_slice::<Value>(&old_ivec).ok(),
+        None => None,
+    };
+    let value = with_next_revision(key, value, old_value.as_ref())?;
+    store_document(tx, key, &value, old_value.as_ref(), config)
+}
+
+// Stores the document as given, moving its index entries off `old_value`.
+fn store_document(tx: &TxTrees, key: &str, value: &Value, old_value: Option<&Value>, config: &DbConfig) -> DbResult<()> {
+    if let Some(old_val) = old_value {
+        unindex_document(tx, key, old_val, config)?;
+    }
+
+    tx.docs.insert(key.as_bytes(), serde_json::to_vec(value)?)?;
+    touch_document(tx, key)?;
+    log_change(tx, || ChangeEvent::Set { key: key.to_string(), value: value.clone() })?;
+    index_document(tx, key, value, config)?; // Pass reference
+    Ok(())
+}
+
+pub fn set_key(db: &Db, key: &str, value: Value, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        // Clone value here as it's moved into the closure
+        set_key_internal(tx, key, &value, config).map_err(ConflictableTransactionError::Abort)
+    })?;
+    Ok(())
+}
+
+// Read-modify-write of one document: `f` receives the stored value (None if
+// the key is absent) and returns the value to store, or None to delete the
+// key. Runs inside a transaction, so `f` may be called again when a
+// concurrent write conflicts; after `CAS_RETRY_LIMIT` retries the update
+// fails with `CasRetryLimit`. Returns the value as stored.
+pub fn update_with<F>(db: &Db, key: &str, f: F, config: &DbConfig) -> DbResult<Option<Value>>
+where
+    F: Fn(Option<Value>) -> DbResult<Option<Value>>,
+{
+    let attempts = Cell::new(0u32);
+    transaction(db, |tx| {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() > CAS_RETRY_LIMIT + 1 {
+            return Err(ConflictableTransactionError::Abort(DbError::CasRetryLimit(format!(
+                "update of key '{}' still conflicting after {} retries", key, CAS_RETRY_LIMIT
+            ))));
+        }
+        let updated = f(read_tx_document(tx, key)?).map_err(ConflictableTransactionError::Abort)?;
+        match &updated {
+            Some(value) => set_key_internal(tx, key, value, config),
+            None => delete_key_internal(tx, key, config),
+        }.map_err(ConflictableTransactionError::Abort)?;
+        read_tx_document(tx, key)
+    })
+}
+
+// Sets the key only if it is absent, failing with `AlreadyExists` otherwise.
+pub fn set_nx(db: &Db, key: &str, value: Value, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        if tx.docs.get(key.as_bytes())?.is_some() {
+            return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(key.to_string())));
+        }
+        set_key_internal(tx, key, &value, config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+// Sets the value at a dot-separated path of the stored document, creating
+// missing objects and arrays on the way (an absent key starts from an empty
+// object). Only the index entries the change affects are rewritten.
+pub fn set_path(db: &Db, key: &str, path: &str, value: Value, config: &DbConfig) -> DbResult<()> {
+    let path_parts = split_field_path(path)?;
+    transaction(db, |tx| {
+        let old_value = read_tx_document(tx, key)?;
+        set_path_internal(tx, key, old_value.as_ref(), &path_parts, value.clone(), config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+fn split_field_path(path: &str) -> DbResult<Vec<&str>> {
+    let path_parts: Vec<&str> = path.split('.').collect();
+    if path_parts.iter().any(|part| part.is_empty()) {
+        return Err(DbError::InvalidPath(format!("Invalid path '{}'", path)));
+    }
+    Ok(path_parts)
+}
+
+fn set_path_internal(tx: &TxTrees, key: &str, old_value: Option<&Value>, path_parts: &[&str], value: Value, config: &DbConfig) -> DbResult<()> {
+    let mut document = old_value.cloned().unwrap_or_else(|| Value::Object(Map::new()));
+    insert_value_by_path(&mut document, path_parts, value)?;
+    let document = with_next_revision(key, &document, old_value)?;
+    tx.docs.insert(key.as_bytes(), serde_json::to_vec(&document)?)?;
+    touch_document(tx, key)?;
+    log_change(tx, || ChangeEvent::Set { key: key.to_string(), value: document.clone().into_owned() })?;
+    match old_value {
+        Some(old_value) => reindex_document(tx, key, old_value, &document, config),
+        None => index_document(tx, key, &document, config),
+    }
+}
+
+
+// A change to the array at a path of a document. Elements are compared the
+// way `Eq` queries compare values.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ArrayOperation {
+    Push { value: Value },
+    // Pushes the value unless the array already holds an equal element.
+    PushUnique { value: Value },
+    // Removes the last element.
+    Pop,
+    // Removes every element equal to the value.
+    Remove { value: Value },
+    // Inserts the value before the element at `index`; the array length appends.
+    Insert { index: usize, value: Value },
+}
+
+// Applies an array operation to the array at `path` of the document. A
+// missing or null field is an empty array, and an absent key starts from an
+// empty object. Returns the popped element (null if the array was empty)
+// for `Pop` and the resulting array otherwise.
+pub fn array_op(db: &Db, key: &str, path: &str, operation: &ArrayOperation, config: &DbConfig) -> DbResult<Value> {
+    let path_parts = split_field_path(path)?;
+    transaction(db, |tx| {
+        let old_value = read_tx_document(tx, key)?;
+        array_op_internal(tx, key, old_value.as_ref(), &path_parts, operation, config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+fn array_op_internal(tx: &TxTrees, key: &str, old_value: Option<&Value>, path_parts: &[&str], operation: &ArrayOperation, config: &DbConfig) -> DbResult<Value> {
+    let path = path_parts.join(".");
+    let mut array = match old_value.and_then(|doc| get_value_by_path(doc, &path)) {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(arr)) => arr.clone(),
+        Some(_) => return Err(DbError::InvalidPath(format!("Value at '{}' of key '{}' is not an array", path, key))),
+    };
+    let equal = |a: &Value, b: &Value| evaluate_condition_on_value(a, "Eq", b, false, false);
+    let mut popped = Value::Null;
+    let changed = match operation {
+        ArrayOperation::Push { value } => {
+            array.push(value.clone());
+            true
+        }
+        ArrayOperation::PushUnique { value } => {
+            let missing = !array.iter().any(|element| equal(element, value));
+            if missing {
+                array.push(value.clone());
+            }
+            missing
+        }
+        ArrayOperation::Pop => {
+            let element = array.pop();
+            let changed = element.is_some();
+            popped = element.unwrap_or(Value::Null);
+            changed
+        }
+        ArrayOperation::Remove { value } => {
+            let before = array.len();
+            array.retain(|element| !equal(element, value));
+            array.len() != before
+        }
+        ArrayOperation::Insert { index, value } => {
+            if *index > array.len() {
+                return Err(DbError::InvalidPath(format!("Index {} out of bounds for array at '{}'", index, path)));
+            }
+            array.insert(*index, value.clone());
+            true
+        }
+    };
+    // Operations that leave the array as it was don't write (or create) the document.
+    if changed {
+        set_path_internal(tx, key, old_value, path_parts, Value::Array(array.clone()), config)?;
+    }
+    Ok(match operation {
+        ArrayOperation::Pop => popped,
+        _ => Value::Array(array),
+    })
+}
+
+// Applies a JSON Merge Patch (RFC 7386): objects are merged recursively, null
+// members remove the key, and any other patch value replaces the target.
+fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch_map) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target_map) = target {
+        for (field, patch_value) in patch_map {
+            if patch_value.is_null() {
+                target_map.remove(field);
+            } else {
+                apply_merge_patch(target_map.entry(field.clone()).or_insert(Value::Null), patch_value);
+            }
+        }
+    }
+}
+
+// Merges the patch into the stored document (an absent key starts from null)
+// and returns the document as written. A `_rev` in the patch is checked like
+// in `set_key`.
+pub fn merge_key(db: &Db, key: &str, patch: &Value, config: &DbConfig) -> DbResult<Value> {
+    transaction(db, |tx| {
+        let mut document = read_tx_document(tx, key)?.unwrap_or(Value::Null);
+        apply_merge_patch(&mut document, patch);
+        set_key_internal(tx, key, &document, config).map_err(ConflictableTransactionError::Abort)?;
+        Ok(read_tx_document(tx, key)?.unwrap_or(Value::Null))
+    })
+}
+
+// One JSON Patch (RFC 6902) operation; paths are JSON Pointers (RFC 6901).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+// Splits a JSON Pointer into its unescaped reference tokens.
+fn parse_json_pointer(pointer: &str) -> DbResult<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(DbError::InvalidPath(format!("JSON Pointer must start with '/': {}", pointer)));
+    };
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+// Array index of a reference token, rejecting leading zeros as RFC 6901 does.
+fn pointer_index(token: &str, pointer: &str) -> DbResult<usize> {
+    let valid = token == "0" || (!token.starts_with('0') && !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()));
+    valid.then(|| token.parse().ok()).flatten()
+        .ok_or_else(|| DbError::InvalidPath(format!("Invalid array index '{}' in {}", token, pointer)))
+}
+
+fn pointer_get<'a>(doc: &'a Value, tokens: &[String], pointer: &str) -> DbResult<&'a Value> {
+    let mut current = doc;
+    for token in tokens {
+        current = match current {
+            Value::Object(map) => map.get(token),
+            Value::Array(arr) => arr.get(pointer_index(token, pointer)?),
+            _ => None,
+        }.ok_or_else(|| DbError::InvalidPath(format!("Path does not exist: {}", pointer)))?;
+    }
+    Ok(current)
+}
+
+fn pointer_get_mut<'a>(doc: &'a mut Value, tokens: &[String], pointer: &str) -> DbResult<&'a mut Value> {
+    let mut current = doc;
+    for token in tokens {
+        current = match current {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(arr) => arr.get_mut(pointer_index(token, pointer)?),
+            _ => None,
+        }.ok_or_else(|| DbError::InvalidPath(format!("Path does not exist: {}", pointer)))?;
+    }
+    Ok(current)
+}
+
+fn patch_add(doc: &mut Value, pointer: &str, value: Value) -> DbResult<()> {
+    let tokens = parse_json_pointer(pointer)?;
+    let Some((last, parent_tokens)) = tokens.split_last() else {
+        *doc = value;
+        return Ok(());
+    };
+    match pointer_get_mut(doc, parent_tokens, pointer)? {
+        Value::Object(map) => { map.insert(last.clone(), value); }
+        Value::Array(arr) if last == "-" => arr.push(value),
+        Value::Array(arr) => {
+            let index = pointer_index(last, pointer)?;
+            if index > arr.len() {
+                return Err(DbError::InvalidPath(format!("Array index out of bounds: {}", pointer)));
+            }
+            arr.insert(index, value);
+        }
+        _ => return Err(DbError::InvalidPath(format!("Parent is not an object or array: {}", pointer))),
+    }
+    Ok(())
+}
+
+fn patch_remove(doc: &mut Value, pointer: &str) -> DbResult<Value> {
+    let tokens = parse_json_pointer(pointer)?;
+    let Some((last, parent_tokens)) = tokens.split_last() else {
+        return Err(DbError::InvalidPath("Cannot remove the whole document".to_string()));
+    };
+    let removed = match pointer_get_mut(doc, parent_tokens, pointer)? {
+        Value::Object(map) => map.remove(last),
+        Value::Array(arr) => {
+            let index = pointer_index(last, pointer)?;
+            (index < arr.len()).then(|| arr.remove(index))
+        }
+        _ => None,
+    };
+    removed.ok_or_else(|| DbError::InvalidPath(format!("Path does not exist: {}", pointer)))
+}
+
+// Applies the operations in order to the document, stopping at the first
+// one that fails.
+fn apply_json_patch(doc: &mut Value, operations: &[PatchOperation]) -> DbResult<()> {
+    for operation in operations {
+        match operation {
+            PatchOperation::Add { path, value } => patch_add(doc, path, value.clone())?,
+            PatchOperation::Remove { path } => { patch_remove(doc, path)?; }
+            PatchOperation::Replace { path, value } => {
+                *pointer_get_mut(doc, &parse_json_pointer(path)?, path)? = value.clone();
+            }
+            PatchOperation::Move { from, path } => {
+                if path.starts_with(&format!("{}/", from)) {
+                    return Err(DbError::InvalidPath(format!("Cannot move {} into its own child {}", from, path)));
+                }
+                let value = patch_remove(doc, from)?;
+                patch_add(doc, path, value)?;
+            }
+            PatchOperation::Copy { from, path } => {
+                let value = pointer_get(doc, &parse_json_pointer(from)?, from)?.clone();
+                patch_add(doc, path, value)?;
+            }
+            PatchOperation::Test { path, value } => {
+                let current = pointer_get(doc, &parse_json_pointer(path)?, path)?;
+                if !evaluate_condition_on_value(current, "Eq", value, false, false) {
+                    return Err(DbError::PatchTestFailed(format!("{} is {}, not {}", path, current, value)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Applies a JSON Patch to the stored document atomically: if any operation
+// fails, the document is left unchanged. Returns the document as written.
+pub fn patch_key(db: &Db, key: &str, operations: &[PatchOperation], config: &DbConfig) -> DbResult<Value> {
+    transaction(db, |tx| {
+        let mut document = read_tx_document(tx, key)?.ok_or(ConflictableTransactionError::Abort(DbError::NotFound))?;
+        apply_json_patch(&mut document, operations).map_err(ConflictableTransactionError::Abort)?;
+        set_key_internal(tx, key, &document, config).map_err(ConflictableTransactionError::Abort)?;
+        Ok(read_tx_document(tx, key)?.unwrap_or(Value::Null))
+    })
+}
+
+// Optional expiry of a set: an absolute `expires_at` (RFC3339 or Unix
+// seconds) or `ttl_seconds` from now, stored in the document's
+// `_expires_at` field. Without either, the value is stored as given, so
+// rewriting a document without its `_expires_at` clears the expiry.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Expiry {
+    #[serde(default)]
+    pub expires_at: Option<Value>,
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Expiry {
+    // The value to store, carrying the expiry; `ttl_seconds` counts from
+    // `now_secs`. Only object documents can expire.
+    pub fn apply<'a>(&self, value: &'a Value, now_secs: u64) -> DbResult<Cow<'a, Value>> {
+        let expires_at = match (&self.expires_at, self.ttl_seconds) {
+            (None, None) => return Ok(Cow::Borrowed(value)),
+            (Some(_), Some(_)) => return Err(DbError::MissingData("Set either expires_at or ttl_seconds, not both".to_string())),
+            (Some(at), None) => timestamp_secs(at).ok_or_else(|| DbError::MissingData(format!("Invalid expires_at: {}", at)))?,
+            (None, Some(ttl)) => now_secs.saturating_add(ttl),
+        };
+        let Value::Object(map) = value else {
+            return Err(DbError::MissingData("Only object documents can have an expiry".to_string()));
+        };
+        let mut map = map.clone();
+        map.insert(EXPIRES_AT_FIELD.to_string(), json!(expires_at));
+        Ok(Cow::Owned(Value::Object(map)))
+    }
+}
+
+// Modified: Make fields public
+#[derive(Deserialize, Debug)]
+pub struct BatchSetItem {
+    pub key: String,
+    pub value: Value,
+    #[serde(flatten)]
+    pub expiry: Expiry,
+}
+
+pub fn batch_set(db: &Db, items: &[BatchSetItem], config: &DbConfig) -> DbResult<()> { // Take slice
+     let now_secs = unix_now_secs();
+     transaction(db, |tx| {
+         for item in items { // Iterate over slice
+             item.expiry.apply(&item.value, now_secs)
+                 .and_then(|value| set_key_internal(tx, &item.key, &value, config))
+                 .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Batch set failed for key '{}': {}", item.key, e))))?;
+         }
+         Ok(())
+     })?;
+     Ok(())
+}
+
+fn delete_key_internal(tx: &TxTrees, key: &str, config: &DbConfig) -> DbResult<()> {
+    let key_bytes = key.as_bytes();
+    if let Some(ivec) = tx.docs.get(key_bytes)? {
+        if let Ok(val) = serde_json::from_slice::<Value>(&ivec) {
+             unindex_document(tx, key, &val, config)?;
+        }
+        tx.docs.remove(key_bytes)?;
+        tx.modified.remove(key_bytes)?;
+        log_change(tx, || ChangeEvent::Delete { key: key.to_string() })?;
+    }
+    Ok(())
 }
 
 pub async fn delete_key(db: &Db, key: &str, config: &DbConfig) -> DbResult<()> {
-    db.transaction(|tx_db| {
-        delete_key_internal(tx_db, key, config).map_err(ConflictableTransactionError::Abort)
+    transaction(db, |tx| {
+        delete_key_internal(tx, key, config).map_err(ConflictableTransactionError::Abort)
+    })?;
+    db.flush_async().await?;
+    Ok(())
+}
+
+// Moves the document stored under `from` to `to`, along with its index
+// entries. Fails with `AlreadyExists` if `to` is taken, unless `overwrite`
+// is set, in which case the document at `to` is replaced. The document
+// itself, including its revision, is moved unchanged.
+pub fn rename_key(db: &Db, from: &str, to: &str, overwrite: bool, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        let document = read_tx_document(tx, from)?.ok_or(ConflictableTransactionError::Abort(DbError::NotFound))?;
+        if from == to {
+            return Ok(());
+        }
+        if tx.docs.get(to.as_bytes())?.is_some() {
+            if !overwrite {
+                return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(to.to_string())));
+            }
+            delete_key_internal(tx, to, config).map_err(ConflictableTransactionError::Abort)?;
+        }
+        let modified = tx.modified.get(from.as_bytes())?;
+        delete_key_internal(tx, from, config).map_err(ConflictableTransactionError::Abort)?;
+        let bytes = serde_json::to_vec(&document).map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+        tx.docs.insert(to.as_bytes(), bytes)?;
+        if let Some(modified) = modified {
+            tx.modified.insert(to.as_bytes(), modified)?;
+        }
+        log_change(tx, || ChangeEvent::Set { key: to.to_string(), value: document.clone() }).map_err(ConflictableTransactionError::Abort)?;
+        index_document(tx, to, &document, config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+// Stores a copy of the document under `from` as `to`, indexed like any
+// other write. Fails with `AlreadyExists` if `to` is taken, unless
+// `overwrite` is set. The copy's revision continues from the document it
+// replaces, not from the source.
+pub fn copy_key(db: &Db, from: &str, to: &str, overwrite: bool, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        let mut document = read_tx_document(tx, from)?.ok_or(ConflictableTransactionError::Abort(DbError::NotFound))?;
+        if !overwrite && tx.docs.get(to.as_bytes())?.is_some() {
+            return Err(ConflictableTransactionError::Abort(DbError::AlreadyExists(to.to_string())));
+        }
+        if let Value::Object(map) = &mut document {
+            map.remove(REVISION_FIELD);
+        }
+        set_key_internal(tx, to, &document, config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+// 64-bit FNV-1a, a hash that stays the same across builds and restarts.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+// A strong HTTP entity tag for a document: a 64-bit FNV-1a hash of its JSON
+// form, quoted. It changes with any change to the document.
+pub fn document_etag(value: &Value) -> String {
+    format!("\"{:016x}\"", fnv1a(value.to_string().as_bytes()))
+}
+
+// Whether an If-Match or If-None-Match header value lists the entity tag:
+// `*` lists any, and weak tags (W/"...") are compared by their value.
+pub fn etag_listed(header_value: &str, etag: &str) -> bool {
+    header_value.trim() == "*" || header_value.split(',').map(str::trim).any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+// Evaluates an If-Match header value against the stored document, which
+// must exist.
+fn if_match_holds(if_match: &str, current: Option<&Value>) -> bool {
+    current.is_some_and(|current| etag_listed(if_match, &document_etag(current)))
+}
+
+fn check_if_match(tx: &TxTrees, key: &str, if_match: &str) -> Result<(), ConflictableTransactionError<DbError>> {
+    if if_match_holds(if_match, read_tx_document(tx, key)?.as_ref()) {
+        Ok(())
+    } else {
+        Err(ConflictableTransactionError::Abort(DbError::PreconditionFailed(format!("key '{}' does not match If-Match {}", key, if_match))))
+    }
+}
+
+// Same as `set_key`, failing with `PreconditionFailed` unless the stored
+// document satisfies the If-Match header value.
+pub fn set_key_if_match(db: &Db, key: &str, value: Value, if_match: &str, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        check_if_match(tx, key, if_match)?;
+        set_key_internal(tx, key, &value, config).map_err(ConflictableTransactionError::Abort)
+    })
+}
+
+// Same as `delete_key`, failing with `PreconditionFailed` unless the stored
+// document satisfies the If-Match header value.
+pub async fn delete_key_if_match(db: &Db, key: &str, if_match: &str, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        check_if_match(tx, key, if_match)?;
+        delete_key_internal(tx, key, config).map_err(ConflictableTransactionError::Abort)
     })?;
     db.flush_async().await?;
     Ok(())
@@ -454,28 +1784,147 @@ pub async fn delete_key(db: &Db, key: &str, config: &DbConfig) -> DbResult<()> {
 #[serde(tag = "type")]
 pub enum TransactionOperation {
     #[serde(rename = "set")]
-    Set { key: String, value: Value },
+    Set {
+        key: String,
+        value: Value,
+        #[serde(flatten)]
+        expiry: Expiry,
+    },
     #[serde(rename = "delete")]
     Delete { key: String },
+    // Aborts the transaction unless the value at `path` of the document
+    // satisfies the condition (a missing document fails every check).
+    // `operator` is one of Eq, Ne, Gt, Gte, Lt, Lte or Includes.
+    #[serde(rename = "check")]
+    Check { key: String, path: String, operator: String, value: Value },
+    // Reads the document as left by the operations before it.
+    #[serde(rename = "get")]
+    Get { key: String },
+    // Changes the array at `path` of the document, e.g.
+    // {"type": "array", "key": "k", "path": "tags", "op": "push", "value": "x"}.
+    #[serde(rename = "array")]
+    Array {
+        key: String,
+        path: String,
+        #[serde(flatten)]
+        operation: ArrayOperation,
+    },
+}
+
+impl TransactionOperation {
+    // The document the operation reads or writes.
+    pub fn key(&self) -> &str {
+        match self {
+            TransactionOperation::Set { key, .. }
+            | TransactionOperation::Delete { key }
+            | TransactionOperation::Check { key, .. }
+            | TransactionOperation::Get { key }
+            | TransactionOperation::Array { key, .. } => key,
+        }
+    }
+}
+
+// What one operation of a committed transaction did.
+#[derive(Serialize, Debug, Clone)]
+pub struct TransactionOpResult {
+    // False when a `get` or `delete` found no document; true otherwise.
+    pub ok: bool,
+    // The document read by a `get` (null if absent), the document as stored
+    // by a `set`, or the result of an `array` operation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+}
+
+// Reads a document within a transaction, seeing the transaction's own writes.
+fn read_tx_document(tx: &TxTrees, key: &str) -> Result<Option<Value>, ConflictableTransactionError<DbError>> {
+    match tx.docs.get(key.as_bytes())? {
+        Some(ivec) => serde_json::from_slice(&ivec).map(Some).map_err(|e| ConflictableTransactionError::Abort(e.into())),
+        None => Ok(None),
+    }
+}
+
+// Replaces the document with `new` only if its current value equals
+// `expected`, `None` expecting the key to be absent. Fails with `CasConflict`
+// otherwise, leaving the document as it was.
+pub fn compare_and_set(db: &Db, key: &str, expected: Option<Value>, new: Value, config: &DbConfig) -> DbResult<()> {
+    compare_and_set_at(db, key, None, expected, new, config)
+}
+
+// Same as `compare_and_set`, comparing the value at `path` of the current
+// document when one is given (`None` then expects the path to be absent).
+pub fn compare_and_set_at(db: &Db, key: &str, path: Option<&str>, expected: Option<Value>, new: Value, config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        let current = read_tx_document(tx, key)?;
+        let current_value = match path {
+            Some(path) => current.as_ref().and_then(|doc| get_value_by_path(doc, path)),
+            None => current.as_ref(),
+        };
+        let matches = match (current_value, &expected) {
+            (Some(current_value), Some(expected)) => evaluate_condition_on_value(current_value, "Eq", expected, false, false),
+            (None, None) => true,
+            _ => false,
+        };
+        if !matches {
+            return Err(ConflictableTransactionError::Abort(DbError::CasConflict(format!(
+                "key '{}'{} does not hold the expected value",
+                key, path.map(|path| format!(" at '{}'", path)).unwrap_or_default()
+            ))));
+        }
+        set_key_internal(tx, key, &new, config).map_err(ConflictableTransactionError::Abort)
+    })
 }
 
-pub fn execute_transaction(db: &Db, operations: &[TransactionOperation], config: &DbConfig) -> DbResult<()> { // Take slice
-    db.transaction(|tx_db| {
+// Runs the operations atomically and returns one result per operation, in order.
+pub fn execute_transaction(db: &Db, operations: &[TransactionOperation], config: &DbConfig) -> DbResult<Vec<TransactionOpResult>> { // Take slice
+    for op in operations {
+        if let TransactionOperation::Check { operator, .. } = op {
+            if !matches!(operator.as_str(), "Eq" | "Ne" | "Gt" | "Gte" | "Lt" | "Lte" | "Includes") {
+                return Err(DbError::MissingData(format!("Unsupported operator: {}", operator)));
+            }
+        }
+    }
+    let now_secs = unix_now_secs();
+    transaction(db, |tx| {
+        let mut results = Vec::with_capacity(operations.len());
         for op in operations { // Iterate over slice
-            match op {
-                TransactionOperation::Set { key, value } => {
-                    set_key_internal(tx_db, key, value, config) // Pass references
+            let result = match op {
+                TransactionOperation::Set { key, value, expiry } => {
+                    expiry.apply(value, now_secs)
+                        .and_then(|value| set_key_internal(tx, key, &value, config))
                         .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Set failed for key '{}': {}", key, e))))?;
+                    TransactionOpResult { ok: true, value: read_tx_document(tx, key)? }
                 }
                 TransactionOperation::Delete { key } => {
-                    delete_key_internal(tx_db, key, config)
+                    let existed = tx.docs.get(key.as_bytes())?.is_some();
+                    delete_key_internal(tx, key, config)
                          .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Delete failed for key '{}': {}", key, e))))?;
+                    TransactionOpResult { ok: existed, value: None }
                 }
-            }
+                TransactionOperation::Check { key, path, operator, value } => {
+                    let holds = read_tx_document(tx, key)?.is_some_and(|doc| evaluate_condition_on_doc(&doc, path, operator, value, false, false));
+                    if !holds {
+                        return Err(ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!(
+                            "Check failed for key '{}': {} {} {}", key, path, operator, value
+                        ))));
+                    }
+                    TransactionOpResult { ok: true, value: None }
+                }
+                TransactionOperation::Get { key } => {
+                    let document = read_tx_document(tx, key)?;
+                    TransactionOpResult { ok: document.is_some(), value: Some(document.unwrap_or(Value::Null)) }
+                }
+                TransactionOperation::Array { key, path, operation } => {
+                    let old_value = read_tx_document(tx, key)?;
+                    let value = split_field_path(path)
+                        .and_then(|path_parts| array_op_internal(tx, key, old_value.as_ref(), &path_parts, operation, config))
+                        .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Array operation failed for key '{}': {}", key, e))))?;
+                    TransactionOpResult { ok: true, value: Some(value) }
+                }
+            };
+            results.push(result);
         }
-        Ok(())
-    })?;
-    Ok(())
+        Ok(results)
+    })
 }
 
 
@@ -489,6 +1938,29 @@ pub fn get_key(db: &Db, key: &str) -> DbResult<Value> {
     }
 }
 
+// Whether a document is stored under the key, without reading it.
+pub fn key_exists(db: &Db, key: &str) -> DbResult<bool> {
+    Ok(db.contains_key(key.as_bytes())?)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct GetManyResult {
+    pub found: Map<String, Value>,
+    pub missing: Vec<String>,
+}
+
+pub fn get_many(db: &Db, keys: &[String]) -> DbResult<GetManyResult> {
+    let mut result = GetManyResult::default();
+    for key in keys {
+        match get_key(db, key) {
+            Ok(value) => { result.found.insert(key.clone(), value); }
+            Err(DbError::NotFound) => result.missing.push(key.clone()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(result)
+}
+
 fn get_value_by_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
     let mut current = value;
     for part in path.split('.') {
@@ -538,7 +2010,7 @@ fn insert_value_by_path(target: &mut Value, path_parts: &[&str], value_to_insert
         let next_target = if let Some(obj) = target.as_object_mut() {
             obj.entry(key.to_string())
                .or_insert_with(|| {
-                   if path_parts.get(1).map_or(false, |p| p.parse::<usize>().is_ok()) {
+                   if path_parts.get(1).is_some_and(|p| p.parse::<usize>().is_ok()) {
                        Value::Array(vec![])
                    } else {
                        Value::Object(Map::new())
@@ -549,7 +2021,7 @@ fn insert_value_by_path(target: &mut Value, path_parts: &[&str], value_to_insert
                  if index < arr.len() {
                      &mut arr[index]
                  } else if index == arr.len() {
-                      let new_val = if path_parts.get(1).map_or(false, |p| p.parse::<usize>().is_ok()) {
+                      let new_val = if path_parts.get(1).is_some_and(|p| p.parse::<usize>().is_ok()) {
                            Value::Array(vec![])
                        } else {
                            Value::Object(Map::new())
@@ -606,7 +2078,7 @@ fn apply_projection(documents: Vec<Value>, projection: &Vec<String>) -> DbResult
                   }
              }
         }
-         if projected_doc.as_object().map_or(false, |m| !m.is_empty()) || doc.as_object().map_or(false, |m| m.is_empty()) {
+         if projected_doc.as_object().is_some_and(|m| !m.is_empty()) || doc.as_object().is_some_and(|m| m.is_empty()) {
              projected_results.push(projected_doc);
          } else if !doc.is_object() && !doc.is_null() {
               warn!("Projection applied to non-object document, skipping result.");
@@ -620,7 +2092,7 @@ fn apply_projection(documents: Vec<Value>, projection: &Vec<String>) -> DbResult
 
 pub fn get_partial_key(db: &Db, key: &str, fields: &[String]) -> DbResult<Value> {
     let full_value = get_key(db, key)?;
-    let projection_paths: Vec<String> = fields.iter().cloned().collect();
+    let projection_paths: Vec<String> = fields.to_vec();
     let projected_docs = apply_projection(vec![full_value], &projection_paths)?;
     projected_docs.into_iter().next().ok_or(DbError::NotFound)
 }
@@ -641,7 +2113,7 @@ pub fn query_and(db: &Db, conditions: Vec<(&str, &str, &str)>) -> DbResult<Vec<V
             ">" | "<" | ">=" | "<=" | "!=" => {
                 let value = parse_value(value_str)?;
 
-                let keys = fetch_keys_sorted_index(db, field, operator, &value, &DataType::Number)?;
+                let keys = fetch_keys_sorted_index(db, field, operator, &value, &DataType::Number, false, &DbConfig::default())?;
                 current_keys.extend(keys);
             }
             _ => return Err(DbError::MissingData(format!("Unsupported operator: {}", operator))),
@@ -664,15 +2136,17 @@ pub fn query_and(db: &Db, conditions: Vec<(&str, &str, &str)>) -> DbResult<Vec<V
     results
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum DataType {
     String,
     Number,
     Bool,
+    // RFC3339 timestamp strings, compared as instants
+    DateTime,
 }
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum QueryNode {
     Eq(String, Value, DataType),
     Includes(String, Value, DataType),
@@ -684,155 +2158,220 @@ pub enum QueryNode {
     And(Box<QueryNode>, Box<QueryNode>),
     Or(Box<QueryNode>, Box<QueryNode>),
     Not(Box<QueryNode>),
-    GeoWithinRadius { field: String, lat: f64, lon: f64, radius: f64 },
+    GeoWithinRadius {
+        field: String,
+        lat: f64,
+        lon: f64,
+        radius: f64,
+        #[serde(default)]
+        metric: DistanceMetric,
+    },
     GeoInBox { field: String, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64 },
+    // Documents within `distance` meters of the route, a polyline through the
+    // points in order.
+    GeoNearRoute { field: String, route: Vec<GeoPoint>, distance: f64 },
+    // Documents whose geometry intersects `geometry`, a `{lat, lon}` point or a
+    // GeoJSON Point, LineString or Polygon.
+    GeoIntersects { field: String, geometry: Value },
+    // Documents that have the field, whatever its value (including null).
+    Exists(String),
+    KeyEq(String),
+    KeyPrefix(String),
+    // Primary keys in [start, end); either bound may be omitted.
+    KeyRange { start: Option<String>, end: Option<String> },
 }
 
 
 // Modified: Fetch keys by scanning prefix and parsing primary key from index key
 fn fetch_keys_hash_index(db: &Db, field_path: &str, value: &Value) -> DbResult<HashSet<String>> {
-    let value_str = value.to_string().trim_matches('"').to_string();
+    let value_str = hash_index_value(value);
     let prefix = get_field_index_prefix(field_path, &value_str);
     let mut primary_keys = HashSet::new();
 
-    for result in db.scan_prefix(prefix.as_bytes()) {
+    for result in db.open_tree(FIELD_INDEX_TREE)?.scan_prefix(&prefix) {
         let (index_key_bytes, _) = result?;
-        let index_key_str = String::from_utf8_lossy(&index_key_bytes);
+        // Format: <field_path>, <value_str>, <primary_key>
+        let (_, primary_key) = parse_index_entry(&index_key_bytes).inspect_err(|_| {
+            warn!("Invalid field index key format encountered during scan: {}", String::from_utf8_lossy(&index_key_bytes));
+        })?;
+        primary_keys.insert(primary_key);
+    }
+    Ok(primary_keys)
+}
 
-        // Extract primary key from the end of the index key string
-        // Format: __field_index__:<field_path>:<value_str>:<primary_key>
-        if let Some(primary_key) = index_key_str.split(':').last() {
-            primary_keys.insert(primary_key.to_string());
-        } else {
-             warn!("Invalid field index key format encountered during scan: {}", index_key_str);
-             // Optionally return an error:
-             return Err(DbError::InvalidFieldIndexKey(index_key_str.into_owned()));
+// Keys of documents that have (or with `present` false, lack) the field,
+// read from its hash index. Only dense indexes record missing documents.
+fn fetch_keys_by_presence(db: &Db, field_path: &str, present: bool) -> DbResult<HashSet<String>> {
+    let prefix = if present { index_key(&[field_path.as_bytes()]) } else { index_key(&[field_path.as_bytes(), HASH_MISSING_MARKER]) };
+    let mut primary_keys = HashSet::new();
+    for key_result in db.open_tree(FIELD_INDEX_TREE)?.scan_prefix(&prefix).keys() {
+        let (value, primary_key) = parse_index_entry(&key_result?)?;
+        if !present || value != HASH_MISSING_MARKER {
+            primary_keys.insert(primary_key);
         }
     }
     Ok(primary_keys)
 }
 
-fn fetch_keys_sorted_index(db: &Db, field_path: &str, operator: &str, value: &Value, _expected_type: &DataType) -> DbResult<HashSet<String>> {
-    let mut current_keys = HashSet::new();
-    let encoded_value = encode_sorted_value(value)?;
-    let value_type_byte = encoded_value.first().copied();
+fn check_datetime_value(value: &Value, expected_type: &DataType) -> DbResult<()> {
+    if *expected_type == DataType::DateTime && value.as_str().and_then(parse_datetime_micros).is_none() {
+        return Err(DbError::InvalidComparisonValue(format!("Expected an RFC3339 timestamp, got {}", value)));
+    }
+    Ok(())
+}
 
+fn fetch_keys_sorted_index(db: &Db, field_path: &str, operator: &str, value: &Value, expected_type: &DataType, coerce: bool, config: &DbConfig) -> DbResult<HashSet<String>> {
+    let mut current_keys = HashSet::new();
+    check_datetime_value(value, expected_type)?;
+    let datetime = config.datetime_fields.contains(field_path);
+    let encoded_value = encode_sorted_value(value, config.collations.get(field_path), datetime)?;
     let prefix = get_field_sorted_index_prefix(field_path);
-    let prefix_bytes = prefix.as_bytes();
-
-    let start_key_gt = get_field_sorted_index_key(field_path, &encoded_value, "");
-    let start_key_gte = get_field_sorted_index_key(field_path, &encoded_value, "");
-    let end_key_lt = get_field_sorted_index_key(field_path, &encoded_value, "");
-    let end_key_lte = get_field_sorted_index_key(field_path, &encoded_value, "\u{FFFF}");
-
-    let range: (Bound<&[u8]>, Bound<&[u8]>) = match operator {
-         ">" => (Bound::Excluded(start_key_gt.as_bytes()), Bound::Unbounded),
-         ">=" => (Bound::Included(start_key_gte.as_bytes()), Bound::Unbounded),
-         "<" => (Bound::Included(prefix_bytes), Bound::Excluded(end_key_lt.as_bytes())),
-         "<=" => (Bound::Included(prefix_bytes), Bound::Included(end_key_lte.as_bytes())),
-         "!=" => (Bound::Unbounded, Bound::Unbounded),
-         _ => return Err(DbError::AstQueryError(format!("Unsupported operator for sorted index: {}", operator))),
-     };
-
-    let iterator = if operator == "!=" {
-        Box::new(db.scan_prefix(prefix_bytes)) as Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>>>
+    let index_tree = db.open_tree(FIELD_SORTED_INDEX_TREE)?;
+
+    // Coercion compares numbers with numeric strings, which live under a
+    // different type byte, so the whole field index is decoded and compared.
+    if coerce && coerce_numeric(value).is_some() {
+        for item_result in index_tree.scan_prefix(&prefix) {
+            let (k, _) = item_result?;
+            let Ok((stored_encoded, primary_key)) = parse_index_entry(&k) else {
+                warn!("Invalid sorted index key format: {}", String::from_utf8_lossy(&k));
+                continue;
+            };
+            let Ok(stored_value) = decode_sorted_value(&stored_encoded) else {
+                warn!("Failed to decode sorted value for key: {}", String::from_utf8_lossy(&k));
+                continue;
+            };
+            let comparison_result = compare_values_coerced(&stored_value, value, datetime);
+            let matches = match operator {
+                ">" => comparison_result == Some(Ordering::Greater),
+                "<" => comparison_result == Some(Ordering::Less),
+                ">=" => comparison_result == Some(Ordering::Greater) || comparison_result == Some(Ordering::Equal),
+                "<=" => comparison_result == Some(Ordering::Less) || comparison_result == Some(Ordering::Equal),
+                "!=" => comparison_result != Some(Ordering::Equal),
+                _ => false,
+            };
+            if matches {
+                current_keys.insert(primary_key);
+            }
+        }
+        return Ok(current_keys);
+    }
+
+    // Entries of the value's type start with `type_prefix`, and entries equal
+    // to the value with `eq_prefix`. Values whose encoding continues past the
+    // order-determining part (datetimes, numbers) are compared on that part
+    // only; the others must match the whole component.
+    let mut type_prefix = prefix.clone();
+    push_escaped(&mut type_prefix, &encoded_value[..1]);
+    let order_len = sorted_order_len(&encoded_value);
+    let eq_prefix = if order_len < encoded_value.len() {
+        let mut eq_prefix = prefix.clone();
+        push_escaped(&mut eq_prefix, &encoded_value[..order_len]);
+        eq_prefix
     } else {
-        Box::new(db.range::<&[u8], _>(range)) as Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>>>
+        index_key(&[field_path.as_bytes(), &encoded_value])
+    };
+    // The field path leads every prefix, so they never consist of 0xFF bytes only.
+    let type_end = prefix_upper_bound(&type_prefix).map_or(Bound::Unbounded, Bound::Excluded);
+    let eq_end = prefix_upper_bound(&eq_prefix).unwrap_or_default();
+
+    let ranges = match operator {
+        ">" => vec![(Bound::Included(eq_end), type_end)],
+        ">=" => vec![(Bound::Included(eq_prefix), type_end)],
+        "<" => vec![(Bound::Included(type_prefix), Bound::Excluded(eq_prefix))],
+        "<=" => vec![(Bound::Included(type_prefix), Bound::Excluded(eq_end))],
+        "!=" => vec![(Bound::Included(type_prefix), Bound::Excluded(eq_prefix)), (Bound::Included(eq_end), type_end)],
+        "==" => vec![(Bound::Included(eq_prefix), Bound::Excluded(eq_end))],
+        // Entries of every other type, which no comparison with the value matches.
+        "!type" => {
+            let field_end = prefix_upper_bound(&prefix).map_or(Bound::Unbounded, Bound::Excluded);
+            let mut ranges = vec![(Bound::Included(prefix), Bound::Excluded(type_prefix))];
+            if let Bound::Excluded(type_end) = type_end {
+                ranges.push((Bound::Included(type_end), field_end));
+            }
+            ranges
+        }
+        _ => return Err(DbError::AstQueryError(format!("Unsupported operator for sorted index: {}", operator))),
     };
 
-    for item_result in iterator {
-        let (k, _) = item_result?;
-        let key_str = String::from_utf8_lossy(&k);
+    for range in ranges {
+        for key_result in index_tree.range::<Vec<u8>, _>(range).keys() {
+            let k = key_result?;
+            // Format: <field_path>, <encoded_value>, <primary_key>
+            match parse_index_entry(&k) {
+                Ok((_, primary_key)) => { current_keys.insert(primary_key); }
+                Err(_) => warn!("Invalid sorted index key format: {}", String::from_utf8_lossy(&k)),
+            }
+        }
+    }
+    Ok(current_keys)
+}
 
-        let parts: Vec<&str> = key_str.splitn(4, ':').collect();
-        if parts.len() < 4 { continue; }
-
-
-        let stored_field_path = parts[1];
-        if stored_field_path != field_path { continue; }
-
-        let stored_encoded_hex = parts[2];
-        let primary_key = parts[3];
-
-        if let Ok(stored_encoded) = hex::decode(stored_encoded_hex) {
-             if let Some(query_type) = value_type_byte {
-                 if stored_encoded.is_empty() || stored_encoded[0] != query_type {
-                     continue;
-                 }
-             }
-
-             if let Ok(stored_value) = decode_sorted_value(&stored_encoded) {
-                 let comparison_result = compare_values(&stored_value, value);
-
-                 let matches = match operator {
-                     ">" => comparison_result == Some(Ordering::Greater),
-                     "<" => comparison_result == Some(Ordering::Less),
-                     ">=" => comparison_result == Some(Ordering::Greater) || comparison_result == Some(Ordering::Equal),
-                     "<=" => comparison_result == Some(Ordering::Less) || comparison_result == Some(Ordering::Equal),
-                     "!=" => comparison_result != Some(Ordering::Equal),
-                     _ => false,
-                 };
-
-                 if matches {
-                     current_keys.insert(primary_key.to_string());
-                 }
-             } else {
-                  warn!("Failed to decode sorted value for key: {}", key_str);
-             }
-        } else {
-             warn!("Failed to decode hex for sorted key: {}", key_str);
-        }
-    }
-    Ok(current_keys)
-}
-
-fn fetch_documents(db: &Db, keys: HashSet<String>) -> DbResult<Vec<Value>> {
+fn fetch_documents<I: IntoIterator<Item = String>>(db: &Db, keys: I) -> DbResult<Vec<Value>> {
     keys.into_iter()
         .map(|k| get_key(db, &k))
         .collect()
 }
 
-#[derive(Clone, Debug, Eq)]
-struct HashableValue(Value);
-
-impl PartialEq for HashableValue {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+fn evaluate_condition_on_value(doc_value: &Value, operator: &str, query_value: &Value, coerce: bool, datetime: bool) -> bool {
+    // Numerically equal values match however they are written, as in the hash index.
+    let query_number = canonical_number(query_value);
+    let equals = |v: &Value| v == query_value
+        || (query_number.is_some() && canonical_number(v) == query_number)
+        || (coerce && compare_values_coerced(v, query_value, false) == Some(Ordering::Equal));
+    match operator {
+        "Eq" => equals(doc_value),
+        "Includes" => {
+            if let Some(arr) = doc_value.as_array() {
+                arr.iter().any(equals)
+            } else {
+                equals(doc_value)
+            }
+        }
+        "Gt" | "Lt" | "Gte" | "Lte" | "Ne" => {
+            let comparison_result = if coerce { compare_values_coerced(doc_value, query_value, datetime) } else { compare_values(doc_value, query_value, datetime) };
+            match operator {
+                "Gt" => comparison_result == Some(Ordering::Greater),
+                "Lt" => comparison_result == Some(Ordering::Less),
+                "Gte" => comparison_result == Some(Ordering::Greater) || comparison_result == Some(Ordering::Equal),
+                "Lte" => comparison_result == Some(Ordering::Less) || comparison_result == Some(Ordering::Equal),
+                "Ne" => comparison_result != Some(Ordering::Equal),
+                _ => false,
+            }
+        }
+        _ => false,
     }
 }
 
-impl Hash for HashableValue {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let canonical_string = serde_json::to_string(&self.0).unwrap_or_default();
-        canonical_string.hash(state);
+// Resolves a path that may contain `*` segments to every value it reaches.
+fn get_values_by_path<'a>(value: &'a Value, path: &str) -> Vec<&'a Value> {
+    let mut current = vec![value];
+    for part in path.split('.') {
+        let mut next = Vec::new();
+        for v in current {
+            match (v, part) {
+                (Value::Array(arr), "*") => next.extend(arr.iter()),
+                (Value::Object(obj), "*") => next.extend(obj.values()),
+                (Value::Object(obj), _) => next.extend(obj.get(part)),
+                (Value::Array(arr), _) => next.extend(part.parse::<usize>().ok().and_then(|i| arr.get(i))),
+                _ => {}
+            }
+        }
+        current = next;
     }
+    current
 }
 
-
-fn evaluate_condition_on_doc(doc: &Value, field_path: &str, operator: &str, query_value: &Value) -> bool {
+fn evaluate_condition_on_doc(doc: &Value, field_path: &str, operator: &str, query_value: &Value, coerce: bool, datetime: bool) -> bool {
+     if let Some(expr) = parse_index_expr(field_path).ok().flatten() {
+         return expr.evaluate(doc).is_some_and(|v| evaluate_condition_on_value(&v, operator, query_value, coerce, datetime));
+     }
+     if field_path.contains('*') {
+         return get_values_by_path(doc, field_path).into_iter()
+             .any(|v| evaluate_condition_on_value(v, operator, query_value, coerce, datetime));
+     }
      if let Some(doc_value) = get_value_by_path(doc, field_path) {
-         match operator {
-             "Eq" => doc_value == query_value,
-             "Includes" => {
-                 if let Some(arr) = doc_value.as_array() {
-                     arr.contains(query_value)
-                 } else {
-                     doc_value == query_value
-                 }
-             }
-             "Gt" | "Lt" | "Gte" | "Lte" | "Ne" => {
-                 let comparison_result = compare_values(doc_value, query_value);
-                 match operator {
-                     "Gt" => comparison_result == Some(Ordering::Greater),
-                     "Lt" => comparison_result == Some(Ordering::Less),
-                     "Gte" => comparison_result == Some(Ordering::Greater) || comparison_result == Some(Ordering::Equal),
-                     "Lte" => comparison_result == Some(Ordering::Less) || comparison_result == Some(Ordering::Equal),
-                     "Ne" => comparison_result != Some(Ordering::Equal),
-                     _ => false,
-                 }
-             }
-             _ => false,
-         }
+         evaluate_condition_on_value(doc_value, operator, query_value, coerce, datetime)
      } else {
          let parts: Vec<&str> = field_path.split('.').collect();
          if parts.len() > 1 {
@@ -841,7 +2380,7 @@ fn evaluate_condition_on_doc(doc: &Value, field_path: &str, operator: &str, quer
                  let last_part = parts.last().unwrap();
                  return arr.iter().any(|elem| {
                      if let Some(nested_val) = elem.get(*last_part) {
-                         evaluate_condition_on_doc(nested_val, "", operator, query_value)
+                         evaluate_condition_on_value(nested_val, operator, query_value, coerce, datetime)
                      } else { false }
                  });
              }
@@ -852,342 +2391,3108 @@ fn evaluate_condition_on_doc(doc: &Value, field_path: &str, operator: &str, quer
 
 fn get_all_keys(db: &Db) -> DbResult<HashSet<String>> {
      let mut keys = HashSet::new();
-     for result in db.iter() {
-         let (key_bytes, _) = result?;
-         if !key_bytes.starts_with(GEO_SORTED_INDEX_PREFIX.as_bytes()) &&
-            !key_bytes.starts_with(FIELD_INDEX_PREFIX.as_bytes()) &&
-            !key_bytes.starts_with(FIELD_SORTED_INDEX_PREFIX.as_bytes()) {
-             if let Ok(key_str) = String::from_utf8(key_bytes.to_vec()) {
-                 keys.insert(key_str);
-             } else {
-                 warn!("Found non-UTF8 key in database during get_all_keys");
-             }
+     for key_result in db.iter().keys() {
+         let key_bytes = key_result?;
+         if let Ok(key_str) = String::from_utf8(key_bytes.to_vec()) {
+             keys.insert(key_str);
+         } else {
+             warn!("Found non-UTF8 key in database during get_all_keys");
          }
      }
      Ok(keys)
  }
 
+// Collects the document keys yielded by a key scan.
+fn collect_document_keys(iter: sled::Iter) -> DbResult<HashSet<String>> {
+    let mut keys = HashSet::new();
+    for key_result in iter.keys() {
+        keys.insert(String::from_utf8(key_result?.to_vec())?);
+    }
+    Ok(keys)
+}
 
-pub fn execute_ast_query(
-    db: &Db,
-    query_node: QueryNode,
-    projection: Option<Vec<String>>,
-    limit: Option<usize>,
-    offset: Option<usize>,
-    config: &DbConfig, // Added config parameter
-) -> DbResult<Vec<Value>> {
+// Keeps only the keys whose document satisfies the condition.
+fn filter_keys_by_condition(db: &Db, keys: HashSet<String>, field_path: &str, operator: &str, query_value: &Value, coerce: bool, datetime: bool) -> DbResult<HashSet<String>> {
+    let mut matching = HashSet::new();
+    for key in keys {
+        let doc = get_key(db, &key)?;
+        if scoped_field_path(field_path, &key).is_some_and(|path| evaluate_condition_on_doc(&doc, path, operator, query_value, coerce, datetime)) {
+            matching.insert(key);
+        }
+    }
+    Ok(matching)
+}
+
+// Whether Not(node) can be answered from the indexes rather than as the
+// complement over all keys: Not(Not(x)) is x, Not(Exists) reads the missing
+// markers, De Morgan's laws carry the negation through And/Or, and a negated
+// comparison becomes the opposite sorted range plus the documents of other
+// types and those lacking the field. The last only holds for dense, unscoped
+// fields with both indexes whose documents each hold one orderable scalar;
+// Ne 1 matches the 9 entry of an array [1, 9], which Not(Eq 1) does not. Eq is
+// answered from the hash index, where 5 and "5" are one value, so only
+// strings no other type shares are negated.
+fn negatable(ctx: &QueryContext, node: &QueryNode) -> DbResult<bool> {
+    let dense = |field: &str| {
+        !field.contains(INDEX_SCOPE_SEPARATOR) && ctx.config.is_index_ready(field, IndexKind::Hash) && !ctx.config.sparse_fields.contains(field)
+    };
+    let orderable = |field: &str| -> DbResult<bool> {
+        Ok(!ctx.coerce_types && dense(field) && ctx.config.is_index_ready(field, IndexKind::Sorted)
+            && ctx.db.open_tree(FIELD_INDEX_TREE)?.scan_prefix(index_key(&[field.as_bytes(), HASH_UNORDERED_MARKER])).next().is_none())
+    };
+    Ok(match node {
+        QueryNode::Not(_) => true,
+        QueryNode::Exists(field) => dense(field),
+        QueryNode::And(left, right) | QueryNode::Or(left, right) => negatable(ctx, left)? && negatable(ctx, right)?,
+        QueryNode::Eq(field, Value::String(s), _) => {
+            canonical_number(&Value::String(s.clone())).is_none() && !matches!(s.as_str(), "true" | "false" | "null")
+                && !ctx.config.datetime_fields.contains(field) && !ctx.config.collations.contains_key(field) && orderable(field)?
+        }
+        QueryNode::Ne(field, _, t) | QueryNode::Gt(field, _, t) | QueryNode::Lt(field, _, t) | QueryNode::Gte(field, _, t) | QueryNode::Lte(field, _, t) => {
+            (*t != DataType::DateTime || ctx.config.datetime_fields.contains(field)) && orderable(field)?
+        }
+        _ => false,
+    })
+}
+
+// The keys matching Not(node), for a node `negatable` accepts.
+fn evaluate_negation(ctx: &QueryContext, node: &QueryNode) -> DbResult<HashSet<String>> {
+    let (field, value, expected_type, operator) = match node {
+        QueryNode::Not(inner) => return evaluate_query_keys(ctx, inner),
+        QueryNode::Exists(field) => {
+            let keys = fetch_keys_by_presence(ctx.db, field, false)?;
+            ctx.note(|| format!("hash index on {} (absent)", field), keys.len(), 0);
+            return Ok(keys);
+        }
+        QueryNode::And(left, right) => {
+            let mut keys = evaluate_negation(ctx, left)?;
+            keys.extend(evaluate_negation(ctx, right)?);
+            return Ok(keys);
+        }
+        QueryNode::Or(left, right) => {
+            let left_keys = evaluate_negation(ctx, left)?;
+            let right_keys = evaluate_negation(ctx, right)?;
+            return Ok(left_keys.into_iter().filter(|k| right_keys.contains(k)).collect());
+        }
+        QueryNode::Eq(f, v, t) => (f, v, t, "!="),
+        QueryNode::Ne(f, v, t) => (f, v, t, "=="),
+        QueryNode::Gt(f, v, t) => (f, v, t, "<="),
+        QueryNode::Lt(f, v, t) => (f, v, t, ">="),
+        QueryNode::Gte(f, v, t) => (f, v, t, "<"),
+        QueryNode::Lte(f, v, t) => (f, v, t, ">"),
+        _ => return Err(DbError::AstQueryError("Condition cannot be negated from the indexes".to_string())),
+    };
+    let mut keys = fetch_keys_sorted_index(ctx.db, field, operator, value, expected_type, false, ctx.config)?;
+    keys.extend(fetch_keys_sorted_index(ctx.db, field, "!type", value, expected_type, false, ctx.config)?);
+    keys.extend(fetch_keys_by_presence(ctx.db, field, false)?);
+    ctx.note(|| format!("sorted index on {} ({}), other types and absent", field, operator), keys.len(), 0);
+    Ok(keys)
+}
+
+// State shared by every node while evaluating one query.
+struct QueryContext<'a> {
+    db: &'a Db,
+    config: &'a DbConfig,
+    coerce_types: bool,
+    profile: RefCell<QueryProfile>,
+}
+
+impl QueryContext<'_> {
+    fn note(&self, step: impl FnOnce() -> String, keys_scanned: usize, documents_fetched: usize) {
+        let mut profile = self.profile.borrow_mut();
+        profile.plan.push(step());
+        profile.keys_scanned += keys_scanned;
+        profile.documents_fetched += documents_fetched;
+    }
+}
+
+// How a query ran: the access path taken for each condition, the keys read
+// from indexes or the document tree, and the documents loaded. Geo conditions
+// count their matches rather than every candidate they checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QueryProfile {
+    pub plan: Vec<String>,
+    pub keys_scanned: usize,
+    pub documents_fetched: usize,
+}
 
-    let mut results = match query_node {
-        QueryNode::Eq(ref field, ref value, _) => { // Borrow field and value
+// Resolves a query node to the set of matching primary keys without fetching
+// documents for intermediate results. Only leaves that need to inspect the
+// document body (Includes, geo) load documents.
+fn evaluate_query_keys(ctx: &QueryContext, query_node: &QueryNode) -> DbResult<HashSet<String>> {
+    let (db, config, coerce) = (ctx.db, ctx.config, ctx.coerce_types);
+    let keys = match query_node {
+        // Without a ready hash index (dynamic indexing disabled or a build in
+        // progress) fall back to scanning every document; Includes mirrors the
+        // index's per-element matching.
+        QueryNode::Eq(field, value, _) | QueryNode::Includes(field, value, _) if !config.is_index_ready(field, IndexKind::Hash) => {
+            let all_keys = get_all_keys(db)?;
+            ctx.note(|| format!("scan for {} (no ready hash index)", field), all_keys.len(), all_keys.len());
+            filter_keys_by_condition(db, all_keys, field, "Includes", value, coerce, false)?
+        }
+        QueryNode::Eq(field, value, _) => {
             let keys = fetch_keys_hash_index(db, field, value)?;
-            if keys.is_empty() && config.hash_indexed_fields.contains(field) {
-                // Fallback for dynamically indexed field with missing entries
-                warn!("Index entries missing for dynamically indexed field '{}'. Falling back to full scan.", field);
-                let all_keys = get_all_keys(db)?;
-                let all_docs = fetch_documents(db, all_keys)?;
-                all_docs.into_iter()
-                    .filter(|doc| evaluate_condition_on_doc(doc, field, "Eq", value))
-                    .collect()
-            } else {
-                fetch_documents(db, keys)?
+            ctx.note(|| format!("hash index on {}", field), keys.len(), 0);
+            keys
+        }
+        QueryNode::Includes(field, value, _) => {
+            let keys = fetch_keys_hash_index(db, field, value)?;
+            ctx.note(|| format!("hash index on {}, then filter", field), keys.len(), keys.len());
+            filter_keys_by_condition(db, keys, field, "Includes", value, coerce, false)?
+        }
+        // The sorted index of a field being built is incomplete, and that of a
+        // field not declared as datetime orders timestamps as text, so scan instead.
+        QueryNode::Gt(field, value, expected_type) | QueryNode::Lt(field, value, expected_type) | QueryNode::Gte(field, value, expected_type)
+        | QueryNode::Lte(field, value, expected_type) | QueryNode::Ne(field, value, expected_type)
+            if config.building_indexes.contains(&(field.clone(), IndexKind::Sorted))
+                || (*expected_type == DataType::DateTime && !config.datetime_fields.contains(field)) =>
+        {
+            check_datetime_value(value, expected_type)?;
+            let operator = match query_node {
+                QueryNode::Gt(..) => "Gt",
+                QueryNode::Lt(..) => "Lt",
+                QueryNode::Gte(..) => "Gte",
+                QueryNode::Lte(..) => "Lte",
+                _ => "Ne",
+            };
+            let all_keys = get_all_keys(db)?;
+            let building = config.building_indexes.contains(&(field.clone(), IndexKind::Sorted));
+            let reason = if building { "sorted index building" } else { "timestamps of a text field" };
+            ctx.note(|| format!("scan for {} ({})", field, reason), all_keys.len(), all_keys.len());
+            let datetime = *expected_type == DataType::DateTime || config.datetime_fields.contains(field);
+            filter_keys_by_condition(db, all_keys, field, operator, value, coerce, datetime)?
+        }
+        QueryNode::Gt(field, value, expected_type) | QueryNode::Lt(field, value, expected_type) | QueryNode::Gte(field, value, expected_type)
+        | QueryNode::Lte(field, value, expected_type) | QueryNode::Ne(field, value, expected_type) => {
+            let operator = match query_node {
+                QueryNode::Gt(..) => ">",
+                QueryNode::Lt(..) => "<",
+                QueryNode::Gte(..) => ">=",
+                QueryNode::Lte(..) => "<=",
+                _ => "!=",
+            };
+            let keys = fetch_keys_sorted_index(db, field, operator, value, expected_type, coerce, config)?;
+            ctx.note(|| format!("sorted index on {} ({})", field, operator), keys.len(), 0);
+            keys
+        }
+        QueryNode::And(left, right) => {
+            if let Some(keys) = evaluate_geo_conjunction(ctx, left, right)? {
+                return Ok(keys);
             }
+            let left_keys = evaluate_query_keys(ctx, left)?;
+            if left_keys.is_empty() {
+                return Ok(left_keys);
+            }
+            let right_keys = evaluate_query_keys(ctx, right)?;
+            // Iterate over the smaller set when intersecting
+            let (small, large) = if left_keys.len() <= right_keys.len() { (left_keys, right_keys) } else { (right_keys, left_keys) };
+            small.into_iter().filter(|k| large.contains(k)).collect()
         }
-        QueryNode::Includes(ref field, ref value, _) => { // Borrow field and value
-             let keys = fetch_keys_hash_index(db, field, value)?;
-             // Fallback logic similar to Eq could be added here if needed,
-             // but Includes often requires post-filtering anyway.
-             let docs = fetch_documents(db, keys)?;
-             docs.into_iter()
-                 .filter(|doc| evaluate_condition_on_doc(doc, field, "Includes", value))
-                 .collect()
-         }
-        QueryNode::Gt(field, value, expected_type) => {
-            let keys = fetch_keys_sorted_index(db, &field, ">", &value, &expected_type)?;
-            fetch_documents(db, keys)?
+        QueryNode::Or(left, right) => {
+            let mut keys = evaluate_query_keys(ctx, left)?;
+            keys.extend(evaluate_query_keys(ctx, right)?);
+            keys
+        }
+        QueryNode::Not(child_node) => {
+            if negatable(ctx, child_node)? {
+                return evaluate_negation(ctx, child_node);
+            }
+            // Complement at the key level: all keys minus the excluded ones
+            let excluded_keys = evaluate_query_keys(ctx, child_node)?;
+            let mut keys = get_all_keys(db)?;
+            ctx.note(|| "complement over all keys".to_string(), keys.len(), 0);
+            keys.retain(|k| !excluded_keys.contains(k));
+            keys
         }
-        QueryNode::Lt(field, value, expected_type) => {
-            let keys = fetch_keys_sorted_index(db, &field, "<", &value, &expected_type)?;
-            fetch_documents(db, keys)?
+        QueryNode::GeoWithinRadius { .. } | QueryNode::GeoInBox { .. } | QueryNode::GeoNearRoute { .. } | QueryNode::GeoIntersects { .. } => {
+            evaluate_geo_keys(ctx, query_node, None)?
         }
-        QueryNode::Gte(field, value, expected_type) => {
-            let keys = fetch_keys_sorted_index(db, &field, ">=", &value, &expected_type)?;
-            fetch_documents(db, keys)?
+        QueryNode::Exists(field) if config.is_index_ready(field, IndexKind::Hash) => {
+            let keys = fetch_keys_by_presence(db, field, true)?;
+            ctx.note(|| format!("hash index on {} (present)", field), keys.len(), 0);
+            keys
         }
-        QueryNode::Lte(field, value, expected_type) => {
-            let keys = fetch_keys_sorted_index(db, &field, "<=", &value, &expected_type)?;
-            fetch_documents(db, keys)?
+        QueryNode::Exists(field) => {
+            let mut keys = HashSet::new();
+            let mut scanned = 0;
+            for result in db.iter() {
+                scanned += 1;
+                let (key_bytes, value_bytes) = result?;
+                let key = String::from_utf8(key_bytes.to_vec())?;
+                let doc: Value = serde_json::from_slice(&value_bytes)?;
+                if scoped_field_path(field, &key).is_some_and(|path| field_present(&doc, path)) {
+                    keys.insert(key);
+                }
+            }
+            ctx.note(|| format!("scan for {} (no ready hash index)", field), scanned, scanned);
+            keys
         }
-        QueryNode::Ne(field, value, expected_type) => {
-            let keys = fetch_keys_sorted_index(db, &field, "!=", &value, &expected_type)?;
-            fetch_documents(db, keys)?
+        QueryNode::KeyEq(key) => {
+            let mut keys = HashSet::new();
+            if db.contains_key(key.as_bytes())? {
+                keys.insert(key.clone());
+            }
+            ctx.note(|| "key lookup".to_string(), 1, 0);
+            keys
         }
-        QueryNode::And(left, right) => {
-            let left_results = execute_ast_query(db, *left, None, None, None, config)?; // Pass config
-            let right_results = execute_ast_query(db, *right, None, None, None, config)?; // Pass config
+        QueryNode::KeyPrefix(prefix) => {
+            let keys = collect_document_keys(db.scan_prefix(prefix.as_bytes()))?;
+            ctx.note(|| format!("key prefix {:?}", prefix), keys.len(), 0);
+            keys
+        }
+        QueryNode::KeyRange { start, end } => {
+            let range: (Bound<&[u8]>, Bound<&[u8]>) = (
+                start.as_ref().map_or(Bound::Unbounded, |s| Bound::Included(s.as_bytes())),
+                end.as_ref().map_or(Bound::Unbounded, |e| Bound::Excluded(e.as_bytes())),
+            );
+            let keys = collect_document_keys(db.range::<&[u8], _>(range))?;
+            ctx.note(|| "key range".to_string(), keys.len(), 0);
+            keys
+        }
+    };
+    Ok(keys)
+}
+
+// The primary keys of the documents matching the query.
+pub fn query_keys(db: &Db, query_node: &QueryNode, config: &DbConfig) -> DbResult<HashSet<String>> {
+    evaluate_query_keys(&QueryContext { db, config, coerce_types: false, profile: RefCell::default() }, query_node)
+}
+
+// Whether one document satisfies the query, judged from the document alone
+// without indexes (e.g. to filter a change feed). Conditions match the way
+// the scanning fallbacks of `evaluate_query_keys` do.
+pub fn document_matches(key: &str, doc: &Value, query_node: &QueryNode) -> DbResult<bool> {
+    let condition = |field: &str, operator: &str, value: &Value, data_type: &DataType| {
+        scoped_field_path(field, key).is_some_and(|path| evaluate_condition_on_doc(doc, path, operator, value, false, *data_type == DataType::DateTime))
+    };
+    let geometry = |field: &str| scoped_field_path(field, key).and_then(|path| get_value_by_path(doc, path)).and_then(parse_geometry);
+    Ok(match query_node {
+        QueryNode::Eq(field, value, _) | QueryNode::Includes(field, value, _) => condition(field, "Includes", value, &DataType::String),
+        QueryNode::Gt(field, value, data_type) => condition(field, "Gt", value, data_type),
+        QueryNode::Lt(field, value, data_type) => condition(field, "Lt", value, data_type),
+        QueryNode::Gte(field, value, data_type) => condition(field, "Gte", value, data_type),
+        QueryNode::Lte(field, value, data_type) => condition(field, "Lte", value, data_type),
+        QueryNode::Ne(field, value, data_type) => condition(field, "Ne", value, data_type),
+        QueryNode::And(left, right) => document_matches(key, doc, left)? && document_matches(key, doc, right)?,
+        QueryNode::Or(left, right) => document_matches(key, doc, left)? || document_matches(key, doc, right)?,
+        QueryNode::Not(child) => !document_matches(key, doc, child)?,
+        QueryNode::GeoWithinRadius { field, lat, lon, radius, metric } => {
+            geometry(field).is_some_and(|g| geometry_distance(&g, &Point::new(*lon, *lat), *metric) <= *radius)
+        }
+        QueryNode::GeoInBox { field, min_lat, min_lon, max_lat, max_lon } => geometry(field).is_some_and(|g| {
+            box_bounds(*min_lat, *min_lon, *max_lat, *max_lon).iter().any(|bounding_box| g.intersects(bounding_box))
+        }),
+        QueryNode::GeoNearRoute { field, route, distance } => {
+            if route.len() < 2 {
+                return Err(DbError::MissingData("A route needs at least two points".to_string()));
+            }
+            let route_geometry = Geometry::LineString(route.iter().map(|point| Coord::from(*point)).collect());
+            geometry(field).is_some_and(|g| route_distance(&g, &route_geometry) <= *distance)
+        }
+        QueryNode::GeoIntersects { field, geometry: geometry_value } => {
+            let query_geometry = parse_geometry(geometry_value)
+                .ok_or_else(|| DbError::AstQueryError(format!("Not a valid GeoPoint or geometry: {}", geometry_value)))?;
+            geometry(field).is_some_and(|g| g.intersects(&query_geometry))
+        }
+        QueryNode::Exists(field) => scoped_field_path(field, key).is_some_and(|path| field_present(doc, path)),
+        QueryNode::KeyEq(k) => key == k,
+        QueryNode::KeyPrefix(prefix) => key.starts_with(prefix.as_str()),
+        QueryNode::KeyRange { start, end } => {
+            start.as_ref().is_none_or(|start| key >= start.as_str()) && end.as_ref().is_none_or(|end| key < end.as_str())
+        }
+    })
+}
 
-            let left_set: HashSet<HashableValue> = left_results.into_iter().map(HashableValue).collect();
-            let right_set: HashSet<HashableValue> = right_results.into_iter().map(HashableValue).collect();
+// What a change feed subscribes to: one key, every key with a prefix, or
+// the documents matching a query, e.g. {"prefix": "users:"}.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchTarget {
+    Key(String),
+    Prefix(String),
+    Query(QueryNode),
+}
 
-            left_set.intersection(&right_set).cloned().map(|hv| hv.0).collect()
+impl WatchTarget {
+    // The key prefix to subscribe to on the documents tree.
+    pub fn prefix(&self) -> Vec<u8> {
+        match self {
+            WatchTarget::Key(key) => key.as_bytes().to_vec(),
+            WatchTarget::Prefix(prefix) => prefix.as_bytes().to_vec(),
+            WatchTarget::Query(_) => Vec::new(),
         }
-         QueryNode::Or(left, right) => {
-             let left_results = execute_ast_query(db, *left, None, None, None, config)?; // Pass config
-             let right_results = execute_ast_query(db, *right, None, None, None, config)?; // Pass config
+    }
+}
 
-             let mut combined_set: HashSet<HashableValue> = left_results.into_iter().map(HashableValue).collect();
-             for val in right_results {
-                 combined_set.insert(HashableValue(val));
-             }
+// A change delivered to a watcher.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChangeEvent {
+    Set { key: String, value: Value },
+    Delete { key: String },
+}
 
-             combined_set.into_iter().map(|hv| hv.0).collect()
-         }
-         QueryNode::Not(child_node) => {
-             // Inefficient NOT implementation: Fetch all, fetch excluded, filter
-             let all_docs = get_all_keys(db)?.into_iter()
-                 .map(|k| get_key(db, &k))
-                 .collect::<DbResult<Vec<Value>>>()?;
-
-             let excluded_docs = execute_ast_query(db, *child_node, None, None, None, config)?; // Pass config
-             let excluded_set: HashSet<HashableValue> = excluded_docs.into_iter().map(HashableValue).collect();
-
-             all_docs.into_iter()
-                 .filter(|doc| !excluded_set.contains(&HashableValue(doc.clone()))) // Clone needed for check
-                 .collect()
-         }
-         QueryNode::GeoWithinRadius { field, lat, lon, radius } => {
-              query_within_radius_simplified(db, &field, lat, lon, radius)?
-         }
-         QueryNode::GeoInBox { field, min_lat, min_lon, max_lat, max_lon } => {
-              query_in_box(db, &field, min_lat, min_lon, max_lat, max_lon)?
-         }
-    };
+impl ChangeEvent {
+    pub fn key(&self) -> &str {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Delete { key } => key,
+        }
+    }
 
-    // Apply Pagination
-    let start = offset.unwrap_or(0);
-    // let _end = start + limit.unwrap_or(usize::MAX); // _end is unused
-    if start < results.len() {
-         let limit_count = limit.unwrap_or(results.len() - start);
-         results = results.into_iter().skip(start).take(limit_count).collect();
-    } else {
-         results = vec![];
+    // The change a documents tree event made.
+    pub fn from_event(event: &sled::Event) -> DbResult<Self> {
+        Ok(match event {
+            sled::Event::Insert { key, value } => ChangeEvent::Set { key: String::from_utf8(key.to_vec())?, value: serde_json::from_slice(value)? },
+            sled::Event::Remove { key } => ChangeEvent::Delete { key: String::from_utf8(key.to_vec())? },
+        })
     }
+}
 
+// Turns document tree events into the changes a watch target sees. Query
+// watchers track which documents match, so a document that stops matching
+// (or is deleted) is reported as a delete.
+#[derive(Debug)]
+pub struct Watcher {
+    target: WatchTarget,
+    matching: HashSet<String>,
+}
 
-    // Apply Projection
-    if let Some(proj_paths) = projection {
-        apply_projection(results, &proj_paths)
-    } else {
-        Ok(results)
+impl Watcher {
+    // Subscribe to `target.prefix()` before creating the watcher so no
+    // change between the two is missed.
+    pub fn new(db: &Db, target: WatchTarget, config: &DbConfig) -> DbResult<Self> {
+        let matching = match &target {
+            WatchTarget::Query(query_node) => query_keys(db, query_node, config)?,
+            _ => HashSet::new(),
+        };
+        Ok(Watcher { target, matching })
+    }
+
+    pub fn apply(&mut self, event: &sled::Event) -> DbResult<Option<ChangeEvent>> {
+        self.filter(ChangeEvent::from_event(event)?)
+    }
+
+    // The change as the target sees it, if at all. Unlike `apply`, also
+    // checks the key against the target, for changes not read from a
+    // subscriber to `target.prefix()` (e.g. from the changelog).
+    pub fn filter(&mut self, change: ChangeEvent) -> DbResult<Option<ChangeEvent>> {
+        let change = match (&self.target, change) {
+            (WatchTarget::Key(watched), change) if watched != change.key() => None,
+            (WatchTarget::Prefix(prefix), change) if !change.key().starts_with(prefix.as_str()) => None,
+            (WatchTarget::Query(query_node), ChangeEvent::Set { key, value }) => {
+                if document_matches(&key, &value, query_node)? {
+                    self.matching.insert(key.clone());
+                    Some(ChangeEvent::Set { key, value })
+                } else {
+                    self.matching.remove(&key).then_some(ChangeEvent::Delete { key })
+                }
+            }
+            (WatchTarget::Query(_), ChangeEvent::Delete { key }) => self.matching.remove(&key).then_some(ChangeEvent::Delete { key }),
+            (_, change) => Some(change),
+        };
+        Ok(change)
     }
 }
 
+fn is_geo_node(query_node: &QueryNode) -> bool {
+    matches!(query_node, QueryNode::GeoWithinRadius { .. } | QueryNode::GeoInBox { .. } | QueryNode::GeoNearRoute { .. } | QueryNode::GeoIntersects { .. })
+}
 
-pub fn export_data(db: &Db) -> DbResult<String> {
-    let mut data = Vec::new();
-    for result in db.iter() {
-        let (key, value) = result?;
-        if !key.starts_with(GEO_SORTED_INDEX_PREFIX.as_bytes()) &&
-           !key.starts_with(FIELD_INDEX_PREFIX.as_bytes()) &&
-           !key.starts_with(FIELD_SORTED_INDEX_PREFIX.as_bytes()) {
-            let key_str = String::from_utf8(key.to_vec())?;
-            let value_json: Value = serde_json::from_slice(&value)?;
-            data.push(json!({ "key": key_str, "value": value_json }));
+// Resolves a geo node to its matching keys. With `candidates`, only those keys
+// are considered, so only their documents are loaded for the exact check.
+fn evaluate_geo_keys(ctx: &QueryContext, query_node: &QueryNode, candidates: Option<&HashSet<String>>) -> DbResult<HashSet<String>> {
+    let (db, config) = (ctx.db, ctx.config);
+    let keys: HashSet<String> = match query_node {
+        QueryNode::GeoWithinRadius { field, lat, lon, radius, metric } => {
+            let center = Point::new(*lon, *lat);
+            radius_matches(db, field, center, *radius, *metric, config, candidates)?.into_keys().collect()
+        }
+        QueryNode::GeoInBox { field, min_lat, min_lon, max_lat, max_lon } => {
+            box_matches(db, field, &box_bounds(*min_lat, *min_lon, *max_lat, *max_lon), config, candidates)?.into_keys().collect()
+        }
+        QueryNode::GeoNearRoute { field, route, distance } => {
+            route_matches(db, field, route, *distance, config, candidates)?.into_keys().collect()
+        }
+        QueryNode::GeoIntersects { field, geometry } => {
+            intersects_matches(db, field, geometry, config, candidates)?.into_keys().collect()
+        }
+        _ => return Err(DbError::AstQueryError("Expected a geo query node".to_string())),
+    };
+    ctx.note(|| format!("geo index{}", if candidates.is_some() { " over candidate keys" } else { "" }), keys.len(), keys.len());
+    Ok(keys)
+}
+
+// Whether the node resolves to keys through its indexes rather than by loading
+// every document.
+fn answered_by_index(query_node: &QueryNode, config: &DbConfig) -> bool {
+    match query_node {
+        QueryNode::Eq(field, ..) | QueryNode::Includes(field, ..) | QueryNode::Exists(field) => {
+            config.is_index_ready(field, IndexKind::Hash)
+        }
+        QueryNode::Gt(field, ..) | QueryNode::Lt(field, ..) | QueryNode::Gte(field, ..)
+        | QueryNode::Lte(field, ..) | QueryNode::Ne(field, ..) => {
+            !config.building_indexes.contains(&(field.clone(), IndexKind::Sorted))
         }
+        QueryNode::KeyEq(_) | QueryNode::KeyPrefix(_) | QueryNode::KeyRange { .. } => true,
+        QueryNode::And(left, right) | QueryNode::Or(left, right) => {
+            answered_by_index(left, config) && answered_by_index(right, config)
+        }
+        _ => false,
     }
-    Ok(serde_json::to_string(&data)?)
 }
 
-pub fn import_data(db: &Db, data: &str, config: &DbConfig) -> DbResult<()> {
-    let json_data: Vec<Value> = serde_json::from_str(data)?;
-    for item in json_data {
-        let key = item.get("key")
-            .and_then(Value::as_str)
-            .ok_or_else(|| DbError::ImportError("Invalid key format".to_string()))?;
-        let value_json = item.get("value")
-            .ok_or_else(|| DbError::ImportError("Missing value".to_string()))?;
+// Plans an And with a geo side, which would otherwise load every document in
+// the covering cells. The other side runs first when its indexes answer it
+// (or it is geo too) and the geo condition is then checked only for its keys;
+// a comparison that would scan every document is instead checked only on the
+// geo matches. Returns None for Ands without a geo side.
+fn evaluate_geo_conjunction(ctx: &QueryContext, left: &QueryNode, right: &QueryNode) -> DbResult<Option<HashSet<String>>> {
+    let (geo, other) = if is_geo_node(right) {
+        (right, left)
+    } else if is_geo_node(left) {
+        (left, right)
+    } else {
+        return Ok(None);
+    };
 
-        set_key(db, key, value_json.clone(), config)?;
+    if is_geo_node(other) || answered_by_index(other, ctx.config) {
+        let other_keys = evaluate_query_keys(ctx, other)?;
+        if other_keys.is_empty() {
+            return Ok(Some(other_keys));
+        }
+        return evaluate_geo_keys(ctx, geo, Some(&other_keys)).map(Some);
     }
-    Ok(())
-}
 
-fn index_geospatial_field(tx_db: &TransactionalTree, key: &str, field_path: &str, point: &GeoPoint) -> DbResult<()> {
-    let coord: Coord<f64> = point.clone().into();
-    let hash = encode(coord, GEOHASH_PRECISION).map_err(|e| DbError::Geohash(e.to_string()))?;
-    let index_key = get_geo_sorted_index_key(field_path, &hash, key);
-    debug!(key=key, field_path=field_path, hash=hash, index_key=%index_key, "Indexing geo field (transactional)");
-    tx_db.insert(index_key.as_bytes(), vec![])?;
-    debug!(key=key, field_path=field_path, hash=hash, index_key=%index_key, "Successfully inserted geo sorted index (transactional)");
-    Ok(())
+    let (field, operator, value, data_type) = match other {
+        QueryNode::Eq(f, v, _) | QueryNode::Includes(f, v, _) => (f, "Includes", v, &DataType::String),
+        QueryNode::Gt(f, v, t) => (f, "Gt", v, t),
+        QueryNode::Lt(f, v, t) => (f, "Lt", v, t),
+        QueryNode::Gte(f, v, t) => (f, "Gte", v, t),
+        QueryNode::Lte(f, v, t) => (f, "Lte", v, t),
+        QueryNode::Ne(f, v, t) => (f, "Ne", v, t),
+        _ => return Ok(None),
+    };
+    let datetime = *data_type == DataType::DateTime || ctx.config.datetime_fields.contains(field);
+    let geo_keys = evaluate_geo_keys(ctx, geo, None)?;
+    ctx.note(|| format!("filter geo matches on {}", field), geo_keys.len(), geo_keys.len());
+    filter_keys_by_condition(ctx.db, geo_keys, field, operator, value, ctx.coerce_types, datetime).map(Some)
 }
 
-fn remove_geospatial_index(tx_db: &TransactionalTree, key: &str, field_path: &str, point: &GeoPoint) -> DbResult<()> {
-    let coord: Coord<f64> = point.clone().into();
-    let hash = encode(coord, GEOHASH_PRECISION).map_err(|e| DbError::Geohash(e.to_string()))?;
-    let index_key = get_geo_sorted_index_key(field_path, &hash, key);
-    debug!(key=key, field_path=field_path, hash=hash, index_key=%index_key, "Removing geo sorted index (transactional)");
-    tx_db.remove(index_key.as_bytes())?;
-    debug!(key=key, field_path=field_path, hash=hash, index_key=%index_key, "Successfully removed geo sorted index (transactional)");
-    Ok(())
+#[derive(Debug, Deserialize, Clone)]
+pub struct SortSpec {
+    pub field: String,
+    #[serde(default)]
+    pub descending: bool,
 }
 
-pub fn query_within_radius_simplified(db: &Db, field_path: &str, center_lat: f64, center_lon: f64, radius_meters: f64) -> DbResult<Vec<Value>> {
-    // use geo::prelude::Distance; // Import the trait for .distance() // Removed unused import
-
-    let center_point_geo: Point<f64> = GeoPoint { lat: center_lat, lon: center_lon }.into();
-    let center_coord_geo: Coord<f64> = GeoPoint { lat: center_lat, lon: center_lon }.into();
-    let center_hash = encode(center_coord_geo, GEOHASH_PRECISION).map_err(|e| DbError::Geohash(e.to_string()))?;
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct QueryOptions {
+    pub projection: Option<Vec<String>>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub sort: Option<SortSpec>,
+    // Return this many uniformly random matches instead of all of them
+    pub sample: Option<usize>,
+    // Compare numbers and numeric strings numerically (e.g. "42" == 42)
+    #[serde(default)]
+    pub coerce_types: bool,
+    // Wrap each result as {"key": ..., "value": ...}
+    #[serde(default)]
+    pub include_key: bool,
+    // Only match keys starting with one of these prefixes. Never read from
+    // requests; callers set it to confine a query to what the caller may see.
+    #[serde(skip)]
+    pub key_prefixes: Option<Vec<String>>,
+}
 
-    let neighbors: Neighbors = geohash_neighbors(&center_hash).map_err(|e| DbError::Geohash(e.to_string()))?;
-    let mut hashes_to_check = vec![center_hash.clone()];
-    hashes_to_check.extend([neighbors.n, neighbors.ne, neighbors.e, neighbors.se, neighbors.s, neighbors.sw, neighbors.w, neighbors.nw]);
+// Walks the sorted index of `field_path` in order and returns up to `needed`
+// candidate keys in that order, without touching documents. Candidates without
+// an index entry (field missing or not sortable) are appended last, by key.
+fn top_k_from_sorted_index(db: &Db, field_path: &str, descending: bool, candidates: &HashSet<String>, needed: usize) -> DbResult<Vec<String>> {
+    let prefix = get_field_sorted_index_prefix(field_path);
+    let mut ordered = Vec::new();
+    let mut seen = HashSet::new();
 
-    let mut results_map: HashMap<String, Value> = HashMap::new();
+    let index_tree = db.open_tree(FIELD_SORTED_INDEX_TREE)?;
+    let iterator = if descending {
+        Box::new(index_tree.scan_prefix(&prefix).rev()) as Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>>>
+    } else {
+        Box::new(index_tree.scan_prefix(&prefix)) as Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>>>
+    };
 
+    for item_result in iterator {
+        if ordered.len() >= needed {
+            return Ok(ordered);
+        }
+        let (k, _) = item_result?;
+        let Ok((_, primary_key)) = parse_index_entry(&k) else {
+            warn!("Invalid sorted index key format: {}", String::from_utf8_lossy(&k));
+            continue;
+        };
+        if candidates.contains(&primary_key) && seen.insert(primary_key.clone()) {
+            ordered.push(primary_key);
+        }
+    }
 
-    for hash in hashes_to_check {
-        let prefix = get_geo_sorted_index_prefix_for_hash(field_path, &hash);
-        for item_result in db.scan_prefix(prefix.as_bytes()) {
-            let (index_key_bytes, _) = item_result?;
-            let index_key_str = String::from_utf8_lossy(&index_key_bytes);
-            let parts: Vec<&str> = index_key_str.split(':').collect();
+    let mut unindexed: Vec<&String> = candidates.iter().filter(|k| !seen.contains(*k)).collect();
+    unindexed.sort();
+    ordered.extend(unindexed.into_iter().take(needed.saturating_sub(ordered.len())).cloned());
+    Ok(ordered)
+}
 
-            if parts.len() < 4 {
-                 warn!("Invalid geo sorted index key format: {}", index_key_str);
-                 continue;
+// Orders documents by the value at the sort path, comparing strings under the
+// field's collation if it has one, or as instants for a datetime field;
+// documents missing the field sort last.
+fn sort_documents(docs: &mut [(String, Value)], sort: &SortSpec, collation: Option<&Collation>, datetime: bool) {
+    docs.sort_by(|(k1, d1), (k2, d2)| {
+        let sort_value = |k: &str, d| scoped_field_path(&sort.field, k).and_then(|path| field_value(d, path));
+        let ordering = match (sort_value(k1, d1), sort_value(k2, d2)) {
+            (Some(v1), Some(v2)) => {
+                let ordering = match (v1.as_ref(), v2.as_ref(), collation) {
+                    (Value::String(s1), Value::String(s2), Some(collation))
+                        if !(datetime && parse_datetime_micros(s1).is_some() && parse_datetime_micros(s2).is_some()) =>
+                    {
+                        collation_key(s1, collation).cmp(&collation_key(s2, collation))
+                    }
+                    (v1, v2, _) => compare_values(v1, v2, datetime).unwrap_or(Ordering::Equal),
+                };
+                if sort.descending { ordering.reverse() } else { ordering }
             }
-            let stored_field_path = parts[1];
-            if stored_field_path != field_path { continue; }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        ordering.then_with(|| k1.cmp(k2))
+    });
+}
 
-            if let Some(primary_key) = parts.last() {
-                 if results_map.contains_key(*primary_key) {
-                     continue;
-                 }
+// A field's sorted index is read whole to cover a projection, which only pays
+// off while it has at most this many entries per key of the page.
+const COVERING_SCAN_FACTOR: usize = 8;
+
+// Builds projected documents from the sorted index entries of the projected
+// fields instead of loading the documents. A key whose field has no entry,
+// several entries or array element entries (missing, non-scalar or array
+// values) falls back to loading and projecting its document. Returns None,
+// having read little, if an index is too large for the page to be worth it.
+fn project_from_sorted_index(db: &Db, keys: &[String], projection: &[String]) -> DbResult<Option<Vec<(String, Value)>>> {
+    let wanted: HashSet<&str> = keys.iter().map(String::as_str).collect();
+    let scan_limit = keys.len().saturating_mul(COVERING_SCAN_FACTOR);
+    let mut field_values: Vec<HashMap<String, Vec<Value>>> = Vec::with_capacity(projection.len());
+    let mut in_arrays: HashSet<String> = HashSet::new();
+
+    for path in projection {
+        let prefix = get_field_sorted_index_prefix(path);
+        let mut values: HashMap<String, Vec<Value>> = HashMap::new();
+        for (scanned, item_result) in db.open_tree(FIELD_SORTED_INDEX_TREE)?.scan_prefix(&prefix).enumerate() {
+            if scanned == scan_limit {
+                return Ok(None);
+            }
+            let (k, v) = item_result?;
+            let Ok((encoded, primary_key)) = parse_index_entry(&k) else {
+                warn!("Invalid sorted index key format: {}", String::from_utf8_lossy(&k));
+                continue;
+            };
+            if wanted.contains(primary_key.as_str()) {
+                if v.as_ref() == SORTED_ARRAY_ELEMENT {
+                    in_arrays.insert(primary_key.clone());
+                }
+                let value = decode_sorted_value(&encoded)?;
+                values.entry(primary_key).or_default().push(value);
+            }
+        }
+        field_values.push(values);
+    }
 
-                 match get_key(db, primary_key) {
-                     Ok(value) => {
-                         if let Some(point_val) = get_value_by_path(&value, field_path) {
-                             if let Ok(geo_point) = serde_json::from_value::<GeoPoint>(point_val.clone()) {
-                                 let entry_point: Point<f64> = geo_point.into();
-
-                                 // Use Distance trait method
-                                 let distance = entry_point.haversine_distance(&center_point_geo);
-                                 if distance <= radius_meters {
-                                     results_map.insert(primary_key.to_string(), value);
-                                 }
-
-                             } else {
-                                 warn!(key = primary_key, field_path = field_path, "Field is not a valid GeoPoint");
-                             }
-                         } else {
-                              warn!(key = primary_key, field_path = field_path, "Geo field not found in document");
-                         }
-                     },
-                     Err(DbError::NotFound) => warn!(key = primary_key, "Geo index points to non-existent key"),
-                     Err(e) => return Err(e),
-                 }
-            } else {
-                 warn!("Invalid geo sorted index key format (missing primary key?): {}", index_key_str);
+    let projection_paths = projection.to_vec();
+    let mut results = Vec::with_capacity(keys.len());
+    for key in keys {
+        let covered = !in_arrays.contains(key) && field_values.iter().all(|values| values.get(key).is_some_and(|v| v.len() == 1));
+        if covered {
+            let mut projected_doc = Value::Object(Map::new());
+            for (path, values) in projection.iter().zip(&field_values) {
+                let parts: Vec<&str> = path.split('.').collect();
+                insert_value_by_path(&mut projected_doc, &parts, values[key][0].clone())?;
             }
+            results.push((key.clone(), projected_doc));
+        } else {
+            let projected = apply_projection(vec![get_key(db, key)?], &projection_paths)?;
+            results.extend(projected.into_iter().map(|doc| (key.clone(), doc)));
         }
     }
-    Ok(results_map.into_values().collect())
+    Ok(Some(results))
+}
+
+pub fn execute_ast_query(
+    db: &Db,
+    query_node: QueryNode,
+    projection: Option<Vec<String>>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    config: &DbConfig, // Added config parameter
+) -> DbResult<Vec<Value>> {
+    let options = QueryOptions { projection, limit, offset, ..Default::default() };
+    execute_ast_query_with_options(db, query_node, &options, config)
 }
 
-pub fn query_in_box(db: &Db, field_path: &str, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> DbResult<Vec<Value>> {
+#[derive(Serialize, Debug)]
+pub struct QueryPage {
+    pub results: Vec<Value>,
+    // Number of matching documents before offset/limit are applied
+    pub total: usize,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
 
-    let bounding_box = Rect::new(
-        Coord { x: min_lon, y: min_lat },
-        Coord { x: max_lon, y: max_lat },
-    );
-    let prefix = get_geo_sorted_index_prefix_for_field(field_path);
-    let mut results_map: HashMap<String, Value> = HashMap::new();
+pub fn execute_ast_query_with_options(
+    db: &Db,
+    query_node: QueryNode,
+    options: &QueryOptions,
+    config: &DbConfig,
+) -> DbResult<Vec<Value>> {
+    Ok(run_query(db, query_node, options, config)?.0.results)
+}
 
-    for item_result in db.scan_prefix(prefix.as_bytes()) {
-        let (index_key_bytes, _) = item_result?;
-        let index_key_str = String::from_utf8_lossy(&index_key_bytes);
-        let parts: Vec<&str> = index_key_str.split(':').collect();
+// Like execute_ast_query_with_options, but also reports the total match count.
+pub fn execute_ast_query_page(
+    db: &Db,
+    query_node: QueryNode,
+    options: &QueryOptions,
+    config: &DbConfig,
+) -> DbResult<QueryPage> {
+    Ok(run_query(db, query_node, options, config)?.0)
+}
 
-         if parts.len() < 4 {
-              warn!("Invalid geo sorted index key format: {}", index_key_str);
-              continue;
-         }
-         let stored_field_path = parts[1];
-         if stored_field_path != field_path { continue; }
+// Like execute_ast_query_page, but also reports how the query ran.
+pub fn execute_ast_query_profiled(
+    db: &Db,
+    query_node: QueryNode,
+    options: &QueryOptions,
+    config: &DbConfig,
+) -> DbResult<(QueryPage, QueryProfile)> {
+    run_query(db, query_node, options, config)
+}
 
+const SLOW_QUERY_KEY_PREFIX: &str = "__slow_query__:";
 
-         if let Some(primary_key) = parts.last() {
-             if results_map.contains_key(*primary_key) {
-                 continue;
-             }
+// A query that took longer than the server's slow query threshold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowQuery {
+    // Unix time in milliseconds when the query finished.
+    pub at_ms: u64,
+    pub duration_ms: f64,
+    pub query: QueryNode,
+    pub total: usize,
+    #[serde(flatten)]
+    pub profile: QueryProfile,
+}
 
-             match get_key(db, primary_key) {
-                 Ok(value) => {
-                     if let Some(point_val) = get_value_by_path(&value, field_path) {
-                         if let Ok(geo_point) = serde_json::from_value::<GeoPoint>(point_val.clone()) {
-                             let entry_point: Point<f64> = geo_point.into();
-                             if bounding_box.contains(&entry_point) {
-                                 results_map.insert(primary_key.to_string(), value);
-                             }
-                         } else {
-                             warn!(key = primary_key, field_path = field_path, "Field is not a valid GeoPoint");
-                         }
-                     } else {
-                          warn!(key = primary_key, field_path = field_path, "Geo field not found in document");
-                     }
-                 },
-                 Err(DbError::NotFound) => warn!(key = primary_key, "Geo index points to non-existent key"),
-                 Err(e) => return Err(e),
-             }
-        } else {
-             warn!("Invalid geo sorted index key format (missing primary key?): {}", index_key_str);
-        }
+// Keeps the slow query in the meta tree, dropping the oldest beyond `keep`.
+pub fn record_slow_query(db: &Db, slow_query: &SlowQuery, keep: usize) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    let key = format!("{}{:020}", SLOW_QUERY_KEY_PREFIX, db.generate_id()?);
+    meta.insert(key.as_bytes(), serde_json::to_vec(slow_query)?)?;
+    let stored = meta.scan_prefix(SLOW_QUERY_KEY_PREFIX.as_bytes()).count();
+    for key in meta.scan_prefix(SLOW_QUERY_KEY_PREFIX.as_bytes()).keys().take(stored.saturating_sub(keep)) {
+        meta.remove(key?)?;
     }
-    Ok(results_map.into_values().collect())
+    Ok(())
 }
 
-// Simulates deleting a "table" by removing all keys with a given prefix
-pub fn clear_prefix(db: &Db, prefix: &str, config: &DbConfig) -> DbResult<usize> {
-    let keys_to_delete: Vec<String> = db.scan_prefix(prefix.as_bytes())
-        .keys()
-        .filter_map(|res| res.ok())
-        .filter_map(|key_bytes| String::from_utf8(key_bytes.to_vec()).ok())
-        .filter(|key_str| {
-            !key_str.starts_with(GEO_SORTED_INDEX_PREFIX) &&
-            !key_str.starts_with(FIELD_INDEX_PREFIX) &&
-            !key_str.starts_with(FIELD_SORTED_INDEX_PREFIX)
-        })
-        .collect();
+// The stored slow queries, newest first.
+pub fn slow_queries(db: &Db) -> DbResult<Vec<SlowQuery>> {
+    db.open_tree(META_TREE)?
+        .scan_prefix(SLOW_QUERY_KEY_PREFIX.as_bytes())
+        .values()
+        .rev()
+        .map(|value| Ok(serde_json::from_slice(&value?)?))
+        .collect()
+}
 
-    let count = keys_to_delete.len();
+// Returns the requested page of results along with the total number of matches.
+fn run_query(
+    db: &Db,
+    query_node: QueryNode,
+    options: &QueryOptions,
+    config: &DbConfig,
+) -> DbResult<(QueryPage, QueryProfile)> {
 
-    if count > 0 {
-        db.transaction(|tx_db| {
-            for key in &keys_to_delete {
-                delete_key_internal(tx_db, key, config)
-                    .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Clear prefix failed for key '{}': {}", key, e))))?;
-            }
-            Ok(())
-        })?;
+    let ctx = QueryContext { db, config, coerce_types: options.coerce_types, profile: RefCell::default() };
+    let mut matching_keys = evaluate_query_keys(&ctx, &query_node)?;
+    if let Some(prefixes) = &options.key_prefixes {
+        matching_keys.retain(|key| prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())));
     }
 
-    Ok(count)
-}
+    if let Some(sample_size) = options.sample {
+        // Reservoir sampling over the matching keys; documents are only fetched for the sample
+        matching_keys = matching_keys.into_iter().choose_multiple(&mut rand::thread_rng(), sample_size).into_iter().collect();
+    }
+    let total = matching_keys.len();
 
-// Clears all user data from the database
-pub fn drop_database(db: &Db, config: &DbConfig) -> DbResult<usize> {
-    let all_keys = get_all_keys(db)?;
-    let count = all_keys.len();
+    let start = options.offset.unwrap_or(0);
+    let limit_count = options.limit.unwrap_or(usize::MAX);
 
-    if count > 0 {
-        db.transaction(|tx_db| {
-            for key in &all_keys {
-                delete_key_internal(tx_db, key, config)
-                    .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Drop database failed for key '{}': {}", key, e))))?;
-            }
-            Ok(())
-        })?;
-    }
+    let (page_keys, sorted_page) = match &options.sort {
+        None => {
+            // Apply Pagination on keys so only the returned page is fetched
+            let mut keys: Vec<String> = matching_keys.into_iter().collect();
+            keys.sort();
+            (keys.into_iter().skip(start).take(limit_count).collect(), None)
+        }
+        Some(sort) if options.limit.is_some() && config.is_index_ready(&sort.field, IndexKind::Sorted) => {
+            // Top-k: walk the sorted index and stop once the requested page is covered
+            let needed = start.saturating_add(limit_count);
+            let keys = top_k_from_sorted_index(db, &sort.field, sort.descending, &matching_keys, needed)?;
+            ctx.note(|| format!("top {} from sorted index on {}", needed, sort.field), keys.len(), 0);
+            (keys.into_iter().skip(start).take(limit_count).collect(), None)
+        }
+        Some(sort) => {
+            ctx.note(|| format!("load and sort by {}", sort.field), 0, matching_keys.len());
+            let mut docs = matching_keys.into_iter()
+                .map(|k| get_key(db, &k).map(|doc| (k, doc)))
+                .collect::<DbResult<Vec<(String, Value)>>>()?;
+            sort_documents(&mut docs, sort, config.collations.get(&sort.field), config.datetime_fields.contains(&sort.field));
+            (Vec::new(), Some(docs.into_iter().skip(start).take(limit_count).collect()))
+        }
+    };
+
+    // Covering index: answer the projection from sorted index entries. Only
+    // plain paths qualify, as an expression index holds a computed value.
+    let covers = |path: &String| {
+        !path.contains('*') && matches!(parse_index_expr(path), Ok(None)) && config.is_index_ready(path, IndexKind::Sorted)
+    };
+    let covered_page = match (&sorted_page, &options.projection) {
+        (None, Some(proj_paths)) if !proj_paths.is_empty() && proj_paths.iter().all(covers) => {
+            project_from_sorted_index(db, &page_keys, proj_paths)?
+        }
+        _ => None,
+    };
+
+    let page: Vec<(String, Value)> = match (sorted_page, covered_page, &options.projection) {
+        (Some(page), _, projection) => project_documents(page, projection)?,
+        (None, Some(page), _) => {
+            ctx.note(|| "projection from sorted indexes".to_string(), 0, 0);
+            page
+        }
+        (None, None, projection) => {
+            ctx.note(|| "fetch page".to_string(), 0, page_keys.len());
+            let docs = fetch_documents(db, page_keys.iter().cloned())?;
+            project_documents(page_keys.into_iter().zip(docs).collect(), projection)?
+        }
+    };
+
+    let results = page.into_iter()
+        .map(|(key, doc)| if options.include_key { json!({ "key": key, "value": doc }) } else { doc })
+        .collect();
+    let page = QueryPage {
+        results,
+        total,
+        offset: options.offset.unwrap_or(0),
+        limit: options.limit,
+    };
+    Ok((page, ctx.profile.into_inner()))
+}
+
+// Applies the projection to each document, keeping it paired with its key.
+fn project_documents(docs: Vec<(String, Value)>, projection: &Option<Vec<String>>) -> DbResult<Vec<(String, Value)>> {
+    let Some(proj_paths) = projection else { return Ok(docs); };
+    let mut projected = Vec::with_capacity(docs.len());
+    for (key, doc) in docs {
+        if let Some(projected_doc) = apply_projection(vec![doc], proj_paths)?.into_iter().next() {
+            projected.push((key, projected_doc));
+        }
+    }
+    Ok(projected)
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexKind {
+    Hash,
+    Sorted,
+    Geo,
+    Unique,
+}
+
+const REBUILD_CHUNK_SIZE: usize = 256;
+
+fn index_tree_name(kind: IndexKind) -> &'static str {
+    match kind {
+        IndexKind::Hash => FIELD_INDEX_TREE,
+        IndexKind::Sorted => FIELD_SORTED_INDEX_TREE,
+        IndexKind::Geo => GEO_SORTED_INDEX_TREE,
+        IndexKind::Unique => UNIQUE_INDEX_TREE,
+    }
+}
+
+fn index_fields(config: &DbConfig, kind: IndexKind) -> &HashSet<String> {
+    match kind {
+        IndexKind::Hash => &config.hash_indexed_fields,
+        IndexKind::Sorted => &config.sorted_indexed_fields,
+        IndexKind::Geo => &config.geo_indexed_fields,
+        IndexKind::Unique => &config.unique_fields,
+    }
+}
+
+fn index_fields_mut(config: &mut DbConfig, kind: IndexKind) -> &mut HashSet<String> {
+    match kind {
+        IndexKind::Hash => &mut config.hash_indexed_fields,
+        IndexKind::Sorted => &mut config.sorted_indexed_fields,
+        IndexKind::Geo => &mut config.geo_indexed_fields,
+        IndexKind::Unique => &mut config.unique_fields,
+    }
+}
+
+// Removes every index entry of the field, returning how many were removed.
+fn drop_index_entries(db: &Db, field_path: &str, kind: IndexKind) -> DbResult<usize> {
+    // Every index tree keys its entries by the field path first.
+    let prefix = index_key(&[field_path.as_bytes()]);
+    let tree = db.open_tree(index_tree_name(kind))?;
+    let mut batch = Batch::default();
+    let mut removed = 0;
+    for key_result in tree.scan_prefix(&prefix).keys() {
+        batch.remove(key_result?);
+        removed += 1;
+    }
+    tree.apply_batch(batch)?;
+    Ok(removed)
+}
+
+fn index_documents(db: &Db, docs: &[(String, Value)], config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        for (key, value) in docs {
+            index_document(tx, key, value, config).map_err(ConflictableTransactionError::Abort)?;
+        }
+        Ok(())
+    })
+}
+
+// A configuration with only the field's index of this kind, for backfilling it.
+fn single_index_config(config: &DbConfig, field_path: &str, kind: IndexKind) -> DbConfig {
+    let mut field_config = DbConfig {
+        geohash_precision: config.geohash_precision.clone(),
+        sparse_fields: config.sparse_fields.clone(),
+        collations: config.collations.clone(),
+        datetime_fields: config.datetime_fields.clone(),
+        ..Default::default()
+    };
+    index_fields_mut(&mut field_config, kind).insert(field_path.to_string());
+    field_config
+}
+
+// Rebuilds one field's index from the stored documents, replacing any
+// existing entries. Returns the number of documents scanned.
+pub fn rebuild_index(db: &Db, field_path: &str, kind: IndexKind, config: &DbConfig) -> DbResult<usize> {
+    let mut config = config.clone();
+    if !index_fields_mut(&mut config, kind).contains(field_path) {
+        return Err(DbError::MissingData(format!("Field '{}' is not configured for {:?} indexing", field_path, kind)));
+    }
+
+    let removed = drop_index_entries(db, field_path, kind)?;
+    debug!(field_path = field_path, removed = removed, "Cleared index entries before rebuild");
+
+    let field_config = single_index_config(&config, field_path, kind);
+
+    // A failed rebuild (e.g. duplicates under a unique index) must not leave
+    // a partial index behind.
+    match populate_index(db, &field_config) {
+        Ok(scanned) => {
+            debug!(field_path = field_path, scanned = scanned, "Rebuilt index");
+            Ok(scanned)
+        }
+        Err(e) => {
+            drop_index_entries(db, field_path, kind)?;
+            Err(e)
+        }
+    }
+}
+
+// Rejects malformed index expressions; geo indexes only cover stored points.
+fn validate_index_field(field_path: &str, kind: IndexKind) -> DbResult<()> {
+    if parse_index_expr(index_field_path(field_path))?.is_some() && kind == IndexKind::Geo {
+        return Err(DbError::InvalidExpression(format!("Geo indexes cannot be defined over '{}'", field_path)));
+    }
+    Ok(())
+}
+
+// Adds the field to the index configuration, builds its index over the
+// existing documents and persists the new configuration. The configuration is
+// left untouched if the build fails. Returns the number of documents scanned.
+pub fn create_index(db: &Db, field_path: &str, kind: IndexKind, config: &mut DbConfig) -> DbResult<usize> {
+    validate_index_field(field_path, kind)?;
+    let mut updated = config.clone();
+    index_fields_mut(&mut updated, kind).insert(field_path.to_string());
+    let scanned = rebuild_index(db, field_path, kind, &updated)?;
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(scanned)
+}
+
+const INDEX_BUILD_KEY_PREFIX: &str = "__index_build__:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexBuildState {
+    Building,
+    Ready,
+    Failed,
+}
+
+// Progress of a background index build, persisted in the meta tree so an
+// interrupted build resumes where it stopped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexBuild {
+    pub field: String,
+    pub kind: IndexKind,
+    pub state: IndexBuildState,
+    pub processed: usize,
+    // Key of the last document backfilled; the next batch starts after it.
+    pub resume_after: Option<String>,
+    pub error: Option<String>,
+}
+
+fn index_build_key(field_path: &str, kind: IndexKind) -> String {
+    format!("{}{:?}:{}", INDEX_BUILD_KEY_PREFIX, kind, field_path)
+}
+
+fn save_index_build(db: &Db, build: &IndexBuild) -> DbResult<()> {
+    db.open_tree(META_TREE)?.insert(index_build_key(&build.field, build.kind).as_bytes(), serde_json::to_vec(build)?)?;
+    Ok(())
+}
+
+pub fn get_index_build(db: &Db, field_path: &str, kind: IndexKind) -> DbResult<Option<IndexBuild>> {
+    match db.open_tree(META_TREE)?.get(index_build_key(field_path, kind).as_bytes())? {
+        Some(ivec) => Ok(Some(serde_json::from_slice(&ivec)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn index_builds(db: &Db) -> DbResult<Vec<IndexBuild>> {
+    db.open_tree(META_TREE)?.scan_prefix(INDEX_BUILD_KEY_PREFIX.as_bytes())
+        .values()
+        .map(|value_result| Ok(serde_json::from_slice(&value_result?)?))
+        .collect()
+}
+
+// Registers a background build of the field's index and persists the
+// configuration. From then on writes maintain the index, while queries ignore
+// it until `continue_index_build` has backfilled every existing document.
+// Starting a build that is already running returns its progress.
+pub fn start_index_build(db: &Db, field_path: &str, kind: IndexKind, config: &mut DbConfig) -> DbResult<IndexBuild> {
+    validate_index_field(field_path, kind)?;
+    let build_id = (field_path.to_string(), kind);
+    if config.building_indexes.contains(&build_id) {
+        if let Some(build) = get_index_build(db, field_path, kind)? {
+            return Ok(build);
+        }
+    }
+
+    let removed = drop_index_entries(db, field_path, kind)?;
+    debug!(field_path = field_path, removed = removed, "Cleared index entries before background build");
+    let mut updated = config.clone();
+    index_fields_mut(&mut updated, kind).insert(field_path.to_string());
+    updated.building_indexes.insert(build_id);
+    save_config(db, &updated)?;
+    *config = updated;
+
+    let build = IndexBuild {
+        field: field_path.to_string(),
+        kind,
+        state: IndexBuildState::Building,
+        processed: 0,
+        resume_after: None,
+        error: None,
+    };
+    save_index_build(db, &build)?;
+    Ok(build)
+}
+
+// Removes the field's index of this kind: its entries, any background build
+// of it, and its place in the persisted configuration. Returns the number of
+// index entries removed.
+pub fn drop_index(db: &Db, field_path: &str, kind: IndexKind, config: &mut DbConfig) -> DbResult<usize> {
+    let mut updated = config.clone();
+    if !index_fields_mut(&mut updated, kind).remove(field_path) {
+        return Err(DbError::MissingData(format!("Field '{}' is not configured for {:?} indexing", field_path, kind)));
+    }
+    updated.building_indexes.remove(&(field_path.to_string(), kind));
+    save_config(db, &updated)?;
+    *config = updated;
+    db.open_tree(META_TREE)?.remove(index_build_key(field_path, kind).as_bytes())?;
+    drop_index_entries(db, field_path, kind)
+}
+
+// Backfills up to `batch_size` more documents of a background build in one
+// transaction, marking the index ready once every document has been visited.
+// Duplicates under a unique index fail the build: its partial entries are
+// dropped and the index is removed from the configuration.
+pub fn continue_index_build(db: &Db, field_path: &str, kind: IndexKind, batch_size: usize, config: &mut DbConfig) -> DbResult<IndexBuild> {
+    let mut build = get_index_build(db, field_path, kind)?
+        .ok_or_else(|| DbError::MissingData(format!("No {:?} index build for field '{}'", kind, field_path)))?;
+    if build.state != IndexBuildState::Building {
+        return Ok(build);
+    }
+
+    let batch_size = batch_size.max(1);
+    let lower = match &build.resume_after {
+        Some(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        None => Bound::Unbounded,
+    };
+    let keys: Vec<IVec> = db.range::<Vec<u8>, _>((lower, Bound::Unbounded))
+        .keys()
+        .take(batch_size)
+        .collect::<Result<_, _>>()?;
+
+    let field_config = single_index_config(config, field_path, kind);
+    let result = transaction(db, |tx| {
+        for key in &keys {
+            // The document may have been deleted since the scan.
+            let Some(value_bytes) = tx.docs.get(key)? else { continue };
+            let indexed = String::from_utf8(key.to_vec()).map_err(DbError::from).and_then(|key_str| {
+                let value = serde_json::from_slice::<Value>(&value_bytes)?;
+                index_document(tx, &key_str, &value, &field_config)
+            });
+            indexed.map_err(ConflictableTransactionError::Abort)?;
+        }
+        Ok(())
+    });
+
+    let build_id = (field_path.to_string(), kind);
+    match result {
+        Ok(()) => {}
+        Err(DbError::UniqueViolation(message)) => {
+            warn!(field_path = field_path, error = %message, "Background index build failed");
+            drop_index_entries(db, field_path, kind)?;
+            let mut updated = config.clone();
+            index_fields_mut(&mut updated, kind).remove(field_path);
+            updated.building_indexes.remove(&build_id);
+            save_config(db, &updated)?;
+            *config = updated;
+            build.state = IndexBuildState::Failed;
+            build.error = Some(message);
+            save_index_build(db, &build)?;
+            return Ok(build);
+        }
+        Err(e) => return Err(e),
+    }
+
+    build.processed += keys.len();
+    if let Some(last_key) = keys.last() {
+        build.resume_after = Some(String::from_utf8(last_key.to_vec())?);
+    }
+    if keys.len() < batch_size {
+        let mut updated = config.clone();
+        updated.building_indexes.remove(&build_id);
+        save_config(db, &updated)?;
+        *config = updated;
+        build.state = IndexBuildState::Ready;
+        debug!(field_path = field_path, processed = build.processed, "Background index build completed");
+    }
+    save_index_build(db, &build)?;
+    Ok(build)
+}
+
+// Makes the field's hash index sparse (omitting documents that lack the field)
+// or dense, re-indexing it when the field is hash indexed, and persists the
+// configuration. Set this before creating the index to build it only once.
+pub fn set_index_sparse(db: &Db, field_path: &str, sparse: bool, config: &mut DbConfig) -> DbResult<usize> {
+    let mut updated = config.clone();
+    if sparse {
+        updated.sparse_fields.insert(field_path.to_string());
+    } else {
+        updated.sparse_fields.remove(field_path);
+    }
+    let scanned = if updated.hash_indexed_fields.contains(field_path) {
+        rebuild_index(db, field_path, IndexKind::Hash, &updated)?
+    } else {
+        0
+    };
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(scanned)
+}
+
+// Sets (or with `None` resets to byte order) the collation of a field's string
+// values in the sorted index, re-indexing it when the field is sorted indexed,
+// and persists the configuration.
+pub fn set_collation(db: &Db, field_path: &str, collation: Option<Collation>, config: &mut DbConfig) -> DbResult<usize> {
+    let mut updated = config.clone();
+    match collation {
+        Some(collation) => updated.collations.insert(field_path.to_string(), collation),
+        None => updated.collations.remove(field_path),
+    };
+    let scanned = if updated.sorted_indexed_fields.contains(field_path) {
+        rebuild_index(db, field_path, IndexKind::Sorted, &updated)?
+    } else {
+        0
+    };
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(scanned)
+}
+
+// Declares (or with `false` no longer) a field as holding RFC3339 timestamps,
+// re-indexing it when the field is sorted indexed, and persists the
+// configuration. Its sorted index and comparisons then order them as instants.
+pub fn set_datetime_field(db: &Db, field_path: &str, datetime: bool, config: &mut DbConfig) -> DbResult<usize> {
+    let mut updated = config.clone();
+    if datetime {
+        updated.datetime_fields.insert(field_path.to_string());
+    } else {
+        updated.datetime_fields.remove(field_path);
+    }
+    let scanned = if updated.sorted_indexed_fields.contains(field_path) {
+        rebuild_index(db, field_path, IndexKind::Sorted, &updated)?
+    } else {
+        0
+    };
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(scanned)
+}
+
+// Changes the geohash precision of a geo field's index (1 to 12 characters),
+// re-indexing it when the field is geo indexed, and persists the configuration.
+pub fn set_geohash_precision(db: &Db, field_path: &str, precision: usize, config: &mut DbConfig) -> DbResult<usize> {
+    if !(1..=12).contains(&precision) {
+        return Err(DbError::InvalidComparisonValue(format!("Geohash precision must be between 1 and 12, got {}", precision)));
+    }
+    let mut updated = config.clone();
+    updated.geohash_precision.insert(field_path.to_string(), precision);
+    let scanned = if updated.geo_indexed_fields.contains(field_path) {
+        rebuild_index(db, field_path, IndexKind::Geo, &updated)?
+    } else {
+        0
+    };
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(scanned)
+}
+
+// Sets (or with `None` removes) the TTL of a timestamp field, re-indexing the
+// expiry of every stored document, and persists the new configuration.
+pub fn set_ttl(db: &Db, field_path: &str, ttl_secs: Option<u64>, config: &mut DbConfig) -> DbResult<usize> {
+    let mut old_config = DbConfig::default();
+    if let Some(old_ttl) = config.ttl_fields.get(field_path) {
+        old_config.ttl_fields.insert(field_path.to_string(), *old_ttl);
+    }
+    let mut new_config = DbConfig::default();
+    if let Some(ttl) = ttl_secs {
+        new_config.ttl_fields.insert(field_path.to_string(), ttl);
+    }
+
+    let scanned = for_each_document_chunk(db, |chunk| reindex_documents(db, chunk, &old_config, &new_config))?;
+
+    match ttl_secs {
+        Some(ttl) => config.ttl_fields.insert(field_path.to_string(), ttl),
+        None => config.ttl_fields.remove(field_path),
+    };
+    save_config(db, config)?;
+    Ok(scanned)
+}
+
+fn reindex_documents(db: &Db, docs: &[(String, Value)], old_config: &DbConfig, new_config: &DbConfig) -> DbResult<()> {
+    transaction(db, |tx| {
+        for (key, value) in docs {
+            unindex_document(tx, key, value, old_config).map_err(ConflictableTransactionError::Abort)?;
+            index_document(tx, key, value, new_config).map_err(ConflictableTransactionError::Abort)?;
+        }
+        Ok(())
+    })
+}
+
+// Deletes every document whose TTL expired at or before `now_secs`, along
+// with its index entries. Returns the number of documents deleted.
+pub fn expire_before(db: &Db, now_secs: u64, config: &DbConfig) -> DbResult<usize> {
+    let end = get_ttl_index_key(now_secs.saturating_add(1), "");
+    let expired: Vec<(IVec, String)> = db.open_tree(TTL_INDEX_TREE)?.range(..end)
+        .keys()
+        .map(|key_result| {
+            let entry = key_result?;
+            let primary_key = match <[Vec<u8>; 2]>::try_from(split_index_key(&entry)?) {
+                Ok([_, primary_key]) => String::from_utf8(primary_key)?,
+                Err(_) => return Err(DbError::InvalidFieldIndexKey(String::from_utf8_lossy(&entry).into_owned())),
+            };
+            Ok((entry, primary_key))
+        })
+        .collect::<DbResult<_>>()?;
+
+    let mut deleted = 0;
+    for chunk in expired.chunks(REBUILD_CHUNK_SIZE) {
+        deleted += transaction(db, |tx| {
+            let mut count = 0;
+            for (entry, primary_key) in chunk {
+                // The document may have been updated with a later expiry since the scan.
+                if tx.ttl.get(entry)?.is_none() {
+                    continue;
+                }
+                if tx.docs.get(primary_key.as_bytes())?.is_some() {
+                    delete_key_internal(tx, primary_key, config).map_err(ConflictableTransactionError::Abort)?;
+                    count += 1;
+                }
+                // Drops entries left behind by documents that no longer exist.
+                tx.ttl.remove(entry)?;
+            }
+            Ok(count)
+        })?;
+    }
+    debug!(deleted = deleted, "Expired documents");
+    Ok(deleted)
+}
+
+pub fn expire_now(db: &Db, config: &DbConfig) -> DbResult<usize> {
+    expire_before(db, unix_now_secs(), config)
+}
+
+fn populate_index(db: &Db, field_config: &DbConfig) -> DbResult<usize> {
+    for_each_document_chunk(db, |chunk| index_documents(db, chunk, field_config))
+}
+
+// Feeds the stored documents to `f` in chunks, returning how many were visited.
+fn for_each_document_chunk<F>(db: &Db, mut f: F) -> DbResult<usize>
+where
+    F: FnMut(&[(String, Value)]) -> DbResult<()>,
+{
+    let mut scanned = 0;
+    let mut chunk = Vec::with_capacity(REBUILD_CHUNK_SIZE);
+    for result in db.iter() {
+        let (key_bytes, value_bytes) = result?;
+        chunk.push((String::from_utf8(key_bytes.to_vec())?, serde_json::from_slice::<Value>(&value_bytes)?));
+        if chunk.len() == REBUILD_CHUNK_SIZE {
+            f(&chunk)?;
+            scanned += chunk.len();
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        f(&chunk)?;
+        scanned += chunk.len();
+    }
+    Ok(scanned)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IndexStats {
+    pub field: String,
+    pub kind: IndexKind,
+    pub entries: usize,
+    // Total bytes of the entries' keys and values, before sled's own overhead.
+    pub approximate_size_bytes: usize,
+    pub distinct_values: usize,
+}
+
+// Computes statistics for every configured index by scanning its entries.
+pub fn index_stats(db: &Db, config: &DbConfig) -> DbResult<Vec<IndexStats>> {
+    let configured = [
+        (IndexKind::Hash, &config.hash_indexed_fields),
+        (IndexKind::Sorted, &config.sorted_indexed_fields),
+        (IndexKind::Geo, &config.geo_indexed_fields),
+        (IndexKind::Unique, &config.unique_fields),
+    ];
+    let mut stats = Vec::new();
+    for (kind, fields) in configured {
+        let tree = db.open_tree(index_tree_name(kind))?;
+        let mut sorted_fields: Vec<&String> = fields.iter().collect();
+        sorted_fields.sort();
+        for field in sorted_fields {
+            stats.push(field_index_stats(&tree, field, kind)?);
+        }
+    }
+    Ok(stats)
+}
+
+fn field_index_stats(tree: &sled::Tree, field_path: &str, kind: IndexKind) -> DbResult<IndexStats> {
+    let mut stats = IndexStats { field: field_path.to_string(), kind, entries: 0, approximate_size_bytes: 0, distinct_values: 0 };
+    // Entries are ordered by value within a field, so a new value starts a new run.
+    let mut previous_value: Option<Vec<u8>> = None;
+    for item_result in tree.scan_prefix(index_key(&[field_path.as_bytes()])) {
+        let (k, v) = item_result?;
+        stats.entries += 1;
+        stats.approximate_size_bytes += k.len() + v.len();
+        let value = split_index_key(&k)?.into_iter().nth(1).filter(|value| !is_presence_marker(value));
+        if value.is_some() && value != previous_value {
+            stats.distinct_values += 1;
+            previous_value = value;
+        }
+    }
+    Ok(stats)
+}
+
+const VERIFY_SAMPLE_LIMIT: usize = 100;
+
+#[derive(Serialize, Debug, Default)]
+pub struct IndexVerification {
+    pub documents_scanned: usize,
+    // Entries a document should have but that are absent or hold the wrong value.
+    pub missing_entries: usize,
+    // Entries no document accounts for, e.g. pointing at a deleted key.
+    pub orphaned_entries: usize,
+    pub repaired: bool,
+    // The first issues found, as `<tree> <entry components>`.
+    pub missing_samples: Vec<String>,
+    pub orphaned_samples: Vec<String>,
+}
+
+pub fn verify_indexes(db: &Db, config: &DbConfig) -> DbResult<IndexVerification> {
+    check_indexes(db, config, false)
+}
+
+// Like `verify_indexes`, but also writes missing entries and removes orphaned
+// ones. Not atomic with respect to concurrent writes.
+pub fn repair_indexes(db: &Db, config: &DbConfig) -> DbResult<IndexVerification> {
+    check_indexes(db, config, true)
+}
+
+fn describe_index_entry(tree: &str, key: &[u8]) -> String {
+    let components = split_index_key(key).unwrap_or_else(|_| vec![key.to_vec()]);
+    // Binary components (encoded sorted values, expiry times) are shown as hex.
+    let parts: Vec<String> = components.iter()
+        .map(|c| match std::str::from_utf8(c) {
+            Ok(text) if !text.chars().any(char::is_control) => text.to_string(),
+            _ => format!("0x{}", hex::encode(c)),
+        })
+        .collect();
+    format!("{} {}", tree, parts.join(" | "))
+}
+
+fn check_indexes(db: &Db, config: &DbConfig, repair: bool) -> DbResult<IndexVerification> {
+    let mut report = IndexVerification { repaired: repair, ..Default::default() };
+
+    let mut expected: HashMap<(&'static str, Vec<u8>), Vec<u8>> = HashMap::new();
+    report.documents_scanned = for_each_document_chunk(db, |chunk| {
+        for (key, value) in chunk {
+            let mut entries = Vec::new();
+            collect_index_entries(key, value, config, &mut entries)?;
+            for entry in entries {
+                expected.entry((entry.tree, entry.key)).or_insert(entry.value);
+            }
+        }
+        Ok(())
+    })?;
+
+    for ((tree_name, key), value) in &expected {
+        let tree = db.open_tree(tree_name)?;
+        if tree.get(key)?.is_some_and(|stored| stored == value.as_slice()) {
+            continue;
+        }
+        report.missing_entries += 1;
+        if report.missing_samples.len() < VERIFY_SAMPLE_LIMIT {
+            report.missing_samples.push(describe_index_entry(tree_name, key));
+        }
+        if repair {
+            tree.insert(key.as_slice(), value.as_slice())?;
+        }
+    }
+
+    for tree_name in INDEX_TREES {
+        let tree = db.open_tree(tree_name)?;
+        for key_result in tree.iter().keys() {
+            let key = key_result?;
+            if expected.contains_key(&(tree_name, key.to_vec())) {
+                continue;
+            }
+            report.orphaned_entries += 1;
+            if report.orphaned_samples.len() < VERIFY_SAMPLE_LIMIT {
+                report.orphaned_samples.push(describe_index_entry(tree_name, &key));
+            }
+            if repair {
+                tree.remove(key)?;
+            }
+        }
+    }
+
+    debug!(missing = report.missing_entries, orphaned = report.orphaned_entries, repair = repair, "Verified indexes");
+    Ok(report)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct IndexGcReport {
+    pub entries_scanned: usize,
+    pub entries_removed: usize,
+}
+
+// The primary key an index entry points at: the last key component, except in
+// the unique index, which stores it as the value.
+fn index_entry_primary_key(tree_name: &str, key: &[u8], value: &[u8]) -> DbResult<Vec<u8>> {
+    if tree_name == UNIQUE_INDEX_TREE {
+        return Ok(value.to_vec());
+    }
+    split_index_key(key)?.pop().ok_or_else(|| DbError::InvalidFieldIndexKey(String::from_utf8_lossy(key).into_owned()))
+}
+
+// Removes index entries whose document no longer exists, e.g. left behind by
+// writes made before a field was indexed or by an interrupted import. Cheaper
+// than `repair_indexes`, as documents are looked up rather than re-indexed.
+// Removals re-check the document in a transaction so concurrent writes win.
+pub fn gc_index_entries(db: &Db) -> DbResult<IndexGcReport> {
+    let mut report = IndexGcReport::default();
+    for tree_name in INDEX_TREES {
+        let mut orphans = Vec::new();
+        for item_result in db.open_tree(tree_name)?.iter() {
+            let (key, value) = item_result?;
+            report.entries_scanned += 1;
+            match index_entry_primary_key(tree_name, &key, &value) {
+                Ok(primary_key) => {
+                    if !db.contains_key(&primary_key)? {
+                        orphans.push((key, primary_key));
+                    }
+                }
+                Err(_) => warn!(tree = tree_name, "Skipping malformed index entry during GC: {}", describe_index_entry(tree_name, &key)),
+            }
+        }
+
+        let mut removed = 0;
+        for chunk in orphans.chunks(REBUILD_CHUNK_SIZE) {
+            removed += transaction(db, |tx| {
+                let mut count = 0;
+                for (key, primary_key) in chunk {
+                    if tx.docs.get(primary_key)?.is_none() && tx.index_tree(tree_name).remove(key)?.is_some() {
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            })?;
+        }
+        debug!(tree = tree_name, removed = removed, "Collected orphaned index entries");
+        report.entries_removed += removed;
+    }
+    Ok(report)
+}
+
+pub fn export_data(db: &Db) -> DbResult<String> {
+    export_data_with_progress(db, &|_, _| {})
+}
+
+// The total is not known until the export ends.
+pub fn export_data_with_progress(db: &Db, progress: Progress) -> DbResult<String> {
+    let mut data = Vec::new();
+    for result in db.iter() {
+        let (key, value) = result?;
+        let key_str = String::from_utf8(key.to_vec())?;
+        let value_json: Value = serde_json::from_slice(&value)?;
+        data.push(json!({ "key": key_str, "value": value_json }));
+        if data.len() % PROGRESS_INTERVAL == 0 {
+            progress(data.len(), None);
+        }
+    }
+    progress(data.len(), Some(data.len()));
+    Ok(serde_json::to_string(&data)?)
+}
+
+// Each document as a newline-terminated `{"key", "value"}` record, read
+// lazily so an export never holds the whole database in memory.
+pub fn export_records(db: &Db) -> impl Iterator<Item = DbResult<Vec<u8>>> {
+    db.iter().map(|result| {
+        let (key, value) = result?;
+        let key_str = String::from_utf8(key.to_vec())?;
+        let value_json: Value = serde_json::from_slice(&value)?;
+        let mut record = serde_json::to_vec(&json!({ "key": key_str, "value": value_json }))?;
+        record.push(b'\n');
+        Ok(record)
+    })
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct BackupReport {
+    pub trees: usize,
+    pub entries: usize,
+}
+
+const BACKUP_BATCH_SIZE: usize = 1024;
+
+// Copies every tree of the database, documents and indexes alike, into a new
+// sled database at `target`, which must be missing or empty. The copy only
+// reflects a single moment if nothing writes while it runs; the server holds
+// the config lock its writers take.
+pub fn backup_to(db: &Db, target: &std::path::Path) -> DbResult<BackupReport> {
+    if std::fs::read_dir(target).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(DbError::PreconditionFailed(format!("Backup target {} is not empty", target.display())));
+    }
+    let backup = sled::Config::default().path(target).use_compression(true).open()?;
+    let report = copy_trees(db, &backup)?;
+    backup.flush()?;
+    Ok(report)
+}
+
+// Copies every tree of `source` into `target`, overwriting entries with the
+// same keys.
+pub fn copy_trees(source: &Db, target: &Db) -> DbResult<BackupReport> {
+    let mut report = BackupReport::default();
+    for name in source.tree_names() {
+        let (from, to) = (source.open_tree(&name)?, target.open_tree(&name)?);
+        let mut batch = Batch::default();
+        let mut pending = 0;
+        for entry in from.iter() {
+            let (key, value) = entry?;
+            batch.insert(key, value);
+            pending += 1;
+            if pending == BACKUP_BATCH_SIZE {
+                to.apply_batch(std::mem::take(&mut batch))?;
+                report.entries += pending;
+                pending = 0;
+            }
+        }
+        to.apply_batch(batch)?;
+        report.entries += pending;
+        report.trees += 1;
+    }
+    Ok(report)
+}
+
+// Every tree of the database as one byte string, for storage that isn't a
+// filesystem, such as a browser's IndexedDB. Each tree is its name and entry
+// count followed by its keys and values, all length-prefixed big-endian.
+pub fn snapshot(db: &Db) -> DbResult<Vec<u8>> {
+    fn put(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(bytes);
+    }
+    let mut out = Vec::new();
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        put(&mut out, &name);
+        out.extend_from_slice(&(tree.len() as u64).to_be_bytes());
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            put(&mut out, &key);
+            put(&mut out, &value);
+        }
+    }
+    Ok(out)
+}
+
+// Writes the trees of a `snapshot` into the database, overwriting entries
+// with the same keys.
+pub fn restore_snapshot(db: &Db, snapshot: &[u8]) -> DbResult<BackupReport> {
+    fn take<'a>(input: &mut &'a [u8], len: usize) -> DbResult<&'a [u8]> {
+        if input.len() < len {
+            return Err(DbError::ImportError("Truncated snapshot".to_string()));
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        Ok(bytes)
+    }
+    fn take_prefixed<'a>(input: &mut &'a [u8]) -> DbResult<&'a [u8]> {
+        let len = u32::from_be_bytes(take(input, 4)?.try_into()?);
+        take(input, len as usize)
+    }
+    let mut report = BackupReport::default();
+    let mut input = snapshot;
+    while !input.is_empty() {
+        let tree = db.open_tree(take_prefixed(&mut input)?)?;
+        let count = u64::from_be_bytes(take(&mut input, 8)?.try_into()?);
+        let mut batch = Batch::default();
+        for _ in 0..count {
+            let key = take_prefixed(&mut input)?;
+            batch.insert(key, take_prefixed(&mut input)?);
+        }
+        tree.apply_batch(batch)?;
+        report.entries += count as usize;
+        report.trees += 1;
+    }
+    Ok(report)
+}
+
+// Document changes in the order they were made, for replicas to follow,
+// keyed by big-endian sequence number. Each change is logged in the
+// transaction that makes it, once `set_changelog_size` has enabled the log.
+pub const CHANGELOG_TREE: &str = "__changelog__";
+// Meta key of the changelog's id, replaced whenever the log starts over (as
+// after a restore), so replicas following the old log bootstrap again.
+const CHANGELOG_ID_KEY: &str = "__changelog_id__";
+// Meta key of the number of changes the changelog keeps; nothing is logged
+// without it.
+const CHANGELOG_SIZE_KEY: &str = "__changelog_size__";
+// Meta key of the sequence number of the last change logged.
+const CHANGELOG_SEQ_KEY: &str = "__changelog_seq__";
+// Meta key of a replica's position in its primary's changelog.
+const REPLICA_POSITION_KEY: &str = "__replica_position__";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoggedChange {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: ChangeEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeBatch {
+    pub log_id: String,
+    pub changes: Vec<LoggedChange>,
+    // The sequence number to ask for the changes after next.
+    pub last_seq: u64,
+}
+
+// A point in a changelog: the last change applied, or for a primary, the
+// last change logged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangelogPosition {
+    pub log_id: String,
+    pub seq: u64,
+}
+
+fn changelog_id(db: &Db) -> DbResult<String> {
+    let meta = db.open_tree(META_TREE)?;
+    if let Some(id) = meta.get(CHANGELOG_ID_KEY.as_bytes())? {
+        return Ok(String::from_utf8(id.to_vec())?);
+    }
+    let id = format!("{:016x}", rand::random::<u64>());
+    meta.insert(CHANGELOG_ID_KEY.as_bytes(), id.as_bytes())?;
+    Ok(id)
+}
+
+fn changelog_seq(key: &[u8]) -> DbResult<u64> {
+    let bytes = key.try_into().map_err(|_| DbError::MissingData(format!("Invalid changelog key {:?}", key)))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn last_logged_seq(meta: &sled::Tree, changelog: &sled::Tree) -> DbResult<u64> {
+    if let Some(seq) = meta.get(CHANGELOG_SEQ_KEY.as_bytes())? {
+        return changelog_seq(&seq);
+    }
+    // Logs from before the sequence number was kept in the meta tree.
+    match changelog.last()? {
+        Some((key, _)) => changelog_seq(&key),
+        None => Ok(0),
+    }
+}
+
+pub fn changelog_position(db: &Db) -> DbResult<ChangelogPosition> {
+    let seq = last_logged_seq(&db.open_tree(META_TREE)?, &db.open_tree(CHANGELOG_TREE)?)?;
+    Ok(ChangelogPosition { log_id: changelog_id(db)?, seq })
+}
+
+// Logs document changes, keeping the last `keep` of them; 0 stops logging
+// and leaves the log as it is.
+pub fn set_changelog_size(db: &Db, keep: usize) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    if keep == 0 {
+        meta.remove(CHANGELOG_SIZE_KEY.as_bytes())?;
+        return Ok(());
+    }
+    let changelog = db.open_tree(CHANGELOG_TREE)?;
+    let seq = last_logged_seq(&meta, &changelog)?;
+    meta.insert(CHANGELOG_SEQ_KEY.as_bytes(), &seq.to_be_bytes())?;
+    meta.insert(CHANGELOG_SIZE_KEY.as_bytes(), &(keep as u64).to_be_bytes())?;
+    let first_kept = seq.saturating_sub(keep as u64) + 1;
+    for key in changelog.range(..first_kept.to_be_bytes()).keys() {
+        changelog.remove(key?)?;
+    }
+    Ok(())
+}
+
+// Logs the change `change` builds under the next sequence number, dropping
+// the entry that falls out of the log. Does nothing unless the changelog is
+// enabled.
+fn log_change(tx: &TxTrees, change: impl FnOnce() -> ChangeEvent) -> DbResult<()> {
+    let Some(keep) = tx.meta.get(CHANGELOG_SIZE_KEY.as_bytes())? else { return Ok(()) };
+    let keep = changelog_seq(&keep)?;
+    let seq = match tx.meta.get(CHANGELOG_SEQ_KEY.as_bytes())? {
+        Some(seq) => changelog_seq(&seq)? + 1,
+        None => 1,
+    };
+    tx.changelog.insert(&seq.to_be_bytes(), serde_json::to_vec(&change())?)?;
+    tx.meta.insert(CHANGELOG_SEQ_KEY.as_bytes(), &seq.to_be_bytes())?;
+    if seq > keep {
+        tx.changelog.remove(&(seq - keep).to_be_bytes())?;
+    }
+    Ok(())
+}
+
+// Up to `limit` changes after sequence number `after`. Fails with
+// `PreconditionFailed` if some were already dropped from the log.
+pub fn changes_since(db: &Db, after: u64, limit: usize) -> DbResult<ChangeBatch> {
+    let changelog = db.open_tree(CHANGELOG_TREE)?;
+    let log_id = changelog_id(db)?;
+    if let Some((first, _)) = changelog.first()? {
+        if first.as_ref() > (after + 1).to_be_bytes().as_slice() {
+            return Err(DbError::PreconditionFailed(format!("Changes after {} are no longer in the changelog", after)));
+        }
+    }
+    let changes = changelog.range((after + 1).to_be_bytes()..)
+        .take(limit)
+        .map(|entry| {
+            let (key, value) = entry?;
+            Ok(LoggedChange { seq: changelog_seq(&key)?, change: serde_json::from_slice(&value)? })
+        })
+        .collect::<DbResult<Vec<_>>>()?;
+    let last_seq = changes.last().map_or(after, |logged| logged.seq);
+    Ok(ChangeBatch { log_id, changes, last_seq })
+}
+
+// Empties the changelog and gives it a new id.
+pub fn reset_changelog(db: &Db) -> DbResult<()> {
+    db.open_tree(CHANGELOG_TREE)?.clear()?;
+    let meta = db.open_tree(META_TREE)?;
+    meta.remove(CHANGELOG_ID_KEY.as_bytes())?;
+    meta.remove(CHANGELOG_SEQ_KEY.as_bytes())?;
+    Ok(())
+}
+
+// Applies changes followed from a primary in one transaction, which also
+// saves `position` as the replica's position, so a crash cannot apply the
+// changes without moving past them or the other way round. Documents are
+// stored as the primary wrote them, revisions included, and indexed per this
+// database's configuration.
+pub fn apply_changes(db: &Db, changes: &[LoggedChange], position: &ChangelogPosition, config: &DbConfig) -> DbResult<()> {
+    let position = serde_json::to_vec(position)?;
+    transaction(db, |tx| {
+        for logged in changes {
+            match &logged.change {
+                ChangeEvent::Set { key, value } => {
+                    let old_value = read_tx_document(tx, key)?;
+                    store_document(tx, key, value, old_value.as_ref(), config)
+                }
+                ChangeEvent::Delete { key } => delete_key_internal(tx, key, config),
+            }.map_err(ConflictableTransactionError::Abort)?;
+        }
+        tx.meta.insert(REPLICA_POSITION_KEY.as_bytes(), position.as_slice())?;
+        Ok(())
+    })
+}
+
+pub fn replica_position(db: &Db) -> DbResult<Option<ChangelogPosition>> {
+    match db.open_tree(META_TREE)?.get(REPLICA_POSITION_KEY.as_bytes())? {
+        Some(ivec) => Ok(Some(serde_json::from_slice(&ivec)?)),
+        None => Ok(None),
+    }
+}
+
+// Saves the replica's position, or clears it with None so the next sync
+// bootstraps from scratch.
+pub fn save_replica_position(db: &Db, position: Option<&ChangelogPosition>) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    match position {
+        Some(position) => meta.insert(REPLICA_POSITION_KEY.as_bytes(), serde_json::to_vec(position)?)?,
+        None => meta.remove(REPLICA_POSITION_KEY.as_bytes())?,
+    };
+    Ok(())
+}
+
+const WEBHOOK_KEY_PREFIX: &str = "__webhook__:";
+const DEAD_LETTER_KEY_PREFIX: &str = "__webhook_dead_letter__:";
+
+// A URL the server POSTs the changes matching `target` to, in changelog order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    #[serde(flatten)]
+    pub target: WatchTarget,
+    // The last change delivered, or given up on.
+    pub position: ChangelogPosition,
+}
+
+// A change a webhook gave up delivering.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    // Unix time in milliseconds of the last attempt.
+    pub at_ms: u64,
+    pub attempts: u32,
+    pub error: String,
+    pub change: LoggedChange,
+}
+
+fn webhook_key(id: &str) -> String {
+    format!("{}{}", WEBHOOK_KEY_PREFIX, id)
+}
+
+fn dead_letter_prefix(id: &str) -> String {
+    format!("{}{}:", DEAD_LETTER_KEY_PREFIX, id)
+}
+
+// Registers a webhook for the changes logged from now on.
+pub fn create_webhook(db: &Db, url: &str, target: WatchTarget) -> DbResult<Webhook> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(DbError::MissingData(format!("Webhook URL must be http or https: {}", url)));
+    }
+    let id = format!("{:016x}", rand::random::<u64>());
+    let webhook = Webhook { id, url: url.to_string(), target, position: changelog_position(db)? };
+    db.open_tree(META_TREE)?.insert(webhook_key(&webhook.id).as_bytes(), serde_json::to_vec(&webhook)?)?;
+    Ok(webhook)
+}
+
+pub fn webhook(db: &Db, id: &str) -> DbResult<Option<Webhook>> {
+    match db.open_tree(META_TREE)?.get(webhook_key(id).as_bytes())? {
+        Some(ivec) => Ok(Some(serde_json::from_slice(&ivec)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn webhooks(db: &Db) -> DbResult<Vec<Webhook>> {
+    db.open_tree(META_TREE)?
+        .scan_prefix(WEBHOOK_KEY_PREFIX.as_bytes())
+        .values()
+        .map(|value| Ok(serde_json::from_slice(&value?)?))
+        .collect()
+}
+
+// Removes the webhook along with its dead letters.
+pub fn delete_webhook(db: &Db, id: &str) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    if meta.remove(webhook_key(id).as_bytes())?.is_none() {
+        return Err(DbError::WebhookNotFound(id.to_string()));
+    }
+    clear_dead_letters(db, id)?;
+    Ok(())
+}
+
+// Moves the webhook on to `position`. Returns false, saving nothing, if the
+// webhook was deleted meanwhile.
+pub fn save_webhook_position(db: &Db, id: &str, position: &ChangelogPosition) -> DbResult<bool> {
+    let updated = db.open_tree(META_TREE)?.update_and_fetch(webhook_key(id).as_bytes(), |stored| {
+        let mut webhook: Webhook = serde_json::from_slice(stored?).ok()?;
+        webhook.position = position.clone();
+        serde_json::to_vec(&webhook).ok()
+    })?;
+    Ok(updated.is_some())
+}
+
+// Does nothing if the webhook was deleted meanwhile.
+pub fn record_dead_letter(db: &Db, id: &str, dead_letter: &DeadLetter) -> DbResult<()> {
+    if webhook(db, id)?.is_none() {
+        return Ok(());
+    }
+    let key = format!("{}{:020}", dead_letter_prefix(id), db.generate_id()?);
+    db.open_tree(META_TREE)?.insert(key.as_bytes(), serde_json::to_vec(dead_letter)?)?;
+    Ok(())
+}
+
+// The webhook's dead letters, oldest first.
+pub fn dead_letters(db: &Db, id: &str) -> DbResult<Vec<DeadLetter>> {
+    if webhook(db, id)?.is_none() {
+        return Err(DbError::WebhookNotFound(id.to_string()));
+    }
+    db.open_tree(META_TREE)?
+        .scan_prefix(dead_letter_prefix(id).as_bytes())
+        .values()
+        .map(|value| Ok(serde_json::from_slice(&value?)?))
+        .collect()
+}
+
+// Removes the webhook's dead letters, returning how many there were.
+pub fn clear_dead_letters(db: &Db, id: &str) -> DbResult<usize> {
+    let meta = db.open_tree(META_TREE)?;
+    let mut count = 0;
+    for key in meta.scan_prefix(dead_letter_prefix(id).as_bytes()).keys() {
+        meta.remove(key?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+const IDEMPOTENCY_KEY_PREFIX: &str = "__idempotency__:";
+
+// The response to a request made with an Idempotency-Key, replayed when the
+// request is retried with the same key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredResponse {
+    // Unix seconds after which the key is forgotten.
+    pub expires_at: u64,
+    // Fingerprint of the request, so the key isn't reused for another one.
+    pub request_hash: u64,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+fn idempotency_key(scope: &str, key: &str) -> String {
+    format!("{}{}:{}", IDEMPOTENCY_KEY_PREFIX, scope, key)
+}
+
+// The response stored for the idempotency key in `scope` (e.g. one per
+// credential), unless it expired.
+pub fn stored_response(db: &Db, scope: &str, key: &str) -> DbResult<Option<StoredResponse>> {
+    let Some(ivec) = db.open_tree(META_TREE)?.get(idempotency_key(scope, key).as_bytes())? else { return Ok(None) };
+    let stored: StoredResponse = serde_json::from_slice(&ivec)?;
+    Ok((stored.expires_at > unix_now_secs()).then_some(stored))
+}
+
+pub fn store_response(db: &Db, scope: &str, key: &str, response: &StoredResponse) -> DbResult<()> {
+    db.open_tree(META_TREE)?.insert(idempotency_key(scope, key).as_bytes(), serde_json::to_vec(response)?)?;
+    Ok(())
+}
+
+// Removes the stored responses that expired, returning how many.
+pub fn expire_stored_responses(db: &Db) -> DbResult<usize> {
+    let meta = db.open_tree(META_TREE)?;
+    let now = unix_now_secs();
+    let mut count = 0;
+    for entry in meta.scan_prefix(IDEMPOTENCY_KEY_PREFIX.as_bytes()) {
+        let (key, value) = entry?;
+        let expired = serde_json::from_slice::<StoredResponse>(&value).map_or(true, |stored| stored.expires_at <= now);
+        if expired {
+            meta.remove(key)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+const WRITE_PROBE_KEY: &str = "__write_probe__";
+
+// Writes a meta entry and reads it back, to check the database takes writes.
+pub fn probe_write(db: &Db) -> DbResult<()> {
+    let meta = db.open_tree(META_TREE)?;
+    let probe = rand::random::<u64>().to_be_bytes();
+    meta.insert(WRITE_PROBE_KEY.as_bytes(), &probe)?;
+    match meta.get(WRITE_PROBE_KEY.as_bytes())? {
+        Some(read) if read == probe => Ok(()),
+        _ => Err(DbError::Transaction("Write probe did not read back".to_string())),
+    }
+}
+
+// Database size on disk in bytes around a flush or compaction.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct SizeReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+// Writes all pending changes to disk.
+pub fn flush(db: &Db) -> DbResult<SizeReport> {
+    let bytes_before = db.size_on_disk()?;
+    db.flush()?;
+    Ok(SizeReport { bytes_before, bytes_after: db.size_on_disk()? })
+}
+
+// Copies every tree of `db` into the empty database `target`, which then holds
+// the same data without the space sled has yet to reclaim from removed and
+// overwritten entries. As with backup_to, nothing should write meanwhile.
+pub fn compact(db: &Db, target: &Db) -> DbResult<SizeReport> {
+    db.flush()?;
+    let bytes_before = db.size_on_disk()?;
+    copy_trees(db, target)?;
+    target.flush()?;
+    Ok(SizeReport { bytes_before, bytes_after: target.size_on_disk()? })
+}
+
+// Rebuilds every index from the stored documents as `config` declares them
+// and saves `config` as the database's configuration, for restored data whose
+// indexes may follow another configuration. Unfinished background builds are
+// dropped; their indexes are built here. Returns the documents scanned.
+pub fn reindex_with_config(db: &Db, config: &DbConfig) -> DbResult<usize> {
+    let mut config = config.clone();
+    config.building_indexes.clear();
+    for tree_name in INDEX_TREES {
+        db.open_tree(tree_name)?.clear()?;
+    }
+    let meta = db.open_tree(META_TREE)?;
+    for key in meta.scan_prefix(INDEX_BUILD_KEY_PREFIX.as_bytes()).keys() {
+        meta.remove(key?)?;
+    }
+    let scanned = populate_index(db, &config)?;
+    save_config(db, &config)?;
+    Ok(scanned)
+}
+
+pub fn import_data(db: &Db, data: &str, config: &DbConfig) -> DbResult<()> {
+    let items = parse_import_data(data)?;
+    import_items(db, &items, config, DEFAULT_IMPORT_CHUNK_SIZE)?;
+    Ok(())
+}
+
+// The documents of an `export_data` array, for importing them in chunks.
+pub fn parse_import_data(data: &str) -> DbResult<Vec<BatchSetItem>> {
+    let json_data: Vec<Value> = serde_json::from_str(data)?;
+    json_data.into_iter()
+        .map(|mut item| {
+            let key = item.get("key")
+                .and_then(Value::as_str)
+                .ok_or_else(|| DbError::ImportError("Invalid key format".to_string()))?
+                .to_string();
+            let value = item.get_mut("value")
+                .map(Value::take)
+                .ok_or_else(|| DbError::ImportError("Missing value".to_string()))?;
+            Ok(BatchSetItem { key, value, expiry: Expiry::default() })
+        })
+        .collect()
+}
+
+// Imports documents `chunk_size` at a time, deferring their index writes into
+// one batch per index tree for each chunk. Chunks written before a failing one
+// are kept. Returns the number of documents imported.
+pub fn import_items(db: &Db, items: &[BatchSetItem], config: &DbConfig, chunk_size: usize) -> DbResult<usize> {
+    import_items_with_progress(db, items, config, chunk_size, &|_, _| {})
+}
+
+// Reports progress after each chunk.
+pub fn import_items_with_progress(db: &Db, items: &[BatchSetItem], config: &DbConfig, chunk_size: usize, progress: Progress) -> DbResult<usize> {
+    let mut imported = 0;
+    for chunk in items.chunks(chunk_size.max(1)) {
+        import_chunk(db, chunk, config)?;
+        imported += chunk.len();
+        debug!(imported = imported, total = items.len(), "Imported chunk");
+        progress(imported, Some(items.len()));
+    }
+    Ok(imported)
+}
+
+// Writes a chunk's documents, with their modification times and changelog
+// entries, in one transaction, then the entries of each index tree with sled
+// batches. Unique constraints are checked before anything is written, so a
+// violation leaves the chunk out entirely. The index trees are not updated
+// atomically with the documents; a crash mid-chunk can leave index entries
+// missing until `repair_indexes` is run. Imported objects replace
+// whatever is stored, so an exported `_rev` is dropped and the revision
+// continues from the stored document.
+fn import_chunk(db: &Db, chunk: &[BatchSetItem], config: &DbConfig) -> DbResult<()> {
+    let unique_tree = db.open_tree(UNIQUE_INDEX_TREE)?;
+    let now = unix_now_millis().to_be_bytes();
+    let mut index_batches: HashMap<&'static str, Batch> = HashMap::new();
+    // The documents to store, in order.
+    let mut stored: Vec<(&str, Value)> = Vec::with_capacity(chunk.len());
+    // Position in `stored` of each key's latest document, so a repeated key unindexes it.
+    let mut written: HashMap<&str, usize> = HashMap::new();
+    // Unique entries claimed (Some(owner)) or released (None) earlier in the chunk.
+    let mut unique_owners: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+    let owner_of = |unique_owners: &HashMap<Vec<u8>, Option<Vec<u8>>>, key: &[u8]| -> DbResult<Option<Vec<u8>>> {
+        match unique_owners.get(key) {
+            Some(owner) => Ok(owner.clone()),
+            None => Ok(unique_tree.get(key)?.map(|owner| owner.to_vec())),
+        }
+    };
+
+    for item in chunk {
+        let old_value = match written.get(item.key.as_str()) {
+            Some(&position) => Some(stored[position].1.clone()),
+            None => db.get(item.key.as_bytes())?.and_then(|ivec| serde_json::from_slice::<Value>(&ivec).ok()),
+        };
+        let mut entries = Vec::new();
+        if let Some(old_value) = &old_value {
+            collect_index_entries(&item.key, old_value, config, &mut entries)?;
+        }
+        for entry in entries.drain(..) {
+            if entry.tree == UNIQUE_INDEX_TREE {
+                if owner_of(&unique_owners, &entry.key)?.is_some_and(|owner| owner != entry.value) {
+                    continue;
+                }
+                unique_owners.insert(entry.key.clone(), None);
+            }
+            index_batches.entry(entry.tree).or_default().remove(entry.key);
+        }
+
+        let mut value = item.value.clone();
+        if let Value::Object(map) = &mut value {
+            map.remove(REVISION_FIELD);
+        }
+        let value = with_next_revision(&item.key, &value, old_value.as_ref())?.into_owned();
+        collect_index_entries(&item.key, &value, config, &mut entries)?;
+        for entry in entries {
+            if entry.tree == UNIQUE_INDEX_TREE {
+                if let Some(owner) = owner_of(&unique_owners, &entry.key)? {
+                    if owner != entry.value {
+                        return Err(unique_violation(&entry.key, &owner));
+                    }
+                }
+                unique_owners.insert(entry.key.clone(), Some(entry.value.clone()));
+            }
+            index_batches.entry(entry.tree).or_default().insert(entry.key, entry.value);
+        }
+        written.insert(&item.key, stored.len());
+        stored.push((&item.key, value));
+    }
+
+    transaction(db, |tx| {
+        for (key, value) in &stored {
+            let bytes = serde_json::to_vec(value).map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            tx.docs.insert(key.as_bytes(), bytes)?;
+            tx.modified.insert(key.as_bytes(), &now)?;
+            log_change(tx, || ChangeEvent::Set { key: key.to_string(), value: value.clone() }).map_err(ConflictableTransactionError::Abort)?;
+        }
+        Ok(())
+    })?;
+    for (tree_name, batch) in index_batches {
+        db.open_tree(tree_name)?.apply_batch(batch)?;
+    }
+    Ok(())
+}
+
+// Upper bound on the geohash cells indexed for one line or polygon, or
+// searched for one query region; larger areas use coarser cells.
+const MAX_GEOMETRY_CELLS: usize = 64;
+// Mean Earth radius, as used by the haversine distance.
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+// Longest step between the route vertices whose surroundings are searched in
+// route queries, unless the search distance is longer.
+const ROUTE_STEP_METERS: f64 = 1_000.0;
+
+// How radius and nearest queries measure distances.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+    // Great-circle distance on a sphere of the mean Earth radius
+    #[default]
+    Haversine,
+    // Shortest path on the WGS84 ellipsoid (Karney's algorithm); slower, but
+    // accurate over long distances, where haversine is off by up to 0.5%
+    Geodesic,
+    // Straight-line distance on a flat projection centered on the query point;
+    // cheapest, but only accurate over short distances
+    Planar,
+}
+
+impl DistanceMetric {
+    // Distance in meters from the query point to the other point.
+    fn distance(self, origin: Point<f64>, other: Point<f64>) -> f64 {
+        match self {
+            DistanceMetric::Haversine => Haversine.distance(origin, other),
+            DistanceMetric::Geodesic => Geodesic.distance(origin, other),
+            DistanceMetric::Planar => {
+                let lon_delta = (other.x() - origin.x() + 540.0).rem_euclid(360.0) - 180.0;
+                let x = lon_delta.to_radians() * origin.y().to_radians().cos();
+                let y = (other.y() - origin.y()).to_radians();
+                EARTH_RADIUS_METERS * x.hypot(y)
+            }
+        }
+    }
+
+    // Great-circle radius enclosing every point within `radius_meters` of the
+    // center under this metric, so candidates can be found by haversine bounds.
+    fn search_radius(self, center: &Point<f64>, radius_meters: f64) -> f64 {
+        match self {
+            DistanceMetric::Haversine => radius_meters,
+            DistanceMetric::Geodesic => radius_meters * 1.01,
+            // The planar circle fits in the box spanning the radius in both
+            // directions; its farthest corners bound the great-circle distance.
+            DistanceMetric::Planar => {
+                let lat_delta = (radius_meters / EARTH_RADIUS_METERS).to_degrees();
+                let lon_delta = lat_delta / center.y().to_radians().cos();
+                if !lon_delta.is_finite() || lon_delta >= 180.0 {
+                    return f64::INFINITY;
+                }
+                [center.y() - lat_delta, center.y() + lat_delta].into_iter()
+                    .map(|lat| Haversine.distance(*center, Point::new(center.x() + lon_delta, lat.clamp(-90.0, 90.0))))
+                    .fold(radius_meters, f64::max)
+            }
+        }
+    }
+}
+
+// Reads a geo-indexed value: a `{lat, lon}` object, a GeoJSON Point,
+// MultiPoint, LineString or Polygon geometry, whose positions are [lon, lat]
+// pairs, or an array of those (e.g. the entrances of a store).
+fn parse_geometry(value: &Value) -> Option<Geometry<f64>> {
+    if let Ok(point) = serde_json::from_value::<GeoPoint>(value.clone()) {
+        return Some(Geometry::Point(point.into()));
+    }
+    if let Value::Array(elements) = value {
+        let geometries = elements.iter().map(parse_geometry).collect::<Option<Vec<_>>>()?;
+        return (!geometries.is_empty()).then(|| Geometry::GeometryCollection(GeometryCollection::from(geometries)));
+    }
+    let position = |v: &Value| -> Option<Coord<f64>> {
+        match v.as_array()?.as_slice() {
+            [lon, lat, ..] => Some(Coord { x: lon.as_f64()?, y: lat.as_f64()? }),
+            _ => None,
+        }
+    };
+    let line = |v: &Value| -> Option<LineString<f64>> {
+        v.as_array()?.iter().map(position).collect::<Option<Vec<_>>>().map(LineString::new)
+    };
+    let coordinates = value.get("coordinates")?;
+    match value.get("type")?.as_str()? {
+        "Point" => position(coordinates).map(|coord| Geometry::Point(coord.into())),
+        "MultiPoint" => line(coordinates).filter(|l| !l.0.is_empty()).map(|l| Geometry::MultiPoint(l.into_points().into())),
+        "LineString" => line(coordinates).filter(|l| l.0.len() >= 2).map(Geometry::LineString),
+        "Polygon" => {
+            let mut rings = coordinates.as_array()?.iter().map(line).collect::<Option<Vec<_>>>()?.into_iter();
+            let exterior = rings.next().filter(|ring| ring.0.len() >= 4)?;
+            Some(Geometry::Polygon(Polygon::new(exterior, rings.collect())))
+        }
+        _ => None,
+    }
+}
+
+// The geohash crate wraps coordinates on the +90 and +180 edges around to the
+// opposite edge; they are kept in the last cell instead.
+fn encode_geohash(coord: Coord<f64>, precision: usize) -> DbResult<String> {
+    let coord = Coord { x: coord.x.min(180.0 - 1e-9), y: coord.y.min(90.0 - 1e-9) };
+    encode(coord, precision).map_err(|e| DbError::Geohash(e.to_string()))
+}
+
+// Geohash cells a geometry is indexed under: the cell of a point at
+// `precision`, or for lines and polygons the cells they touch at the finest
+// precision (at most `precision`) whose cover of their bounding box stays
+// within MAX_GEOMETRY_CELLS. Collections get the cells of each member.
+fn geometry_cells(geometry: &Geometry<f64>, precision: usize) -> DbResult<Vec<String>> {
+    let members: Vec<Geometry<f64>> = match geometry {
+        Geometry::Point(point) => return Ok(vec![encode_geohash(point.0, precision)?]),
+        Geometry::MultiPoint(points) => points.iter().map(|point| Geometry::Point(*point)).collect(),
+        Geometry::GeometryCollection(collection) => collection.iter().cloned().collect(),
+        _ => Vec::new(),
+    };
+    if !members.is_empty() {
+        let mut cells = Vec::new();
+        for member in &members {
+            for cell in geometry_cells(member, precision)? {
+                if !cells.contains(&cell) {
+                    cells.push(cell);
+                }
+            }
+        }
+        return Ok(cells);
+    }
+    let Some(bounds) = geometry.bounding_rect() else { return Ok(Vec::new()) };
+    for cell_precision in (1..=precision).rev() {
+        let lon_bits = (5 * cell_precision).div_ceil(2) as i32;
+        let lat_bits = (5 * cell_precision / 2) as i32;
+        let (cell_width, cell_height) = (360.0 / 2f64.powi(lon_bits), 180.0 / 2f64.powi(lat_bits));
+        let first_col = ((bounds.min().x + 180.0) / cell_width).floor();
+        let first_row = ((bounds.min().y + 90.0) / cell_height).floor();
+        let cols = ((bounds.max().x + 180.0) / cell_width).floor() - first_col + 1.0;
+        let rows = ((bounds.max().y + 90.0) / cell_height).floor() - first_row + 1.0;
+        if cols * rows > MAX_GEOMETRY_CELLS as f64 && cell_precision > 1 {
+            continue;
+        }
+
+        let mut cells = Vec::new();
+        for row in 0..rows as usize {
+            for col in 0..cols as usize {
+                // Cells are found by their center; positions on the +180/+90
+                // edges fall in the last cell.
+                let center = Coord {
+                    x: ((first_col + col as f64 + 0.5) * cell_width - 180.0).min(180.0 - cell_width / 2.0),
+                    y: ((first_row + row as f64 + 0.5) * cell_height - 90.0).min(90.0 - cell_height / 2.0),
+                };
+                let cell = encode_geohash(center, cell_precision)?;
+                let cell_rect = decode_bbox(&cell).map_err(|e| DbError::Geohash(e.to_string()))?;
+                if geometry.intersects(&cell_rect) && !cells.contains(&cell) {
+                    cells.push(cell);
+                }
+            }
+        }
+        return Ok(cells);
+    }
+    Ok(Vec::new())
+}
+
+// Keys with a geo entry in any of the cells: entries in the cells or finer
+// cells inside them (points, small shapes), and entries in the coarser cells
+// enclosing them (large shapes). Given `candidates`, keys outside it are skipped.
+fn geo_candidate_keys(db: &Db, field_path: &str, cells: &[String], candidates: Option<&HashSet<String>>) -> DbResult<HashSet<String>> {
+    let tree = db.open_tree(GEO_SORTED_INDEX_TREE)?;
+    let mut prefixes: Vec<Vec<u8>> = cells.iter().map(|cell| get_geo_sorted_index_prefix_for_hash(field_path, cell)).collect();
+    let enclosing: HashSet<&str> = cells.iter().flat_map(|cell| (1..cell.len()).map(move |len| &cell[..len])).collect();
+    prefixes.extend(enclosing.into_iter().map(|cell| index_key(&[field_path.as_bytes(), cell.as_bytes()])));
+
+    let mut keys = HashSet::new();
+    for prefix in prefixes {
+        for key_result in tree.scan_prefix(&prefix).keys() {
+            let index_key_bytes = key_result?;
+            match parse_index_entry(&index_key_bytes) {
+                Ok((_, primary_key)) => {
+                    if candidates.is_none_or(|candidates| candidates.contains(&primary_key)) {
+                        keys.insert(primary_key);
+                    }
+                }
+                Err(_) => warn!("Invalid geo sorted index key format (missing primary key?): {}", String::from_utf8_lossy(&index_key_bytes)),
+            }
+        }
+    }
+    Ok(keys)
+}
+
+// Loads a document found through the geo index with the geometry at its geo field.
+fn load_geometry(db: &Db, primary_key: &str, field_path: &str) -> DbResult<Option<(Value, Geometry<f64>)>> {
+    let value = match get_key(db, primary_key) {
+        Ok(value) => value,
+        Err(DbError::NotFound) => {
+            warn!(key = primary_key, "Geo index points to non-existent key");
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    let Some(geo_value) = get_value_by_path(&value, index_field_path(field_path)) else {
+        warn!(key = primary_key, field_path = field_path, "Geo field not found in document");
+        return Ok(None);
+    };
+    match parse_geometry(geo_value) {
+        Some(geometry) => Ok(Some((value, geometry))),
+        None => {
+            warn!(key = primary_key, field_path = field_path, "Field is not a valid GeoPoint or geometry");
+            Ok(None)
+        }
+    }
+}
+
+// Distance in meters from the point to the nearest point of the geometry; 0
+// when the point is on or inside it.
+fn geometry_distance(geometry: &Geometry<f64>, point: &Point<f64>, metric: DistanceMetric) -> f64 {
+    if geometry.intersects(point) {
+        return 0.0;
+    }
+    match geometry.haversine_closest_point(point) {
+        Closest::Intersection(closest) | Closest::SinglePoint(closest) => metric.distance(*point, closest),
+        Closest::Indeterminate => f64::INFINITY,
+    }
+}
+
+// Returns the documents within the radius nearest-first, up to `limit`, each
+// annotated with its distance from the center in `_distance_m` (when the
+// document is an object), measured with `metric`.
+pub fn query_within_radius_simplified(db: &Db, field_path: &str, center: GeoPoint, radius_meters: f64, limit: Option<usize>, metric: DistanceMetric, config: &DbConfig) -> DbResult<Vec<Value>> {
+    let mut matches: Vec<(String, (Value, f64))> = radius_matches(db, field_path, center.into(), radius_meters, metric, config, None)?.into_iter().collect();
+    matches.sort_by(|(k1, (_, d1)), (k2, (_, d2))| d1.total_cmp(d2).then_with(|| k1.cmp(k2)));
+    Ok(matches.into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(_, (value, distance))| with_distance(value, distance))
+        .collect())
+}
+
+fn with_distance(mut value: Value, distance: f64) -> Value {
+    if let Value::Object(map) = &mut value {
+        map.insert("_distance_m".to_string(), json!(distance));
+    }
+    value
+}
+
+// Bounding box of the circle, split in two where it crosses the antimeridian.
+// Circles reaching a pole span all longitudes.
+fn radius_bounds(center_lat: f64, center_lon: f64, radius_meters: f64) -> Vec<Rect<f64>> {
+    let lat_delta = (radius_meters / EARTH_RADIUS_METERS).to_degrees();
+    let (min_lat, max_lat) = (center_lat - lat_delta, center_lat + lat_delta);
+    if min_lat <= -90.0 || max_lat >= 90.0 {
+        return vec![Rect::new(Coord { x: -180.0, y: min_lat.max(-90.0) }, Coord { x: 180.0, y: max_lat.min(90.0) })];
+    }
+
+    let lon_delta = (lat_delta / center_lat.to_radians().cos()).min(180.0);
+    let (min_lon, max_lon) = (center_lon - lon_delta, center_lon + lon_delta);
+    let rect = |min_lon: f64, max_lon: f64| Rect::new(Coord { x: min_lon, y: min_lat }, Coord { x: max_lon, y: max_lat });
+    if max_lon - min_lon >= 360.0 {
+        vec![rect(-180.0, 180.0)]
+    } else if min_lon < -180.0 {
+        vec![rect(min_lon + 360.0, 180.0), rect(-180.0, max_lon)]
+    } else if max_lon > 180.0 {
+        vec![rect(min_lon, 180.0), rect(-180.0, max_lon - 360.0)]
+    } else {
+        vec![rect(min_lon, max_lon)]
+    }
+}
+
+// Returns the documents within the radius and their distance in meters, keyed
+// by primary key. Given `candidates`, only those keys are checked.
+fn radius_matches(db: &Db, field_path: &str, center: Point<f64>, radius_meters: f64, metric: DistanceMetric, config: &DbConfig, candidates: Option<&HashSet<String>>) -> DbResult<HashMap<String, (Value, f64)>> {
+
+    // Cells covering the circle's bounding box, at the finest precision that
+    // keeps their number bounded; the distance check below trims the corners.
+    let precision = config.geohash_precision_for(field_path);
+    let mut hashes_to_check = Vec::new();
+    for bounds in radius_bounds(center.y(), center.x(), metric.search_radius(&center, radius_meters)) {
+        hashes_to_check.extend(geometry_cells(&Geometry::Rect(bounds), precision)?);
+    }
+
+    let mut results_map: HashMap<String, (Value, f64)> = HashMap::new();
+    for primary_key in geo_candidate_keys(db, field_path, &hashes_to_check, candidates)? {
+        if let Some((value, geometry)) = load_geometry(db, &primary_key, field_path)? {
+            let distance = geometry_distance(&geometry, &center, metric);
+            if distance <= radius_meters {
+                results_map.insert(primary_key, (value, distance));
+            }
+        }
+    }
+    Ok(results_map)
+}
+
+pub fn query_in_box(db: &Db, field_path: &str, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64, config: &DbConfig) -> DbResult<Vec<Value>> {
+    let bounding_boxes = box_bounds(min_lat, min_lon, max_lat, max_lon);
+    Ok(box_matches(db, field_path, &bounding_boxes, config, None)?.into_values().collect())
+}
+
+// The bounding box as rectangles. A box whose min_lon is east of its max_lon
+// crosses the antimeridian and is split in its two halves.
+fn box_bounds(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Vec<Rect<f64>> {
+    let rect = |min_lon: f64, max_lon: f64| Rect::new(
+        Coord { x: min_lon, y: min_lat },
+        Coord { x: max_lon, y: max_lat },
+    );
+    if min_lon > max_lon {
+        vec![rect(min_lon, 180.0), rect(-180.0, max_lon)]
+    } else {
+        vec![rect(min_lon, max_lon)]
+    }
+}
+
+// Returns the documents whose geometry intersects any of the bounding boxes
+// keyed by primary key. Given `candidates`, only those keys are checked.
+fn box_matches(db: &Db, field_path: &str, bounding_boxes: &[Rect<f64>], config: &DbConfig, candidates: Option<&HashSet<String>>) -> DbResult<HashMap<String, Value>> {
+    // Only the index ranges of the cells covering the box are scanned
+    let mut cells = Vec::new();
+    for bounding_box in bounding_boxes {
+        cells.extend(geometry_cells(&Geometry::Rect(*bounding_box), config.geohash_precision_for(field_path))?);
+    }
+    let mut results_map: HashMap<String, Value> = HashMap::new();
+
+    for primary_key in geo_candidate_keys(db, field_path, &cells, candidates)? {
+        if let Some((value, geometry)) = load_geometry(db, &primary_key, field_path)? {
+            if bounding_boxes.iter().any(|bounding_box| geometry.intersects(bounding_box)) {
+                results_map.insert(primary_key, value);
+            }
+        }
+    }
+    Ok(results_map)
+}
+
+// Returns the documents whose geometry intersects the given one (a `{lat, lon}`
+// point or a GeoJSON geometry) keyed by primary key. Given `candidates`, only
+// those keys are checked.
+fn intersects_matches(db: &Db, field_path: &str, geometry_value: &Value, config: &DbConfig, candidates: Option<&HashSet<String>>) -> DbResult<HashMap<String, Value>> {
+    let query_geometry = parse_geometry(geometry_value)
+        .ok_or_else(|| DbError::AstQueryError(format!("Not a valid GeoPoint or geometry: {}", geometry_value)))?;
+    let cells = geometry_cells(&query_geometry, config.geohash_precision_for(field_path))?;
+
+    let mut results_map = HashMap::new();
+    for primary_key in geo_candidate_keys(db, field_path, &cells, candidates)? {
+        if let Some((value, geometry)) = load_geometry(db, &primary_key, field_path)? {
+            if geometry.intersects(&query_geometry) {
+                results_map.insert(primary_key, value);
+            }
+        }
+    }
+    Ok(results_map)
+}
+
+// Returns the documents within `distance_meters` of the route, a polyline
+// through the points in order, nearest-first up to `limit`, each annotated
+// with its distance to the route in `_distance_m`.
+pub fn query_near_route(db: &Db, field_path: &str, route: &[GeoPoint], distance_meters: f64, limit: Option<usize>, config: &DbConfig) -> DbResult<Vec<Value>> {
+    let mut matches: Vec<(String, (Value, f64))> = route_matches(db, field_path, route, distance_meters, config, None)?.into_iter().collect();
+    matches.sort_by(|(k1, (_, d1)), (k2, (_, d2))| d1.total_cmp(d2).then_with(|| k1.cmp(k2)));
+    Ok(matches.into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(_, (value, distance))| with_distance(value, distance))
+        .collect())
+}
+
+// Returns the documents within `distance_meters` of the route and their
+// distance to it in meters, keyed by primary key. Given `candidates`, only
+// those keys are checked.
+fn route_matches(db: &Db, field_path: &str, route: &[GeoPoint], distance_meters: f64, config: &DbConfig, candidates: Option<&HashSet<String>>) -> DbResult<HashMap<String, (Value, f64)>> {
+    if route.len() < 2 {
+        return Err(DbError::MissingData("A route needs at least two points".to_string()));
+    }
+    let line: LineString<f64> = route.iter().map(|point| Coord::from(*point)).collect();
+
+    // Every point of a segment is within half its length of one of its ends,
+    // so circles around the vertices of the densified route cover the corridor.
+    let step = distance_meters.max(ROUTE_STEP_METERS);
+    let precision = config.geohash_precision_for(field_path);
+    let mut cells = HashSet::new();
+    for vertex in Haversine.densify(&line, step).points() {
+        for bounds in radius_bounds(vertex.y(), vertex.x(), distance_meters + step / 2.0) {
+            cells.extend(geometry_cells(&Geometry::Rect(bounds), precision)?);
+        }
+    }
+    let cells: Vec<String> = cells.into_iter().collect();
+
+    let route_geometry = Geometry::LineString(line);
+    let mut results_map = HashMap::new();
+    for primary_key in geo_candidate_keys(db, field_path, &cells, candidates)? {
+        if let Some((value, geometry)) = load_geometry(db, &primary_key, field_path)? {
+            let distance = route_distance(&geometry, &route_geometry);
+            if distance <= distance_meters {
+                results_map.insert(primary_key, (value, distance));
+            }
+        }
+    }
+    Ok(results_map)
+}
+
+// Haversine distance in meters between a geometry and the route; 0 when they
+// touch. The nearest points of two disjoint shapes include a vertex of one of
+// them, so only vertices are measured against the other shape.
+fn route_distance(geometry: &Geometry<f64>, route: &Geometry<f64>) -> f64 {
+    if geometry.intersects(route) {
+        return 0.0;
+    }
+    let to_route = geometry.coords_iter()
+        .map(|coord| geometry_distance(route, &coord.into(), DistanceMetric::Haversine));
+    let to_geometry = route.coords_iter()
+        .filter(|_| !matches!(geometry, Geometry::Point(_) | Geometry::MultiPoint(_)))
+        .map(|coord| geometry_distance(geometry, &coord.into(), DistanceMetric::Haversine));
+    to_route.chain(to_geometry).fold(f64::INFINITY, f64::min)
+}
+
+type RTreePoint = GeomWithData<[f64; 3], String>;
+
+// In-memory R-tree over the geometries of one geo field, for read-heavy
+// workloads: radius and nearest-neighbour queries without index scans. Points
+// are stored as unit-sphere vectors, whose straight-line distances order them
+// like great-circle distances; other shapes are checked one by one.
+#[derive(Debug)]
+pub struct GeoRTree {
+    field: String,
+    points: RTree<RTreePoint>,
+    // Points of each key, to remove them when the document changes
+    key_points: HashMap<String, Vec<[f64; 3]>>,
+    shapes: HashMap<String, Geometry<f64>>,
+}
+
+fn unit_vector(point: &Point<f64>) -> [f64; 3] {
+    let (lat, lon) = (point.y().to_radians(), point.x().to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn vector_point(vector: &[f64; 3]) -> Point<f64> {
+    let [x, y, z] = *vector;
+    Point::new(y.atan2(x).to_degrees(), z.clamp(-1.0, 1.0).asin().to_degrees())
+}
+
+// Great-circle distance in meters for a squared straight-line distance between unit vectors.
+fn chord_distance_meters(distance_2: f64) -> f64 {
+    2.0 * EARTH_RADIUS_METERS * (distance_2.sqrt() / 2.0).min(1.0).asin()
+}
+
+// Distance in meters from the center to a tree point at `distance_2` from it.
+fn rtree_point_distance(center: &Point<f64>, entry: &RTreePoint, distance_2: f64, metric: DistanceMetric) -> f64 {
+    match metric {
+        DistanceMetric::Haversine => chord_distance_meters(distance_2),
+        _ => metric.distance(*center, vector_point(entry.geom())),
+    }
+}
+
+impl GeoRTree {
+    // Loads the geometries of the field from every document.
+    pub fn build(db: &Db, field_path: &str) -> DbResult<GeoRTree> {
+        let mut rtree = GeoRTree { field: field_path.to_string(), points: RTree::new(), key_points: HashMap::new(), shapes: HashMap::new() };
+        let mut points = Vec::new();
+        for_each_document_chunk(db, |chunk| {
+            for (key, doc) in chunk {
+                points.extend(rtree.add_document(key, doc).into_iter().map(|point| RTreePoint::new(point, key.clone())));
+            }
+            Ok(())
+        })?;
+        rtree.points = RTree::bulk_load(points);
+        Ok(rtree)
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    // Number of documents with a geometry in the field.
+    pub fn len(&self) -> usize {
+        self.key_points.len() + self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Applies a change to the key, given its new stored value or `None` once it
+    // is removed. Taking the value from the change event rather than reading it
+    // back keeps this safe to call while a batch write still holds the tree.
+    pub fn apply_change(&mut self, key: &str, value: Option<&[u8]>) -> DbResult<()> {
+        if let Some(points) = self.key_points.remove(key) {
+            for point in points {
+                self.points.remove(&RTreePoint::new(point, key.to_string()));
+            }
+        }
+        self.shapes.remove(key);
+        if let Some(bytes) = value {
+            let doc: Value = serde_json::from_slice(bytes)?;
+            for point in self.add_document(key, &doc) {
+                self.points.insert(RTreePoint::new(point, key.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // Records the document's geometry, returning the points to insert in the tree.
+    fn add_document(&mut self, key: &str, doc: &Value) -> Vec<[f64; 3]> {
+        let Some(geometry) = scoped_field_path(&self.field, key)
+            .and_then(|path| get_value_by_path(doc, path))
+            .and_then(parse_geometry) else { return Vec::new() };
+        let points: Option<Vec<Point<f64>>> = match &geometry {
+            Geometry::Point(point) => Some(vec![*point]),
+            Geometry::MultiPoint(points) => Some(points.0.clone()),
+            Geometry::GeometryCollection(collection) => collection.iter()
+                .map(|member| match member { Geometry::Point(point) => Some(*point), _ => None })
+                .collect(),
+            _ => None,
+        };
+        match points {
+            Some(points) => {
+                let vectors: Vec<[f64; 3]> = points.iter().map(unit_vector).collect();
+                self.key_points.insert(key.to_string(), vectors.clone());
+                vectors
+            }
+            None => {
+                self.shapes.insert(key.to_string(), geometry);
+                Vec::new()
+            }
+        }
+    }
+
+    // Keys within the radius and their distance in meters, in no particular order.
+    fn within_radius(&self, center: &Point<f64>, radius_meters: f64, metric: DistanceMetric) -> HashMap<String, f64> {
+        let angle = metric.search_radius(center, radius_meters) / EARTH_RADIUS_METERS;
+        let max_distance_2 = if angle >= std::f64::consts::PI { 4.0 } else { (2.0 * (angle / 2.0).sin()).powi(2) };
+
+        let mut matches: HashMap<String, f64> = HashMap::new();
+        let query_point = unit_vector(center);
+        for entry in self.points.locate_within_distance(query_point, max_distance_2 * (1.0 + 1e-9)) {
+            let distance = rtree_point_distance(center, entry, entry.distance_2(&query_point), metric);
+            if distance <= radius_meters {
+                let best = matches.entry(entry.data.clone()).or_insert(distance);
+                *best = best.min(distance);
+            }
+        }
+        for (key, geometry) in &self.shapes {
+            let distance = geometry_distance(geometry, center, metric);
+            if distance <= radius_meters {
+                matches.insert(key.clone(), distance);
+            }
+        }
+        matches
+    }
+
+    // The `k` nearest keys and their distance in meters, nearest first.
+    fn nearest(&self, center: &Point<f64>, k: usize, metric: DistanceMetric) -> Vec<(String, f64)> {
+        if k == 0 {
+            return Vec::new();
+        }
+        let mut best: HashMap<&String, f64> = HashMap::new();
+        // Distance of the k-th nearest document found so far
+        let mut kth_distance = f64::INFINITY;
+        // Points come in great-circle order; under other metrics a farther
+        // point can still be nearer, up to the metric's search radius.
+        for (entry, distance_2) in self.points.nearest_neighbor_iter_with_distance_2(&unit_vector(center)) {
+            if chord_distance_meters(distance_2) > metric.search_radius(center, kth_distance) {
+                break;
+            }
+            let distance = rtree_point_distance(center, entry, distance_2, metric);
+            let nearest_point = best.entry(&entry.data).or_insert(distance);
+            *nearest_point = nearest_point.min(distance);
+            if best.len() >= k {
+                let mut distances: Vec<f64> = best.values().copied().collect();
+                distances.select_nth_unstable_by(k - 1, f64::total_cmp);
+                kth_distance = distances[k - 1];
+            }
+        }
+        let mut nearest: Vec<(String, f64)> = best.into_iter().map(|(key, distance)| (key.clone(), distance)).collect();
+        nearest.extend(self.shapes.iter().map(|(key, geometry)| (key.clone(), geometry_distance(geometry, center, metric))));
+        nearest.sort_by(|(k1, d1), (k2, d2)| d1.total_cmp(d2).then_with(|| k1.cmp(k2)));
+        nearest.truncate(k);
+        nearest
+    }
+}
+
+// Loads the documents of (key, distance) matches, annotating each with `_distance_m`.
+fn documents_with_distance(db: &Db, matches: Vec<(String, f64)>) -> DbResult<Vec<Value>> {
+    let mut documents = Vec::with_capacity(matches.len());
+    for (key, distance) in matches {
+        match get_key(db, &key) {
+            Ok(value) => documents.push(with_distance(value, distance)),
+            // Deleted since the tree was last refreshed
+            Err(DbError::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(documents)
+}
+
+// Same as `query_within_radius_simplified`, answered from the field's R-tree.
+pub fn query_within_radius_rtree(db: &Db, rtree: &GeoRTree, center: GeoPoint, radius_meters: f64, limit: Option<usize>, metric: DistanceMetric) -> DbResult<Vec<Value>> {
+    let mut matches: Vec<(String, f64)> = rtree.within_radius(&center.into(), radius_meters, metric).into_iter().collect();
+    matches.sort_by(|(k1, d1), (k2, d2)| d1.total_cmp(d2).then_with(|| k1.cmp(k2)));
+    matches.truncate(limit.unwrap_or(usize::MAX));
+    documents_with_distance(db, matches)
+}
+
+// The `k` documents nearest to the point under `metric`, nearest first,
+// annotated with `_distance_m`.
+pub fn query_nearest(db: &Db, rtree: &GeoRTree, center: GeoPoint, k: usize, metric: DistanceMetric) -> DbResult<Vec<Value>> {
+    documents_with_distance(db, rtree.nearest(&center.into(), k, metric))
+}
+
+// Simulates deleting a "table" by removing all keys with a given prefix
+pub fn clear_prefix(db: &Db, prefix: &str, config: &DbConfig) -> DbResult<usize> {
+    clear_prefix_with_progress(db, prefix, config, &|_, _| {})
+}
+
+// The deletes run in one transaction, so progress starts over if it retries.
+pub fn clear_prefix_with_progress(db: &Db, prefix: &str, config: &DbConfig, progress: Progress) -> DbResult<usize> {
+    let keys_to_delete: Vec<String> = db.scan_prefix(prefix.as_bytes())
+        .keys()
+        .filter_map(|res| res.ok())
+        .filter_map(|key_bytes| String::from_utf8(key_bytes.to_vec()).ok())
+        .collect();
+
+    let count = keys_to_delete.len();
+
+    if count > 0 {
+        transaction(db, |tx| {
+            for (i, key) in keys_to_delete.iter().enumerate() {
+                delete_key_internal(tx, key, config)
+                    .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Clear prefix failed for key '{}': {}", key, e))))?;
+                if (i + 1) % PROGRESS_INTERVAL == 0 {
+                    progress(i + 1, Some(count));
+                }
+            }
+            Ok(())
+        })?;
+    }
+    progress(count, Some(count));
+
+    Ok(count)
+}
+
+// Up to `limit` stored keys starting with `prefix` in byte order, beginning
+// after `after` when given. Index entries live in their own trees and are
+// never listed.
+pub fn list_keys(db: &Db, prefix: &str, after: Option<&str>, limit: usize) -> DbResult<Vec<String>> {
+    let lower = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after.as_bytes().to_vec()),
+        _ => Bound::Included(prefix.as_bytes().to_vec()),
+    };
+    db.range::<Vec<u8>, _>((lower, Bound::Unbounded))
+        .keys()
+        .take_while(|key| key.as_ref().map_or(true, |key| key.starts_with(prefix.as_bytes())))
+        .take(limit)
+        .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+        .collect()
+}
+
+// Collections group documents under the key prefix `{name}:`. Their
+// documents are addressed by id, and their indexes and queries use fields
+// scoped to the prefix (`{name}:*/{field}`), so each collection has its own
+// index configuration.
+const COLLECTION_SEPARATOR: char = ':';
+
+// The key prefix of a collection's documents.
+pub fn collection_prefix(name: &str) -> DbResult<String> {
+    if name.is_empty() || name.contains(COLLECTION_SEPARATOR) || name.contains('*') {
+        return Err(DbError::MissingData(format!("Invalid collection name '{}'", name)));
+    }
+    Ok(format!("{}{}", name, COLLECTION_SEPARATOR))
+}
+
+// The primary key of a collection's document.
+pub fn collection_key(name: &str, id: &str) -> DbResult<String> {
+    if id.is_empty() {
+        return Err(DbError::MissingData("Document id cannot be empty".to_string()));
+    }
+    Ok(format!("{}{}", collection_prefix(name)?, id))
+}
+
+// A field scoped to a collection's documents, as used for its indexes.
+pub fn collection_field(name: &str, field: &str) -> DbResult<String> {
+    Ok(format!("{}{}{}", collection_prefix(name)?, INDEX_SCOPE_SEPARATOR, field))
+}
+
+// The collection's key prefix, if it exists.
+pub fn require_collection(name: &str, config: &DbConfig) -> DbResult<String> {
+    if !config.collections.contains(name) {
+        return Err(DbError::CollectionNotFound(name.to_string()));
+    }
+    collection_prefix(name)
+}
+
+pub fn create_collection(db: &Db, name: &str, config: &mut DbConfig) -> DbResult<()> {
+    collection_prefix(name)?;
+    if config.collections.contains(name) {
+        return Err(DbError::AlreadyExists(name.to_string()));
+    }
+    let mut updated = config.clone();
+    updated.collections.insert(name.to_string());
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(())
+}
+
+// Deletes the collection's documents along with its index configuration.
+// Returns the number of documents deleted.
+pub fn drop_collection(db: &Db, name: &str, config: &mut DbConfig) -> DbResult<usize> {
+    let prefix = require_collection(name, config)?;
+    let count = clear_prefix(db, &prefix, config)?;
+    let scope = format!("{}{}", prefix, INDEX_SCOPE_SEPARATOR);
+    let in_collection = |field: &String| field.starts_with(&scope);
+    let mut updated = config.clone();
+    for kind in [IndexKind::Hash, IndexKind::Sorted, IndexKind::Geo, IndexKind::Unique] {
+        index_fields_mut(&mut updated, kind).retain(|field| !in_collection(field));
+    }
+    updated.ttl_fields.retain(|field, _| !in_collection(field));
+    updated.geohash_precision.retain(|field, _| !in_collection(field));
+    updated.building_indexes.retain(|(field, _)| !in_collection(field));
+    updated.sparse_fields.retain(|field| !in_collection(field));
+    updated.collations.retain(|field, _| !in_collection(field));
+    updated.datetime_fields.retain(|field| !in_collection(field));
+    updated.collections.remove(name);
+    save_config(db, &updated)?;
+    *config = updated;
+    Ok(count)
+}
+
+// Rewrites a query over a collection's documents into one over the whole
+// database: fields become scoped fields, key conditions take document ids,
+// and only the collection's keys can match.
+pub fn scope_query(query_node: QueryNode, name: &str) -> DbResult<QueryNode> {
+    let prefix = collection_prefix(name)?;
+    let scoped = scope_query_node(query_node, name, &prefix)?;
+    Ok(QueryNode::And(Box::new(QueryNode::KeyPrefix(prefix)), Box::new(scoped)))
+}
+
+fn scope_query_node(query_node: QueryNode, name: &str, prefix: &str) -> DbResult<QueryNode> {
+    let field = |field: String| collection_field(name, &field);
+    let node = |node: Box<QueryNode>| scope_query_node(*node, name, prefix).map(Box::new);
+    Ok(match query_node {
+        QueryNode::Eq(f, v, t) => QueryNode::Eq(field(f)?, v, t),
+        QueryNode::Includes(f, v, t) => QueryNode::Includes(field(f)?, v, t),
+        QueryNode::Gt(f, v, t) => QueryNode::Gt(field(f)?, v, t),
+        QueryNode::Lt(f, v, t) => QueryNode::Lt(field(f)?, v, t),
+        QueryNode::Gte(f, v, t) => QueryNode::Gte(field(f)?, v, t),
+        QueryNode::Lte(f, v, t) => QueryNode::Lte(field(f)?, v, t),
+        QueryNode::Ne(f, v, t) => QueryNode::Ne(field(f)?, v, t),
+        QueryNode::And(a, b) => QueryNode::And(node(a)?, node(b)?),
+        QueryNode::Or(a, b) => QueryNode::Or(node(a)?, node(b)?),
+        QueryNode::Not(a) => QueryNode::Not(node(a)?),
+        QueryNode::GeoWithinRadius { field: f, lat, lon, radius, metric } => QueryNode::GeoWithinRadius { field: field(f)?, lat, lon, radius, metric },
+        QueryNode::GeoInBox { field: f, min_lat, min_lon, max_lat, max_lon } => QueryNode::GeoInBox { field: field(f)?, min_lat, min_lon, max_lat, max_lon },
+        QueryNode::GeoNearRoute { field: f, route, distance } => QueryNode::GeoNearRoute { field: field(f)?, route, distance },
+        QueryNode::GeoIntersects { field: f, geometry } => QueryNode::GeoIntersects { field: field(f)?, geometry },
+        QueryNode::Exists(f) => QueryNode::Exists(field(f)?),
+        QueryNode::KeyEq(id) => QueryNode::KeyEq(format!("{}{}", prefix, id)),
+        QueryNode::KeyPrefix(id_prefix) => QueryNode::KeyPrefix(format!("{}{}", prefix, id_prefix)),
+        QueryNode::KeyRange { start, end } => QueryNode::KeyRange {
+            start: Some(format!("{}{}", prefix, start.unwrap_or_default())),
+            // Without an end, the range stops at the end of the collection:
+            // the separator's successor bounds every key under the prefix.
+            end: Some(match end {
+                Some(end) => format!("{}{}", prefix, end),
+                None => format!("{}{}", name, char::from(COLLECTION_SEPARATOR as u8 + 1)),
+            }),
+        },
+    })
+}
+
+// Clears all user data from the database
+pub fn drop_database(db: &Db, config: &DbConfig) -> DbResult<usize> {
+    drop_database_with_progress(db, config, &|_, _| {})
+}
+
+// Like clear_prefix_with_progress, progress starts over if the transaction retries.
+pub fn drop_database_with_progress(db: &Db, config: &DbConfig, progress: Progress) -> DbResult<usize> {
+    let all_keys = get_all_keys(db)?;
+    let count = all_keys.len();
+
+    if count > 0 {
+        transaction(db, |tx| {
+            for (i, key) in all_keys.iter().enumerate() {
+                delete_key_internal(tx, key, config)
+                    .map_err(|e| ConflictableTransactionError::Abort(DbError::TransactionOperationFailed(format!("Drop database failed for key '{}': {}", key, e))))?;
+                if (i + 1) % PROGRESS_INTERVAL == 0 {
+                    progress(i + 1, Some(count));
+                }
+            }
+            Ok(())
+        })?;
+    }
+    progress(count, Some(count));
 
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(values: &[Value], collation: Option<&Collation>, datetime: bool) -> Vec<Vec<u8>> {
+        values.iter().map(|value| encode_sorted_value(value, collation, datetime).unwrap()).collect()
+    }
+
+    fn assert_ascending(encoded: &[Vec<u8>]) {
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} does not sort before {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn index_keys_round_trip_separators() {
+        let components: [&[u8]; 3] = [b"a:b", b"\x00\xff\x01", b""];
+        assert_eq!(split_index_key(&index_key(&components)).unwrap(), components.map(<[u8]>::to_vec));
+        // Keys sort component by component, so a shorter component sorts first
+        assert!(index_key(&[b"a", b"z"]) < index_key(&[b"ab", b"a"]));
+        assert!(split_index_key(b"a\x00\x02").is_err());
+    }
+
+    #[test]
+    fn numbers_encode_in_numeric_order() {
+        let values = [
+            json!(-1e300), json!(i64::MIN), json!(-5.5), json!(-1), json!(0), json!(0.5), json!(1), json!(1.0),
+            json!(2), json!(u64::MAX - 1), json!(u64::MAX), json!(1e300),
+        ];
+        assert_ascending(&encoded(&values, None, false));
+        assert_eq!(encode_sorted_value(&json!(-0.0), None, false).unwrap()[..9], encode_sorted_value(&json!(0.0), None, false).unwrap()[..9]);
+        for value in values {
+            assert_eq!(decode_sorted_value(&encode_sorted_value(&value, None, false).unwrap()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn strings_and_bools_round_trip() {
+        let values = [json!("a:b"), json!("b"), json!(""), json!(false), json!(true)];
+        for value in values {
+            assert_eq!(decode_sorted_value(&encode_sorted_value(&value, None, false).unwrap()).unwrap(), value);
+        }
+        assert_ascending(&encoded(&[json!(""), json!("B"), json!("a"), json!("ab")], None, false));
+    }
+
+    #[test]
+    fn collations_order_strings() {
+        let case_insensitive = Collation { case_insensitive: true, ..Default::default() };
+        assert_ascending(&encoded(&[json!("apple"), json!("Banana"), json!("cherry")], Some(&case_insensitive), false));
+
+        let numeric = Collation { numeric: true, ..Default::default() };
+        assert_ascending(&encoded(&[json!("file2"), json!("file10"), json!("file010b"), json!("file11")], Some(&numeric), false));
+        assert_eq!(collation_key("a007", &numeric), collation_key("a7", &numeric));
+
+        let locale = Collation { locale: true, case_insensitive: true, ..Default::default() };
+        assert_eq!(collation_key("Éclair", &locale), collation_key("eclair", &locale));
+        assert_ascending(&encoded(&[json!("eclair"), json!("éclair"), json!("ecole")], Some(&locale), false));
+
+        let value = json!("Straße 10");
+        let collated = encode_sorted_value(&value, Some(&locale), false).unwrap();
+        assert_eq!(decode_sorted_value(&collated).unwrap(), value);
+    }
+
+    #[test]
+    fn numeric_collation_orders_long_digit_runs() {
+        let numeric = Collation { numeric: true, ..Default::default() };
+        let values = [
+            "9".repeat(252), "1".repeat(253), "9".repeat(253), "1".repeat(254), "9".repeat(254), "1".repeat(255),
+            "9".repeat(999), "1".repeat(1000),
+        ];
+        let keys: Vec<Vec<u8>> = values.iter().map(|s| collation_key(s, &numeric)).collect();
+        assert_ascending(&keys);
+        assert!(keys.iter().all(|key| !key.contains(&0x00)));
+    }
+
+    #[test]
+    fn datetimes_encode_as_instants_only_when_declared() {
+        let values = [json!("2023-12-31T23:30:00+02:00"), json!("2023-12-31T22:00:00Z"), json!("2024-01-01T00:00:00Z")];
+        assert_ascending(&encoded(&values, None, true));
+        for value in &values {
+            let encoded = encode_sorted_value(value, None, true).unwrap();
+            assert_eq!(encoded[0], 0x06);
+            assert_eq!(&decode_sorted_value(&encoded).unwrap(), value);
+            assert_eq!(encode_sorted_value(value, None, false).unwrap()[0], 0x04);
+        }
+        assert_eq!(encode_sorted_value(&json!("not a timestamp"), None, true).unwrap()[0], 0x04);
+    }
+
+    fn query(db: &Db, node: QueryNode, options: &QueryOptions, config: &DbConfig) -> (Vec<Value>, Vec<String>) {
+        let (page, profile) = execute_ast_query_profiled(db, node, options, config).unwrap();
+        (page.results, profile.plan)
+    }
+
+    fn all(field: &str) -> QueryNode {
+        QueryNode::Exists(field.to_string())
+    }
+
+    #[test]
+    fn top_k_walks_a_ready_sorted_index() {
+        let db = open_temporary().unwrap();
+        let mut config = DbConfig::default();
+        create_index(&db, "n", IndexKind::Sorted, &mut config).unwrap();
+        for n in [5, 3, 9, 1, 7] {
+            set_key(&db, &format!("k{}", n), json!({ "n": n }), &config).unwrap();
+        }
+        let options = QueryOptions {
+            projection: Some(vec!["n".to_string()]),
+            limit: Some(2),
+            sort: Some(SortSpec { field: "n".to_string(), descending: true }),
+            ..Default::default()
+        };
+        let (results, plan) = query(&db, all("n"), &options, &config);
+        assert_eq!(results, vec![json!({ "n": 9 }), json!({ "n": 7 })]);
+        assert!(plan.iter().any(|step| step.starts_with("top 2 from sorted index on n")), "{:?}", plan);
+    }
+
+    #[test]
+    fn top_k_skips_an_index_still_building() {
+        let db = open_temporary().unwrap();
+        let mut config = DbConfig::default();
+        // Key order differs from the order of n
+        for n in [5, 3, 9] {
+            set_key(&db, &format!("k{}", 10 - n), json!({ "n": n }), &config).unwrap();
+        }
+        // Declared but not backfilled yet: the index holds no entries
+        config.sorted_indexed_fields.insert("n".to_string());
+        config.building_indexes.insert(("n".to_string(), IndexKind::Sorted));
+        let options = QueryOptions { limit: Some(2), sort: Some(SortSpec { field: "n".to_string(), descending: false }), ..Default::default() };
+        let (results, plan) = query(&db, all("n"), &options, &config);
+        assert_eq!(results, vec![json!({ "n": 3, "_rev": 1 }), json!({ "n": 5, "_rev": 1 })]);
+        assert!(plan.iter().any(|step| step.starts_with("load and sort by n")), "{:?}", plan);
+    }
+
+    #[test]
+    fn covering_projection_answers_from_the_index() {
+        let db = open_temporary().unwrap();
+        let mut config = DbConfig::default();
+        create_index(&db, "a.b", IndexKind::Sorted, &mut config).unwrap();
+        set_key(&db, "k1", json!({ "a": { "b": 1 }, "c": "x" }), &config).unwrap();
+        set_key(&db, "k2", json!({ "a": { "b": [2, 3] } }), &config).unwrap();
+        let options = QueryOptions { projection: Some(vec!["a.b".to_string()]), ..Default::default() };
+        let (results, plan) = query(&db, all("a"), &options, &config);
+        // An array is read from the document rather than rebuilt from its element entries
+        assert_eq!(results, vec![json!({ "a": { "b": 1 } }), json!({ "a": { "b": [2, 3] } })]);
+        assert!(plan.iter().any(|step| step == "projection from sorted indexes"), "{:?}", plan);
+    }
+
+    #[test]
+    fn covering_projection_needs_a_ready_plain_index() {
+        let db = open_temporary().unwrap();
+        let mut config = DbConfig::default();
+        set_key(&db, "k1", json!({ "n": 1 }), &config).unwrap();
+        config.sorted_indexed_fields.insert("n".to_string());
+        config.building_indexes.insert(("n".to_string(), IndexKind::Sorted));
+        let options = QueryOptions { projection: Some(vec!["n".to_string()]), ..Default::default() };
+        let (results, plan) = query(&db, all("n"), &options, &config);
+        assert_eq!(results, vec![json!({ "n": 1 })]);
+        assert!(plan.iter().any(|step| step == "fetch page"), "{:?}", plan);
+    }
+
+    fn operations(operations: Value) -> Vec<TransactionOperation> {
+        serde_json::from_value(operations).unwrap()
+    }
+
+    #[test]
+    fn transaction_returns_a_result_per_operation() {
+        let db = open_temporary().unwrap();
+        let config = DbConfig::default();
+        set_key(&db, "stock", json!({ "quantity": 1 }), &config).unwrap();
+        let results = execute_transaction(&db, &operations(json!([
+            { "type": "check", "key": "stock", "path": "quantity", "operator": "Gt", "value": 0 },
+            { "type": "set", "key": "stock", "value": { "quantity": 0 } },
+            { "type": "get", "key": "stock" },
+            { "type": "get", "key": "missing" },
+            { "type": "delete", "key": "missing" },
+        ])), &config).unwrap();
+        let summary: Vec<(bool, Option<Value>)> = results.into_iter().map(|result| (result.ok, result.value)).collect();
+        assert_eq!(summary, vec![
+            (true, None),
+            (true, Some(json!({ "quantity": 0, "_rev": 2 }))),
+            (true, Some(json!({ "quantity": 0, "_rev": 2 }))),
+            (false, Some(Value::Null)),
+            (false, None),
+        ]);
+    }
+
+    #[test]
+    fn failed_check_aborts_the_whole_transaction() {
+        let db = open_temporary().unwrap();
+        let config = DbConfig::default();
+        set_key(&db, "stock", json!({ "quantity": 0 }), &config).unwrap();
+        let result = execute_transaction(&db, &operations(json!([
+            { "type": "set", "key": "order", "value": { "item": "stock" } },
+            { "type": "check", "key": "stock", "path": "quantity", "operator": "Gt", "value": 0 },
+            { "type": "set", "key": "stock", "value": { "quantity": -1 } },
+        ])), &config);
+        assert!(matches!(result, Err(DbError::TransactionOperationFailed(_))));
+        assert!(matches!(get_key(&db, "order"), Err(DbError::NotFound)));
+        assert_eq!(get_key(&db, "stock").unwrap(), json!({ "quantity": 0, "_rev": 1 }));
+        // A missing document fails every check
+        let result = execute_transaction(&db, &operations(json!([
+            { "type": "check", "key": "missing", "path": "quantity", "operator": "Ne", "value": 0 },
+        ])), &config);
+        assert!(result.is_err());
+    }
+}
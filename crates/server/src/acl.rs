@@ -0,0 +1,82 @@
+// API keys confined to key prefixes, for multi-tenant deployments. They are
+// loaded from a JSON file such as
+//   [{"key": "...", "prefixes": ["tenant_a:"], "role": "write"}]
+// or the config file's scoped_api_keys, and checked by each handler against the keys it reads or writes.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::jwt::Role;
+use crate::AppError;
+
+#[derive(Deserialize)]
+pub struct ScopedKeyEntry {
+    key: String,
+    prefixes: Vec<String>,
+    #[serde(default = "default_scoped_role")]
+    role: Role,
+}
+
+fn default_scoped_role() -> Role {
+    Role::Write
+}
+
+// Who made a request: their role and, for scoped API keys, the key
+// prefixes they may touch.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub role: Role,
+    prefixes: Option<Arc<[String]>>,
+}
+
+impl Principal {
+    pub fn unrestricted(role: Role) -> Self {
+        Principal { role, prefixes: None }
+    }
+
+    // The prefixes the caller is confined to, or None if it may use every key.
+    pub fn prefixes(&self) -> Option<&[String]> {
+        self.prefixes.as_deref()
+    }
+
+    pub fn allows(&self, key: &str) -> bool {
+        self.prefixes().is_none_or(|prefixes| prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())))
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<(), AppError> {
+        if self.allows(key) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!("Key '{}' is outside the prefixes of this API key", key)))
+        }
+    }
+
+    pub fn check_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Result<(), AppError> {
+        keys.into_iter().try_for_each(|key| self.check_key(key))
+    }
+}
+
+pub fn read_scoped_keys_file(path: &Path) -> Result<Vec<ScopedKeyEntry>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Invalid scoped keys in {}: {}", path.display(), e))
+}
+
+// Turns scoped key entries into principals by API key.
+pub fn scoped_keys(entries: Vec<ScopedKeyEntry>, api_key: &str) -> Result<HashMap<String, Principal>, String> {
+    let mut principals = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        if entry.key.is_empty() || entry.key == api_key {
+            return Err("Scoped API keys must be non-empty and differ from the main API key".to_string());
+        }
+        if entry.prefixes.is_empty() {
+            return Err("Each scoped API key must list at least one prefix".to_string());
+        }
+        let principal = Principal { role: entry.role, prefixes: Some(entry.prefixes.into()) };
+        if principals.insert(entry.key, principal).is_some() {
+            return Err("Scoped API keys must be unique".to_string());
+        }
+    }
+    Ok(principals)
+}
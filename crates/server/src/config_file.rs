@@ -0,0 +1,122 @@
+// Server settings read from a TOML or YAML file given with --config, such as
+//   listen_addr = "0.0.0.0:8989"
+//   db_name = "main"
+//   [cors]
+//   allowed_origins = ["https://app.example.com"]
+//   [indexes]
+//   hash = ["email"]
+//   sorted = ["created_at"]
+// Flags and environment variables take precedence over the file.
+use std::path::{Path, PathBuf};
+
+use clap::{parser::ValueSource, ArgMatches};
+use rust_db_logic::IndexKind;
+use serde::Deserialize;
+use serde_json::Value;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::acl::ScopedKeyEntry;
+use crate::Args;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    listen_addr: Option<String>,
+    base_path: Option<PathBuf>,
+    db_name: Option<String>,
+    databases: Vec<String>,
+    api_key: Option<String>,
+    pub scoped_api_keys: Vec<ScopedKeyEntry>,
+    cors: CorsConfig,
+    pub indexes: IndexDeclarations,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CorsConfig {
+    allowed_origins: Vec<String>,
+}
+
+// Indexed fields every database should have; missing ones are built when the
+// database opens.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexDeclarations {
+    hash: Vec<String>,
+    sorted: Vec<String>,
+    geo: Vec<String>,
+    unique: Vec<String>,
+}
+
+impl IndexDeclarations {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, IndexKind)> {
+        self.hash.iter().map(|field| (field.as_str(), IndexKind::Hash))
+            .chain(self.sorted.iter().map(|field| (field.as_str(), IndexKind::Sorted)))
+            .chain(self.geo.iter().map(|field| (field.as_str(), IndexKind::Geo)))
+            .chain(self.unique.iter().map(|field| (field.as_str(), IndexKind::Unique)))
+    }
+}
+
+impl ConfigFile {
+    // Parses the file as YAML when it ends in .yaml or .yml, TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let invalid = |e: String| format!("Invalid config file {}: {}", path.display(), e);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => {
+                let documents = YamlLoader::load_from_str(&text).map_err(|e| invalid(e.to_string()))?;
+                let value = documents.into_iter().next().map_or(Value::Null, yaml_to_json);
+                if value.is_null() {
+                    return Ok(ConfigFile::default());
+                }
+                serde_json::from_value(value).map_err(|e| invalid(e.to_string()))
+            }
+            _ => toml::from_str(&text).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    // Fills in the settings that were not given as flags or environment variables.
+    pub fn apply(&mut self, args: &mut Args, matches: &ArgMatches) {
+        let unset = |id: &str| !matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+        if let Some(listen_addr) = self.listen_addr.take().filter(|_| unset("listen_addr")) {
+            args.listen_addr = listen_addr;
+        }
+        if let Some(base_path) = self.base_path.take().filter(|_| unset("base_path")) {
+            args.base_path = base_path;
+        }
+        if args.db_name.is_none() {
+            args.db_name = self.db_name.take();
+        }
+        if unset("databases") {
+            args.databases.append(&mut self.databases);
+        }
+        if args.api_key.is_none() {
+            args.api_key = self.api_key.take();
+        }
+        if unset("cors_allowed_origins") {
+            args.cors_allowed_origins.append(&mut self.cors.allowed_origins);
+        }
+    }
+}
+
+fn yaml_to_json(yaml: Yaml) -> Value {
+    match yaml {
+        Yaml::Real(s) => s.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map_or(Value::String(s), Value::Number),
+        Yaml::Integer(i) => Value::from(i),
+        Yaml::String(s) => Value::String(s),
+        Yaml::Boolean(b) => Value::Bool(b),
+        Yaml::Array(items) => Value::Array(items.into_iter().map(yaml_to_json).collect()),
+        Yaml::Hash(entries) => Value::Object(entries.into_iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Yaml::String(s) | Yaml::Real(s) => s,
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => String::new(),
+                };
+                (key, yaml_to_json(value))
+            })
+            .collect()),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
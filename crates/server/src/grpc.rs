@@ -0,0 +1,185 @@
+// gRPC service next to the REST routes, calling the same logic crate
+// functions. See proto/commando.proto for the messages.
+// tonic's Status is large, but it is the error type its handlers must return.
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use rust_db_logic::{self as logic, QueryNode, QueryOptions, TransactionOperation, WatchTarget};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::{authenticate, charge_credential, check_writable, logic_error_status, run_ast_query, DATABASE_HEADER, subscribe, AppError, AppState, Principal, Role};
+
+pub mod proto {
+    tonic::include_proto!("commando");
+}
+
+use proto::commando_db_server::{CommandoDb, CommandoDbServer};
+use proto::{
+    change_event::Kind, watch_request::Target, ChangeEvent, GetRequest, GetResponse, QueryRequest, QueryResponse,
+    SetRequest, SetResponse, TransactionRequest, TransactionResponse, WatchRequest,
+};
+
+// Change events buffered per watch stream before the watcher waits for the client.
+const WATCH_BUFFER: usize = 256;
+
+pub struct GrpcService;
+
+// Serves the gRPC API until the listener fails. Requests authenticate like
+// the REST routes, with the `x-api-key` or `authorization` metadata, and may
+// pick a database with `x-database`.
+pub async fn serve(databases: HashMap<String, AppState>, default_database: String, addr: std::net::SocketAddr) -> Result<(), tonic::transport::Error> {
+    let service = CommandoDbServer::with_interceptor(GrpcService, move |mut request: Request<()>| {
+        let name = match request.metadata().get(DATABASE_HEADER) {
+            Some(value) => value.to_str().map_err(|_| Status::invalid_argument("Invalid x-database metadata"))?,
+            None => default_database.as_str(),
+        };
+        let state = databases.get(name).ok_or_else(|| Status::not_found(format!("Database not found: {}", name)))?.clone();
+        if let (Some(limiter), Some(addr)) = (&state.ip_rate_limiter, request.remote_addr()) {
+            limiter.check(&addr.ip().to_string()).map_err(rate_limited)?;
+        }
+        let headers = request.metadata().clone().into_headers();
+        let principal = authenticate(&state, &headers).map_err(|e| Status::unauthenticated(e.to_string()))?;
+        charge_credential(&state, &headers).map_err(rate_limited)?;
+        request.extensions_mut().insert(principal);
+        request.extensions_mut().insert(state);
+        Ok(request)
+    });
+    info!("Serving gRPC on {}", addr);
+    tonic::transport::Server::builder().add_service(service).serve(addr).await
+}
+
+// The caller and the database they picked, if their role covers `required`.
+// Scoped API keys are further checked against the keys each call touches.
+fn authorize<T>(request: &Request<T>, required: Role) -> Result<(Principal, AppState), Status> {
+    let extensions = request.extensions();
+    match (extensions.get::<Principal>(), extensions.get::<AppState>()) {
+        (Some(principal), Some(state)) if principal.role >= required => Ok((principal.clone(), state.clone())),
+        _ => Err(Status::permission_denied(format!("Requires the {:?} role", required))),
+    }
+}
+
+fn rate_limited(wait: Duration) -> Status {
+    Status::resource_exhausted(format!("Too many requests; retry in {:.1}s", wait.as_secs_f64()))
+}
+
+fn forbidden(err: AppError) -> Status {
+    Status::permission_denied(err.to_string())
+}
+
+fn status(err: logic::DbError) -> Status {
+    let (http_status, message) = logic_error_status(&err);
+    warn!("Error processing gRPC request: {}", err);
+    match http_status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::aborted(message),
+        StatusCode::PRECONDITION_FAILED => Status::failed_precondition(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(text: &str, field: &str) -> Result<T, Status> {
+    serde_json::from_str(text).map_err(|e| Status::invalid_argument(format!("Invalid {}: {}", field, e)))
+}
+
+fn change_event(change: logic::ChangeEvent) -> ChangeEvent {
+    match change {
+        logic::ChangeEvent::Set { key, value } => ChangeEvent { kind: Kind::Set.into(), key, value_json: value.to_string() },
+        logic::ChangeEvent::Delete { key } => ChangeEvent { kind: Kind::Delete.into(), key, value_json: String::new() },
+    }
+}
+
+#[tonic::async_trait]
+impl CommandoDb for GrpcService {
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let (principal, state) = authorize(&request, Role::Write)?;
+        check_writable(&state).map_err(forbidden)?;
+        let request = request.into_inner();
+        principal.check_key(&request.key).map_err(forbidden)?;
+        let value: Value = parse_json(&request.value_json, "value_json")?;
+        let db_config_guard = state.db_config.lock().unwrap();
+        logic::set_key(&state.db.load(), &request.key, value, &db_config_guard).map_err(status)?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let (principal, state) = authorize(&request, Role::Read)?;
+        let key = request.into_inner().key;
+        principal.check_key(&key).map_err(forbidden)?;
+        let value = logic::get_key(&state.db.load(), &key).map_err(status)?;
+        Ok(Response::new(GetResponse { value_json: value.to_string() }))
+    }
+
+    async fn query(&self, request: Request<QueryRequest>) -> Result<Response<QueryResponse>, Status> {
+        let (principal, state) = authorize(&request, Role::Read)?;
+        let request = request.into_inner();
+        let ast: QueryNode = parse_json(&request.ast_json, "ast_json")?;
+        let mut options: QueryOptions = if request.options_json.is_empty() {
+            QueryOptions::default()
+        } else {
+            parse_json(&request.options_json, "options_json")?
+        };
+        options.key_prefixes = principal.prefixes().map(<[String]>::to_vec);
+        let config_clone = state.db_config.lock().unwrap().clone();
+        let page = run_ast_query(&state, ast, &options, &config_clone).map_err(status)?;
+        Ok(Response::new(QueryResponse { documents_json: page.results.iter().map(Value::to_string).collect() }))
+    }
+
+    async fn transaction(&self, request: Request<TransactionRequest>) -> Result<Response<TransactionResponse>, Status> {
+        let (principal, state) = authorize(&request, Role::Write)?;
+        check_writable(&state).map_err(forbidden)?;
+        let operations = request.into_inner().operations_json.iter()
+            .map(|operation| parse_json::<TransactionOperation>(operation, "operations_json"))
+            .collect::<Result<Vec<_>, _>>()?;
+        principal.check_keys(operations.iter().map(TransactionOperation::key)).map_err(forbidden)?;
+        let db_config_guard = state.db_config.lock().unwrap();
+        let results = logic::execute_transaction(&state.db.load(), &operations, &db_config_guard).map_err(status)?;
+        let results_json = results.iter()
+            .map(|result| serde_json::to_string(result).map_err(|e| status(e.into())))
+            .collect::<Result<_, _>>()?;
+        Ok(Response::new(TransactionResponse { results_json }))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<ChangeEvent, Status>> + Send>>;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let (principal, state) = authorize(&request, Role::Read)?;
+        let target = match request.into_inner().target {
+            Some(Target::Key(key)) => WatchTarget::Key(key),
+            Some(Target::Prefix(prefix)) => WatchTarget::Prefix(prefix),
+            Some(Target::QueryJson(query_json)) => WatchTarget::Query(parse_json(&query_json, "query_json")?),
+            None => return Err(Status::invalid_argument("Missing watch target")),
+        };
+        let (mut subscriber, mut watcher) = subscribe(&state, target).await.map_err(status)?;
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = &mut subscriber => {
+                        let Some(event) = event else { break };
+                        match watcher.apply(&event) {
+                            Ok(Some(change)) if principal.allows(change.key()) => {
+                                if sender.send(Ok(change_event(change))).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => warn!("Skipping change event the watcher could not read: {}", e),
+                        }
+                    }
+                    _ = sender.closed() => break,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
@@ -0,0 +1,100 @@
+// Probes for orchestrators such as Kubernetes, served without auth. /healthz
+// (and /) answers while the process runs; /readyz also checks that the
+// database is open and takes writes, that its disk has room and that a flush
+// finishes in time, answering 503 when any check fails.
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use rust_db_logic as logic;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{instrument, warn};
+
+use crate::{AppError, AppState};
+
+// What /readyz expects of the database; see --readyz-min-free-mb and
+// --readyz-max-flush-ms.
+#[derive(Clone, Copy, Debug)]
+pub struct Readiness {
+    pub started: Instant,
+    pub min_free_bytes: u64,
+    pub max_flush: Duration,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Liveness {
+    status: &'static str,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessReport {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+#[derive(Serialize, Debug)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    // What was measured, or the error that failed the check.
+    #[serde(flatten)]
+    details: Value,
+}
+
+impl Check {
+    fn new(name: &'static str, ok: bool, details: Value) -> Self {
+        Check { name, ok, details }
+    }
+
+    fn failed(name: &'static str, error: impl std::fmt::Display) -> Self {
+        Check::new(name, false, json!({ "error": error.to_string() }))
+    }
+}
+
+#[instrument(skip(state), fields(handler="healthz_handler"))]
+pub async fn healthz_handler(State(state): State<AppState>) -> Json<Liveness> {
+    Json(Liveness { status: "ok", uptime_secs: state.readiness.started.elapsed().as_secs() })
+}
+
+#[instrument(skip(state), fields(handler="readyz_handler"))]
+pub async fn readyz_handler(State(state): State<AppState>) -> Result<(StatusCode, Json<ReadinessReport>), AppError> {
+    let report = tokio::task::spawn_blocking(move || check_readiness(&state)).await
+        .map_err(|e| logic::DbError::Transaction(format!("Readiness check failed: {}", e)))?;
+    if !report.ready {
+        let failed: Vec<_> = report.checks.iter().filter(|check| !check.ok).map(|check| check.name).collect();
+        warn!("Not ready: failed {:?}", failed);
+    }
+    let status = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Ok((status, Json(report)))
+}
+
+fn check_readiness(state: &AppState) -> ReadinessReport {
+    let db = state.db.load();
+    let readiness = state.readiness;
+    let open = match db.size_on_disk() {
+        Ok(size) => Check::new("open", true, json!({ "size_bytes": size })),
+        Err(e) => Check::failed("open", e),
+    };
+    let writable = match logic::probe_write(&db) {
+        Ok(()) => Check::new("writable", true, json!({})),
+        Err(e) => Check::failed("writable", e),
+    };
+    let disk_space = match fs2::available_space(&state.db_dir) {
+        Ok(free) => Check::new("disk_space", free >= readiness.min_free_bytes, json!({ "free_bytes": free, "min_free_bytes": readiness.min_free_bytes })),
+        Err(e) => Check::failed("disk_space", e),
+    };
+    let started = Instant::now();
+    let flush = match db.flush() {
+        Ok(_) => {
+            let elapsed = started.elapsed();
+            let details = json!({ "flush_ms": elapsed.as_secs_f64() * 1000.0, "max_flush_ms": readiness.max_flush.as_millis() as u64 });
+            Check::new("flush", elapsed <= readiness.max_flush, details)
+        }
+        Err(e) => Check::failed("flush", e),
+    };
+    let checks = vec![open, writable, disk_space, flush];
+    ReadinessReport { ready: checks.iter().all(|check| check.ok), checks }
+}
@@ -0,0 +1,145 @@
+// Idempotency keys for /set, /batch_set, /transaction and /batch. The
+// response to a request sent with an Idempotency-Key header is stored for
+// --idempotency-window-secs, and a retry with the same key gets it back,
+// marked Idempotent-Replayed, instead of applying the writes again. Keys are
+// scoped to the credential that sent them, and a key reused for a different
+// request, or for one still running, fails with 409.
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::{to_bytes, Body};
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rust_db_logic::{self as logic, DbError, StoredResponse};
+use tracing::{error, info, warn};
+
+use crate::{credential, AppError, AppState};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// axum's default body limit, which the handlers' JSON extractors apply too.
+const MAX_REQUEST_BYTES: usize = 2 * 1024 * 1024;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct Idempotency {
+    window: Duration,
+    // Scoped keys whose first request is still running.
+    in_flight: Mutex<HashSet<String>>,
+}
+
+// Marks a scoped key as running until dropped.
+struct InFlight<'a> {
+    idempotency: &'a Idempotency,
+    key: String,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.idempotency.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Idempotency {
+    // None when `window_secs` is zero, which disables idempotency keys.
+    pub fn new(window_secs: u64) -> Option<Arc<Self>> {
+        (window_secs > 0).then(|| Arc::new(Idempotency { window: Duration::from_secs(window_secs), in_flight: Mutex::default() }))
+    }
+
+    fn begin(&self, key: String) -> Option<InFlight<'_>> {
+        self.in_flight.lock().unwrap().insert(key.clone()).then_some(InFlight { idempotency: self, key })
+    }
+}
+
+fn takes_idempotency_key(path: &str) -> bool {
+    matches!(path, "/set" | "/batch_set" | "/transaction" | "/batch")
+}
+
+// Runs after authentication, so only requests allowed to write are stored.
+pub async fn idempotency_keys(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(idempotency) = state.idempotency.as_deref().filter(|_| takes_idempotency_key(req.uri().path())) else {
+        return Ok(next.run(req).await);
+    };
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else { return Ok(next.run(req).await) };
+    let key = key.to_str().ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| DbError::MissingData(format!("Idempotency-Key must be 1 to {} visible ASCII characters", MAX_IDEMPOTENCY_KEY_LEN)))?
+        .to_string();
+    let scope = format!("{:016x}", logic::fnv1a(credential(req.headers()).as_bytes()));
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body, MAX_REQUEST_BYTES).await
+        .map_err(|e| DbError::MissingData(format!("Failed to read request body: {}", e)))?;
+    let request_hash = logic::fnv1a(&[parts.method.as_str().as_bytes(), parts.uri.path().as_bytes(), &body].concat());
+
+    let Some(_in_flight) = idempotency.begin(format!("{}:{}", scope, key)) else {
+        return Err(AppError::IdempotencyConflict(format!("a request with Idempotency-Key '{}' is still running", key)));
+    };
+    if let Some(stored) = logic::stored_response(&state.db.load(), &scope, &key)? {
+        if stored.request_hash != request_hash {
+            return Err(AppError::IdempotencyConflict(format!("Idempotency-Key '{}' was used for a different request", key)));
+        }
+        info!("Replaying the response to Idempotency-Key {}", key);
+        return Ok(replay(stored));
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    // Server errors may pass, so a retry runs the request again.
+    if response.status().is_server_error() {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to read the response to Idempotency-Key {}: {}", key, e);
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let expires_at = (SystemTime::now() + idempotency.window).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let stored = StoredResponse {
+        expires_at,
+        request_hash,
+        status: parts.status.as_u16(),
+        content_type: parts.headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+    if let Err(e) = logic::store_response(&state.db.load(), &scope, &key, &stored) {
+        error!("Failed to store the response to Idempotency-Key {}: {}", key, e);
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    if let Some(content_type) = stored.content_type.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    }
+    response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+// Removes expired responses every EXPIRY_INTERVAL.
+pub fn spawn_expiry(state: AppState) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let db = Arc::clone(&state.db.load());
+            match tokio::task::spawn_blocking(move || logic::expire_stored_responses(&db)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => info!("Forgot {} expired idempotency keys", count),
+                Ok(Err(e)) => warn!("Idempotency key expiry failed: {}", e),
+                Err(e) => error!("Idempotency key expiry task panicked: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,216 @@
+// Bearer-token authentication: JWTs signed with an HS256 secret, an RS256
+// key or a key from a JWKS URL, with the caller's role taken from a claim.
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+// What a caller may do. Each role includes the ones before it; the API key
+// always has Admin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{}', expected read, write or admin", s)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("Invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("No signing key for key id {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("Algorithm {0:?} is not accepted for JWKS keys")]
+    Algorithm(Algorithm),
+    #[error("Token grants no role")]
+    NoRole,
+}
+
+// Where token signing keys come from.
+pub enum KeySource {
+    Hs256Secret(String),
+    Rs256PublicKey(PathBuf),
+    JwksUrl(String),
+}
+
+// JWKS keys by key id, replaced on every refresh.
+type JwksKeys = Arc<RwLock<Vec<(Option<String>, DecodingKey)>>>;
+
+enum Keys {
+    Static(Algorithm, Box<DecodingKey>),
+    Jwks { url: String, keys: JwksKeys },
+}
+
+pub struct JwtAuth {
+    keys: Keys,
+    issuer: Option<String>,
+    audience: Option<String>,
+    roles_claim: Vec<String>,
+    role_map: HashMap<String, Role>,
+}
+
+// Leaves out the keys, which may include the shared secret.
+impl fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("roles_claim", &self.roles_claim)
+            .field("role_map", &self.role_map)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtAuth {
+    // `roles_claim` is a dotted path to the claim holding the caller's roles
+    // (a string of space-separated values or an array of strings), and each
+    // `role_map` entry is `claim-value=role`. Claim values without a mapping
+    // are used when they name a role themselves.
+    pub fn new(
+        source: KeySource,
+        issuer: Option<String>,
+        audience: Option<String>,
+        roles_claim: &str,
+        role_map: &[String],
+    ) -> Result<Self, String> {
+        let keys = match source {
+            KeySource::Hs256Secret(secret) => {
+                if secret.is_empty() {
+                    return Err("JWT secret cannot be empty".to_string());
+                }
+                Keys::Static(Algorithm::HS256, Box::new(DecodingKey::from_secret(secret.as_bytes())))
+            }
+            KeySource::Rs256PublicKey(path) => {
+                let pem = std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| format!("Invalid RS256 public key in {}: {}", path.display(), e))?;
+                Keys::Static(Algorithm::RS256, Box::new(key))
+            }
+            KeySource::JwksUrl(url) => Keys::Jwks { url, keys: JwksKeys::default() },
+        };
+        let role_map = role_map.iter()
+            .map(|entry| {
+                let (value, role) = entry.split_once('=').ok_or_else(|| format!("Invalid role mapping '{}', expected claim-value=role", entry))?;
+                Ok((value.to_string(), role.parse()?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(JwtAuth {
+            keys,
+            issuer,
+            audience,
+            roles_claim: roles_claim.split('.').map(str::to_string).collect(),
+            role_map,
+        })
+    }
+
+    // Verifies the token's signature, expiry, issuer and audience, and
+    // returns the highest role its claims grant.
+    pub fn verify(&self, token: &str) -> Result<Role, JwtError> {
+        let claims = match &self.keys {
+            Keys::Static(algorithm, key) => decode::<Value>(token, key, &self.validation(*algorithm))?.claims,
+            Keys::Jwks { keys, .. } => {
+                let header = decode_header(token)?;
+                // Only asymmetric keys are published; refusing HMAC here keeps a
+                // public key from being used as a shared secret.
+                if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                    return Err(JwtError::Algorithm(header.alg));
+                }
+                let keys = keys.read().unwrap();
+                let key = match &header.kid {
+                    Some(kid) => keys.iter().find(|(key_id, _)| key_id.as_deref() == Some(kid.as_str())),
+                    None if keys.len() == 1 => keys.first(),
+                    None => None,
+                };
+                let (_, key) = key.ok_or_else(|| JwtError::UnknownKey(header.kid.clone()))?;
+                decode::<Value>(token, key, &self.validation(header.alg))?.claims
+            }
+        };
+        self.role(&claims).ok_or(JwtError::NoRole)
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        validation
+    }
+
+    fn role(&self, claims: &Value) -> Option<Role> {
+        let claim = self.roles_claim.iter().try_fold(claims, |value, part| value.get(part))?;
+        let values: Vec<&str> = match claim {
+            Value::String(s) => s.split_whitespace().collect(),
+            Value::Array(items) => items.iter().filter_map(Value::as_str).collect(),
+            _ => return None,
+        };
+        values.into_iter()
+            .filter_map(|value| self.role_map.get(value).copied().or_else(|| value.parse().ok()))
+            .max()
+    }
+
+    // Fetches the JWKS now and then every `interval`, keeping the previous
+    // keys when a refresh fails. Does nothing for static keys.
+    pub async fn start_jwks_refresh(self: &Arc<Self>, interval: Duration) {
+        let Keys::Jwks { url, keys } = &self.keys else { return };
+        refresh_jwks(url, keys).await;
+        let (url, keys) = (url.clone(), Arc::clone(keys));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately and the keys were just fetched.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                refresh_jwks(&url, &keys).await;
+            }
+        });
+    }
+}
+
+async fn refresh_jwks(url: &str, keys: &JwksKeys) {
+    match fetch_jwks(url).await {
+        Ok(fetched) => {
+            info!("Loaded {} signing keys from {}", fetched.len(), url);
+            *keys.write().unwrap() = fetched;
+        }
+        Err(e) => error!("Failed to fetch JWKS from {}: {}", url, e),
+    }
+}
+
+async fn fetch_jwks(url: &str) -> Result<Vec<(Option<String>, DecodingKey)>, String> {
+    let response = reqwest::get(url).await.and_then(|r| r.error_for_status()).map_err(|e| e.to_string())?;
+    let jwks: JwkSet = response.json().await.map_err(|e| e.to_string())?;
+    Ok(jwks.keys.iter()
+        .filter_map(|jwk| match DecodingKey::from_jwk(jwk) {
+            Ok(key) => Some((jwk.common.key_id.clone(), key)),
+            Err(e) => {
+                warn!("Skipping JWKS key {:?}: {}", jwk.common.key_id, e);
+                None
+            }
+        })
+        .collect())
+}
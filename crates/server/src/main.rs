@@ -1,9 +1,9 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
-    response::{IntoResponse, Response, Json},
-    http::{StatusCode, Request, header::{HeaderName, HeaderValue}}, // Corrected header import
-    extract::{State, FromRequestParts},
+    response::{AppendHeaders, IntoResponse, Response, Json},
+    http::{StatusCode, Method, Request, HeaderMap, HeaderValue, header::{self, HeaderName}}, // Corrected header import
+    extract::{ConnectInfo, Extension, Path, Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
     middleware::{self, Next},
     body::Body, // Import Body
 };
@@ -12,48 +12,294 @@ use rust_db_logic::{
     export_data,
     DbConfig as LogicDbConfig,
     BatchSetItem,
+    Expiry,
     TransactionOperation,
+    TransactionOpResult,
+    PatchOperation,
+    ArrayOperation,
     QueryNode,
+    QueryOptions,
+    IndexKind,
+    IndexStats,
+    IndexVerification,
+    IndexBuild,
+    IndexBuildState,
+    IndexGcReport,
+    GeoRTree,
+    WatchTarget,
+    Watcher,
+    GeoPoint,
+    DistanceMetric,
 };
 use serde::{Serialize, Deserialize};
 use serde_json::{Value, json};
-use sled::{Db, Config};
+use sled::{Db, Config, Event};
 use std::sync::Arc;
+use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
 use std::fs;
 use std::env;
 use tokio::net::TcpListener;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tower::ServiceExt;
+use tokio::sync::{mpsc, oneshot};
+use tower_http::{cors::{AllowOrigin, Any, CorsLayer}, trace::TraceLayer};
 use tracing::{info, error, warn, Level, instrument};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use thiserror::Error;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rand::{distributions::Alphanumeric, Rng};
 
+mod acl;
+mod config_file;
+mod grpc;
+mod health;
+mod idempotency;
+mod jwt;
+mod rate_limit;
+mod replication;
+mod restore;
+mod webhooks;
+
+use acl::Principal;
+use config_file::{ConfigFile, IndexDeclarations};
+use health::Readiness;
+use idempotency::Idempotency;
+use jwt::{JwtAuth, KeySource, Role};
+use rate_limit::RateLimiter;
+use replication::Changelog;
+
 const DEFAULT_BASE_PATH: &str = "database_data_server";
 const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8989";
+const DEFAULT_TTL_INTERVAL_SECS: u64 = 60;
+const DEFAULT_INDEX_GC_INTERVAL_SECS: u64 = 3600;
+const INDEX_BUILD_BATCH_SIZE: usize = 256;
+const DEFAULT_INGEST_BATCH_SIZE: usize = 1000;
+const DEFAULT_INGEST_MAX_DELAY_MS: u64 = 10;
+const DEFAULT_INGEST_QUEUE_CAPACITY: usize = 1024;
+const DEFAULT_RATE_LIMIT_BURST: u32 = 100;
+const DEFAULT_READYZ_MIN_FREE_MB: u64 = 100;
+const DEFAULT_IDEMPOTENCY_WINDOW_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_READYZ_MAX_FLUSH_MS: u64 = 1000;
+const EXPORT_STREAM_BUFFER: usize = 64;
+const DEFAULT_LIST_KEYS_LIMIT: usize = 100;
+const MAX_LIST_KEYS_LIMIT: usize = 10_000;
+const MAX_BATCH_OPERATIONS: usize = 1000;
+const SLOW_QUERY_LOG_SIZE: usize = 200;
+const DEFAULT_JWKS_REFRESH_SECS: u64 = 300;
+const DEFAULT_JWT_ROLES_CLAIM: &str = "roles";
 const API_KEY_HEADER: &str = "X-API-Key";
 const API_KEY_HEADER_LOWERCASE: &str = "x-api-key"; // Lowercase version
+const DATABASE_HEADER: &str = "x-database";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// TOML or YAML file with server settings and indexed fields; flags and environment variables override it.
+    #[arg(long, env = "CONFIG_FILE", value_name = "FILE")]
+    config: Option<PathBuf>,
     #[arg(short, long, env = "DB_PATH", value_name = "DIR", default_value = DEFAULT_BASE_PATH)]
     base_path: PathBuf,
-    #[arg(short, long, env = "DB_NAME", value_name = "NAME")]
-    db_name: String,
+    #[arg(short, long, env = "DB_NAME", value_name = "NAME", required_unless_present = "config")]
+    db_name: Option<String>,
+    /// Comma-separated names of more databases to open under the base path. Requests pick one with a
+    /// /db/{name}/... path prefix or the X-Database header; others go to --db-name.
+    #[arg(long, env = "DB_NAMES", value_name = "NAMES", value_delimiter = ',')]
+    databases: Vec<String>,
     #[arg(short, long, env = "LISTEN_ADDR", value_name = "HOST:PORT", default_value = DEFAULT_LISTEN_ADDR)]
     listen_addr: String,
     #[arg(long, env = "DB_API_KEY")] // Reads from --api-key OR DB_API_KEY env var
     api_key: Option<String>,
+    /// JSON file of extra API keys confined to key prefixes: [{"key", "prefixes", "role"}].
+    #[arg(long, env = "SCOPED_API_KEYS_FILE", value_name = "FILE")]
+    scoped_api_keys_file: Option<PathBuf>,
+    /// HS256 secret for verifying `Authorization: Bearer` JWTs, accepted alongside the API key.
+    #[arg(long, env = "JWT_HS256_SECRET", conflicts_with_all = ["jwt_rs256_public_key", "jwt_jwks_url"])]
+    jwt_hs256_secret: Option<String>,
+    /// PEM file with the RS256 public key for verifying bearer JWTs.
+    #[arg(long, env = "JWT_RS256_PUBLIC_KEY", value_name = "FILE", conflicts_with = "jwt_jwks_url")]
+    jwt_rs256_public_key: Option<PathBuf>,
+    /// JWKS URL to fetch bearer JWT signing keys from.
+    #[arg(long, env = "JWT_JWKS_URL", value_name = "URL")]
+    jwt_jwks_url: Option<String>,
+    /// Seconds between JWKS refreshes.
+    #[arg(long, env = "JWT_JWKS_REFRESH_SECS", value_name = "SECONDS", default_value_t = DEFAULT_JWKS_REFRESH_SECS)]
+    jwt_jwks_refresh_secs: u64,
+    /// Required `iss` claim of bearer JWTs.
+    #[arg(long, env = "JWT_ISSUER")]
+    jwt_issuer: Option<String>,
+    /// Required `aud` claim of bearer JWTs.
+    #[arg(long, env = "JWT_AUDIENCE")]
+    jwt_audience: Option<String>,
+    /// Claim holding a JWT's roles (read, write, admin), as a dotted path such as realm_access.roles.
+    #[arg(long, env = "JWT_ROLES_CLAIM", value_name = "CLAIM", default_value = DEFAULT_JWT_ROLES_CLAIM)]
+    jwt_roles_claim: String,
+    /// Comma-separated claim-value=role mappings, e.g. editor=write,viewer=read.
+    #[arg(long, env = "JWT_ROLE_MAP", value_name = "MAPPINGS", value_delimiter = ',')]
+    jwt_role_map: Vec<String>,
+    /// Comma-separated origins allowed to call the API from browsers; any origin if unset.
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_name = "ORIGINS", value_delimiter = ',')]
+    cors_allowed_origins: Vec<String>,
+    /// Address for the gRPC API; disabled unless set.
+    #[arg(long, env = "GRPC_LISTEN_ADDR", value_name = "HOST:PORT")]
+    grpc_listen_addr: Option<String>,
+    #[arg(long, env = "TTL_INTERVAL_SECS", value_name = "SECONDS", default_value_t = DEFAULT_TTL_INTERVAL_SECS)]
+    ttl_interval_secs: u64,
+    /// Seconds between sweeps removing index entries of deleted documents; 0 disables them.
+    #[arg(long, env = "INDEX_GC_INTERVAL_SECS", value_name = "SECONDS", default_value_t = DEFAULT_INDEX_GC_INTERVAL_SECS)]
+    index_gc_interval_secs: u64,
+    /// Documents written per transaction by /import.
+    #[arg(long, env = "IMPORT_CHUNK_SIZE", value_name = "DOCS", default_value_t = logic::DEFAULT_IMPORT_CHUNK_SIZE)]
+    import_chunk_size: usize,
+    /// Automatically hash-index fields used in Eq queries. Off by default; declare indexes via /index/create instead.
+    #[arg(long, env = "DYNAMIC_INDEXING")]
+    dynamic_indexing: bool,
+    /// Comma-separated geo fields to load into in-memory R-trees at startup, for fast radius and nearest queries.
+    #[arg(long, env = "GEO_RTREE_FIELDS", value_name = "FIELDS", value_delimiter = ',')]
+    geo_rtree_fields: Vec<String>,
+    /// Documents /ingest groups into one transaction at most.
+    #[arg(long, env = "INGEST_BATCH_SIZE", value_name = "DOCS", default_value_t = DEFAULT_INGEST_BATCH_SIZE)]
+    ingest_batch_size: usize,
+    /// Milliseconds /ingest waits for more writes before committing a group.
+    #[arg(long, env = "INGEST_MAX_DELAY_MS", value_name = "MS", default_value_t = DEFAULT_INGEST_MAX_DELAY_MS)]
+    ingest_max_delay_ms: u64,
+    /// /ingest requests queued before new ones wait for room.
+    #[arg(long, env = "INGEST_QUEUE_CAPACITY", value_name = "REQUESTS", default_value_t = DEFAULT_INGEST_QUEUE_CAPACITY)]
+    ingest_queue_capacity: usize,
+    /// Requests per second allowed per API key or bearer token; 0 disables the limit.
+    #[arg(long, env = "RATE_LIMIT_PER_KEY", value_name = "REQUESTS", default_value_t = 0.0)]
+    rate_limit_per_key: f64,
+    /// Requests per second allowed per client IP; 0 disables the limit.
+    #[arg(long, env = "RATE_LIMIT_PER_IP", value_name = "REQUESTS", default_value_t = 0.0)]
+    rate_limit_per_ip: f64,
+    /// Requests a client may make at once before its rate limit applies.
+    #[arg(long, env = "RATE_LIMIT_BURST", value_name = "REQUESTS", default_value_t = DEFAULT_RATE_LIMIT_BURST)]
+    rate_limit_burst: u32,
+    /// Log AST queries slower than this many milliseconds with their plan, for /admin/slow_queries.
+    #[arg(long, env = "SLOW_QUERY_MS", value_name = "MS")]
+    slow_query_ms: Option<u64>,
+    /// Also store slow queries in the database, so /admin/slow_queries survives restarts.
+    #[arg(long, env = "SLOW_QUERY_STORE", requires = "slow_query_ms")]
+    slow_query_store: bool,
+    /// Log the last N document changes for /changes, webhooks and replicas; 0 disables the changelog.
+    #[arg(long, env = "CHANGELOG_SIZE", default_value_t = 0)]
+    changelog_size: usize,
+    /// Run as a read-only replica of the server at this URL, following its changelog.
+    #[arg(long, env = "REPLICA_OF", value_name = "URL")]
+    replica_of: Option<String>,
+    /// API key with the admin role on the primary.
+    #[arg(long, env = "REPLICA_API_KEY", requires = "replica_of")]
+    replica_api_key: Option<String>,
+    /// Seconds responses to requests with an Idempotency-Key are kept for replay; 0 disables the header.
+    #[arg(long, env = "IDEMPOTENCY_WINDOW_SECS", value_name = "SECS", default_value_t = DEFAULT_IDEMPOTENCY_WINDOW_SECS)]
+    idempotency_window_secs: u64,
+    /// /readyz fails when the disk holding a database has fewer megabytes free.
+    #[arg(long, env = "READYZ_MIN_FREE_MB", value_name = "MB", default_value_t = DEFAULT_READYZ_MIN_FREE_MB)]
+    readyz_min_free_mb: u64,
+    /// /readyz fails when flushing a database takes longer.
+    #[arg(long, env = "READYZ_MAX_FLUSH_MS", value_name = "MS", default_value_t = DEFAULT_READYZ_MAX_FLUSH_MS)]
+    readyz_max_flush_ms: u64,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replaces --db-name with a backup (a directory or .tar.gz from /admin/backup), rebuilding its indexes
+    /// per the replaced database's configuration, then exits. The server must not be running on it.
+    Restore {
+        backup: PathBuf,
+    },
+}
+
+// In-memory R-trees by geo field; see `load_geo_rtree`.
+type GeoRTrees = Arc<RwLock<HashMap<String, Arc<RwLock<GeoRTree>>>>>;
+
+// The open database, which /admin/restore replaces while requests run.
+#[derive(Clone, Debug)]
+struct DbHandle(Arc<RwLock<Arc<Db>>>);
+
+impl DbHandle {
+    fn new(db: Arc<Db>) -> Self {
+        DbHandle(Arc::new(RwLock::new(db)))
+    }
+
+    fn load(&self) -> Arc<Db> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn swap(&self, db: Arc<Db>) -> Arc<Db> {
+        std::mem::replace(&mut *self.0.write().unwrap(), db)
+    }
+}
+
+// The latest queries over the slow query threshold, oldest first.
+#[derive(Debug)]
+struct SlowQueryLog {
+    threshold: Duration,
+    store: bool,
+    recent: Mutex<VecDeque<logic::SlowQuery>>,
+}
+
+impl SlowQueryLog {
+    // None when --slow-query-ms is unset, which disables the log.
+    fn new(args: &Args) -> Option<Arc<Self>> {
+        args.slow_query_ms.map(|ms| Arc::new(SlowQueryLog {
+            threshold: Duration::from_millis(ms),
+            store: args.slow_query_store,
+            recent: Mutex::new(VecDeque::with_capacity(SLOW_QUERY_LOG_SIZE)),
+        }))
+    }
 }
 
 #[derive(Clone, Debug)]
 struct AppState {
-    db: Arc<Db>,
+    db: DbHandle,
+    db_dir: Arc<std::path::Path>,
     db_config: Arc<Mutex<LogicDbConfig>>,
     api_key: Arc<String>,
+    scoped_keys: Arc<HashMap<String, Principal>>,
+    jwt: Option<Arc<JwtAuth>>,
+    dynamic_indexing: bool,
+    import_chunk_size: usize,
+    geo_rtrees: GeoRTrees,
+    ingest_queue: mpsc::Sender<IngestRequest>,
+    key_rate_limiter: Option<Arc<RateLimiter>>,
+    ip_rate_limiter: Option<Arc<RateLimiter>>,
+    slow_queries: Option<Arc<SlowQueryLog>>,
+    changelog: Option<Arc<Changelog>>,
+    // The primary's URL when this server is a replica.
+    replica_of: Option<Arc<str>>,
+    idempotency: Option<Arc<Idempotency>>,
+    readiness: Readiness,
+}
+
+// When /ingest responds: once the writes are queued, once they are
+// committed, or once they are also flushed to disk.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum IngestAck {
+    Queued,
+    #[default]
+    Committed,
+    Durable,
+}
+
+#[derive(Deserialize, Debug)]
+struct IngestPayload {
+    items: Vec<BatchSetItem>,
+    #[serde(default)]
+    ack: IngestAck,
+}
+
+// One /ingest request waiting for the writer; `reply` is None for queued acks.
+#[derive(Debug)]
+struct IngestRequest {
+    items: Vec<BatchSetItem>,
+    ack: IngestAck,
+    reply: Option<oneshot::Sender<Result<(), logic::DbError>>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -61,10 +307,91 @@ struct KeyPayload {
     key: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct GetManyPayload {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListKeysParams {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+    after: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ExistsResponse {
+    exists: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ListKeysResponse {
+    keys: Vec<String>,
+    // Pass as `after` for the next page; None once the keys are exhausted.
+    next: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct SetPayload {
     key: String,
     value: Value,
+    // Optional `expires_at` or `ttl_seconds`
+    #[serde(flatten)]
+    expiry: Expiry,
+}
+
+#[derive(Deserialize, Debug)]
+struct SetPathPayload {
+    key: String,
+    // Dot-separated path of the field to set, e.g. "address.city"
+    path: String,
+    value: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArrayOpPayload {
+    key: String,
+    path: String,
+    // {"op": "push" | "push_unique" | "pop" | "remove" | "insert", ...}
+    #[serde(flatten)]
+    operation: ArrayOperation,
+}
+
+// Shared by /rename and /copy.
+#[derive(Deserialize, Debug)]
+struct RenamePayload {
+    from: String,
+    to: String,
+    // Replace the document at `to` instead of failing with 409
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct MergePayload {
+    key: String,
+    // JSON Merge Patch (RFC 7386)
+    patch: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatchPayload {
+    key: String,
+    // JSON Patch (RFC 6902) operations, applied in order
+    operations: Vec<PatchOperation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CasPayload {
+    key: String,
+    // Compare the value at this path instead of the whole document
+    #[serde(default)]
+    path: Option<String>,
+    // Omitted or null when the document (or path) must not exist yet
+    #[serde(default)]
+    expected: Option<Value>,
+    value: Value,
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,6 +406,37 @@ struct QueryRadiusPayload {
     lat: f64,
     lon: f64,
     radius: f64,
+    // Only the nearest `limit` documents
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    metric: DistanceMetric,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryNearestPayload {
+    field: String,
+    lat: f64,
+    lon: f64,
+    k: usize,
+    #[serde(default)]
+    metric: DistanceMetric,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryRoutePayload {
+    field: String,
+    // Polyline through these points, in order
+    route: Vec<GeoPoint>,
+    // Meters from the route
+    distance: f64,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeoRTreePayload {
+    field: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -98,26 +456,139 @@ struct QueryAndPayload {
 #[derive(Deserialize, Debug)]
 struct QueryAstPayload {
     ast: logic::QueryNode,
-    projection: Option<Vec<String>>,
-    limit: Option<usize>,
-    offset: Option<usize>,
+    #[serde(flatten)]
+    options: QueryOptions,
+    // Respond with {results, total, offset, limit} instead of a bare array
+    #[serde(default)]
+    with_total: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ImportItem {
-    key: String,
+#[derive(Deserialize, Debug)]
+struct CollectionPayload {
+    name: String,
+}
+
+// A document of a collection, by id within it. /collections/{name}/docs
+// generates the id when it is omitted.
+#[derive(Deserialize, Debug)]
+struct CollectionDocPayload {
+    id: Option<String>,
     value: Value,
+    #[serde(flatten)]
+    expiry: Expiry,
+}
+
+#[derive(Deserialize, Debug)]
+struct CollectionIdPayload {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct CollectionDocResponse {
+    id: String,
 }
 
-type ImportPayload = Vec<ImportItem>;
+type ImportPayload = Vec<BatchSetItem>;
 type BatchSetPayload = Vec<BatchSetItem>;
 type TransactionPayload = Vec<TransactionOperation>;
 
+#[derive(Serialize, Debug)]
+struct TransactionResponse {
+    // One result per operation, in order
+    results: Vec<TransactionOpResult>,
+}
+
+// One step of a /batch request, e.g. {"type": "get", "key": "k"}. Unlike
+// /transaction, each step runs on its own: one that fails neither stops
+// nor undoes the others.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum BatchOperation {
+    Get { key: String },
+    Set {
+        key: String,
+        value: Value,
+        #[serde(flatten)]
+        expiry: Expiry,
+    },
+    Delete { key: String },
+    Query {
+        ast: QueryNode,
+        #[serde(flatten)]
+        options: QueryOptions,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct BatchResult {
+    // The HTTP status the step would have had as a request of its own.
+    status: u16,
+    // The document read by a get, or the documents a query matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchResponse {
+    // One result per operation, in order
+    results: Vec<BatchResult>,
+}
+
 #[derive(Deserialize, Debug)]
 struct ClearPrefixPayload {
     prefix: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct SetTtlPayload {
+    field: String,
+    // Omit or pass null to remove the TTL from the field.
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerifyIndexesPayload {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct SparseIndexPayload {
+    field: String,
+    sparse: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeohashPrecisionPayload {
+    field: String,
+    precision: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct CollationPayload {
+    field: String,
+    // Absent or null resets the field to byte order
+    #[serde(default)]
+    collation: Option<logic::Collation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DateTimeFieldPayload {
+    field: String,
+    datetime: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct CreateIndexPayload {
+    field: String,
+    kind: IndexKind,
+    // Build in the background instead of before responding.
+    #[serde(default)]
+    background: bool,
+}
+
 #[derive(Serialize)]
 struct CountResponse {
     count: usize,
@@ -133,8 +604,10 @@ fn extract_eq_field(query_node: &QueryNode) -> Option<String> {
     }
 }
 
-fn add_field_to_index(db_config: &mut LogicDbConfig, field_path: &str) {
+// Returns the paths that were newly indexed.
+fn add_field_to_index(db_config: &mut LogicDbConfig, field_path: &str) -> Vec<String> {
     let mut current_path = String::new();
+    let mut added = Vec::new();
     for part in field_path.split('.') {
         if !current_path.is_empty() {
             current_path.push('.');
@@ -142,37 +615,137 @@ fn add_field_to_index(db_config: &mut LogicDbConfig, field_path: &str) {
         current_path.push_str(part);
         if db_config.hash_indexed_fields.insert(current_path.clone()) {
             info!("Dynamically indexing field: {}", current_path);
+            added.push(current_path.clone());
         }
     }
+    added
 }
 
-// Corrected middleware signature
+// Authenticates with the API key, which may use every route, a scoped API
+// key, or a bearer JWT when configured, whose role must cover the route.
+// Handlers get the caller as an `Extension<Principal>`.
 async fn api_key_auth(
     State(state): State<AppState>,
-    req: Request<Body>, // Use axum::body::Body
+    mut req: Request<Body>, // Use axum::body::Body
     next: Next, // Remove generic parameter
 ) -> Result<Response, AppError> {
-    let headers = req.headers();
+    let principal = authenticate(&state, req.headers())?;
+    charge_credential(&state, req.headers()).map_err(AppError::RateLimited)?;
+    let path = req.uri().path();
+    let required = required_role(path);
+    if principal.role < required {
+        warn!("Role {:?} may not use {}", principal.role, path);
+        return Err(AppError::Forbidden(format!("{} requires the {:?} role", path, required)));
+    }
+    // Scoped keys can't use routes that span every key: the admin routes and
+    // the queries that return documents without their keys.
+    if principal.prefixes().is_some() && (required == Role::Admin || (path.starts_with("/query/") && path != "/query/ast")) {
+        warn!("Scoped API key may not use {}", path);
+        return Err(AppError::Forbidden(format!("{} is not available to scoped API keys", path)));
+    }
+    if changes_documents(path) && req.method() != Method::GET {
+        check_writable(&state)?;
+    }
+    req.extensions_mut().insert(principal);
+    Ok(next.run(req).await)
+}
+
+// Whether the route may change documents, which a replica only takes from
+// its primary.
+fn changes_documents(path: &str) -> bool {
+    match required_role(path) {
+        Role::Write => true,
+        Role::Admin => matches!(path, "/drop_database" | "/clear_prefix" | "/import" | "/admin/restore")
+            || (path.starts_with("/collections/") && path.ends_with("/drop")),
+        _ => false,
+    }
+}
+
+fn check_writable(state: &AppState) -> Result<(), AppError> {
+    match &state.replica_of {
+        Some(primary) => Err(AppError::Forbidden(format!("this server is a read-only replica of {}", primary))),
+        None => Ok(()),
+    }
+}
+
+fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Principal, AppError> {
     // Use HeaderName::from_static for efficiency
     let api_key_header_name = HeaderName::from_static(API_KEY_HEADER_LOWERCASE);
-
     if let Some(provided_key) = headers.get(&api_key_header_name).and_then(|value| value.to_str().ok()) {
         if provided_key == state.api_key.as_str() {
-            Ok(next.run(req).await) // Pass the original req
-        } else {
-            warn!("Invalid API Key provided");
+            return Ok(Principal::unrestricted(Role::Admin));
+        }
+        if let Some(principal) = state.scoped_keys.get(provided_key) {
+            return Ok(principal.clone());
+        }
+        warn!("Invalid API Key provided");
+        return Err(AppError::Unauthorized);
+    }
+    let bearer = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (bearer, &state.jwt) {
+        (Some(token), Some(jwt)) => jwt.verify(token).map(Principal::unrestricted).map_err(|e| {
+            warn!("Rejected bearer token: {}", e);
+            AppError::Unauthorized
+        }),
+        _ => {
+            warn!("Missing API Key header: {}", API_KEY_HEADER);
             Err(AppError::Unauthorized)
         }
-    } else {
-        warn!("Missing API Key header: {}", API_KEY_HEADER);
-        Err(AppError::Unauthorized)
+    }
+}
+
+// Counts the request against the rate limit of the credential it
+// authenticated with, returning how long to wait if it is over the limit.
+fn charge_credential(state: &AppState, headers: &HeaderMap) -> Result<(), Duration> {
+    let Some(limiter) = &state.key_rate_limiter else { return Ok(()) };
+    limiter.check(credential(headers))
+}
+
+// The API key or Authorization header a request came with.
+fn credential(headers: &HeaderMap) -> &str {
+    headers.get(API_KEY_HEADER_LOWERCASE)
+        .or_else(|| headers.get(header::AUTHORIZATION))
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+// Limits requests by client IP, before they authenticate.
+async fn ip_rate_limit(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    if let Some(limiter) = &state.ip_rate_limiter {
+        limiter.check(&addr.ip().to_string()).map_err(AppError::RateLimited)?;
+    }
+    Ok(next.run(req).await)
+}
+
+// The least role a route needs: reads, writes, or schema, bulk and
+// maintenance operations.
+fn required_role(path: &str) -> Role {
+    match path {
+        "/get" | "/exists" | "/get_partial" | "/get_many" | "/keys" | "/watch" | "/changes" | "/batch" | "/index/builds" => Role::Read,
+        "/drop_database" | "/clear_prefix" | "/export" | "/export/stream" | "/import" | "/collections" => Role::Admin,
+        _ if path.starts_with("/collections/") => match path.rsplit('/').next() {
+            Some("get" | "query") => Role::Read,
+            Some("drop" | "indexes") => Role::Admin,
+            _ => Role::Write,
+        },
+        _ if path.starts_with("/query/") => Role::Read,
+        _ if path.starts_with("/index/") || path.starts_with("/admin/") => Role::Admin,
+        _ => Role::Write,
     }
 }
 
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
             "rust_db_server=info,tower_http=warn".into()
@@ -180,7 +753,36 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let api_key = match args.api_key.or_else(|| env::var("DB_API_KEY").ok()) {
+    let mut config_file = match &args.config {
+        Some(path) => match ConfigFile::load(path) {
+            Ok(config_file) => {
+                info!("Loaded config file {:?}", path);
+                config_file
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => ConfigFile::default(),
+    };
+    config_file.apply(&mut args, &matches);
+    let Some(db_name) = args.db_name.clone() else {
+        error!("No database name given via --db-name, DB_NAME or the config file's db_name");
+        std::process::exit(1);
+    };
+    if let Some(Command::Restore { backup }) = &args.command {
+        match restore::restore_offline(backup, &args.base_path.join(&db_name)) {
+            Ok(report) => info!("Restored {} entries in {} trees into {:?}", report.entries, report.trees, db_name),
+            Err(e) => {
+                error!("Failed to restore {:?}: {}", backup, AppError::from(e));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let api_key = match args.api_key.clone().or_else(|| env::var("DB_API_KEY").ok()) {
         Some(key) => {
             if key.is_empty() {
                  error!("Provided API Key (via --api-key or DB_API_KEY) cannot be empty.");
@@ -210,60 +812,139 @@ async fn main() {
         error!("Failed to create base directory at {:?}: {}", args.base_path, e);
         std::process::exit(1);
     }
+    let mut database_names = vec![db_name.clone()];
+    for name in &args.databases {
+        if !valid_database_name(name) || database_names.contains(name) {
+            error!("Invalid or duplicate database name {:?}; use letters, digits, '_' and '-'", name);
+            std::process::exit(1);
+        }
+        database_names.push(name.clone());
+    }
+    let (db, db_config) = open_database(&args.base_path, &db_name, &config_file.indexes);
 
-    let db_dir = args.base_path.join(&args.db_name);
-    info!("Opening database {:?} at path: {:?} with compression enabled", args.db_name, db_dir);
-    let db_result = Config::default()
-        .path(&db_dir)
-        .use_compression(true)
-        .open();
-
-    let db = match db_result {
-        Ok(db) => Arc::new(db),
+    let mut scoped_key_entries = std::mem::take(&mut config_file.scoped_api_keys);
+    if let Some(path) = &args.scoped_api_keys_file {
+        match acl::read_scoped_keys_file(path) {
+            Ok(entries) => scoped_key_entries.extend(entries),
+            Err(e) => {
+                error!("Invalid scoped API keys: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let scoped_keys = match acl::scoped_keys(scoped_key_entries, &api_key) {
+        Ok(scoped_keys) => {
+            if !scoped_keys.is_empty() {
+                info!("Loaded {} scoped API keys", scoped_keys.len());
+            }
+            scoped_keys
+        }
         Err(e) => {
-            let logic_error = logic::DbError::from(e);
-            let app_error = AppError::from(logic_error);
-            error!("Failed to open database {:?}: {}", db_dir, app_error);
+            error!("Invalid scoped API keys: {}", e);
             std::process::exit(1);
         }
     };
 
-    let db_config = Arc::new(Mutex::new(LogicDbConfig::default()));
-    info!("Using default DbConfig: {:?}", db_config);
+    let jwt_key_source = match (&args.jwt_hs256_secret, &args.jwt_rs256_public_key, &args.jwt_jwks_url) {
+        (Some(secret), _, _) => Some(KeySource::Hs256Secret(secret.clone())),
+        (_, Some(path), _) => Some(KeySource::Rs256PublicKey(path.clone())),
+        (_, _, Some(url)) => Some(KeySource::JwksUrl(url.clone())),
+        _ => None,
+    };
+    let jwt = match jwt_key_source {
+        Some(source) => match JwtAuth::new(source, args.jwt_issuer.clone(), args.jwt_audience.clone(), &args.jwt_roles_claim, &args.jwt_role_map) {
+            Ok(jwt) => {
+                let jwt = Arc::new(jwt);
+                jwt.start_jwks_refresh(Duration::from_secs(args.jwt_jwks_refresh_secs.max(1))).await;
+                info!("Accepting bearer JWTs alongside the API key");
+                Some(jwt)
+            }
+            Err(e) => {
+                error!("Invalid JWT configuration: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
+    let (ingest_queue, ingest_receiver) = mpsc::channel(args.ingest_queue_capacity.max(1));
     let app_state = AppState {
-        db,
+        db: DbHandle::new(db),
+        db_dir: Arc::from(args.base_path.join(&db_name)),
         db_config,
         api_key: Arc::new(api_key),
+        scoped_keys: Arc::new(scoped_keys),
+        jwt,
+        dynamic_indexing: args.dynamic_indexing,
+        import_chunk_size: args.import_chunk_size,
+        geo_rtrees: GeoRTrees::default(),
+        ingest_queue,
+        key_rate_limiter: RateLimiter::new(args.rate_limit_per_key, args.rate_limit_burst),
+        ip_rate_limiter: RateLimiter::new(args.rate_limit_per_ip, args.rate_limit_burst),
+        slow_queries: SlowQueryLog::new(&args),
+        changelog: Changelog::new(args.changelog_size),
+        replica_of: args.replica_of.as_deref().map(Arc::from),
+        idempotency: Idempotency::new(args.idempotency_window_secs),
+        readiness: Readiness {
+            started: Instant::now(),
+            min_free_bytes: args.readyz_min_free_mb.saturating_mul(1024 * 1024),
+            max_flush: Duration::from_millis(args.readyz_max_flush_ms),
+        },
     };
+    if app_state.dynamic_indexing {
+        info!("Dynamic indexing enabled: Eq-queried fields will be hash-indexed on first use");
+    }
+    start_database_tasks(&args, &db_name, &app_state, ingest_receiver);
 
-    let api_routes = Router::new()
-        .route("/set", post(set_handler))
-        .route("/get", post(get_handler))
-        .route("/get_partial", post(get_partial_handler))
-        .route("/delete", post(delete_handler))
-        .route("/batch_set", post(batch_set_handler))
-        .route("/transaction", post(transaction_handler))
-        .route("/clear_prefix", post(clear_prefix_handler))
-        .route("/drop_database", post(drop_database_handler))
-        .route("/query/radius", post(query_radius_handler))
-        .route("/query/box", post(query_box_handler))
-        .route("/query/and", post(query_and_handler))
-        .route("/query/ast", post(query_ast_handler))
-        .route("/export", get(export_handler))
-        .route("/import", post(import_handler))
-        .route_layer(middleware::from_fn_with_state(app_state.clone(), api_key_auth));
+    // The other databases share the default one's keys and rate limits.
+    let mut database_states = HashMap::new();
+    for name in &database_names[1..] {
+        let (db, db_config) = open_database(&args.base_path, name, &config_file.indexes);
+        let (ingest_queue, ingest_receiver) = mpsc::channel(args.ingest_queue_capacity.max(1));
+        let state = AppState {
+            db: DbHandle::new(db),
+            db_dir: Arc::from(args.base_path.join(name)),
+            db_config,
+            geo_rtrees: GeoRTrees::default(), ingest_queue,
+            slow_queries: SlowQueryLog::new(&args),
+            changelog: Changelog::new(args.changelog_size),
+            idempotency: Idempotency::new(args.idempotency_window_secs),
+            ..app_state.clone()
+        };
+        start_database_tasks(&args, name, &state, ingest_receiver);
+        database_states.insert(name.clone(), state);
+    }
+    database_states.insert(db_name.clone(), app_state);
+    let databases = Databases {
+        default: Arc::from(db_name.as_str()),
+        routers: Arc::new(database_states.iter().map(|(name, state)| (name.clone(), database_router(state.clone()))).collect()),
+    };
 
     let app = Router::new()
-        .route("/", get(health_check)) // Health check doesn't need auth
-        .merge(api_routes)
-        .with_state(app_state.clone())
+        .fallback(route_to_database)
+        .with_state(databases)
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(tower_http::trace::DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(tower_http::trace::DefaultOnResponse::new().level(Level::INFO).latency_unit(tower_http::LatencyUnit::Micros)),
         )
-        .layer(CorsLayer::permissive()); // Consider making CORS more restrictive
+        .layer(cors_layer(&args.cors_allowed_origins));
+
+    if let Some(grpc_listen_addr) = &args.grpc_listen_addr {
+        let grpc_addr: SocketAddr = match grpc_listen_addr.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Invalid gRPC listen address {}: {}", grpc_listen_addr, e);
+                std::process::exit(1);
+            }
+        };
+        let default_database = db_name;
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(database_states, default_database, grpc_addr).await {
+                error!("gRPC server stopped: {}", e);
+            }
+        });
+    }
 
     info!("Attempting to bind listener to {}", args.listen_addr);
     let listener = match TcpListener::bind(&args.listen_addr).await {
@@ -278,83 +959,636 @@ async fn main() {
     };
 
     info!("Starting Axum server loop...");
-    if let Err(e) = axum::serve(listener, app.into_make_service()).await {
+    if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
         error!("Server error: {}", e);
         std::process::exit(1);
     }
 }
 
-#[instrument(skip(state), fields(handler="health_check"))]
-async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
-    info!("Entering health_check handler");
-    match state.db.size_on_disk() {
-        Ok(size) => info!(db_size = size, "Health check OK"),
-        Err(e) => error!("Health check failed to get DB size: {}", e),
+// Any origin by default, or only the listed ones.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    if allowed_origins.is_empty() || allowed_origins.iter().any(|origin| origin == "*") {
+        return CorsLayer::permissive();
     }
-    (StatusCode::OK, "Server is running")
+    let origins = allowed_origins.iter()
+        .map(|origin| HeaderValue::from_str(origin).unwrap_or_else(|_| {
+            error!("Invalid CORS origin {:?}", origin);
+            std::process::exit(1);
+        }))
+        .collect::<Vec<_>>();
+    info!("Allowing CORS requests from {:?}", allowed_origins);
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers(Any)
 }
 
-#[instrument(skip(state, payload), fields(handler="set_handler"))]
-async fn set_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<SetPayload>,
-) -> Result<StatusCode, AppError> {
-    let db_config_guard = state.db_config.lock().unwrap();
-    logic::set_key(&state.db, &payload.key, payload.value, &db_config_guard)?;
-    Ok(StatusCode::OK)
+fn valid_database_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
 
-#[instrument(skip(state, payload), fields(handler="get_handler"))]
-async fn get_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<KeyPayload>,
-) -> Result<Json<Value>, AppError> {
-    let value = logic::get_key(&state.db, &payload.key)?;
-    Ok(Json(value))
-}
+// Opens the sled database `name` under the base path along with its DbConfig
+// and builds the declared indexes it lacks, exiting if any of it fails.
+fn open_database(base_path: &std::path::Path, name: &str, indexes: &IndexDeclarations) -> (Arc<Db>, Arc<Mutex<LogicDbConfig>>) {
+    let db_dir = base_path.join(name);
+    info!("Opening database {:?} at path: {:?} with compression enabled", name, db_dir);
+    let db_result = Config::default()
+        .path(&db_dir)
+        .use_compression(true)
+        .open();
 
-#[instrument(skip(state, payload), fields(handler="get_partial_handler"))]
-async fn get_partial_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<GetPartialPayload>,
-) -> Result<Json<Value>, AppError> {
-    let value = logic::get_partial_key(&state.db, &payload.key, &payload.fields)?;
-    Ok(Json(value))
-}
+    let db = match db_result {
+        Ok(db) => Arc::new(db),
+        Err(e) => {
+            let logic_error = logic::DbError::from(e);
+            let app_error = AppError::from(logic_error);
+            error!("Failed to open database {:?}: {}", db_dir, app_error);
+            std::process::exit(1);
+        }
+    };
 
-#[instrument(skip(state, payload), fields(handler="delete_handler"))]
-async fn delete_handler(
-    State(state): State<AppState>,
+    let mut config = match logic::load_config(&db) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load DbConfig from {:?}: {}", db_dir, AppError::from(e));
+            std::process::exit(1);
+        }
+    };
+    for (field, kind) in indexes.iter() {
+        if config.has_index(field, kind) {
+            continue;
+        }
+        match logic::create_index(&db, field, kind, &mut config) {
+            Ok(count) => info!("Created declared {:?} index on {} over {} documents", kind, field, count),
+            Err(e) => {
+                error!("Failed to create declared {:?} index on {}: {}", kind, field, AppError::from(e));
+                std::process::exit(1);
+            }
+        }
+    }
+    let db_config = Arc::new(Mutex::new(config));
+    info!("Using DbConfig: {:?}", db_config);
+    (db, db_config)
+}
+
+// Sets up a database's changelog, starts its TTL sweeper, ingest writer and
+// index GC, resumes its interrupted index builds and loads its geo R-trees.
+fn start_database_tasks(args: &Args, name: &str, state: &AppState, ingest_receiver: mpsc::Receiver<IngestRequest>) {
+    if let Err(e) = replication::log_changes(state, &state.db.load()) {
+        error!("Failed to set up the changelog of {}: {}", name, AppError::from(e));
+        std::process::exit(1);
+    }
+    if state.changelog.is_some() {
+        webhooks::spawn_webhooks(state);
+    }
+    if let Some(primary) = &args.replica_of {
+        replication::spawn_follower(state.clone(), primary, args.replica_api_key.clone(), name);
+    }
+    spawn_ttl_expiry(state.clone(), Duration::from_secs(args.ttl_interval_secs.max(1)));
+    if state.idempotency.is_some() {
+        idempotency::spawn_expiry(state.clone());
+    }
+    spawn_ingest_writer(state.clone(), ingest_receiver, args.ingest_batch_size.max(1), Duration::from_millis(args.ingest_max_delay_ms));
+    if args.index_gc_interval_secs > 0 {
+        spawn_index_gc(state.clone(), Duration::from_secs(args.index_gc_interval_secs));
+    }
+    match logic::index_builds(&state.db.load()) {
+        Ok(builds) => {
+            for build in builds.into_iter().filter(|b| b.state == IndexBuildState::Building) {
+                info!("Resuming {:?} index build on {} after {} documents", build.kind, build.field, build.processed);
+                spawn_index_build(state.clone(), build.field, build.kind);
+            }
+        }
+        Err(e) => error!("Failed to load index builds: {}", e),
+    }
+    for field in &args.geo_rtree_fields {
+        match load_geo_rtree(&state.db.load(), &state.geo_rtrees, field) {
+            Ok(count) => info!("Loaded geo R-tree for {} with {} documents", field, count),
+            Err(e) => error!("Failed to load geo R-tree for {}: {}", field, e),
+        }
+    }
+}
+
+// The routes of one database.
+fn database_router(state: AppState) -> Router {
+    let api_routes = Router::new()
+        .route("/set", post(set_handler))
+        .route("/set_nx", post(set_nx_handler))
+        .route("/cas", post(cas_handler))
+        .route("/set_path", post(set_path_handler))
+        .route("/array", post(array_op_handler))
+        .route("/merge", post(merge_handler))
+        .route("/patch", post(patch_handler))
+        .route("/get", post(get_handler))
+        .route("/exists", post(exists_handler))
+        .route("/watch", get(watch_handler))
+        .route("/changes", get(replication::changes_handler))
+        .route("/get_partial", post(get_partial_handler))
+        .route("/get_many", post(get_many_handler))
+        .route("/keys", get(list_keys_handler))
+        .route("/delete", post(delete_handler))
+        .route("/rename", post(rename_handler))
+        .route("/copy", post(copy_handler))
+        .route("/batch_set", post(batch_set_handler))
+        .route("/ingest", post(ingest_handler))
+        .route("/transaction", post(transaction_handler))
+        .route("/batch", post(batch_handler))
+        .route("/clear_prefix", post(clear_prefix_handler))
+        .route("/drop_database", post(drop_database_handler))
+        .route("/query/radius", post(query_radius_handler))
+        .route("/query/box", post(query_box_handler))
+        .route("/query/nearest", post(query_nearest_handler))
+        .route("/query/route", post(query_route_handler))
+        .route("/query/and", post(query_and_handler))
+        .route("/query/ast", post(query_ast_handler))
+        .route("/index/create", post(create_index_handler))
+        .route("/index/builds", get(index_builds_handler))
+        .route("/index/ttl", post(set_ttl_handler))
+        .route("/index/geo_precision", post(set_geohash_precision_handler))
+        .route("/index/sparse", post(set_index_sparse_handler))
+        .route("/index/collation", post(set_collation_handler))
+        .route("/index/datetime", post(set_datetime_field_handler))
+        .route("/index/rtree", post(load_geo_rtree_handler))
+        .route("/admin/config", get(get_config_handler).put(put_config_handler))
+        .route("/admin/indexes/stats", get(index_stats_handler))
+        .route("/admin/slow_queries", get(slow_queries_handler))
+        .route("/admin/webhooks", get(webhooks::list_webhooks_handler).post(webhooks::create_webhook_handler))
+        .route("/admin/webhooks/:id", delete(webhooks::delete_webhook_handler))
+        .route("/admin/webhooks/:id/dead_letters", get(webhooks::dead_letters_handler).delete(webhooks::clear_dead_letters_handler))
+        .route("/admin/flush", post(flush_handler))
+        .route("/admin/compact", post(compact_handler))
+        .route("/admin/verify", post(verify_indexes_handler))
+        .route("/admin/gc", post(gc_indexes_handler))
+        .route("/admin/backup", post(backup_handler))
+        .route("/admin/restore", post(restore_handler))
+        .route("/collections", post(create_collection_handler).get(list_collections_handler))
+        .route("/collections/:name/docs", post(insert_collection_doc_handler))
+        .route("/collections/:name/get", post(get_collection_doc_handler))
+        .route("/collections/:name/set", post(set_collection_doc_handler))
+        .route("/collections/:name/delete", post(delete_collection_doc_handler))
+        .route("/collections/:name/query", post(query_collection_handler))
+        .route("/collections/:name/indexes", post(create_collection_index_handler))
+        .route("/collections/:name/drop", post(drop_collection_handler))
+        .route("/export", get(export_handler))
+        .route("/export/stream", get(export_stream_handler))
+        .route("/import", post(import_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotency::idempotency_keys))
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_key_auth));
+
+    Router::new()
+        // Probes don't need auth
+        .route("/", get(health::healthz_handler))
+        .route("/healthz", get(health::healthz_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .merge(api_routes)
+        .layer(middleware::from_fn_with_state(state.clone(), ip_rate_limit))
+        .with_state(state)
+}
+
+// The routers of the open databases by name, and the one used when a request
+// names none.
+#[derive(Clone)]
+struct Databases {
+    default: Arc<str>,
+    routers: Arc<HashMap<String, Router>>,
+}
+
+// Hands the request to its database's router: the one named by a
+// /db/{name}/... path prefix (which is stripped), else by the X-Database
+// header, else the default.
+async fn route_to_database(State(databases): State<Databases>, mut req: Request<Body>) -> Response {
+    let name = if let Some(rest) = req.uri().path().strip_prefix("/db/") {
+        let (name, path) = match rest.split_once('/') {
+            Some((name, path)) => (name.to_string(), format!("/{}", path)),
+            None => (rest.to_string(), "/".to_string()),
+        };
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        match path_and_query.parse() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => return AppError::Logic(logic::DbError::InvalidPath(path_and_query)).into_response(),
+        }
+        name
+    } else {
+        match req.headers().get(DATABASE_HEADER).map(|value| value.to_str()) {
+            Some(Ok(name)) => name.to_string(),
+            Some(Err(_)) => return AppError::DatabaseNotFound("<invalid header>".to_string()).into_response(),
+            None => databases.default.to_string(),
+        }
+    };
+    match databases.routers.get(&name) {
+        Some(router) => match router.clone().oneshot(req).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        },
+        None => AppError::DatabaseNotFound(name).into_response(),
+    }
+}
+
+#[instrument(skip(state, payload), fields(handler="set_handler"))]
+async fn set_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
+    Json(payload): Json<SetPayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_key(&payload.key)?;
+    let value = payload.expiry.apply(&payload.value, logic::unix_now_secs())?.into_owned();
+    let db_config_guard = state.db_config.lock().unwrap();
+    match if_match_header(&headers)? {
+        Some(if_match) => logic::set_key_if_match(&state.db.load(), &payload.key, value, if_match, &db_config_guard)?,
+        None => logic::set_key(&state.db.load(), &payload.key, value, &db_config_guard)?,
+    }
+    Ok(StatusCode::OK)
+}
+
+// The If-Match request header, for optimistic locking against the ETag returned by /get.
+fn if_match_header(headers: &HeaderMap) -> Result<Option<&str>, AppError> {
+    headers.get(header::IF_MATCH)
+        .map(|value| value.to_str().map_err(|_| logic::DbError::MissingData("Invalid If-Match header".to_string()).into()))
+        .transpose()
+}
+
+#[instrument(skip(state, payload), fields(handler="set_nx_handler"))]
+async fn set_nx_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<SetPayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_key(&payload.key)?;
+    let value = payload.expiry.apply(&payload.value, logic::unix_now_secs())?.into_owned();
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::set_nx(&state.db.load(), &payload.key, value, &db_config_guard)?;
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument(skip(state, payload), fields(handler="set_path_handler"))]
+async fn set_path_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<SetPathPayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_key(&payload.key)?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::set_path(&state.db.load(), &payload.key, &payload.path, payload.value, &db_config_guard)?;
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip(state, payload), fields(handler="array_op_handler"))]
+async fn array_op_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<ArrayOpPayload>,
+) -> Result<Json<Value>, AppError> {
+    principal.check_key(&payload.key)?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    let result = logic::array_op(&state.db.load(), &payload.key, &payload.path, &payload.operation, &db_config_guard)?;
+    Ok(Json(result))
+}
+
+#[instrument(skip(state, payload), fields(handler="merge_handler"))]
+async fn merge_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<MergePayload>,
+) -> Result<Json<Value>, AppError> {
+    principal.check_key(&payload.key)?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    let merged = logic::merge_key(&state.db.load(), &payload.key, &payload.patch, &db_config_guard)?;
+    Ok(Json(merged))
+}
+
+#[instrument(skip(state, payload), fields(handler="patch_handler"))]
+async fn patch_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<PatchPayload>,
+) -> Result<Json<Value>, AppError> {
+    principal.check_key(&payload.key)?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    let patched = logic::patch_key(&state.db.load(), &payload.key, &payload.operations, &db_config_guard)?;
+    Ok(Json(patched))
+}
+
+#[instrument(skip(state, payload), fields(handler="cas_handler"))]
+async fn cas_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<CasPayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_key(&payload.key)?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::compare_and_set_at(&state.db.load(), &payload.key, payload.path.as_deref(), payload.expected, payload.value, &db_config_guard)?;
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip(state, payload), fields(handler="get_handler"))]
+async fn get_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
+    Json(payload): Json<KeyPayload>,
+) -> Result<Response, AppError> {
+    principal.check_key(&payload.key)?;
+    let db = state.db.load();
+    let value = logic::get_key(&db, &payload.key)?;
+    let modified_at = logic::modified_at(&db, &payload.key)?;
+    Ok(document_response(&headers, value, modified_at))
+}
+
+// A read document with its caching headers: the ETag and, when the write
+// time is known (see `logic::modified_at`), Last-Modified. Answers 304
+// without the document when the If-None-Match request header lists its ETag.
+fn document_response(headers: &HeaderMap, value: Value, modified_at: Option<u64>) -> Response {
+    let etag = logic::document_etag(&value);
+    let not_modified = headers.get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|if_none_match| logic::etag_listed(if_none_match, &etag));
+    let mut caching = vec![(header::ETAG, etag)];
+    if let Some(modified_at) = modified_at {
+        caching.push((header::LAST_MODIFIED, httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_millis(modified_at))));
+    }
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, AppendHeaders(caching)).into_response()
+    } else {
+        (AppendHeaders(caching), Json(value)).into_response()
+    }
+}
+
+#[instrument(skip(state, payload), fields(handler="exists_handler"))]
+async fn exists_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<KeyPayload>,
+) -> Result<Json<ExistsResponse>, AppError> {
+    principal.check_key(&payload.key)?;
+    let exists = logic::key_exists(&state.db.load(), &payload.key)?;
+    Ok(Json(ExistsResponse { exists }))
+}
+
+#[instrument(skip(state, payload), fields(handler="get_partial_handler"))]
+async fn get_partial_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<GetPartialPayload>,
+) -> Result<Json<Value>, AppError> {
+    principal.check_key(&payload.key)?;
+    let value = logic::get_partial_key(&state.db.load(), &payload.key, &payload.fields)?;
+    Ok(Json(value))
+}
+
+// Lists key names page by page. Scoped API keys must ask for a prefix within
+// theirs.
+#[instrument(skip(state, principal), fields(handler="list_keys_handler"))]
+async fn list_keys_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(params): Query<ListKeysParams>,
+) -> Result<Json<ListKeysResponse>, AppError> {
+    principal.check_key(&params.prefix)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_KEYS_LIMIT).clamp(1, MAX_LIST_KEYS_LIMIT);
+    let keys = logic::list_keys(&state.db.load(), &params.prefix, params.after.as_deref(), limit)?;
+    let next = if keys.len() == limit { keys.last().cloned() } else { None };
+    Ok(Json(ListKeysResponse { keys, next }))
+}
+
+#[instrument(skip(state, payload), fields(handler="get_many_handler"))]
+async fn get_many_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<GetManyPayload>,
+) -> Result<Json<logic::GetManyResult>, AppError> {
+    principal.check_keys(payload.keys.iter().map(String::as_str))?;
+    let result = logic::get_many(&state.db.load(), &payload.keys)?;
+    Ok(Json(result))
+}
+
+#[instrument(skip(state, payload), fields(handler="delete_handler"))]
+async fn delete_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
     Json(payload): Json<KeyPayload>,
 ) -> Result<StatusCode, AppError> {
+    principal.check_key(&payload.key)?;
     let config_clone = {
         let guard = state.db_config.lock().unwrap();
         let config_clone = guard.clone();
         drop(guard);
         config_clone
     };
-    logic::delete_key(&state.db, &payload.key, &config_clone).await?;
+    match if_match_header(&headers)? {
+        Some(if_match) => logic::delete_key_if_match(&state.db.load(), &payload.key, if_match, &config_clone).await?,
+        None => logic::delete_key(&state.db.load(), &payload.key, &config_clone).await?,
+    }
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip(state, payload), fields(handler="rename_handler"))]
+async fn rename_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<RenamePayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_keys([payload.from.as_str(), payload.to.as_str()])?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::rename_key(&state.db.load(), &payload.from, &payload.to, payload.overwrite, &db_config_guard)?;
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip(state, payload), fields(handler="copy_handler"))]
+async fn copy_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<RenamePayload>,
+) -> Result<StatusCode, AppError> {
+    principal.check_keys([payload.from.as_str(), payload.to.as_str()])?;
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::copy_key(&state.db.load(), &payload.from, &payload.to, payload.overwrite, &db_config_guard)?;
     Ok(StatusCode::OK)
 }
 
 #[instrument(skip(state, payload), fields(handler="batch_set_handler"))]
 async fn batch_set_handler(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<BatchSetPayload>,
 ) -> Result<StatusCode, AppError> {
+    principal.check_keys(payload.iter().map(|item| item.key.as_str()))?;
     let db_config_guard = state.db_config.lock().unwrap();
-    logic::batch_set(&state.db, &payload, &db_config_guard)?;
+    logic::batch_set(&state.db.load(), &payload, &db_config_guard)?;
     Ok(StatusCode::OK)
 }
 
+// Queues the writes for the ingest writer, which commits them grouped with
+// other /ingest requests. Waits for room when the queue is full.
+#[instrument(skip(state, payload), fields(handler="ingest_handler"))]
+async fn ingest_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<IngestPayload>,
+) -> Result<(StatusCode, Json<CountResponse>), AppError> {
+    principal.check_keys(payload.items.iter().map(|item| item.key.as_str()))?;
+    let count = payload.items.len();
+    let (reply, response) = match payload.ack {
+        IngestAck::Queued => (None, None),
+        _ => {
+            let (reply, response) = oneshot::channel();
+            (Some(reply), Some(response))
+        }
+    };
+    let request = IngestRequest { items: payload.items, ack: payload.ack, reply };
+    let closed = || logic::DbError::Transaction("Ingest writer is not running".to_string());
+    state.ingest_queue.send(request).await.map_err(|_| closed())?;
+    match response {
+        None => Ok((StatusCode::ACCEPTED, Json(CountResponse { count }))),
+        Some(response) => {
+            response.await.map_err(|_| closed())??;
+            Ok((StatusCode::OK, Json(CountResponse { count })))
+        }
+    }
+}
+
+// Commits queued /ingest requests in groups of up to `batch_size` documents,
+// waiting at most `max_delay` after the first request of a group for others.
+fn spawn_ingest_writer(state: AppState, mut queue: mpsc::Receiver<IngestRequest>, batch_size: usize, max_delay: Duration) {
+    tokio::spawn(async move {
+        while let Some(first) = queue.recv().await {
+            let deadline = tokio::time::Instant::now() + max_delay;
+            let mut size = first.items.len();
+            let mut requests = vec![first];
+            while size < batch_size {
+                match tokio::time::timeout_at(deadline, queue.recv()).await {
+                    Ok(Some(request)) => {
+                        size += request.items.len();
+                        requests.push(request);
+                    }
+                    _ => break,
+                }
+            }
+            commit_ingest_group(&state, requests).await;
+        }
+    });
+}
+
+async fn commit_ingest_group(state: &AppState, requests: Vec<IngestRequest>) {
+    let (batches, waiters): (Vec<_>, Vec<_>) = requests.into_iter().map(|request| (request.items, (request.ack, request.reply))).unzip();
+    let db = Arc::clone(&state.db.load());
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let mut results = match tokio::task::spawn_blocking(move || write_ingest_group(&db, batches, &config_clone)).await {
+        Ok(results) => results,
+        Err(e) => {
+            error!("Ingest writer task panicked: {}", e);
+            waiters.iter().map(|_| Err(logic::DbError::Transaction(format!("Ingest writer failed: {}", e)))).collect()
+        }
+    };
+    if waiters.iter().any(|(ack, _)| *ack == IngestAck::Durable) {
+        if let Err(e) = state.db.load().flush_async().await {
+            error!("Flushing ingested writes failed: {}", e);
+            for (result, (ack, _)) in results.iter_mut().zip(&waiters) {
+                if result.is_ok() && *ack == IngestAck::Durable {
+                    *result = Err(e.clone().into());
+                }
+            }
+        }
+    }
+    for (result, (_, reply)) in results.into_iter().zip(waiters) {
+        match reply {
+            Some(reply) => { let _ = reply.send(result); }
+            None => if let Err(e) = result { warn!("Queued ingest write failed: {}", e) },
+        }
+    }
+}
+
+// Writes the batches in one transaction. If that fails, each batch is
+// retried on its own so one bad request doesn't fail those grouped with it.
+fn write_ingest_group(db: &Db, batches: Vec<Vec<BatchSetItem>>, config: &LogicDbConfig) -> Vec<Result<(), logic::DbError>> {
+    let lengths: Vec<usize> = batches.iter().map(Vec::len).collect();
+    let items: Vec<BatchSetItem> = batches.into_iter().flatten().collect();
+    if lengths.len() > 1 && logic::batch_set(db, &items, config).is_ok() {
+        return lengths.iter().map(|_| Ok(())).collect();
+    }
+    let mut start = 0;
+    lengths.iter().map(|len| {
+        let result = logic::batch_set(db, &items[start..start + len], config);
+        start += len;
+        result
+    }).collect()
+}
+
 #[instrument(skip(state, payload), fields(handler="transaction_handler"))]
 async fn transaction_handler(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
     Json(payload): Json<TransactionPayload>,
-) -> Result<StatusCode, AppError> {
+) -> Result<Json<TransactionResponse>, AppError> {
+    principal.check_keys(payload.iter().map(TransactionOperation::key))?;
     let db_config_guard = state.db_config.lock().unwrap();
-    logic::execute_transaction(&state.db, &payload, &db_config_guard)?;
-    Ok(StatusCode::OK)
+    let results = logic::execute_transaction(&state.db.load(), &payload, &db_config_guard)?;
+    Ok(Json(TransactionResponse { results }))
+}
+
+// Runs the operations in order. The request needs only the read role; its
+// set and delete steps fail with 403 unless the caller may write.
+#[instrument(skip(state, payload), fields(handler="batch_handler"))]
+async fn batch_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(payload): Json<Vec<BatchOperation>>,
+) -> Result<Json<BatchResponse>, AppError> {
+    if payload.len() > MAX_BATCH_OPERATIONS {
+        return Err(logic::DbError::MissingData(format!("A batch holds at most {} operations", MAX_BATCH_OPERATIONS)).into());
+    }
+    let mut results = Vec::with_capacity(payload.len());
+    for operation in payload {
+        results.push(match run_batch_operation(&state, &principal, operation).await {
+            Ok(value) => BatchResult { status: StatusCode::OK.as_u16(), value, error: None },
+            Err(e) => {
+                let (status, error) = e.status();
+                BatchResult { status: status.as_u16(), value: None, error: Some(error) }
+            }
+        });
+    }
+    Ok(Json(BatchResponse { results }))
+}
+
+async fn run_batch_operation(state: &AppState, principal: &Principal, operation: BatchOperation) -> Result<Option<Value>, AppError> {
+    match operation {
+        BatchOperation::Get { key } => {
+            principal.check_key(&key)?;
+            Ok(Some(logic::get_key(&state.db.load(), &key)?))
+        }
+        BatchOperation::Set { key, value, expiry } => {
+            check_batch_write(state, principal, &key)?;
+            let value = expiry.apply(&value, logic::unix_now_secs())?.into_owned();
+            let db_config_guard = state.db_config.lock().unwrap();
+            logic::set_key(&state.db.load(), &key, value, &db_config_guard)?;
+            Ok(None)
+        }
+        BatchOperation::Delete { key } => {
+            check_batch_write(state, principal, &key)?;
+            let config_clone = state.db_config.lock().unwrap().clone();
+            logic::delete_key(&state.db.load(), &key, &config_clone).await?;
+            Ok(None)
+        }
+        BatchOperation::Query { ast, mut options } => {
+            options.key_prefixes = principal.prefixes().map(<[String]>::to_vec);
+            let config_clone = state.db_config.lock().unwrap().clone();
+            let page = run_ast_query(state, ast, &options, &config_clone)?;
+            Ok(Some(Value::Array(page.results)))
+        }
+    }
+}
+
+fn check_batch_write(state: &AppState, principal: &Principal, key: &str) -> Result<(), AppError> {
+    if principal.role < Role::Write {
+        return Err(AppError::Forbidden(format!("writes require the {:?} role", Role::Write)));
+    }
+    check_writable(state)?;
+    principal.check_key(key)
 }
 
 #[instrument(skip(state, payload), fields(handler="clear_prefix_handler"))]
@@ -363,7 +1597,7 @@ async fn clear_prefix_handler(
     Json(payload): Json<ClearPrefixPayload>,
 ) -> Result<Json<CountResponse>, AppError> {
     let db_config_guard = state.db_config.lock().unwrap();
-    let count = logic::clear_prefix(&state.db, &payload.prefix, &db_config_guard)?;
+    let count = logic::clear_prefix(&state.db.load(), &payload.prefix, &db_config_guard)?;
     Ok(Json(CountResponse { count }))
 }
 
@@ -372,7 +1606,7 @@ async fn drop_database_handler(
     State(state): State<AppState>,
 ) -> Result<Json<CountResponse>, AppError> {
     let db_config_guard = state.db_config.lock().unwrap();
-    let count = logic::drop_database(&state.db, &db_config_guard)?;
+    let count = logic::drop_database(&state.db.load(), &db_config_guard)?;
     Ok(Json(CountResponse { count }))
 }
 
@@ -381,7 +1615,38 @@ async fn query_radius_handler(
     State(state): State<AppState>,
     Json(payload): Json<QueryRadiusPayload>,
 ) -> Result<Json<Vec<Value>>, AppError> {
-    let results = logic::query_within_radius_simplified(&state.db, &payload.field, payload.lat, payload.lon, payload.radius)?;
+    let center = GeoPoint { lat: payload.lat, lon: payload.lon };
+    let rtree = state.geo_rtrees.read().unwrap().get(&payload.field).cloned();
+    if let Some(rtree) = rtree {
+        let rtree_guard = rtree.read().unwrap();
+        let results = logic::query_within_radius_rtree(&state.db.load(), &rtree_guard, center, payload.radius, payload.limit, payload.metric)?;
+        return Ok(Json(results));
+    }
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let results = logic::query_within_radius_simplified(&state.db.load(), &payload.field, center, payload.radius, payload.limit, payload.metric, &config_clone)?;
+    Ok(Json(results))
+}
+
+#[instrument(skip(state, payload), fields(handler="query_nearest_handler"))]
+async fn query_nearest_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<QueryNearestPayload>,
+) -> Result<Json<Vec<Value>>, AppError> {
+    let rtree = state.geo_rtrees.read().unwrap().get(&payload.field).cloned()
+        .ok_or_else(|| logic::DbError::MissingData(format!("No geo R-tree loaded for field '{}'; load it via /index/rtree", payload.field)))?;
+    let rtree_guard = rtree.read().unwrap();
+    let center = GeoPoint { lat: payload.lat, lon: payload.lon };
+    let results = logic::query_nearest(&state.db.load(), &rtree_guard, center, payload.k, payload.metric)?;
+    Ok(Json(results))
+}
+
+#[instrument(skip(state, payload), fields(handler="query_route_handler"))]
+async fn query_route_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<QueryRoutePayload>,
+) -> Result<Json<Vec<Value>>, AppError> {
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let results = logic::query_near_route(&state.db.load(), &payload.field, &payload.route, payload.distance, payload.limit, &config_clone)?;
     Ok(Json(results))
 }
 
@@ -390,7 +1655,8 @@ async fn query_box_handler(
     State(state): State<AppState>,
     Json(payload): Json<QueryBoxPayload>,
 ) -> Result<Json<Vec<Value>>, AppError> {
-    let results = logic::query_in_box(&state.db, &payload.field, payload.min_lat, payload.min_lon, payload.max_lat, payload.max_lon)?;
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let results = logic::query_in_box(&state.db.load(), &payload.field, payload.min_lat, payload.min_lon, payload.max_lat, payload.max_lon, &config_clone)?;
     Ok(Json(results))
 }
 
@@ -402,47 +1668,756 @@ async fn query_and_handler(
     let conditions: Vec<(&str, &str, &str)> = payload.conditions.iter()
         .map(|(field, op, value)| (field.as_str(), op.as_str(), value.as_str()))
         .collect();
-    let results = logic::query_and(&state.db, conditions)?;
+    let results = logic::query_and(&state.db.load(), conditions)?;
     Ok(Json(results))
 }
 
 #[instrument(skip(state, payload), fields(handler="query_ast_handler"))]
 async fn query_ast_handler(
     State(state): State<AppState>,
-    Json(payload): Json<QueryAstPayload>,
-) -> Result<Json<Vec<Value>>, AppError> {
-    let field_to_index = &payload.ast;
-    let field_option = extract_eq_field(field_to_index);
+    Extension(principal): Extension<Principal>,
+    Json(mut payload): Json<QueryAstPayload>,
+) -> Result<Response, AppError> {
+    let field_option = if state.dynamic_indexing { extract_eq_field(&payload.ast) } else { None };
 
     let config_clone = {
         let mut db_config_guard = state.db_config.lock().unwrap();
         if let Some(field) = field_option {
-            add_field_to_index(&mut db_config_guard, &field);
+            let added = add_field_to_index(&mut db_config_guard, &field);
+            if !added.is_empty() {
+                logic::save_config(&state.db.load(), &db_config_guard)?;
+                for path in &added {
+                    let scanned = tokio::task::block_in_place(|| logic::rebuild_index(&state.db.load(), path, IndexKind::Hash, &db_config_guard))?;
+                    info!("Backfilled index for {} over {} documents", path, scanned);
+                }
+            }
         }
         let config_clone = db_config_guard.clone();
         drop(db_config_guard);
         config_clone
     };
 
-    let results = logic::execute_ast_query(&state.db, payload.ast, payload.projection, payload.limit, payload.offset, &config_clone)?;
-    Ok(Json(results))
+    payload.options.key_prefixes = principal.prefixes().map(<[String]>::to_vec);
+    let page = run_ast_query(&state, payload.ast, &payload.options, &config_clone)?;
+    if payload.with_total {
+        return Ok(Json(page).into_response());
+    }
+    Ok(Json(page.results).into_response())
+}
+
+// Runs an AST query, logging it with its plan when it is slower than
+// --slow-query-ms.
+fn run_ast_query(state: &AppState, ast: QueryNode, options: &QueryOptions, config: &LogicDbConfig) -> Result<logic::QueryPage, logic::DbError> {
+    let Some(log) = &state.slow_queries else {
+        return logic::execute_ast_query_page(&state.db.load(), ast, options, config);
+    };
+    let started = Instant::now();
+    let (page, profile) = logic::execute_ast_query_profiled(&state.db.load(), ast.clone(), options, config)?;
+    let elapsed = started.elapsed();
+    if elapsed >= log.threshold {
+        let slow_query = logic::SlowQuery {
+            at_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            query: ast,
+            total: page.total,
+            profile,
+        };
+        warn!(
+            "Slow query took {:.1}ms: {} (plan: {}; {} keys scanned, {} documents fetched)",
+            slow_query.duration_ms,
+            serde_json::to_string(&slow_query.query).unwrap_or_default(),
+            slow_query.profile.plan.join(", "),
+            slow_query.profile.keys_scanned,
+            slow_query.profile.documents_fetched,
+        );
+        if log.store {
+            if let Err(e) = logic::record_slow_query(&state.db.load(), &slow_query, SLOW_QUERY_LOG_SIZE) {
+                error!("Failed to store slow query: {}", e);
+            }
+        }
+        let mut recent = log.recent.lock().unwrap();
+        if recent.len() == SLOW_QUERY_LOG_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(slow_query);
+    }
+    Ok(page)
+}
+
+// The slow queries, newest first: those stored in the database with
+// --slow-query-store, or else the ones logged since startup.
+#[instrument(skip(state), fields(handler="slow_queries_handler"))]
+async fn slow_queries_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<logic::SlowQuery>>, AppError> {
+    match &state.slow_queries {
+        Some(log) if log.store => Ok(Json(logic::slow_queries(&state.db.load())?)),
+        Some(log) => Ok(Json(log.recent.lock().unwrap().iter().rev().cloned().collect())),
+        None => Ok(Json(Vec::new())),
+    }
+}
+
+#[instrument(skip(state, payload), fields(handler="create_collection_handler"))]
+async fn create_collection_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CollectionPayload>,
+) -> Result<StatusCode, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    logic::create_collection(&state.db.load(), &payload.name, &mut db_config_guard)?;
+    info!("Created collection {}", payload.name);
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument(skip(state), fields(handler="list_collections_handler"))]
+async fn list_collections_handler(
+    State(state): State<AppState>,
+) -> Json<Vec<String>> {
+    Json(state.db_config.lock().unwrap().collections.iter().cloned().collect())
+}
+
+#[instrument(skip(state), fields(handler="drop_collection_handler"))]
+async fn drop_collection_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = logic::drop_collection(&state.db.load(), &name, &mut db_config_guard)?;
+    info!("Dropped collection {} with {} documents", name, count);
+    Ok(Json(CountResponse { count }))
+}
+
+// The primary key of a collection's document, checked against the caller's prefixes.
+fn collection_doc_key(state: &AppState, principal: &Principal, name: &str, id: &str) -> Result<String, AppError> {
+    logic::require_collection(name, &state.db_config.lock().unwrap())?;
+    let key = logic::collection_key(name, id)?;
+    principal.check_key(&key)?;
+    Ok(key)
+}
+
+// Adds a document, under a generated id unless one is given; fails if the id is taken.
+#[instrument(skip(state, payload), fields(handler="insert_collection_doc_handler"))]
+async fn insert_collection_doc_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(name): Path<String>,
+    Json(payload): Json<CollectionDocPayload>,
+) -> Result<(StatusCode, Json<CollectionDocResponse>), AppError> {
+    let id = payload.id.unwrap_or_else(|| rand::thread_rng().sample_iter(&Alphanumeric).take(20).map(char::from).collect());
+    let key = collection_doc_key(&state, &principal, &name, &id)?;
+    let value = payload.expiry.apply(&payload.value, logic::unix_now_secs())?.into_owned();
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::set_nx(&state.db.load(), &key, value, &db_config_guard)?;
+    Ok((StatusCode::CREATED, Json(CollectionDocResponse { id })))
+}
+
+#[instrument(skip(state, payload), fields(handler="get_collection_doc_handler"))]
+async fn get_collection_doc_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CollectionIdPayload>,
+) -> Result<Response, AppError> {
+    let key = collection_doc_key(&state, &principal, &name, &payload.id)?;
+    let db = state.db.load();
+    let value = logic::get_key(&db, &key)?;
+    let modified_at = logic::modified_at(&db, &key)?;
+    Ok(document_response(&headers, value, modified_at))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_collection_doc_handler"))]
+async fn set_collection_doc_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(name): Path<String>,
+    Json(payload): Json<CollectionDocPayload>,
+) -> Result<StatusCode, AppError> {
+    let id = payload.id.ok_or_else(|| logic::DbError::MissingData("id".to_string()))?;
+    let key = collection_doc_key(&state, &principal, &name, &id)?;
+    let value = payload.expiry.apply(&payload.value, logic::unix_now_secs())?.into_owned();
+    let db_config_guard = state.db_config.lock().unwrap();
+    logic::set_key(&state.db.load(), &key, value, &db_config_guard)?;
+    Ok(StatusCode::OK)
+}
+
+#[instrument(skip(state, payload), fields(handler="delete_collection_doc_handler"))]
+async fn delete_collection_doc_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(name): Path<String>,
+    Json(payload): Json<CollectionIdPayload>,
+) -> Result<StatusCode, AppError> {
+    let key = collection_doc_key(&state, &principal, &name, &payload.id)?;
+    let config_clone = state.db_config.lock().unwrap().clone();
+    logic::delete_key(&state.db.load(), &key, &config_clone).await?;
+    Ok(StatusCode::OK)
+}
+
+// Queries the collection with fields, sort and key conditions relative to
+// its documents; results with keys carry their ids.
+#[instrument(skip(state, payload), fields(handler="query_collection_handler"))]
+async fn query_collection_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Path(name): Path<String>,
+    Json(payload): Json<QueryAstPayload>,
+) -> Result<Response, AppError> {
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let prefix = logic::require_collection(&name, &config_clone)?;
+    let ast = logic::scope_query(payload.ast, &name)?;
+    let mut options = payload.options;
+    if let Some(sort) = &mut options.sort {
+        sort.field = logic::collection_field(&name, &sort.field)?;
+    }
+    options.key_prefixes = principal.prefixes().map(<[String]>::to_vec);
+    let strip_keys = |results: &mut Vec<Value>| {
+        if options.include_key {
+            for result in results {
+                if let Some(Value::String(key)) = result.get_mut("key") {
+                    *key = key.strip_prefix(prefix.as_str()).unwrap_or(key).to_string();
+                }
+            }
+        }
+    };
+    let mut page = run_ast_query(&state, ast, &options, &config_clone)?;
+    strip_keys(&mut page.results);
+    if payload.with_total {
+        return Ok(Json(page).into_response());
+    }
+    Ok(Json(page.results).into_response())
+}
+
+// Indexes a field of the collection's documents only.
+#[instrument(skip(state, payload), fields(handler="create_collection_index_handler"))]
+async fn create_collection_index_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(mut payload): Json<CreateIndexPayload>,
+) -> Result<Response, AppError> {
+    logic::require_collection(&name, &state.db_config.lock().unwrap())?;
+    payload.field = logic::collection_field(&name, &payload.field)?;
+    create_index(&state, payload)
+}
+
+#[instrument(skip(state, payload), fields(handler="create_index_handler"))]
+async fn create_index_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateIndexPayload>,
+) -> Result<Response, AppError> {
+    create_index(&state, payload)
+}
+
+fn create_index(state: &AppState, payload: CreateIndexPayload) -> Result<Response, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    if payload.background {
+        let build = logic::start_index_build(&state.db.load(), &payload.field, payload.kind, &mut db_config_guard)?;
+        drop(db_config_guard);
+        info!("Started background {:?} index build on {}", payload.kind, payload.field);
+        spawn_index_build(state.clone(), payload.field, payload.kind);
+        return Ok((StatusCode::ACCEPTED, Json(build)).into_response());
+    }
+    // The backfill reads every document; keep it off the async worker.
+    let count = tokio::task::block_in_place(|| logic::create_index(&state.db.load(), &payload.field, payload.kind, &mut db_config_guard))?;
+    info!("Created {:?} index on {} over {} documents", payload.kind, payload.field, count);
+    Ok(Json(CountResponse { count }).into_response())
+}
+
+// The indexed fields of each kind for PUT /admin/config. Kinds left out keep
+// their fields.
+#[derive(Deserialize, Debug)]
+struct IndexConfigPayload {
+    hash: Option<HashSet<String>>,
+    sorted: Option<HashSet<String>>,
+    geo: Option<HashSet<String>>,
+    unique: Option<HashSet<String>>,
+    // Build added indexes in the background instead of before responding.
+    #[serde(default)]
+    background: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct IndexRef {
+    field: String,
+    kind: IndexKind,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct IndexConfigChanges {
+    created: Vec<IndexRef>,
+    dropped: Vec<IndexRef>,
+}
+
+#[instrument(skip(state), fields(handler="get_config_handler"))]
+async fn get_config_handler(
+    State(state): State<AppState>,
+) -> Json<LogicDbConfig> {
+    Json(state.db_config.lock().unwrap().clone())
+}
+
+// Makes the indexed fields match the payload: indexes of removed fields are
+// dropped along with their entries and added fields are backfilled. Changes
+// made before a failing one, such as a unique index over duplicates, are kept.
+#[instrument(skip(state, payload), fields(handler="put_config_handler"))]
+async fn put_config_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<IndexConfigPayload>,
+) -> Result<Json<IndexConfigChanges>, AppError> {
+    let declared = [
+        (IndexKind::Hash, payload.hash),
+        (IndexKind::Sorted, payload.sorted),
+        (IndexKind::Geo, payload.geo),
+        (IndexKind::Unique, payload.unique),
+    ];
+    let mut changes = IndexConfigChanges::default();
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    for (kind, fields) in &declared {
+        let Some(fields) = fields else { continue };
+        let removed: Vec<String> = db_config_guard.indexed_fields(*kind).difference(fields).cloned().collect();
+        for field in removed {
+            let count = tokio::task::block_in_place(|| logic::drop_index(&state.db.load(), &field, *kind, &mut db_config_guard))?;
+            info!("Dropped {:?} index on {} with {} entries", kind, field, count);
+            changes.dropped.push(IndexRef { field, kind: *kind });
+        }
+    }
+    for (kind, fields) in declared {
+        let Some(fields) = fields else { continue };
+        let added: Vec<String> = fields.difference(db_config_guard.indexed_fields(kind)).cloned().collect();
+        for field in added {
+            if payload.background {
+                logic::start_index_build(&state.db.load(), &field, kind, &mut db_config_guard)?;
+                info!("Started background {:?} index build on {}", kind, field);
+                spawn_index_build(state.clone(), field.clone(), kind);
+            } else {
+                let count = tokio::task::block_in_place(|| logic::create_index(&state.db.load(), &field, kind, &mut db_config_guard))?;
+                info!("Created {:?} index on {} over {} documents", kind, field, count);
+            }
+            changes.created.push(IndexRef { field, kind });
+        }
+    }
+    Ok(Json(changes))
+}
+
+#[instrument(skip(state), fields(handler="index_builds_handler"))]
+async fn index_builds_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<IndexBuild>>, AppError> {
+    Ok(Json(logic::index_builds(&state.db.load())?))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_ttl_handler"))]
+async fn set_ttl_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SetTtlPayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = tokio::task::block_in_place(|| logic::set_ttl(&state.db.load(), &payload.field, payload.ttl_secs, &mut db_config_guard))?;
+    info!("Set TTL of {} to {:?} over {} documents", payload.field, payload.ttl_secs, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_index_sparse_handler"))]
+async fn set_index_sparse_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SparseIndexPayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = tokio::task::block_in_place(|| logic::set_index_sparse(&state.db.load(), &payload.field, payload.sparse, &mut db_config_guard))?;
+    info!("Set hash index of {} to {} over {} documents", payload.field, if payload.sparse { "sparse" } else { "dense" }, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_geohash_precision_handler"))]
+async fn set_geohash_precision_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<GeohashPrecisionPayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = tokio::task::block_in_place(|| logic::set_geohash_precision(&state.db.load(), &payload.field, payload.precision, &mut db_config_guard))?;
+    info!("Set geohash precision of {} to {} over {} documents", payload.field, payload.precision, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_collation_handler"))]
+async fn set_collation_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CollationPayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = tokio::task::block_in_place(|| logic::set_collation(&state.db.load(), &payload.field, payload.collation, &mut db_config_guard))?;
+    info!("Set collation of {} to {:?} over {} documents", payload.field, payload.collation, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state, payload), fields(handler="set_datetime_field_handler"))]
+async fn set_datetime_field_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<DateTimeFieldPayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let mut db_config_guard = state.db_config.lock().unwrap();
+    let count = tokio::task::block_in_place(|| logic::set_datetime_field(&state.db.load(), &payload.field, payload.datetime, &mut db_config_guard))?;
+    info!("Set {} as {} over {} documents", payload.field, if payload.datetime { "datetime" } else { "text" }, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state, payload), fields(handler="load_geo_rtree_handler"))]
+async fn load_geo_rtree_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<GeoRTreePayload>,
+) -> Result<Json<CountResponse>, AppError> {
+    let count = load_geo_rtree(&state.db.load(), &state.geo_rtrees, &payload.field)?;
+    info!("Loaded geo R-tree for {} with {} documents", payload.field, count);
+    Ok(Json(CountResponse { count }))
+}
+
+#[instrument(skip(state), fields(handler="index_stats_handler"))]
+async fn index_stats_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<IndexStats>>, AppError> {
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let stats = logic::index_stats(&state.db.load(), &config_clone)?;
+    Ok(Json(stats))
+}
+
+#[instrument(skip(state, payload), fields(handler="verify_indexes_handler"))]
+async fn verify_indexes_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyIndexesPayload>,
+) -> Result<Json<IndexVerification>, AppError> {
+    let config_clone = state.db_config.lock().unwrap().clone();
+    let report = if payload.repair {
+        logic::repair_indexes(&state.db.load(), &config_clone)?
+    } else {
+        logic::verify_indexes(&state.db.load(), &config_clone)?
+    };
+    if report.missing_entries > 0 || report.orphaned_entries > 0 {
+        warn!("Index verification found {} missing and {} orphaned entries (repair: {})", report.missing_entries, report.orphaned_entries, payload.repair);
+    }
+    Ok(Json(report))
+}
+
+#[instrument(skip(state), fields(handler="gc_indexes_handler"))]
+async fn gc_indexes_handler(
+    State(state): State<AppState>,
+) -> Result<Json<IndexGcReport>, AppError> {
+    let report = logic::gc_index_entries(&state.db.load())?;
+    info!("Index GC scanned {} entries, removed {}", report.entries_scanned, report.entries_removed);
+    Ok(Json(report))
+}
+
+// Periodically deletes documents whose TTL has expired.
+fn spawn_ttl_expiry(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            // Documents can expire through their own `_expires_at` even without TTL fields.
+            let config_clone = state.db_config.lock().unwrap().clone();
+            let db = Arc::clone(&state.db.load());
+            match tokio::task::spawn_blocking(move || logic::expire_now(&db, &config_clone)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => info!("Expired {} documents", count),
+                Ok(Err(e)) => error!("TTL expiration failed: {}", e),
+                Err(e) => error!("TTL expiration task panicked: {}", e),
+            }
+        }
+    });
+}
+
+fn spawn_index_gc(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately; skip it so startup isn't slowed by a sweep.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let db = Arc::clone(&state.db.load());
+            match tokio::task::spawn_blocking(move || logic::gc_index_entries(&db)).await {
+                Ok(Ok(report)) => info!("Index GC scanned {} entries, removed {}", report.entries_scanned, report.entries_removed),
+                Ok(Err(e)) => error!("Index GC failed: {}", e),
+                Err(e) => error!("Index GC task panicked: {}", e),
+            }
+        }
+    });
+}
+
+// Change feed over a WebSocket. The client's first message is the watch
+// target ({"key": ..}, {"prefix": ..} or {"query": <QueryNode>}); the server
+// answers {"type": "subscribed"} and then sends a set or delete event for
+// every matching change until either side closes.
+async fn watch_handler(State(state): State<AppState>, Extension(principal): Extension<Principal>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| watch_socket(state, principal, socket))
+}
+
+// Scoped API keys only see changes to keys under their prefixes.
+async fn watch_socket(state: AppState, principal: Principal, mut socket: WebSocket) {
+    let Some(Ok(Message::Text(text))) = socket.recv().await else { return };
+    let subscription = match serde_json::from_str::<WatchTarget>(&text) {
+        Ok(target) => subscribe(&state, target).await,
+        Err(e) => Err(e.into()),
+    };
+    let (mut subscriber, mut watcher) = match subscription {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            warn!("Rejected watch subscription: {}", e);
+            let _ = socket.send(Message::Text(json!({ "type": "error", "error": e.to_string() }).to_string())).await;
+            return;
+        }
+    };
+    if socket.send(Message::Text(json!({ "type": "subscribed" }).to_string())).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            event = &mut subscriber => {
+                let Some(event) = event else { break };
+                match watcher.apply(&event) {
+                    Ok(Some(change)) if principal.allows(change.key()) => {
+                        let Ok(text) = serde_json::to_string(&change) else { continue };
+                        if socket.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Skipping change event the watcher could not read: {}", e),
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+// Subscribes before the watcher looks up the documents already matching, so
+// no change in between is missed.
+async fn subscribe(state: &AppState, target: WatchTarget) -> Result<(sled::Subscriber, Watcher), logic::DbError> {
+    let subscriber = state.db.load().watch_prefix(target.prefix());
+    let (db, config_clone) = (Arc::clone(&state.db.load()), state.db_config.lock().unwrap().clone());
+    let watcher = tokio::task::spawn_blocking(move || Watcher::new(&db, target, &config_clone)).await
+        .map_err(|e| logic::DbError::Transaction(format!("Watch setup failed: {}", e)))??;
+    Ok((subscriber, watcher))
+}
+
+// Builds (or rebuilds) the field's R-tree and keeps it in sync with document
+// writes from a subscriber thread, which stops once the tree is replaced.
+// Subscribing before the build means no write is missed in between.
+fn load_geo_rtree(db: &Arc<Db>, rtrees: &GeoRTrees, field: &str) -> Result<usize, logic::DbError> {
+    let subscriber = db.watch_prefix(vec![]);
+    let rtree = Arc::new(RwLock::new(GeoRTree::build(db, field)?));
+    let count = rtree.read().unwrap().len();
+    let weak_rtree = Arc::downgrade(&rtree);
+    rtrees.write().unwrap().insert(field.to_string(), rtree);
+
+    std::thread::spawn(move || {
+        for event in subscriber {
+            let Some(rtree) = weak_rtree.upgrade() else { break };
+            let (key, value) = match &event {
+                Event::Insert { key, value } => (key, Some(value.as_ref())),
+                Event::Remove { key } => (key, None),
+            };
+            let key = String::from_utf8_lossy(key).into_owned();
+            let mut rtree_guard = rtree.write().unwrap();
+            if let Err(e) = rtree_guard.apply_change(&key, value) {
+                error!("Failed to update geo R-tree for {} with key {}: {}", rtree_guard.field(), key, e);
+            }
+        }
+    });
+    Ok(count)
+}
+
+// Backfills a background index build batch by batch. The config lock is only
+// held for one batch at a time, so writes interleave with the build.
+fn spawn_index_build(state: AppState, field: String, kind: IndexKind) {
+    tokio::spawn(async move {
+        loop {
+            let (step_state, step_field) = (state.clone(), field.clone());
+            let step = tokio::task::spawn_blocking(move || {
+                let mut db_config_guard = step_state.db_config.lock().unwrap();
+                logic::continue_index_build(&step_state.db.load(), &step_field, kind, INDEX_BUILD_BATCH_SIZE, &mut db_config_guard)
+            }).await;
+            match step {
+                Ok(Ok(build)) => match build.state {
+                    IndexBuildState::Building => continue,
+                    IndexBuildState::Ready => info!("Built {:?} index on {} over {} documents", kind, field, build.processed),
+                    IndexBuildState::Failed => error!("{:?} index build on {} failed: {}", kind, field, build.error.unwrap_or_default()),
+                },
+                Ok(Err(e)) => error!("{:?} index build on {} stopped: {}", kind, field, e),
+                Err(e) => error!("Index build task panicked: {}", e),
+            }
+            break;
+        }
+    });
 }
 
 #[instrument(skip(state), fields(handler="export_handler"))]
 async fn export_handler(
     State(state): State<AppState>,
 ) -> Result<Json<String>, AppError> {
-    let data_string = export_data(&state.db)?;
+    let data_string = export_data(&state.db.load())?;
     Ok(Json(data_string))
 }
 
+// Streams the documents as NDJSON while reading them, so large databases
+// export without being buffered. A read error ends the stream early.
+#[instrument(skip(state), fields(handler="export_stream_handler"))]
+async fn export_stream_handler(
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    // Where replicas bootstrapping from this export follow the changelog from.
+    let position = match &state.changelog {
+        Some(_) => Some(logic::changelog_position(&state.db.load())?),
+        None => None,
+    };
+    let (sender, receiver) = mpsc::channel(EXPORT_STREAM_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        for record in logic::export_records(&state.db.load()) {
+            let failed = record.is_err();
+            if let Err(e) = &record {
+                error!("Export stream failed: {}", e);
+            }
+            if sender.blocking_send(record).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let body = Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(receiver));
+    let mut response = ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response();
+    if let Some(position) = position {
+        let headers = response.headers_mut();
+        headers.insert(replication::CHANGELOG_SEQ_HEADER, HeaderValue::from(position.seq));
+        if let Ok(log_id) = HeaderValue::from_str(&position.log_id) {
+            headers.insert(replication::CHANGELOG_ID_HEADER, log_id);
+        }
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct BackupPayload {
+    // Directory on the server to write the backup to; without it the backup
+    // is returned as a .tar.gz download.
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+// Backs up the database while it keeps serving: reads go on, and writers wait
+// on the config lock only while the trees are copied, so the backup is a
+// consistent snapshot of documents and indexes. The result is a sled
+// directory that --base-path and --db-name can open directly.
+#[instrument(skip(state), fields(handler="backup_handler"))]
+async fn backup_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<BackupPayload>,
+) -> Result<Response, AppError> {
+    let copy = |target: &std::path::Path| {
+        let _writers = state.db_config.lock().unwrap();
+        logic::backup_to(&state.db.load(), target)
+    };
+    if let Some(path) = payload.path {
+        let report = tokio::task::block_in_place(|| copy(&path))?;
+        info!("Backed up {} entries in {} trees to {:?}", report.entries, report.trees, path);
+        return Ok(Json(report).into_response());
+    }
+    let archive = tokio::task::block_in_place(|| -> Result<fs::File, logic::DbError> {
+        let scratch = tempfile::tempdir()?;
+        let report = copy(&scratch.path().join("backup"))?;
+        info!("Backed up {} entries in {} trees for download", report.entries, report.trees);
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(tempfile::tempfile()?, flate2::Compression::fast()));
+        builder.append_dir_all("backup", scratch.path().join("backup"))?;
+        let mut archive = builder.into_inner()?.finish()?;
+        std::io::Seek::rewind(&mut archive)?;
+        Ok(archive)
+    })?;
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(tokio::fs::File::from_std(archive)));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"backup.tar.gz\""),
+        ],
+        body,
+    ).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+struct RestorePayload {
+    // A backup directory or .tar.gz on the server.
+    path: PathBuf,
+}
+
+// Replaces the database with a backup while the server runs. The backup is
+// staged and reindexed per the current configuration first; writers then wait
+// on the config lock only while the directories are swapped. Open watches
+// stay on the replaced database and see no further changes.
+#[instrument(skip(state), fields(handler="restore_handler"))]
+async fn restore_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RestorePayload>,
+) -> Result<Json<logic::BackupReport>, AppError> {
+    let report = tokio::task::block_in_place(|| -> Result<logic::BackupReport, logic::DbError> {
+        let scratch = tempfile::tempdir()?;
+        let backup_dir = restore::unpack(&payload.path, scratch.path())?;
+        let config = state.db_config.lock().unwrap().clone();
+        let report = restore::stage(&backup_dir, &state.db_dir, Some(&config))?;
+        let mut db_config_guard = state.db_config.lock().unwrap();
+        state.db.load().flush()?;
+        let replaced = restore::swap_in(&state.db_dir)?;
+        let db = restore::open(&state.db_dir)?;
+        *db_config_guard = logic::load_config(&db)?;
+        replication::log_changes(&state, &db)?;
+        state.db.swap(Arc::new(db));
+        info!("Restored {:?}; the replaced database was moved to {:?}", payload.path, replaced);
+        Ok(report)
+    })?;
+    let fields: Vec<String> = state.geo_rtrees.read().unwrap().keys().cloned().collect();
+    for field in fields {
+        load_geo_rtree(&state.db.load(), &state.geo_rtrees, &field)?;
+    }
+    Ok(Json(report))
+}
+
+// Forces pending writes to disk rather than waiting for sled's periodic flush.
+#[instrument(skip(state), fields(handler="flush_handler"))]
+async fn flush_handler(
+    State(state): State<AppState>,
+) -> Result<Json<logic::SizeReport>, AppError> {
+    let report = tokio::task::block_in_place(|| logic::flush(&state.db.load()))?;
+    info!("Flushed the database: {} bytes before, {} after", report.bytes_before, report.bytes_after);
+    Ok(Json(report))
+}
+
+// Reclaims the space sled keeps after large deletions, such as /clear_prefix
+// or /drop_database, by rewriting the database into the staging directory and
+// swapping it in. Writers wait on the config lock throughout; as with a
+// restore, open watches stay on the replaced database.
+#[instrument(skip(state), fields(handler="compact_handler"))]
+async fn compact_handler(
+    State(state): State<AppState>,
+) -> Result<Json<logic::SizeReport>, AppError> {
+    let report = tokio::task::block_in_place(|| -> Result<logic::SizeReport, logic::DbError> {
+        let _writers = state.db_config.lock().unwrap();
+        let report = logic::compact(&state.db.load(), &restore::open_staging(&state.db_dir)?)?;
+        let replaced = restore::swap_in(&state.db_dir)?;
+        let db = restore::open(&state.db_dir)?;
+        replication::log_changes(&state, &db)?;
+        state.db.swap(Arc::new(db));
+        if let Some(replaced) = replaced {
+            fs::remove_dir_all(replaced)?;
+        }
+        Ok(report)
+    })?;
+    info!("Compacted the database: {} bytes before, {} after", report.bytes_before, report.bytes_after);
+    Ok(Json(report))
+}
+
 #[instrument(skip(state, payload), fields(handler="import_handler"))]
 async fn import_handler(
     State(state): State<AppState>,
     Json(payload): Json<ImportPayload>,
 ) -> Result<StatusCode, AppError> {
     let db_config_guard = state.db_config.lock().unwrap();
-    logic::import_data(&state.db, &serde_json::to_string(&payload).unwrap(), &db_config_guard)?;
+    let count = logic::import_items(&state.db.load(), &payload, &db_config_guard, state.import_chunk_size)?;
+    info!("Imported {} documents", count);
     Ok(StatusCode::CREATED)
 }
 
@@ -454,38 +2429,75 @@ enum AppError {
     Json(#[from] serde_json::Error),
     #[error("Unauthorized: Missing or invalid API key")]
     Unauthorized,
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Database not found: {0}")]
+    DatabaseNotFound(String),
+    #[error("Rate limit exceeded; retry in {0:?}")]
+    RateLimited(Duration),
+    #[error("Idempotency conflict: {0}")]
+    IdempotencyConflict(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match &self {
-            AppError::Logic(logic_err) => match logic_err {
-                logic::DbError::Sled(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database internal error".to_string()),
-                logic::DbError::Serde(_) => (StatusCode::BAD_REQUEST, "Invalid data format in logic".to_string()),
-                logic::DbError::Geohash(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Geospatial processing error".to_string()),
-                logic::DbError::ImportError(msg) => (StatusCode::BAD_REQUEST, format!("Import failed: {}", msg)),
-                logic::DbError::CasRetryLimit(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database concurrency error".to_string()),
-                logic::DbError::Utf8Error(_) => (StatusCode::BAD_REQUEST, "Invalid UTF-8 data".to_string()),
-                logic::DbError::HexError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal encoding error".to_string()),
-                logic::DbError::TryFromSlice(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal byte conversion error".to_string()),
-                logic::DbError::NotFound => (StatusCode::NOT_FOUND, "Key not found".to_string()),
-                logic::DbError::MissingData(field) => (StatusCode::BAD_REQUEST, format!("Missing or invalid data: {}", field)),
-                logic::DbError::Transaction(msg) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Transaction error: {}", msg)),
-                logic::DbError::Io(io_err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("IO error: {}", io_err)),
-                logic::DbError::InvalidComparisonValue(msg) => (StatusCode::BAD_REQUEST, format!("Invalid value for comparison: {}", msg)),
-                logic::DbError::NotAnObject => (StatusCode::BAD_REQUEST, "Value is not an object, cannot retrieve partial fields".to_string()),
-                logic::DbError::FieldNotFound(field) => (StatusCode::BAD_REQUEST, format!("Field not found in object: {}", field)),
-                logic::DbError::NotAGeoPoint(field) => (StatusCode::BAD_REQUEST, format!("Field is not a valid GeoPoint: {}", field)),
-                logic::DbError::InvalidGeoSortedKey(key) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid geo sorted index key format: {}", key)),
-                logic::DbError::AstQueryError(msg) => (StatusCode::BAD_REQUEST, format!("AST Query Error: {}", msg)),
-                logic::DbError::InvalidPath(path) => (StatusCode::BAD_REQUEST, format!("Invalid path specified: {}", path)),
-                logic::DbError::TransactionOperationFailed(msg) => (StatusCode::CONFLICT, format!("Transaction failed: {}", msg)),
-                logic::DbError::InvalidFieldIndexKey(key) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid field index key format: {}", key)),
-            },
+// The HTTP status and client-facing message for a logic error.
+fn logic_error_status(logic_err: &logic::DbError) -> (StatusCode, String) {
+    match logic_err {
+        logic::DbError::Sled(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database internal error".to_string()),
+        logic::DbError::Serde(_) => (StatusCode::BAD_REQUEST, "Invalid data format in logic".to_string()),
+        logic::DbError::Geohash(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Geospatial processing error".to_string()),
+        logic::DbError::ImportError(msg) => (StatusCode::BAD_REQUEST, format!("Import failed: {}", msg)),
+        logic::DbError::CasRetryLimit(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database concurrency error".to_string()),
+        logic::DbError::Utf8Error(_) => (StatusCode::BAD_REQUEST, "Invalid UTF-8 data".to_string()),
+        logic::DbError::HexError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal encoding error".to_string()),
+        logic::DbError::TryFromSlice(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal byte conversion error".to_string()),
+        logic::DbError::NotFound => (StatusCode::NOT_FOUND, "Key not found".to_string()),
+        logic::DbError::MissingData(field) => (StatusCode::BAD_REQUEST, format!("Missing or invalid data: {}", field)),
+        logic::DbError::Transaction(msg) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Transaction error: {}", msg)),
+        logic::DbError::Io(io_err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("IO error: {}", io_err)),
+        logic::DbError::InvalidComparisonValue(msg) => (StatusCode::BAD_REQUEST, format!("Invalid value for comparison: {}", msg)),
+        logic::DbError::NotAnObject => (StatusCode::BAD_REQUEST, "Value is not an object, cannot retrieve partial fields".to_string()),
+        logic::DbError::FieldNotFound(field) => (StatusCode::BAD_REQUEST, format!("Field not found in object: {}", field)),
+        logic::DbError::NotAGeoPoint(field) => (StatusCode::BAD_REQUEST, format!("Field is not a valid GeoPoint: {}", field)),
+        logic::DbError::InvalidGeoSortedKey(key) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid geo sorted index key format: {}", key)),
+        logic::DbError::AstQueryError(msg) => (StatusCode::BAD_REQUEST, format!("AST Query Error: {}", msg)),
+        logic::DbError::InvalidPath(path) => (StatusCode::BAD_REQUEST, format!("Invalid path specified: {}", path)),
+        logic::DbError::TransactionOperationFailed(msg) => (StatusCode::CONFLICT, format!("Transaction failed: {}", msg)),
+        logic::DbError::InvalidFieldIndexKey(key) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid field index key format: {}", key)),
+        logic::DbError::UniqueViolation(msg) => (StatusCode::CONFLICT, format!("Unique constraint violated: {}", msg)),
+        logic::DbError::InvalidExpression(msg) => (StatusCode::BAD_REQUEST, format!("Invalid index expression: {}", msg)),
+        logic::DbError::CasConflict(msg) => (StatusCode::CONFLICT, format!("Compare-and-set conflict: {}", msg)),
+        logic::DbError::RevisionConflict(msg) => (StatusCode::CONFLICT, format!("Revision conflict: {}", msg)),
+        logic::DbError::PatchTestFailed(msg) => (StatusCode::CONFLICT, format!("Patch test failed: {}", msg)),
+        logic::DbError::AlreadyExists(key) => (StatusCode::CONFLICT, format!("Key already exists: {}", key)),
+        logic::DbError::PreconditionFailed(msg) => (StatusCode::PRECONDITION_FAILED, format!("Precondition failed: {}", msg)),
+        logic::DbError::CollectionNotFound(name) => (StatusCode::NOT_FOUND, format!("Collection not found: {}", name)),
+        logic::DbError::WebhookNotFound(id) => (StatusCode::NOT_FOUND, format!("Webhook not found: {}", id)),
+    }
+}
+
+impl AppError {
+    // The HTTP status and client-facing message.
+    fn status(&self) -> (StatusCode, String) {
+        match self {
+            AppError::Logic(logic_err) => logic_error_status(logic_err),
             AppError::Json(json_err) => (StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", json_err)),
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized: Missing or invalid API key".to_string()),
-        };
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, format!("Forbidden: {}", msg)),
+            AppError::DatabaseNotFound(name) => (StatusCode::NOT_FOUND, format!("Database not found: {}", name)),
+            AppError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "Too many requests".to_string()),
+            AppError::IdempotencyConflict(msg) => (StatusCode::CONFLICT, format!("Idempotency conflict: {}", msg)),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = self.status();
         error!("Error processing request: {}", self);
-        (status, Json(json!({ "error": error_message }))).into_response()
+        let mut response = (status, Json(json!({ "error": error_message }))).into_response();
+        if let AppError::RateLimited(wait) = self {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs_f64().ceil().max(1.0) as u64));
+        }
+        response
     }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+// Token-bucket rate limiting by client: each client's bucket holds up to
+// `burst` requests and refills at `rate` requests per second.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+// Idle clients' buckets are dropped once they have refilled, this often.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    // None when `rate` is zero, which disables the limit.
+    pub fn new(rate: f64, burst: u32) -> Option<Arc<Self>> {
+        if rate <= 0.0 {
+            return None;
+        }
+        let limiter = Arc::new(RateLimiter {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        });
+        let pruned = Arc::downgrade(&limiter);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(limiter) = pruned.upgrade() else { break };
+                limiter.prune();
+            }
+        });
+        Some(limiter)
+    }
+
+    // Takes a token from the client's bucket, or returns how long until one
+    // is available.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate < self.burst);
+        debug!("Pruned {} idle rate limit buckets", before - buckets.len());
+    }
+}
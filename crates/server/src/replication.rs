@@ -0,0 +1,202 @@
+// Change data capture and primary/replica replication. A server started
+// with --changelog-size logs every document change with a sequence number
+// (see logic::CHANGELOG_TREE) and serves the log at GET /changes, for
+// replicas and for external systems such as search indexers to sync from.
+// A replica started with --replica-of bootstraps from the primary's
+// /export/stream, then long-polls for the changes since its position and
+// applies them, refusing writes of its own. Each database follows the
+// database of the same name on the primary.
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Extension, Query, State};
+use axum::Json;
+use rust_db_logic::{self as logic, BatchSetItem, ChangeBatch, ChangelogPosition, DbConfig, DbResult};
+use serde::Deserialize;
+use sled::Db;
+use tracing::{error, info, instrument, warn};
+
+use crate::{AppError, AppState, Principal, API_KEY_HEADER_LOWERCASE, DATABASE_HEADER};
+
+const CHANGELOG_BATCH_SIZE: usize = 1000;
+const DEFAULT_CHANGES_LIMIT: usize = 1000;
+const MAX_CHANGES_LIMIT: usize = 10_000;
+// Longest a request for changes waits for new ones when there are none.
+pub const MAX_CHANGES_WAIT: Duration = Duration::from_secs(30);
+const REPLICATION_RETRY: Duration = Duration::from_secs(5);
+// Headers of /export/stream responses giving the changelog position the
+// export starts from, for replicas to follow on from.
+pub const CHANGELOG_ID_HEADER: &str = "x-changelog-id";
+pub const CHANGELOG_SEQ_HEADER: &str = "x-changelog-seq";
+
+// The primary's side: each change is logged by the transaction making it
+// (see logic::set_changelog_size), so the server only sets how many are kept.
+#[derive(Debug)]
+pub struct Changelog {
+    keep: usize,
+}
+
+impl Changelog {
+    // None when `keep` is zero, which disables the changelog.
+    pub fn new(keep: usize) -> Option<Arc<Self>> {
+        (keep > 0).then(|| Arc::new(Changelog { keep }))
+    }
+}
+
+// Enables the changelog of a database being opened or swapped in, or
+// disables it when the server keeps none. Call it before writers can reach
+// the database.
+pub fn log_changes(state: &AppState, db: &Db) -> DbResult<()> {
+    logic::set_changelog_size(db, state.changelog.as_ref().map_or(0, |changelog| changelog.keep))
+}
+
+pub fn require_changelog(state: &AppState) -> Result<(), AppError> {
+    match state.changelog {
+        Some(_) => Ok(()),
+        None => Err(AppError::Forbidden("this server keeps no changelog; start it with --changelog-size".to_string())),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChangesParams {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+    // How long to wait for a change when there is none after `since`.
+    wait_ms: Option<u64>,
+}
+
+// The changes after sequence number `since`, oldest first; scoped API keys
+// only see changes to keys under their prefixes. Fails with 412 once the
+// changes have been dropped from the log, and a reader must start over from
+// an export, as replicas do.
+#[instrument(skip(state), fields(handler="changes_handler"))]
+pub async fn changes_handler(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<ChangeBatch>, AppError> {
+    require_changelog(&state)?;
+    let db = state.db.load();
+    let limit = params.limit.unwrap_or(DEFAULT_CHANGES_LIMIT).clamp(1, MAX_CHANGES_LIMIT);
+    // Subscribed before reading, so a change in between still wakes the wait.
+    let appended = db.open_tree(logic::CHANGELOG_TREE).map_err(logic::DbError::from)?.watch_prefix(vec![]);
+    let mut batch = logic::changes_since(&db, params.since, limit)?;
+    if let Some(wait_ms) = params.wait_ms.filter(|_| batch.changes.is_empty()) {
+        let _ = tokio::time::timeout(Duration::from_millis(wait_ms).min(MAX_CHANGES_WAIT), appended).await;
+        // A restore or compaction may have swapped the database meanwhile.
+        batch = logic::changes_since(&state.db.load(), params.since, limit)?;
+    }
+    batch.changes.retain(|logged| principal.allows(logged.change.key()));
+    Ok(Json(batch))
+}
+
+// The replica's side: one per database, following the same-named database
+// of the primary.
+struct Follower {
+    state: AppState,
+    client: reqwest::Client,
+    primary: String,
+    api_key: Option<String>,
+    database: String,
+}
+
+// Follows the primary at `primary` until the server stops, retrying after
+// errors.
+pub fn spawn_follower(state: AppState, primary: &str, api_key: Option<String>, database: &str) {
+    let client = match reqwest::Client::builder().timeout(MAX_CHANGES_WAIT * 2).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create the replication client: {}", e);
+            return;
+        }
+    };
+    let follower = Follower { state, client, primary: primary.trim_end_matches('/').to_string(), api_key, database: database.to_string() };
+    tokio::spawn(async move {
+        loop {
+            let Err(e) = follower.sync().await;
+            warn!("Replication of {} from {} failed: {}; retrying in {:?}", follower.database, follower.primary, e, REPLICATION_RETRY);
+            tokio::time::sleep(REPLICATION_RETRY).await;
+        }
+    });
+}
+
+impl Follower {
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.get(format!("{}{}", self.primary, path)).header(DATABASE_HEADER, &self.database);
+        match &self.api_key {
+            Some(api_key) => request.header(API_KEY_HEADER_LOWERCASE, api_key),
+            None => request,
+        }
+    }
+
+    // Runs `f` as a writer of the local database.
+    fn write<T>(&self, f: impl FnOnce(&Db, &DbConfig) -> DbResult<T>) -> Result<T, String> {
+        tokio::task::block_in_place(|| {
+            let db_config_guard = self.state.db_config.lock().unwrap();
+            f(&self.state.db.load(), &db_config_guard)
+        }).map_err(|e| e.to_string())
+    }
+
+    async fn sync(&self) -> Result<Infallible, String> {
+        let mut position = match logic::replica_position(&self.state.db.load()).map_err(|e| e.to_string())? {
+            Some(position) => position,
+            None => self.bootstrap().await?,
+        };
+        loop {
+            let path = format!("/changes?since={}&wait_ms={}", position.seq, MAX_CHANGES_WAIT.as_millis());
+            let response = self.get(&path).send().await.map_err(|e| e.to_string())?;
+            if response.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+                warn!("Replica of {} fell behind the changelog of {}", self.database, self.primary);
+                position = self.bootstrap().await?;
+                continue;
+            }
+            let batch: ChangeBatch = response.error_for_status().map_err(|e| e.to_string())?.json().await.map_err(|e| e.to_string())?;
+            if batch.log_id != position.log_id {
+                info!("The changelog of {} on {} restarted", self.database, self.primary);
+                position = self.bootstrap().await?;
+                continue;
+            }
+            if batch.changes.is_empty() {
+                continue;
+            }
+            let next = ChangelogPosition { log_id: batch.log_id.clone(), seq: batch.last_seq };
+            self.write(|db, config| logic::apply_changes(db, &batch.changes, &next, config))?;
+            position = next;
+        }
+    }
+
+    // Replaces the local documents with an export of the primary's, returning
+    // the changelog position to follow on from. Changes the export already
+    // holds may be applied again, which leaves the documents as they were.
+    async fn bootstrap(&self) -> Result<ChangelogPosition, String> {
+        info!("Bootstrapping replica of {} from {}", self.database, self.primary);
+        let mut response = self.get("/export/stream").send().await.and_then(|r| r.error_for_status()).map_err(|e| e.to_string())?;
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let (Some(log_id), Some(seq)) = (header(CHANGELOG_ID_HEADER), header(CHANGELOG_SEQ_HEADER).and_then(|seq| seq.parse().ok())) else {
+            return Err(format!("{} keeps no changelog; start it with --changelog-size", self.primary));
+        };
+        self.write(|db, config| {
+            logic::save_replica_position(db, None)?;
+            logic::drop_database(db, config)
+        })?;
+        let (mut buffer, mut items, mut imported) = (Vec::new(), Vec::new(), 0);
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                items.push(serde_json::from_slice::<BatchSetItem>(&line).map_err(|e| e.to_string())?);
+            }
+            if items.len() >= CHANGELOG_BATCH_SIZE {
+                imported += self.write(|db, config| logic::import_items(db, &items, config, items.len()))?;
+                items.clear();
+            }
+        }
+        imported += self.write(|db, config| logic::import_items(db, &items, config, items.len()))?;
+        let position = ChangelogPosition { log_id, seq };
+        self.write(|db, _| logic::save_replica_position(db, Some(&position)))?;
+        info!("Bootstrapped replica of {} with {} documents, following from change {}", self.database, imported, position.seq);
+        Ok(position)
+    }
+}
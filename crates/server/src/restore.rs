@@ -0,0 +1,92 @@
+// Restoring a backup from /admin/backup: a sled directory or a .tar.gz of
+// one. The backup is copied into a staging directory beside the database,
+// reindexed, and renamed into place; the replaced directory is kept as
+// <name>.replaced-<unix time> until removed by hand. /admin/compact swaps in
+// a rewritten copy of the database through the same staging directory.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rust_db_logic::{self as logic, BackupReport, DbConfig, DbError};
+use sled::Db;
+use tracing::info;
+
+pub fn open(path: &Path) -> Result<Db, DbError> {
+    Ok(sled::Config::default().path(path).use_compression(true).open()?)
+}
+
+fn staging_dir(db_dir: &Path) -> PathBuf {
+    let mut name = db_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".restoring");
+    db_dir.with_file_name(name)
+}
+
+// Opens a fresh database in the staging directory of `db_dir`, removing one
+// left behind by an interrupted restore or compaction.
+pub fn open_staging(db_dir: &Path) -> Result<Db, DbError> {
+    let staging = staging_dir(db_dir);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    open(&staging)
+}
+
+// The sled directory of the backup at `source`, unpacking an archive into
+// `scratch` first.
+pub fn unpack(source: &Path, scratch: &Path) -> Result<PathBuf, DbError> {
+    if source.is_dir() {
+        return Ok(source.to_path_buf());
+    }
+    let archive = fs::File::open(source)?;
+    tar::Archive::new(flate2::read::GzDecoder::new(archive)).unpack(scratch)?;
+    // Archives from /admin/backup hold the database in one top-level directory.
+    let entries = fs::read_dir(scratch)?.collect::<Result<Vec<_>, _>>()?;
+    match entries.as_slice() {
+        [entry] if entry.path().is_dir() => Ok(entry.path()),
+        _ => Ok(scratch.to_path_buf()),
+    }
+}
+
+// Copies the backup into the staging directory of `db_dir` and rebuilds its
+// indexes per `config`, or per the backup's own configuration if None.
+pub fn stage(backup_dir: &Path, db_dir: &Path, config: Option<&DbConfig>) -> Result<BackupReport, DbError> {
+    let staged = open_staging(db_dir)?;
+    let report = logic::copy_trees(&open(backup_dir)?, &staged)?;
+    let backup_config = logic::load_config(&staged)?;
+    let scanned = logic::reindex_with_config(&staged, config.unwrap_or(&backup_config))?;
+    // Replicas following the replaced database's changelog bootstrap again.
+    logic::reset_changelog(&staged)?;
+    staged.flush()?;
+    info!("Staged backup with {} entries in {} trees, reindexed {} documents", report.entries, report.trees, scanned);
+    Ok(report)
+}
+
+// Moves the staged database to `db_dir`, returning where the directory it
+// replaced was moved.
+pub fn swap_in(db_dir: &Path) -> Result<Option<PathBuf>, DbError> {
+    let replaced = if db_dir.exists() {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut name = db_dir.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".replaced-{}", secs));
+        let replaced = db_dir.with_file_name(name);
+        fs::rename(db_dir, &replaced)?;
+        Some(replaced)
+    } else {
+        None
+    };
+    fs::rename(staging_dir(db_dir), db_dir)?;
+    Ok(replaced)
+}
+
+// The `restore` subcommand: replaces the database at `db_dir`, which must not
+// be open in a running server, keeping its index configuration if it exists.
+pub fn restore_offline(backup: &Path, db_dir: &Path) -> Result<BackupReport, DbError> {
+    let scratch = tempfile::tempdir()?;
+    let backup_dir = unpack(backup, scratch.path())?;
+    let config = if db_dir.exists() { Some(logic::load_config(&open(db_dir)?)?) } else { None };
+    let report = stage(&backup_dir, db_dir, config.as_ref())?;
+    if let Some(replaced) = swap_in(db_dir)? {
+        info!("Moved the replaced database to {:?}", replaced);
+    }
+    Ok(report)
+}
@@ -0,0 +1,186 @@
+// Outgoing webhooks, registered at /admin/webhooks with a URL and a watch
+// target: {"url": "https://...", "prefix": "users:"}, or a "key" or "query"
+// instead of the prefix. Each webhook follows the changelog, so the server
+// must run with --changelog-size, and POSTs every matching change to its URL
+// oldest first, as a /changes entry. A change still failing after
+// WEBHOOK_ATTEMPTS tries is kept as a dead letter and delivery moves on.
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use rust_db_logic::{self as logic, ChangelogPosition, DbError, DeadLetter, LoggedChange, WatchTarget, Watcher, Webhook};
+use serde::Deserialize;
+use tracing::{error, info, instrument, warn};
+
+use crate::replication::{require_changelog, MAX_CHANGES_WAIT};
+use crate::{AppError, AppState, CountResponse};
+
+const WEBHOOK_BATCH_SIZE: usize = 100;
+const WEBHOOK_ATTEMPTS: u32 = 5;
+// Wait before the second attempt, doubled before each one after.
+const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_RETRY: Duration = Duration::from_secs(5);
+const WEBHOOK_ID_HEADER: &str = "x-webhook-id";
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookPayload {
+    url: String,
+    #[serde(flatten)]
+    target: WatchTarget,
+}
+
+#[instrument(skip(state), fields(handler="create_webhook_handler"))]
+pub async fn create_webhook_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<(StatusCode, Json<Webhook>), AppError> {
+    require_changelog(&state)?;
+    let webhook = logic::create_webhook(&state.db.load(), &payload.url, payload.target)?;
+    info!("Created webhook {} for {}", webhook.id, webhook.url);
+    spawn_webhook(state, webhook.id.clone());
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+#[instrument(skip(state), fields(handler="list_webhooks_handler"))]
+pub async fn list_webhooks_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    Ok(Json(logic::webhooks(&state.db.load())?))
+}
+
+// Its delivery stops once it finishes with the change in flight.
+#[instrument(skip(state), fields(handler="delete_webhook_handler"))]
+pub async fn delete_webhook_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    logic::delete_webhook(&state.db.load(), &id)?;
+    info!("Deleted webhook {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(state), fields(handler="dead_letters_handler"))]
+pub async fn dead_letters_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<DeadLetter>>, AppError> {
+    Ok(Json(logic::dead_letters(&state.db.load(), &id)?))
+}
+
+#[instrument(skip(state), fields(handler="clear_dead_letters_handler"))]
+pub async fn clear_dead_letters_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<CountResponse>, AppError> {
+    let db = state.db.load();
+    if logic::webhook(&db, &id)?.is_none() {
+        return Err(DbError::WebhookNotFound(id).into());
+    }
+    Ok(Json(CountResponse { count: logic::clear_dead_letters(&db, &id)? }))
+}
+
+// Starts delivering the stored webhooks.
+pub fn spawn_webhooks(state: &AppState) {
+    match logic::webhooks(&state.db.load()) {
+        Ok(webhooks) => {
+            for webhook in webhooks {
+                spawn_webhook(state.clone(), webhook.id);
+            }
+        }
+        Err(e) => error!("Failed to load webhooks: {}", e),
+    }
+}
+
+// Delivers the webhook's changes until it is deleted, retrying after errors.
+fn spawn_webhook(state: AppState, id: String) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create the client of webhook {}: {}", id, e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        loop {
+            match deliver(&state, &client, &id).await {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!("Delivery of webhook {} failed: {}; retrying in {:?}", id, e, WEBHOOK_RETRY);
+                    tokio::time::sleep(WEBHOOK_RETRY).await;
+                }
+            }
+        }
+        info!("Stopped delivering webhook {}", id);
+    });
+}
+
+// Follows the changelog from the webhook's position, returning once the
+// webhook is deleted.
+async fn deliver(state: &AppState, client: &reqwest::Client, id: &str) -> Result<(), DbError> {
+    let Some(webhook) = logic::webhook(&state.db.load(), id)? else { return Ok(()) };
+    let (db, config_clone, target) = (Arc::clone(&state.db.load()), state.db_config.lock().unwrap().clone(), webhook.target);
+    let mut watcher = tokio::task::spawn_blocking(move || Watcher::new(&db, target, &config_clone)).await
+        .map_err(|e| DbError::Transaction(format!("Webhook setup failed: {}", e)))??;
+    let mut position = webhook.position;
+    loop {
+        let db = state.db.load();
+        // Subscribed before reading, so a change in between still wakes the wait.
+        let appended = db.open_tree(logic::CHANGELOG_TREE)?.watch_prefix(vec![]);
+        let batch = match logic::changes_since(&db, position.seq, WEBHOOK_BATCH_SIZE) {
+            Ok(batch) if batch.log_id != position.log_id => {
+                // The log restarted with a restore; its changes all came after.
+                position = ChangelogPosition { log_id: batch.log_id, seq: 0 };
+                continue;
+            }
+            Err(DbError::PreconditionFailed(_)) => {
+                let skipped_to = logic::changelog_position(&db)?;
+                warn!("Webhook {} fell behind the changelog; skipping changes {} to {}", id, position.seq + 1, skipped_to.seq);
+                position = skipped_to;
+                continue;
+            }
+            batch => batch?,
+        };
+        drop(db);
+        if batch.changes.is_empty() {
+            let _ = tokio::time::timeout(MAX_CHANGES_WAIT, appended).await;
+            if logic::webhook(&state.db.load(), id)?.is_none() {
+                return Ok(());
+            }
+            continue;
+        }
+        for logged in batch.changes {
+            if let Some(change) = watcher.filter(logged.change)? {
+                post(state, client, &webhook.url, id, &LoggedChange { seq: logged.seq, change }).await?;
+            }
+            position.seq = logged.seq;
+            if !logic::save_webhook_position(&state.db.load(), id, &position)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// POSTs the change, backing off between attempts, and records a dead letter
+// if every attempt fails.
+async fn post(state: &AppState, client: &reqwest::Client, url: &str, id: &str, logged: &LoggedChange) -> Result<(), DbError> {
+    let mut backoff = WEBHOOK_BACKOFF;
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let response = client.post(url).header(WEBHOOK_ID_HEADER, id).json(logged).send().await;
+        let error = match response.and_then(|r| r.error_for_status()) {
+            Ok(_) => return Ok(()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == WEBHOOK_ATTEMPTS {
+            warn!("Webhook {} gave up on change {} after {} attempts: {}", id, logged.seq, attempt, error);
+            let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let dead_letter = DeadLetter { at_ms, attempts: attempt, error, change: logged.clone() };
+            return logic::record_dead_letter(&state.db.load(), id, &dead_letter);
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// The format of exportBinary: the magic bytes, a format version, a flags byte
+// and a `logic::snapshot` of every tree, deflated when FLAG_DEFLATE is set.
+// Unlike exportData's JSON it keeps the indexes and config, so importBinary
+// needs no reindexing.
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rust_db_logic::{self as logic, BackupReport};
+use sled::Db;
+
+use crate::{map_logic_error, WasmDbError};
+
+const MAGIC: &[u8; 4] = b"CBDB";
+const VERSION: u8 = 1;
+const FLAG_DEFLATE: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+pub fn export(db: &Db, compress: bool) -> Result<Vec<u8>, WasmDbError> {
+    let snapshot = logic::snapshot(db).map_err(map_logic_error)?;
+    let mut out = Vec::with_capacity(HEADER_LEN + if compress { snapshot.len() / 4 } else { snapshot.len() });
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    if compress {
+        out.push(FLAG_DEFLATE);
+        let mut encoder = DeflateEncoder::new(out, Compression::default());
+        encoder.write_all(&snapshot).map_err(compression_error)?;
+        out = encoder.finish().map_err(compression_error)?;
+    } else {
+        out.push(0);
+        out.extend_from_slice(&snapshot);
+    }
+    Ok(out)
+}
+
+// Writes an export into the database, overwriting documents with the same keys.
+pub fn import(db: &Db, data: &[u8]) -> Result<BackupReport, WasmDbError> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(WasmDbError::new("Not a binary export".to_string(), Some(400)));
+    }
+    let (version, flags, payload) = (data[MAGIC.len()], data[MAGIC.len() + 1], &data[HEADER_LEN..]);
+    if version != VERSION {
+        return Err(WasmDbError::new(format!("Unsupported binary export version {}", version), Some(400)));
+    }
+    let report = if flags & FLAG_DEFLATE != 0 {
+        let mut snapshot = Vec::new();
+        DeflateDecoder::new(payload).read_to_end(&mut snapshot).map_err(compression_error)?;
+        logic::restore_snapshot(db, &snapshot)
+    } else {
+        logic::restore_snapshot(db, payload)
+    };
+    report.map_err(map_logic_error)
+}
+
+fn compression_error(err: std::io::Error) -> WasmDbError {
+    WasmDbError::new(format!("Compression error: {}", err), Some(400))
+}
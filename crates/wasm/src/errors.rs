@@ -0,0 +1,57 @@
+// Errors reach JS as real Error subclasses picked by the status code, so
+// callers can `instanceof`-match them rather than read `code`:
+//
+//     400 ValidationError, 404 NotFoundError, 409/412 ConflictError,
+//     anything else DatabaseError, which the others extend.
+//
+// Each has `name`, `message` and `code`. The classes live in this module's
+// JS snippet, so `errorClasses()` hands them out for `instanceof`.
+use wasm_bindgen::prelude::*;
+
+use crate::WasmDbError;
+
+#[wasm_bindgen(inline_js = r#"
+export class DatabaseError extends Error {
+    constructor(message, code) {
+        super(message);
+        this.name = new.target.name;
+        this.code = code;
+    }
+}
+export class ValidationError extends DatabaseError {}
+export class NotFoundError extends DatabaseError {}
+export class ConflictError extends DatabaseError {}
+
+export function makeError(message, code) {
+    switch (code) {
+        case 400: return new ValidationError(message, code);
+        case 404: return new NotFoundError(message, code);
+        case 409:
+        case 412: return new ConflictError(message, code);
+        default: return new DatabaseError(message, code);
+    }
+}
+
+export function errorClasses() {
+    return { DatabaseError, ValidationError, NotFoundError, ConflictError };
+}
+"#)]
+extern "C" {
+    #[wasm_bindgen(js_name = makeError)]
+    fn make_error(message: &str, code: Option<u16>) -> JsValue;
+
+    #[wasm_bindgen(js_name = errorClasses)]
+    fn classes() -> JsValue;
+}
+
+impl From<WasmDbError> for JsValue {
+    fn from(err: WasmDbError) -> JsValue {
+        make_error(&err.message, err.code)
+    }
+}
+
+// The error classes the Database methods throw, by name.
+#[wasm_bindgen(js_name = errorClasses, unchecked_return_type = "ErrorClasses")]
+pub fn error_classes() -> JsValue {
+    classes()
+}
@@ -4,59 +4,67 @@ use rust_db_logic::{
     self as logic,
     DbConfig as LogicDbConfig,
     BatchSetItem,
+    Expiry,
     TransactionOperation,
+    PatchOperation,
+    ArrayOperation,
     QueryNode,
+    QueryOptions,
+    IndexKind,
     DbError,
+    DistanceMetric,
+    GeoPoint,
 };
 use serde::{Serialize, Deserialize};
 use serde_json::{Value, json};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashSet;
 use js_sys::{Promise, Function};
 use wasm_bindgen_futures::future_to_promise;
-use tracing_wasm::WASMLayerConfigBuilder;
 use tracing::{info, error, instrument};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+mod binary;
+mod errors;
+mod logging;
+mod query_builder;
+mod storage;
+mod subscriptions;
+mod transaction;
+mod types;
+#[cfg(feature = "worker")]
+mod worker;
+
+use storage::{OpenOptions, Persister, Snapshots, StorageKind};
+use subscriptions::Subscriptions;
+use logging::LogOptions;
+pub use query_builder::QueryBuilder;
+pub use transaction::Transaction;
 
 // --- Error Mapping ---
 
-#[wasm_bindgen]
+// Thrown to JS as one of the Error subclasses in `errors`.
 #[derive(Debug)]
 pub struct WasmDbError {
     message: String,
     code: Option<u16>, // Optional HTTP-like status code
 }
 
-#[wasm_bindgen]
 impl WasmDbError {
-    #[wasm_bindgen(getter)]
     pub fn message(&self) -> String {
         self.message.clone()
     }
 
-    #[wasm_bindgen(getter)]
     pub fn code(&self) -> Option<u16> {
         self.code
     }
 
-    // This is an internal Rust function, no need for wasm_bindgen here
     fn new(message: String, code: Option<u16>) -> WasmDbError {
         WasmDbError { message, code }
     }
 }
 
-// Removed #[wasm_bindgen] from this impl block
-impl From<WasmDbError> for JsValue {
-    fn from(err: WasmDbError) -> JsValue {
-        let obj = js_sys::Object::new();
-        js_sys::Reflect::set(&obj, &JsValue::from_str("message"), &JsValue::from_str(&err.message)).unwrap();
-        if let Some(code) = err.code {
-            js_sys::Reflect::set(&obj, &JsValue::from_str("code"), &JsValue::from_f64(code as f64)).unwrap();
-        }
-        JsValue::from(obj)
-    }
-}
-
 fn map_logic_error(err: DbError) -> WasmDbError {
     error!("Logic Error: {}", err); // Log the error
     let (message, code) = match err {
@@ -81,6 +89,15 @@ fn map_logic_error(err: DbError) -> WasmDbError {
         DbError::Io(e) => (format!("IO error: {}", e), Some(500)),
         DbError::InvalidFieldIndexKey(e) => (format!("Invalid field index key: {}", e), Some(500)),
         DbError::InvalidGeoSortedKey(e) => (format!("Invalid geo sorted key: {}", e), Some(500)), // Added missing arm
+        DbError::UniqueViolation(e) => (format!("Unique constraint violated: {}", e), Some(409)),
+        DbError::InvalidExpression(e) => (format!("Invalid index expression: {}", e), Some(400)),
+        DbError::CasConflict(e) => (format!("Compare-and-set conflict: {}", e), Some(409)),
+        DbError::RevisionConflict(e) => (format!("Revision conflict: {}", e), Some(409)),
+        DbError::PatchTestFailed(e) => (format!("Patch test failed: {}", e), Some(409)),
+        DbError::AlreadyExists(e) => (format!("Key already exists: {}", e), Some(409)),
+        DbError::PreconditionFailed(e) => (format!("Precondition failed: {}", e), Some(412)),
+        DbError::CollectionNotFound(name) => (format!("Collection not found: {}", name), Some(404)),
+        DbError::WebhookNotFound(id) => (format!("Webhook not found: {}", id), Some(404)),
     };
     WasmDbError::new(message, code)
 }
@@ -97,44 +114,252 @@ fn map_sled_error(err: sled::Error) -> WasmDbError {
 
 // --- Database Wrapper ---
 
+// Documents importDataAsync writes per turn of the event loop.
+const ASYNC_IMPORT_CHUNK_SIZE: usize = 500;
+// Results queryAstStream hands over per batch unless told otherwise.
+const STREAM_BATCH_SIZE: usize = 500;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(callback: &Function, ms: i32) -> JsValue;
+}
+
+// The browser has no system clock for std, so expiries are computed from
+// Date.now().
+fn date_now_secs() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+// Resolves on the next macrotask, after the browser has had a chance to
+// render and handle events.
+async fn yield_to_event_loop() {
+    let tick = Promise::new(&mut |resolve, _reject| {
+        set_timeout(&resolve, 0);
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(tick).await;
+}
+
 #[wasm_bindgen]
 pub struct Database {
     db: Arc<Db>,
     db_config: Arc<Mutex<LogicDbConfig>>,
+    dynamic_indexing: AtomicBool,
+    // Set when the data is kept in IndexedDB or OPFS; see `open`.
+    persister: Option<Rc<Persister>>,
+    _snapshots: Option<Snapshots>,
+    subscriptions: Rc<Subscriptions>,
 }
 
 #[wasm_bindgen]
 impl Database {
+    // Opens the database in sled's files at `db_name`. A browser has no
+    // filesystem, so there use `Database.open` to keep the data across reloads.
+    // `options` is {logLevel: "off" | "error" | "warn" | "info" | "debug" |
+    // "trace"}, "info" by default.
     #[wasm_bindgen(constructor)]
-    pub fn new(db_name: String) -> Result<Database, WasmDbError> {
-        // Initialize tracing
-        let wasm_layer_config = WASMLayerConfigBuilder::new().set_max_level(tracing::Level::INFO).build();
-        tracing_subscriber::registry()
-            .with(tracing_wasm::WASMLayer::new(wasm_layer_config))
-            .init();
+    pub fn new(db_name: String, #[wasm_bindgen(unchecked_param_type = "LogOptions | null | undefined")] options: JsValue) -> Result<Database, WasmDbError> {
+        let options: LogOptions = parse_options(options, "constructor")?;
+        logging::init(options.log_level);
+        Database::open_file(db_name)
+    }
 
-        info!("Opening database: {}", db_name);
-        let db = Config::default()
-            .path(db_name)
-            .use_compression(true)
-            .open()
-            .map_err(map_sled_error)?;
+    // Opens a database that only lives in memory and is gone once freed, for
+    // tests and ephemeral caches. Takes the constructor's options.
+    #[wasm_bindgen(js_name = newInMemory)]
+    pub fn new_in_memory(#[wasm_bindgen(unchecked_param_type = "LogOptions | null | undefined")] options: JsValue) -> Result<Database, WasmDbError> {
+        let options: LogOptions = parse_options(options, "constructor")?;
+        logging::init(options.log_level);
+        info!("Opening in-memory database");
+        Database::from_db(Arc::new(logic::open_temporary().map_err(map_logic_error)?))
+    }
 
-        let db_config = Arc::new(Mutex::new(LogicDbConfig::default()));
-        info!("Initialized with default DbConfig: {:?}", db_config);
+    // Opens the database with `options` {storage: "file" | "indexedDb" |
+    // "opfs" | "memory", snapshotIntervalMs, logLevel}; "memory" is the same
+    // as `newInMemory`. With IndexedDB storage, the default where the runtime
+    // has it, or OPFS storage, which is faster but only works in a dedicated
+    // worker, the last snapshot saved under `db_name` is restored and changes
+    // are saved every snapshotIntervalMs (5000 by default); call `persist` to
+    // save them sooner, e.g. before the page unloads.
+    #[wasm_bindgen]
+    pub async fn open(db_name: String, #[wasm_bindgen(unchecked_param_type = "OpenOptions | null | undefined")] options: JsValue) -> Result<Database, WasmDbError> {
+        let options: OpenOptions = parse_options(options, "open")?;
+        logging::init(options.log_level);
+        let storage = options.storage();
+        match storage {
+            StorageKind::File => return Database::open_file(db_name),
+            StorageKind::Memory => return Database::new_in_memory(JsValue::UNDEFINED),
+            StorageKind::IndexedDb | StorageKind::Opfs => {}
+        }
 
-        Ok(Database {
-            db: Arc::new(db),
-            db_config,
+        let db = Arc::new(logic::open_temporary().map_err(map_logic_error)?);
+        let persister = if storage == StorageKind::Opfs {
+            info!("Opening database {} in OPFS", db_name);
+            Persister::opfs(Arc::clone(&db), db_name).await?
+        } else {
+            info!("Opening database {} in IndexedDB", db_name);
+            Persister::indexed_db(Arc::clone(&db), db_name)
+        };
+        persister.load().await?;
+        let interval_ms = options.snapshot_interval_ms();
+        let snapshots = (interval_ms > 0).then(|| Snapshots::start(Rc::clone(&persister), interval_ms));
+        let mut database = Database::from_db(db)?;
+        database.persister = Some(persister);
+        database._snapshots = snapshots;
+        Ok(database)
+    }
+
+    // Saves a snapshot to IndexedDB or OPFS now; resolves to whether anything
+    // changed since the last one. Does nothing for file storage.
+    #[wasm_bindgen(unchecked_return_type = "Promise<boolean>")]
+    pub fn persist(&self) -> Promise {
+        let persister = self.persister.clone();
+        future_to_promise(async move {
+            let Some(persister) = persister else { return Ok(JsValue::FALSE) };
+            persister.save().await.map(JsValue::from_bool).map_err(JsValue::from)
+        })
+    }
+
+    // Makes every write so far durable: sled's files are flushed, or with
+    // IndexedDB or OPFS storage a snapshot is saved. Await it before
+    // navigating away.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn flush(&self) -> Promise {
+        let (db, persister) = (Arc::clone(&self.db), self.persister.clone());
+        future_to_promise(async move {
+            flush_storage(&db, persister).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    // Flushes and closes the database, which can't be used afterwards. Once
+    // the returned Promise resolves, sled's lock on the files is released
+    // (unless an async call is still running), so the same name can be
+    // opened again.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn close(self) -> Promise {
+        info!("Closing database");
+        let Database { db, persister, _snapshots: snapshots, .. } = self;
+        drop(snapshots);
+        future_to_promise(async move {
+            flush_storage(&db, persister).await?;
+            drop(db);
+            Ok(JsValue::UNDEFINED)
         })
     }
 
+    // Calls `callback` with {type: "set", key, value} or {type: "delete", key}
+    // after every write through this Database to a key starting with
+    // `prefix`. Returns an id for `unsubscribe`.
     #[wasm_bindgen]
-    pub fn set(&self, key: String, value: JsValue) -> Result<(), WasmDbError> {
+    pub fn subscribe(&self, prefix: String, #[wasm_bindgen(unchecked_param_type = "(event: ChangeEvent) => void")] callback: Function) -> u32 {
+        info!("Subscribing to changes under prefix: {}", prefix);
+        self.subscriptions.subscribe(prefix, callback)
+    }
+
+    // Returns whether the subscription existed.
+    #[wasm_bindgen]
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        self.subscriptions.unsubscribe(id)
+    }
+
+    /// Enables or disables automatic hash indexing of Eq-queried fields (disabled by default; declare indexes with createIndex instead).
+    #[wasm_bindgen(js_name = setDynamicIndexing)]
+    pub fn set_dynamic_indexing(&self, enabled: bool) {
+        info!("Dynamic indexing {}", if enabled { "enabled" } else { "disabled" });
+        self.dynamic_indexing.store(enabled, Ordering::Relaxed);
+    }
+
+    #[wasm_bindgen]
+    // `expiry` is an optional {expires_at} or {ttl_seconds}; expired documents are removed by expireNow.
+    pub fn set(&self, key: String, value: JsValue, #[wasm_bindgen(unchecked_optional_param_type = "Expiry")] expiry: Option<JsValue>) -> Result<(), WasmDbError> {
         info!("Setting key: {}", key);
         let val: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
-        let db_config_guard = self.db_config.lock().unwrap();
-        logic::set_key(&self.db, &key, val, &db_config_guard).map_err(map_logic_error)
+        let expiry: Expiry = match expiry {
+            Some(expiry_js) if !expiry_js.is_undefined() => serde_wasm_bindgen::from_value(expiry_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize expiry: {}", e), Some(400)))?,
+            _ => Expiry::default(),
+        };
+        let val = expiry.apply(&val, date_now_secs()).map_err(map_logic_error)?.into_owned();
+        logic::set_key(&self.db, &key, val, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        Ok(())
+    }
+
+    // Sets the key only if it doesn't exist yet.
+    #[wasm_bindgen(js_name = setNx)]
+    pub fn set_nx(&self, key: String, value: JsValue) -> Result<(), WasmDbError> {
+        info!("Setting key if absent: {}", key);
+        let value: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
+        logic::set_nx(&self.db, &key, value, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        Ok(())
+    }
+
+    // Sets a single nested field of the document, e.g. "address.city".
+    #[wasm_bindgen(js_name = setPath)]
+    pub fn set_path(&self, key: String, path: String, value: JsValue) -> Result<(), WasmDbError> {
+        info!("Setting path {} of key: {}", path, key);
+        let value: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
+        logic::set_path(&self.db, &key, &path, value, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        Ok(())
+    }
+
+    // Applies an array operation ({op: "push", value} etc.) to the array at `path`.
+    // Returns the popped element for "pop" and the resulting array otherwise.
+    #[wasm_bindgen(js_name = arrayOp)]
+    pub fn array_op(&self, key: String, path: String, #[wasm_bindgen(unchecked_param_type = "ArrayOperation")] operation_js: JsValue) -> Result<JsValue, WasmDbError> {
+        info!("Array operation on path {} of key: {}", path, key);
+        let operation: ArrayOperation = serde_wasm_bindgen::from_value(operation_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize array operation: {}", e), Some(400)))?;
+        let result = logic::array_op(&self.db, &key, &path, &operation, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        result.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(|e| WasmDbError::new(format!("Failed to serialize value: {}", e), Some(500)))
+    }
+
+    // Applies a JSON Merge Patch to the document and returns the merged document.
+    #[wasm_bindgen]
+    pub fn merge(&self, key: String, patch: JsValue) -> Result<JsValue, WasmDbError> {
+        info!("Merging into key: {}", key);
+        let patch: Value = serde_wasm_bindgen::from_value(patch).map_err(|e| WasmDbError::new(format!("Failed to deserialize patch: {}", e), Some(400)))?;
+        let merged = logic::merge_key(&self.db, &key, &patch, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        merged.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(|e| WasmDbError::new(format!("Failed to serialize value: {}", e), Some(500)))
+    }
+
+    // Like `merge`, but returns nothing, so the merged document isn't
+    // serialized back across the boundary.
+    #[wasm_bindgen(js_name = mergePatch)]
+    pub fn merge_patch(&self, key: String, partial: JsValue) -> Result<(), WasmDbError> {
+        info!("Merging into key: {}", key);
+        let partial: Value = serde_wasm_bindgen::from_value(partial).map_err(|e| WasmDbError::new(format!("Failed to deserialize patch: {}", e), Some(400)))?;
+        logic::merge_key(&self.db, &key, &partial, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        Ok(())
+    }
+
+    // Applies JSON Patch operations to the document atomically and returns the patched document.
+    #[wasm_bindgen]
+    pub fn patch(&self, key: String, #[wasm_bindgen(unchecked_param_type = "PatchOperation[]")] operations_js: JsValue) -> Result<JsValue, WasmDbError> {
+        info!("Patching key: {}", key);
+        let operations: Vec<PatchOperation> = serde_wasm_bindgen::from_value(operations_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize patch operations: {}", e), Some(400)))?;
+        let patched = logic::patch_key(&self.db, &key, &operations, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        patched.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(|e| WasmDbError::new(format!("Failed to serialize value: {}", e), Some(500)))
+    }
+
+    // Replaces the document only if the value at `path` (or the whole document
+    // without one) equals `expected`; null or undefined expects it to be absent.
+    #[wasm_bindgen(js_name = compareAndSet)]
+    pub fn compare_and_set(&self, key: String, path: Option<String>, expected: JsValue, value: JsValue) -> Result<(), WasmDbError> {
+        info!("Compare-and-set on key: {}", key);
+        let expected: Option<Value> = serde_wasm_bindgen::from_value(expected).map_err(|e| WasmDbError::new(format!("Failed to deserialize expected value: {}", e), Some(400)))?;
+        let val: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
+        logic::compare_and_set_at(&self.db, &key, path.as_deref(), expected, val, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, [&key]);
+        Ok(())
     }
 
     #[wasm_bindgen]
@@ -144,6 +369,11 @@ impl Database {
         serde_wasm_bindgen::to_value(&value).map_err(|e| WasmDbError::new(format!("Failed to serialize value: {}", e), Some(500)))
     }
 
+    #[wasm_bindgen]
+    pub fn has(&self, key: String) -> Result<bool, WasmDbError> {
+        logic::key_exists(&self.db, &key).map_err(map_logic_error)
+    }
+
      #[wasm_bindgen(js_name = getPartial)]
      pub fn get_partial(&self, key: String, fields: Vec<String>) -> Result<JsValue, WasmDbError> {
          info!("Getting partial key: {}, fields: {:?}", key, fields);
@@ -151,53 +381,251 @@ impl Database {
          serde_wasm_bindgen::to_value(&value).map_err(|e| WasmDbError::new(format!("Failed to serialize partial value: {}", e), Some(500)))
      }
 
-    #[wasm_bindgen]
+     #[wasm_bindgen(js_name = getMany, unchecked_return_type = "GetManyResult")]
+     pub fn get_many(&self, keys: Vec<String>) -> Result<JsValue, WasmDbError> {
+         info!("Getting {} keys", keys.len());
+         let result = logic::get_many(&self.db, &keys).map_err(map_logic_error)?;
+         // Serialize `found` as a plain object rather than a JS Map
+         result.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+             .map_err(|e| WasmDbError::new(format!("Failed to serialize values: {}", e), Some(500)))
+     }
+
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
     pub fn delete(&self, key: String) -> Promise {
         info!("Deleting key: {}", key);
         let db_arc = Arc::clone(&self.db);
         let config_clone = self.db_config.lock().unwrap().clone(); // Clone config
         let key_clone = key.clone();
+        let subscriptions = Rc::clone(&self.subscriptions);
 
         future_to_promise(async move {
             logic::delete_key(&db_arc, &key_clone, &config_clone)
                 .await
-                .map(|_| JsValue::UNDEFINED)
-                .map_err(|e| JsValue::from(map_logic_error(e)))
+                .map_err(|e| JsValue::from(map_logic_error(e)))?;
+            subscriptions.notify(&db_arc, [&key_clone]);
+            Ok(JsValue::UNDEFINED)
         })
     }
 
      #[wasm_bindgen(js_name = batchSet)]
-     pub fn batch_set(&self, items_js: JsValue) -> Result<(), WasmDbError> {
+     pub fn batch_set(&self, #[wasm_bindgen(unchecked_param_type = "BatchSetItem[]")] items_js: JsValue) -> Result<(), WasmDbError> {
          info!("Performing batch set");
          let items: Vec<BatchSetItem> = serde_wasm_bindgen::from_value(items_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize batch items: {}", e), Some(400)))?;
-         let db_config_guard = self.db_config.lock().unwrap();
-         logic::batch_set(&self.db, &items, &db_config_guard).map_err(map_logic_error)
+         logic::batch_set(&self.db, &items, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+         self.subscriptions.notify(&self.db, items.iter().map(|item| &item.key));
+         Ok(())
      }
 
+     // Moves a document to a new key; `overwrite` replaces an existing document there.
      #[wasm_bindgen]
-     pub fn transaction(&self, operations_js: JsValue) -> Result<(), WasmDbError> {
+     pub fn rename(&self, from: String, to: String, overwrite: bool) -> Result<(), WasmDbError> {
+         info!("Renaming key {} to {}", from, to);
+         logic::rename_key(&self.db, &from, &to, overwrite, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+         self.subscriptions.notify(&self.db, [&from, &to]);
+         Ok(())
+     }
+
+     // Copies a document to a new key; `overwrite` replaces an existing document there.
+     #[wasm_bindgen]
+     pub fn copy(&self, from: String, to: String, overwrite: bool) -> Result<(), WasmDbError> {
+         info!("Copying key {} to {}", from, to);
+         logic::copy_key(&self.db, &from, &to, overwrite, &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+         self.subscriptions.notify(&self.db, [&to]);
+         Ok(())
+     }
+
+     #[wasm_bindgen(unchecked_return_type = "TransactionOpResult[]")]
+     pub fn transaction(&self, #[wasm_bindgen(unchecked_param_type = "TransactionOperation[]")] operations_js: JsValue) -> Result<JsValue, WasmDbError> {
          info!("Executing transaction");
          let operations: Vec<TransactionOperation> = serde_wasm_bindgen::from_value(operations_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize transaction operations: {}", e), Some(400)))?;
-         let db_config_guard = self.db_config.lock().unwrap();
-         logic::execute_transaction(&self.db, &operations, &db_config_guard).map_err(map_logic_error)
+         run_transaction(&self.db, &self.db_config, &self.subscriptions, &operations)
+     }
+
+     // Starts a Transaction, which builds the operations for `transaction`.
+     #[wasm_bindgen]
+     pub fn begin(&self) -> Transaction {
+         Transaction::new(Arc::clone(&self.db), Arc::clone(&self.db_config), Rc::clone(&self.subscriptions))
      }
 
      #[wasm_bindgen(js_name = clearPrefix)]
-     pub fn clear_prefix(&self, prefix: String) -> Result<usize, WasmDbError> {
+     pub fn clear_prefix(&self, prefix: String, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Result<usize, WasmDbError> {
          info!("Clearing prefix: {}", prefix);
+         let watched = self.subscriptions.watched_keys(&self.db, &prefix);
+         let progress = |processed, total| report_progress(on_progress.as_ref(), processed, total);
+         let count = logic::clear_prefix_with_progress(&self.db, &prefix, &self.db_config.lock().unwrap(), &progress).map_err(map_logic_error)?;
+         self.subscriptions.notify(&self.db, watched);
+         Ok(count)
+     }
+
+     // `kind` is one of "Hash", "Sorted", "Geo" or "Unique".
+     #[wasm_bindgen(js_name = createIndex)]
+     pub fn create_index(&self, field: String, #[wasm_bindgen(unchecked_param_type = "IndexKind")] kind_js: JsValue) -> Result<usize, WasmDbError> {
+         let kind: IndexKind = serde_wasm_bindgen::from_value(kind_js)
+             .map_err(|e| WasmDbError::new(format!("Invalid index kind: {}", e), Some(400)))?;
+         info!("Creating {:?} index on {}", kind, field);
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::create_index(&self.db, &field, kind, &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     // Makes the indexed fields of each kind match the given arrays, like PUT
+     // /admin/config: removed fields are dropped and added ones backfilled.
+     // Kinds passed as undefined or null keep their fields. Returns the
+     // {created, dropped} indexes as [{field, kind}].
+     #[wasm_bindgen(js_name = setIndexedFields, unchecked_return_type = "IndexConfigChanges")]
+     pub fn set_indexed_fields(&self, #[wasm_bindgen(unchecked_param_type = "string[] | null | undefined")] hash_js: JsValue, #[wasm_bindgen(unchecked_param_type = "string[] | null | undefined")] sorted_js: JsValue, #[wasm_bindgen(unchecked_param_type = "string[] | null | undefined")] geo_js: JsValue, #[wasm_bindgen(unchecked_param_type = "string[] | null | undefined")] unique_js: JsValue) -> Result<JsValue, WasmDbError> {
+         let fields = |fields_js: JsValue| -> Result<Option<HashSet<String>>, WasmDbError> {
+             serde_wasm_bindgen::from_value(fields_js).map_err(|e| WasmDbError::new(format!("Invalid indexed fields: {}", e), Some(400)))
+         };
+         let declared = [
+             (IndexKind::Hash, fields(hash_js)?),
+             (IndexKind::Sorted, fields(sorted_js)?),
+             (IndexKind::Geo, fields(geo_js)?),
+             (IndexKind::Unique, fields(unique_js)?),
+         ];
+         let (mut created, mut dropped) = (Vec::new(), Vec::new());
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         for (kind, fields) in &declared {
+             let Some(fields) = fields else { continue };
+             let removed: Vec<String> = db_config_guard.indexed_fields(*kind).difference(fields).cloned().collect();
+             for field in removed {
+                 let count = logic::drop_index(&self.db, &field, *kind, &mut db_config_guard).map_err(map_logic_error)?;
+                 info!("Dropped {:?} index on {} with {} entries", kind, field, count);
+                 dropped.push(json!({ "field": field, "kind": kind }));
+             }
+         }
+         for (kind, fields) in declared {
+             let Some(fields) = fields else { continue };
+             let added: Vec<String> = fields.difference(db_config_guard.indexed_fields(kind)).cloned().collect();
+             for field in added {
+                 let count = logic::create_index(&self.db, &field, kind, &mut db_config_guard).map_err(map_logic_error)?;
+                 info!("Created {:?} index on {} over {} documents", kind, field, count);
+                 created.push(json!({ "field": field, "kind": kind }));
+             }
+         }
+         json!({ "created": created, "dropped": dropped }).serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+             .map_err(|e| WasmDbError::new(format!("Failed to serialize index changes: {}", e), Some(500)))
+     }
+
+     // The whole DbConfig, as served by GET /admin/config.
+     #[wasm_bindgen(js_name = getIndexConfig, unchecked_return_type = "DbConfig")]
+     pub fn get_index_config(&self) -> Result<JsValue, WasmDbError> {
+         let db_config_guard = self.db_config.lock().unwrap();
+         db_config_guard.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+             .map_err(|e| WasmDbError::new(format!("Failed to serialize index config: {}", e), Some(500)))
+     }
+
+     // Registers a background index build; call `continueIndexBuild` until
+     // the returned build is no longer "Building".
+     #[wasm_bindgen(js_name = startIndexBuild, unchecked_return_type = "IndexBuild")]
+     pub fn start_index_build(&self, field: String, #[wasm_bindgen(unchecked_param_type = "IndexKind")] kind_js: JsValue) -> Result<JsValue, WasmDbError> {
+         let kind: IndexKind = serde_wasm_bindgen::from_value(kind_js)
+             .map_err(|e| WasmDbError::new(format!("Invalid index kind: {}", e), Some(400)))?;
+         info!("Starting background {:?} index build on {}", kind, field);
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         let build = logic::start_index_build(&self.db, &field, kind, &mut db_config_guard).map_err(map_logic_error)?;
+         serde_wasm_bindgen::to_value(&build).map_err(|e| WasmDbError::new(format!("Failed to serialize index build: {}", e), Some(500)))
+     }
+
+     #[wasm_bindgen(js_name = continueIndexBuild, unchecked_return_type = "IndexBuild")]
+     pub fn continue_index_build(&self, field: String, #[wasm_bindgen(unchecked_param_type = "IndexKind")] kind_js: JsValue, batch_size: usize) -> Result<JsValue, WasmDbError> {
+         let kind: IndexKind = serde_wasm_bindgen::from_value(kind_js)
+             .map_err(|e| WasmDbError::new(format!("Invalid index kind: {}", e), Some(400)))?;
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         let build = logic::continue_index_build(&self.db, &field, kind, batch_size, &mut db_config_guard).map_err(map_logic_error)?;
+         serde_wasm_bindgen::to_value(&build).map_err(|e| WasmDbError::new(format!("Failed to serialize index build: {}", e), Some(500)))
+     }
+
+     #[wasm_bindgen(js_name = indexBuilds, unchecked_return_type = "IndexBuild[]")]
+     pub fn index_builds(&self) -> Result<JsValue, WasmDbError> {
+         let builds = logic::index_builds(&self.db).map_err(map_logic_error)?;
+         serde_wasm_bindgen::to_value(&builds).map_err(|e| WasmDbError::new(format!("Failed to serialize index builds: {}", e), Some(500)))
+     }
+
+     // Pass `undefined` as `ttl_secs` to remove the TTL from the field.
+     #[wasm_bindgen(js_name = setTtl)]
+     pub fn set_ttl(&self, field: String, ttl_secs: Option<f64>) -> Result<usize, WasmDbError> {
+         info!("Setting TTL of {} to {:?}", field, ttl_secs);
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::set_ttl(&self.db, &field, ttl_secs.map(|secs| secs.max(0.0) as u64), &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     #[wasm_bindgen(js_name = expireNow)]
+     pub fn expire_now(&self) -> Result<usize, WasmDbError> {
+         let watched = self.subscriptions.watched_keys(&self.db, "");
+         let count = logic::expire_before(&self.db, date_now_secs(), &self.db_config.lock().unwrap()).map_err(map_logic_error)?;
+         // Only the expired documents are reported as deleted; the rest are unchanged.
+         self.subscriptions.notify(&self.db, watched.iter().filter(|key| !logic::key_exists(&self.db, key).unwrap_or(true)));
+         Ok(count)
+     }
+
+     #[wasm_bindgen(js_name = setIndexSparse)]
+     pub fn set_index_sparse(&self, field: String, sparse: bool) -> Result<usize, WasmDbError> {
+         info!("Setting hash index of {} to {}", field, if sparse { "sparse" } else { "dense" });
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::set_index_sparse(&self.db, &field, sparse, &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     #[wasm_bindgen(js_name = setGeohashPrecision)]
+     pub fn set_geohash_precision(&self, field: String, precision: usize) -> Result<usize, WasmDbError> {
+         info!("Setting geohash precision of {} to {}", field, precision);
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::set_geohash_precision(&self.db, &field, precision, &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     // Passing undefined or null resets the field to byte order.
+     #[wasm_bindgen(js_name = setCollation)]
+     pub fn set_collation(&self, field: String, #[wasm_bindgen(unchecked_param_type = "Collation | null | undefined")] collation_js: JsValue) -> Result<usize, WasmDbError> {
+         let collation: Option<logic::Collation> = serde_wasm_bindgen::from_value(collation_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize collation: {}", e), Some(400)))?;
+         info!("Setting collation of {} to {:?}", field, collation);
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::set_collation(&self.db, &field, collation, &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     // Declares whether the field holds RFC3339 timestamps, ordered as instants.
+     #[wasm_bindgen(js_name = setDateTimeField)]
+     pub fn set_datetime_field(&self, field: String, datetime: bool) -> Result<usize, WasmDbError> {
+         info!("Setting {} as {}", field, if datetime { "datetime" } else { "text" });
+         let mut db_config_guard = self.db_config.lock().unwrap();
+         logic::set_datetime_field(&self.db, &field, datetime, &mut db_config_guard).map_err(map_logic_error)
+     }
+
+     #[wasm_bindgen(js_name = indexStats, unchecked_return_type = "IndexStats[]")]
+     pub fn index_stats(&self) -> Result<JsValue, WasmDbError> {
+         let db_config_guard = self.db_config.lock().unwrap();
+         let stats = logic::index_stats(&self.db, &db_config_guard).map_err(map_logic_error)?;
+         serde_wasm_bindgen::to_value(&stats).map_err(|e| WasmDbError::new(format!("Failed to serialize index stats: {}", e), Some(500)))
+     }
+
+     #[wasm_bindgen(js_name = gcIndexes, unchecked_return_type = "IndexGcReport")]
+     pub fn gc_indexes(&self) -> Result<JsValue, WasmDbError> {
+         let report = logic::gc_index_entries(&self.db).map_err(map_logic_error)?;
+         info!("Index GC scanned {} entries, removed {}", report.entries_scanned, report.entries_removed);
+         serde_wasm_bindgen::to_value(&report).map_err(|e| WasmDbError::new(format!("Failed to serialize GC report: {}", e), Some(500)))
+     }
+
+     #[wasm_bindgen(js_name = verifyIndexes, unchecked_return_type = "IndexVerification")]
+     pub fn verify_indexes(&self, repair: bool) -> Result<JsValue, WasmDbError> {
          let db_config_guard = self.db_config.lock().unwrap();
-         logic::clear_prefix(&self.db, &prefix, &db_config_guard).map_err(map_logic_error)
+         let report = if repair {
+             logic::repair_indexes(&self.db, &db_config_guard)
+         } else {
+             logic::verify_indexes(&self.db, &db_config_guard)
+         }.map_err(map_logic_error)?;
+         serde_wasm_bindgen::to_value(&report).map_err(|e| WasmDbError::new(format!("Failed to serialize verification report: {}", e), Some(500)))
      }
 
      #[wasm_bindgen(js_name = dropDatabase)]
-     pub fn drop_database(&self) -> Result<usize, WasmDbError> {
+     pub fn drop_database(&self, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Result<usize, WasmDbError> {
          info!("Dropping database");
-         let db_config_guard = self.db_config.lock().unwrap();
-         logic::drop_database(&self.db, &db_config_guard).map_err(map_logic_error)
+         let watched = self.subscriptions.watched_keys(&self.db, "");
+         let progress = |processed, total| report_progress(on_progress.as_ref(), processed, total);
+         let count = logic::drop_database_with_progress(&self.db, &self.db_config.lock().unwrap(), &progress).map_err(map_logic_error)?;
+         self.subscriptions.notify(&self.db, watched);
+         Ok(count)
      }
 
-    #[wasm_bindgen(js_name = queryAst)]
-    pub fn query_ast(&self, query_js: JsValue, projection_js: JsValue, limit_js: JsValue, offset_js: JsValue) -> Result<JsValue, WasmDbError> {
+    #[wasm_bindgen(js_name = queryAst, unchecked_return_type = "any[]")]
+    pub fn query_ast(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "string[] | null | undefined")] projection_js: JsValue, #[wasm_bindgen(unchecked_param_type = "number | null | undefined")] limit_js: JsValue, #[wasm_bindgen(unchecked_param_type = "number | null | undefined")] offset_js: JsValue) -> Result<JsValue, WasmDbError> {
         info!("Executing AST query");
         let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
         let projection: Option<Vec<String>> = serde_wasm_bindgen::from_value(projection_js).ok();
@@ -205,33 +633,349 @@ impl Database {
         let offset: Option<usize> = serde_wasm_bindgen::from_value(offset_js).ok();
 
         // Dynamic Indexing Logic (similar to server)
-        let config_clone = {
-            let mut db_config_guard = self.db_config.lock().unwrap();
-            if let Some(field) = extract_eq_field_wasm(&query_node) { // Use WASM-specific helper
-                 if db_config_guard.hash_indexed_fields.insert(field.clone()) {
-                     info!("Dynamically indexing field (WASM): {}", field);
-                 }
-            }
-            db_config_guard.clone()
-        };
+        let config_clone = self.query_config(&query_node)?;
 
 
         let results = logic::execute_ast_query(&self.db, query_node, projection, limit, offset, &config_clone).map_err(map_logic_error)?; // Pass cloned config
         serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
     }
 
+    #[wasm_bindgen(js_name = queryAstWithOptions, unchecked_return_type = "any[]")]
+    pub fn query_ast_with_options(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "QueryOptions | null | undefined")] options_js: JsValue) -> Result<JsValue, WasmDbError> {
+        info!("Executing AST query with options");
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let options: QueryOptions = if options_js.is_undefined() || options_js.is_null() {
+            QueryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query options: {}", e), Some(400)))?
+        };
+
+        let config_clone = self.query_config(&query_node)?;
+
+        let results = logic::execute_ast_query_with_options(&self.db, query_node, &options, &config_clone).map_err(map_logic_error)?;
+        serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
+    }
+
+    // Runs a QueryBuilder's query with its limit, offset, sort and selection.
+    #[wasm_bindgen(unchecked_return_type = "any[]")]
+    pub fn query(&self, builder: &QueryBuilder) -> Result<JsValue, WasmDbError> {
+        info!("Executing built query");
+        let query_node = builder.node()?;
+        let config_clone = self.query_config(&query_node)?;
+        let results = logic::execute_ast_query_with_options(&self.db, query_node, builder.options(), &config_clone).map_err(map_logic_error)?;
+        serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
+    }
+
+    #[wasm_bindgen(js_name = queryAstPage, unchecked_return_type = "QueryPage")]
+    pub fn query_ast_page(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "QueryOptions | null | undefined")] options_js: JsValue) -> Result<JsValue, WasmDbError> {
+        info!("Executing paged AST query");
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let options: QueryOptions = if options_js.is_undefined() || options_js.is_null() {
+            QueryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query options: {}", e), Some(400)))?
+        };
+        let config_clone = self.query_config(&query_node)?;
+
+        let page = logic::execute_ast_query_page(&self.db, query_node, &options, &config_clone).map_err(map_logic_error)?;
+        serde_wasm_bindgen::to_value(&page).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
+    }
+
+    // The number of documents matching the query, counted from their keys
+    // without loading any document.
+    #[wasm_bindgen]
+    pub fn count(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue) -> Result<usize, WasmDbError> {
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let config_clone = self.query_config(&query_node)?;
+        let keys = logic::query_keys(&self.db, &query_node, &config_clone).map_err(map_logic_error)?;
+        Ok(keys.len())
+    }
+
+    // Documents whose `field` lies within `radius` meters of the point, like
+    // POST /query/radius. `metric` is "Haversine" (default), "Geodesic" or "Planar".
+    #[wasm_bindgen(js_name = queryRadius, unchecked_return_type = "any[]")]
+    pub fn query_radius(&self, field: String, lat: f64, lon: f64, radius: f64, limit: Option<usize>, #[wasm_bindgen(unchecked_optional_param_type = "DistanceMetric")] metric: Option<JsValue>) -> Result<JsValue, WasmDbError> {
+        info!("Querying {} within {}m of ({}, {})", field, radius, lat, lon);
+        let metric: DistanceMetric = match metric {
+            Some(metric_js) if !metric_js.is_undefined() && !metric_js.is_null() => serde_wasm_bindgen::from_value(metric_js).map_err(|e| WasmDbError::new(format!("Invalid distance metric: {}", e), Some(400)))?,
+            _ => DistanceMetric::default(),
+        };
+        let center = GeoPoint { lat, lon };
+        let db_config_guard = self.db_config.lock().unwrap();
+        let results = logic::query_within_radius_simplified(&self.db, &field, center, radius, limit, metric, &db_config_guard).map_err(map_logic_error)?;
+        serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
+    }
+
+    // Documents whose `field` lies within the box, like POST /query/box.
+    #[wasm_bindgen(js_name = queryBox, unchecked_return_type = "any[]")]
+    pub fn query_box(&self, field: String, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Result<JsValue, WasmDbError> {
+        info!("Querying {} within box ({}, {}) - ({}, {})", field, min_lat, min_lon, max_lat, max_lon);
+        let db_config_guard = self.db_config.lock().unwrap();
+        let results = logic::query_in_box(&self.db, &field, min_lat, min_lon, max_lat, max_lon, &db_config_guard).map_err(map_logic_error)?;
+        serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))
+    }
+
+    // exportData, importData, clearPrefix and dropDatabase (and the async
+    // variants of the first two) call `onProgress`, when given, with the
+    // documents processed so far and the total, every thousand documents and
+    // once more at the end. exportData only knows its total at the end.
     #[wasm_bindgen(js_name = exportData)]
-    pub fn export_data(&self) -> Result<String, WasmDbError> {
+    pub fn export_data(&self, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Result<String, WasmDbError> {
         info!("Exporting data");
-        logic::export_data(&self.db).map_err(map_logic_error)
+        let progress = |processed, total| report_progress(on_progress.as_ref(), processed, total);
+        logic::export_data_with_progress(&self.db, &progress).map_err(map_logic_error)
     }
 
     #[wasm_bindgen(js_name = importData)]
-    pub fn import_data(&self, data: String) -> Result<(), WasmDbError> {
+    pub fn import_data(&self, data: String, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Result<(), WasmDbError> {
         info!("Importing data");
-        let db_config_guard = self.db_config.lock().unwrap();
-        logic::import_data(&self.db, &data, &db_config_guard).map_err(map_logic_error)
+        let items = logic::parse_import_data(&data).map_err(map_logic_error)?;
+        let progress = |processed, total| report_progress(on_progress.as_ref(), processed, total);
+        logic::import_items_with_progress(&self.db, &items, &self.db_config.lock().unwrap(), logic::DEFAULT_IMPORT_CHUNK_SIZE, &progress).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, items.iter().map(|item| &item.key));
+        Ok(())
+    }
+
+    // The whole database, indexes included, in a compact binary format for
+    // backups to files or IndexedDB blobs; deflated unless `compress` is false.
+    #[wasm_bindgen(js_name = exportBinary)]
+    pub fn export_binary(&self, compress: Option<bool>) -> Result<Vec<u8>, WasmDbError> {
+        info!("Exporting binary data");
+        binary::export(&self.db, compress.unwrap_or(true))
+    }
+
+    // Restores an exportBinary export, overwriting documents with the same
+    // keys, and returns the number of entries written. Indexes are restored
+    // as exported, so import into an empty database (see dropDatabase) to
+    // keep them consistent.
+    #[wasm_bindgen(js_name = importBinary)]
+    pub fn import_binary(&self, data: Vec<u8>) -> Result<usize, WasmDbError> {
+        info!("Importing {} bytes of binary data", data.len());
+        let report = binary::import(&self.db, &data)?;
+        *self.db_config.lock().unwrap() = logic::load_config(&self.db).map_err(map_logic_error)?;
+        self.subscriptions.notify(&self.db, self.subscriptions.watched_keys(&self.db, ""));
+        Ok(report.entries)
+    }
+
+    // --- Async variants ---
+    // The methods above run to completion on the JS thread. These return a
+    // Promise instead and start on the next turn of the event loop, so the
+    // page can render first; importDataAsync also yields between chunks.
+
+    #[wasm_bindgen(js_name = setAsync, unchecked_return_type = "Promise<void>")]
+    pub fn set_async(&self, key: String, value: JsValue, #[wasm_bindgen(unchecked_optional_param_type = "Expiry")] expiry: Option<JsValue>) -> Result<Promise, WasmDbError> {
+        let val: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
+        let expiry: Expiry = match expiry {
+            Some(expiry_js) if !expiry_js.is_undefined() => serde_wasm_bindgen::from_value(expiry_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize expiry: {}", e), Some(400)))?,
+            _ => Expiry::default(),
+        };
+        let (db, db_config, subscriptions) = (Arc::clone(&self.db), Arc::clone(&self.db_config), Rc::clone(&self.subscriptions));
+        Ok(future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Setting key: {}", key);
+            let val = expiry.apply(&val, date_now_secs()).map_err(map_logic_error)?.into_owned();
+            logic::set_key(&db, &key, val, &db_config.lock().unwrap()).map_err(map_logic_error)?;
+            subscriptions.notify(&db, [&key]);
+            Ok(JsValue::UNDEFINED)
+        }))
+    }
+
+    #[wasm_bindgen(js_name = batchSetAsync, unchecked_return_type = "Promise<void>")]
+    pub fn batch_set_async(&self, #[wasm_bindgen(unchecked_param_type = "BatchSetItem[]")] items_js: JsValue) -> Result<Promise, WasmDbError> {
+        let items: Vec<BatchSetItem> = serde_wasm_bindgen::from_value(items_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize batch items: {}", e), Some(400)))?;
+        let (db, db_config, subscriptions) = (Arc::clone(&self.db), Arc::clone(&self.db_config), Rc::clone(&self.subscriptions));
+        Ok(future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Performing batch set of {} items", items.len());
+            logic::batch_set(&db, &items, &db_config.lock().unwrap()).map_err(map_logic_error)?;
+            subscriptions.notify(&db, items.iter().map(|item| &item.key));
+            Ok(JsValue::UNDEFINED)
+        }))
+    }
+
+    // Resolves to the results of queryAstWithOptions.
+    #[wasm_bindgen(js_name = queryAstAsync, unchecked_return_type = "Promise<any[]>")]
+    pub fn query_ast_async(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "QueryOptions | null | undefined")] options_js: JsValue) -> Result<Promise, WasmDbError> {
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let options: QueryOptions = if options_js.is_undefined() || options_js.is_null() {
+            QueryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query options: {}", e), Some(400)))?
+        };
+        let config_clone = self.query_config(&query_node)?;
+        let db = Arc::clone(&self.db);
+        Ok(future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Executing AST query with options");
+            let results = logic::execute_ast_query_with_options(&db, query_node, &options, &config_clone).map_err(map_logic_error)?;
+            Ok(serde_wasm_bindgen::to_value(&results).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))?)
+        }))
+    }
+
+    #[wasm_bindgen(js_name = queryAstPageAsync, unchecked_return_type = "Promise<QueryPage>")]
+    pub fn query_ast_page_async(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "QueryOptions | null | undefined")] options_js: JsValue) -> Result<Promise, WasmDbError> {
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let options: QueryOptions = if options_js.is_undefined() || options_js.is_null() {
+            QueryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query options: {}", e), Some(400)))?
+        };
+        let config_clone = self.query_config(&query_node)?;
+        let db = Arc::clone(&self.db);
+        Ok(future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Executing paged AST query");
+            let page = logic::execute_ast_query_page(&db, query_node, &options, &config_clone).map_err(map_logic_error)?;
+            Ok(serde_wasm_bindgen::to_value(&page).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))?)
+        }))
+    }
+
+    // Runs the query like queryAstAsync but hands the results to `on_batch`
+    // `batch_size` (STREAM_BATCH_SIZE by default) at a time, one batch per
+    // turn of the event loop, instead of as one array. A Promise returned by
+    // `on_batch` is awaited before the next batch. Resolves to the number of
+    // results delivered.
+    #[wasm_bindgen(js_name = queryAstStream, unchecked_return_type = "Promise<number>")]
+    pub fn query_ast_stream(&self, #[wasm_bindgen(unchecked_param_type = "QueryNode")] query_js: JsValue, #[wasm_bindgen(unchecked_param_type = "QueryOptions | null | undefined")] options_js: JsValue, #[wasm_bindgen(unchecked_param_type = "(batch: any[]) => void | Promise<void>")] on_batch: Function, batch_size: Option<usize>) -> Result<Promise, WasmDbError> {
+        let query_node: QueryNode = serde_wasm_bindgen::from_value(query_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query AST: {}", e), Some(400)))?;
+        let options: QueryOptions = if options_js.is_undefined() || options_js.is_null() {
+            QueryOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options_js).map_err(|e| WasmDbError::new(format!("Failed to deserialize query options: {}", e), Some(400)))?
+        };
+        let batch_size = batch_size.unwrap_or(STREAM_BATCH_SIZE).max(1);
+        let config_clone = self.query_config(&query_node)?;
+        let db = Arc::clone(&self.db);
+        Ok(future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Executing streamed AST query");
+            let results = logic::execute_ast_query_with_options(&db, query_node, &options, &config_clone).map_err(map_logic_error)?;
+            for batch in results.chunks(batch_size) {
+                let batch_js = serde_wasm_bindgen::to_value(batch).map_err(|e| WasmDbError::new(format!("Failed to serialize query results: {}", e), Some(500)))?;
+                let returned = on_batch.call1(&JsValue::NULL, &batch_js)?;
+                if let Some(pending) = returned.dyn_ref::<Promise>() {
+                    wasm_bindgen_futures::JsFuture::from(pending.clone()).await?;
+                } else {
+                    yield_to_event_loop().await;
+                }
+            }
+            Ok(JsValue::from(results.len() as f64))
+        }))
+    }
+
+    #[wasm_bindgen(js_name = exportDataAsync, unchecked_return_type = "Promise<string>")]
+    pub fn export_data_async(&self, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Promise {
+        let db = Arc::clone(&self.db);
+        future_to_promise(async move {
+            yield_to_event_loop().await;
+            info!("Exporting data");
+            let progress = |processed, total| report_progress(on_progress.as_ref(), processed, total);
+            Ok(JsValue::from(logic::export_data_with_progress(&db, &progress).map_err(map_logic_error)?))
+        })
+    }
+
+    // Imports ASYNC_IMPORT_CHUNK_SIZE documents per turn of the event loop and
+    // resolves to the number imported. Chunks imported before a failing one
+    // are kept.
+    #[wasm_bindgen(js_name = importDataAsync, unchecked_return_type = "Promise<number>")]
+    pub fn import_data_async(&self, data: String, #[wasm_bindgen(unchecked_optional_param_type = "ProgressCallback")] on_progress: Option<Function>) -> Promise {
+        let (db, db_config, subscriptions) = (Arc::clone(&self.db), Arc::clone(&self.db_config), Rc::clone(&self.subscriptions));
+        future_to_promise(async move {
+            yield_to_event_loop().await;
+            let items = logic::parse_import_data(&data).map_err(map_logic_error)?;
+            info!("Importing {} documents", items.len());
+            let mut imported = 0;
+            for chunk in items.chunks(ASYNC_IMPORT_CHUNK_SIZE) {
+                // Locked per chunk, so index changes between chunks are honored.
+                imported += logic::import_items(&db, chunk, &db_config.lock().unwrap(), ASYNC_IMPORT_CHUNK_SIZE).map_err(map_logic_error)?;
+                subscriptions.notify(&db, chunk.iter().map(|item| &item.key));
+                report_progress(on_progress.as_ref(), imported, Some(items.len()));
+                yield_to_event_loop().await;
+            }
+            Ok(JsValue::from(imported as f64))
+        })
+    }
+}
+
+impl Database {
+    fn open_file(db_name: String) -> Result<Database, WasmDbError> {
+        info!("Opening database: {}", db_name);
+        let db = Config::default()
+            .path(db_name)
+            .use_compression(true)
+            .open()
+            .map_err(map_sled_error)?;
+        Database::from_db(Arc::new(db))
+    }
+
+    fn from_db(db: Arc<Db>) -> Result<Database, WasmDbError> {
+        // Writes are timed by the logic crate's clock, and the browser has no
+        // system clock for std.
+        logic::set_clock(|| js_sys::Date::now() as u64);
+        let db_config = Arc::new(Mutex::new(logic::load_config(&db).map_err(map_logic_error)?));
+        info!("Initialized with DbConfig: {:?}", db_config);
+
+        Ok(Database {
+            db,
+            db_config,
+            dynamic_indexing: AtomicBool::new(false),
+            persister: None,
+            _snapshots: None,
+            subscriptions: Rc::default(),
+        })
+    }
+
+    // Applies dynamic indexing for the query and returns a snapshot of the config
+    fn query_config(&self, query_node: &QueryNode) -> Result<LogicDbConfig, WasmDbError> {
+        let mut db_config_guard = self.db_config.lock().unwrap();
+        let field_option = if self.dynamic_indexing.load(Ordering::Relaxed) { extract_eq_field_wasm(query_node) } else { None };
+        if let Some(field) = field_option {
+             if db_config_guard.hash_indexed_fields.insert(field.clone()) {
+                 info!("Dynamically indexing field (WASM): {}", field);
+                 logic::save_config(&self.db, &db_config_guard).map_err(map_logic_error)?;
+                 logic::rebuild_index(&self.db, &field, IndexKind::Hash, &db_config_guard).map_err(map_logic_error)?;
+             }
+        }
+        Ok(db_config_guard.clone())
+    }
+}
+
+fn run_transaction(db: &Db, db_config: &Mutex<LogicDbConfig>, subscriptions: &Subscriptions, operations: &[TransactionOperation]) -> Result<JsValue, WasmDbError> {
+    let results = logic::execute_transaction(db, operations, &db_config.lock().unwrap()).map_err(map_logic_error)?;
+    let written = operations.iter().filter(|op| !matches!(op, TransactionOperation::Check { .. } | TransactionOperation::Get { .. }));
+    subscriptions.notify(db, written.map(TransactionOperation::key));
+    results.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(|e| WasmDbError::new(format!("Failed to serialize transaction results: {}", e), Some(500)))
+}
+
+async fn flush_storage(db: &Db, persister: Option<Rc<Persister>>) -> Result<(), WasmDbError> {
+    match persister {
+        Some(persister) => {
+            persister.save().await?;
+        }
+        None => {
+            db.flush().map_err(map_sled_error)?;
+        }
+    }
+    Ok(())
+}
+
+// Calls an optional progress callback with (processed, total), leaving total
+// undefined when it isn't known yet.
+fn report_progress(callback: Option<&Function>, processed: usize, total: Option<usize>) {
+    let Some(callback) = callback else { return };
+    let total = total.map_or(JsValue::UNDEFINED, |total| JsValue::from_f64(total as f64));
+    if let Err(e) = callback.call2(&JsValue::NULL, &JsValue::from_f64(processed as f64), &total) {
+        error!("Progress callback threw at {} documents: {:?}", processed, e);
+    }
+}
+
+// Options objects default when undefined or null.
+fn parse_options<T: Default + serde::de::DeserializeOwned>(options: JsValue, what: &str) -> Result<T, WasmDbError> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(T::default());
     }
+    serde_wasm_bindgen::from_value(options).map_err(|e| WasmDbError::new(format!("Failed to deserialize {} options: {}", what, e), Some(400)))
 }
 
 // Helper for dynamic indexing in WASM context
@@ -0,0 +1,59 @@
+// Console logging through tracing-wasm. The subscriber is installed once per
+// module, and not at all if the page already installed one; its level is
+// shared by every Database, so the last `logLevel` passed wins.
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Once;
+
+use serde::Deserialize;
+use tracing::{Level, Metadata};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_wasm::WASMLayerConfigBuilder;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// The options of `new Database` and `Database.newInMemory`; `Database.open`
+// takes the same `logLevel` among its own.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LogOptions {
+    // "info" unless set.
+    pub log_level: Option<LogLevel>,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static INSTALL: Once = Once::new();
+
+fn enabled(metadata: &Metadata<'_>) -> bool {
+    let level = match *metadata.level() {
+        Level::ERROR => LogLevel::Error,
+        Level::WARN => LogLevel::Warn,
+        Level::INFO => LogLevel::Info,
+        Level::DEBUG => LogLevel::Debug,
+        Level::TRACE => LogLevel::Trace,
+    };
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+// Sets the console log level, if given, and installs the subscriber on first use.
+pub fn init(level: Option<LogLevel>) {
+    if let Some(level) = level {
+        LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+    INSTALL.call_once(|| {
+        let wasm_layer_config = WASMLayerConfigBuilder::new().set_max_level(Level::TRACE).build();
+        let _ = tracing_subscriber::registry()
+            .with(tracing_wasm::WASMLayer::new(wasm_layer_config).with_filter(filter_fn(enabled)))
+            .try_init();
+    });
+}
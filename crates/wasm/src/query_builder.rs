@@ -0,0 +1,239 @@
+// A fluent way to write queries from JS without spelling out QueryNode JSON:
+//
+//     new QueryBuilder().where("age").gt(21).and().eq("active", true).limit(10)
+//
+// Clauses combine left to right with the last `and()` or `or()` (And by
+// default) and no precedence; use `group` for parentheses. Comparisons take
+// the field set by `where`, or the field and value together. Their DataType
+// follows the value: Number for numbers, Bool for booleans, String otherwise;
+// `asDateTime` compares the last clause's strings as RFC3339 instants instead.
+use rust_db_logic::{DataType, DistanceMetric, QueryNode, QueryOptions, SortSpec};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::WasmDbError;
+
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct QueryBuilder {
+    root: Option<QueryNode>,
+    // Field named by `where` for the comparisons that follow.
+    field: Option<String>,
+    or_next: bool,
+    not_next: bool,
+    options: QueryOptions,
+}
+
+type Comparison = fn(String, Value, DataType) -> QueryNode;
+
+#[wasm_bindgen]
+impl QueryBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> QueryBuilder {
+        QueryBuilder::default()
+    }
+
+    #[wasm_bindgen(js_name = "where")]
+    pub fn where_(mut self, field: String) -> QueryBuilder {
+        self.field = Some(field);
+        self
+    }
+
+    pub fn and(mut self) -> QueryBuilder {
+        self.or_next = false;
+        self
+    }
+
+    pub fn or(mut self) -> QueryBuilder {
+        self.or_next = true;
+        self
+    }
+
+    // Negates the next clause.
+    #[wasm_bindgen(js_name = "not")]
+    pub fn not_(mut self) -> QueryBuilder {
+        self.not_next = !self.not_next;
+        self
+    }
+
+    // Adds another builder's clauses as one parenthesized clause.
+    pub fn group(self, other: &QueryBuilder) -> Result<QueryBuilder, WasmDbError> {
+        let node = other.root.clone().ok_or_else(|| WasmDbError::new("Cannot group an empty query".to_string(), Some(400)))?;
+        Ok(self.push(node))
+    }
+
+    pub fn eq(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Eq, field_or_value, value)
+    }
+
+    pub fn ne(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Ne, field_or_value, value)
+    }
+
+    pub fn gt(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Gt, field_or_value, value)
+    }
+
+    pub fn gte(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Gte, field_or_value, value)
+    }
+
+    pub fn lt(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Lt, field_or_value, value)
+    }
+
+    pub fn lte(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Lte, field_or_value, value)
+    }
+
+    // Matches arrays holding the value.
+    pub fn includes(self, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        self.compare(QueryNode::Includes, field_or_value, value)
+    }
+
+    pub fn exists(self, field: Option<String>) -> Result<QueryBuilder, WasmDbError> {
+        let field = self.field_or_where(field)?;
+        Ok(self.push(QueryNode::Exists(field)))
+    }
+
+    #[wasm_bindgen(js_name = asDateTime)]
+    pub fn as_date_time(mut self) -> Result<QueryBuilder, WasmDbError> {
+        let retyped = match self.root.as_mut() {
+            Some(node) => retype_last(node),
+            None => false,
+        };
+        if !retyped {
+            return Err(WasmDbError::new("asDateTime must follow a comparison".to_string(), Some(400)));
+        }
+        Ok(self)
+    }
+
+    #[wasm_bindgen(js_name = keyEq)]
+    pub fn key_eq(self, key: String) -> QueryBuilder {
+        self.push(QueryNode::KeyEq(key))
+    }
+
+    #[wasm_bindgen(js_name = keyPrefix)]
+    pub fn key_prefix(self, prefix: String) -> QueryBuilder {
+        self.push(QueryNode::KeyPrefix(prefix))
+    }
+
+    // Keys from `start` (inclusive) to `end` (exclusive); either may be left out.
+    #[wasm_bindgen(js_name = keyRange)]
+    pub fn key_range(self, start: Option<String>, end: Option<String>) -> QueryBuilder {
+        self.push(QueryNode::KeyRange { start, end })
+    }
+
+    // `radius` is in meters; `metric` is "Haversine" (default), "Geodesic" or "Planar".
+    #[wasm_bindgen(js_name = withinRadius)]
+    pub fn within_radius(self, field: String, lat: f64, lon: f64, radius: f64, metric: Option<String>) -> Result<QueryBuilder, WasmDbError> {
+        let metric = match metric.as_deref() {
+            None | Some("Haversine") => DistanceMetric::Haversine,
+            Some("Geodesic") => DistanceMetric::Geodesic,
+            Some("Planar") => DistanceMetric::Planar,
+            Some(other) => return Err(WasmDbError::new(format!("Unknown distance metric: {}", other), Some(400))),
+        };
+        Ok(self.push(QueryNode::GeoWithinRadius { field, lat, lon, radius, metric }))
+    }
+
+    #[wasm_bindgen(js_name = inBox)]
+    pub fn in_box(self, field: String, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> QueryBuilder {
+        self.push(QueryNode::GeoInBox { field, min_lat, min_lon, max_lat, max_lon })
+    }
+
+    pub fn limit(mut self, limit: usize) -> QueryBuilder {
+        self.options.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> QueryBuilder {
+        self.options.offset = Some(offset);
+        self
+    }
+
+    #[wasm_bindgen(js_name = orderBy)]
+    pub fn order_by(mut self, field: String, descending: Option<bool>) -> QueryBuilder {
+        self.options.sort = Some(SortSpec { field, descending: descending.unwrap_or(false) });
+        self
+    }
+
+    pub fn select(mut self, fields: Vec<String>) -> QueryBuilder {
+        self.options.projection = Some(fields);
+        self
+    }
+
+    #[wasm_bindgen(js_name = includeKey)]
+    pub fn include_key(mut self) -> QueryBuilder {
+        self.options.include_key = true;
+        self
+    }
+
+    // The QueryNode built so far, as queryAst takes it.
+    #[wasm_bindgen(unchecked_return_type = "QueryNode")]
+    pub fn build(&self) -> Result<JsValue, WasmDbError> {
+        let node = self.node()?;
+        serde_wasm_bindgen::to_value(&node).map_err(|e| WasmDbError::new(format!("Failed to serialize query AST: {}", e), Some(500)))
+    }
+}
+
+impl QueryBuilder {
+    pub fn node(&self) -> Result<QueryNode, WasmDbError> {
+        self.root.clone().ok_or_else(|| WasmDbError::new("The query has no clauses".to_string(), Some(400)))
+    }
+
+    pub fn options(&self) -> &QueryOptions {
+        &self.options
+    }
+
+    fn push(mut self, node: QueryNode) -> QueryBuilder {
+        let node = if std::mem::take(&mut self.not_next) { QueryNode::Not(Box::new(node)) } else { node };
+        self.root = Some(match self.root.take() {
+            None => node,
+            Some(root) if self.or_next => QueryNode::Or(Box::new(root), Box::new(node)),
+            Some(root) => QueryNode::And(Box::new(root), Box::new(node)),
+        });
+        self.or_next = false;
+        self
+    }
+
+    fn compare(self, comparison: Comparison, field_or_value: JsValue, value: JsValue) -> Result<QueryBuilder, WasmDbError> {
+        let (field, value) = if value.is_undefined() {
+            (self.field_or_where(None)?, field_or_value)
+        } else {
+            let field = field_or_value.as_string().ok_or_else(|| WasmDbError::new("The field must be a string".to_string(), Some(400)))?;
+            (field, value)
+        };
+        let value: Value = serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize value: {}", e), Some(400)))?;
+        let data_type = match value {
+            Value::Number(_) => DataType::Number,
+            Value::Bool(_) => DataType::Bool,
+            _ => DataType::String,
+        };
+        Ok(self.push(comparison(field, value, data_type)))
+    }
+
+    fn field_or_where(&self, field: Option<String>) -> Result<String, WasmDbError> {
+        field.or_else(|| self.field.clone())
+            .ok_or_else(|| WasmDbError::new("Name the field with where() or pass it to the comparison".to_string(), Some(400)))
+    }
+}
+
+// Switches the most recently added comparison to DateTime; the last clause is
+// the right-hand side of the tree's spine.
+fn retype_last(node: &mut QueryNode) -> bool {
+    match node {
+        QueryNode::And(_, right) | QueryNode::Or(_, right) => retype_last(right),
+        QueryNode::Not(inner) => retype_last(inner),
+        QueryNode::Eq(_, _, data_type)
+        | QueryNode::Ne(_, _, data_type)
+        | QueryNode::Gt(_, _, data_type)
+        | QueryNode::Gte(_, _, data_type)
+        | QueryNode::Lt(_, _, data_type)
+        | QueryNode::Lte(_, _, data_type)
+        | QueryNode::Includes(_, _, data_type) => {
+            *data_type = DataType::DateTime;
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,355 @@
+// Where a Database keeps its data. sled writes files, which a browser doesn't
+// have, so there its data would only live in memory; the IndexedDb and Opfs
+// storages keep sled in memory instead and save snapshots of it (see
+// `logic::snapshot`), restoring the last one on open. IndexedDb puts them in
+// an object store and works anywhere. Opfs writes them to a file in the
+// Origin Private File System through a sync access handle, which is much
+// faster for large databases but only exists in dedicated workers. Its
+// snapshots alternate between two files, each headed by a sequence number and
+// a checksum, so a crash mid-write only tears the older one and opening
+// restores the newest intact snapshot. The files stay locked while the
+// database is open.
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use js_sys::Uint8Array;
+use rust_db_logic as logic;
+use serde::Deserialize;
+use sled::Db;
+use tracing::{error, info, warn};
+use wasm_bindgen::prelude::*;
+
+use crate::logging::LogLevel;
+use crate::{map_logic_error, WasmDbError};
+
+const DEFAULT_SNAPSHOT_INTERVAL_MS: u32 = 5000;
+
+// An OPFS slot holds the snapshot's sequence number, length and FNV-1a hash,
+// little-endian, then the snapshot.
+const SLOT_HEADER_LEN: usize = 24;
+
+#[wasm_bindgen(inline_js = r#"
+const DATABASE = "commandobase";
+const STORE = "snapshots";
+
+function openDatabase() {
+    return new Promise((resolve, reject) => {
+        const request = indexedDB.open(DATABASE, 1);
+        request.onupgradeneeded = () => request.result.createObjectStore(STORE);
+        request.onsuccess = () => resolve(request.result);
+        request.onerror = () => reject(request.error);
+    });
+}
+
+export function hasIndexedDb() {
+    return typeof indexedDB !== "undefined";
+}
+
+export async function loadSnapshot(name) {
+    const db = await openDatabase();
+    try {
+        return await new Promise((resolve, reject) => {
+            const request = db.transaction(STORE, "readonly").objectStore(STORE).get(name);
+            request.onsuccess = () => resolve(request.result);
+            request.onerror = () => reject(request.error);
+        });
+    } finally {
+        db.close();
+    }
+}
+
+export async function saveSnapshot(name, snapshot) {
+    const db = await openDatabase();
+    try {
+        await new Promise((resolve, reject) => {
+            const transaction = db.transaction(STORE, "readwrite");
+            transaction.objectStore(STORE).put(snapshot, name);
+            transaction.oncomplete = () => resolve();
+            transaction.onerror = () => reject(transaction.error);
+            transaction.onabort = () => reject(transaction.error);
+        });
+    } finally {
+        db.close();
+    }
+}
+
+const OPFS_DIRECTORY = "commandobase";
+
+export function hasOpfs() {
+    return typeof FileSystemSyncAccessHandle !== "undefined" && typeof navigator !== "undefined" && !!navigator.storage?.getDirectory;
+}
+
+export async function openOpfsFile(name) {
+    const root = await navigator.storage.getDirectory();
+    const directory = await root.getDirectoryHandle(OPFS_DIRECTORY, { create: true });
+    const file = await directory.getFileHandle(name, { create: true });
+    return file.createSyncAccessHandle();
+}
+
+export function readOpfsFile(handle) {
+    const snapshot = new Uint8Array(handle.getSize());
+    handle.read(snapshot, { at: 0 });
+    return snapshot;
+}
+
+export function writeOpfsFile(handle, snapshot) {
+    handle.write(snapshot, { at: 0 });
+    handle.truncate(snapshot.length);
+    handle.flush();
+}
+
+export function closeOpfsFile(handle) {
+    handle.close();
+}
+
+export function startTimer(ms, callback) {
+    return setInterval(callback, ms);
+}
+
+export function stopTimer(id) {
+    clearInterval(id);
+}
+"#)]
+extern "C" {
+    #[wasm_bindgen(js_name = hasIndexedDb)]
+    fn has_indexed_db() -> bool;
+    // Resolves to the snapshot's Uint8Array, or undefined if there is none.
+    #[wasm_bindgen(catch, js_name = loadSnapshot)]
+    async fn load_snapshot(name: &str) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(catch, js_name = saveSnapshot)]
+    async fn save_snapshot(name: &str, snapshot: Uint8Array) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(js_name = hasOpfs)]
+    fn has_opfs() -> bool;
+    // Resolves to a FileSystemSyncAccessHandle for the database's file.
+    #[wasm_bindgen(catch, js_name = openOpfsFile)]
+    async fn open_opfs_file(name: &str) -> Result<JsValue, JsValue>;
+    // The file's contents; empty for a new file.
+    #[wasm_bindgen(catch, js_name = readOpfsFile)]
+    fn read_opfs_file(handle: &JsValue) -> Result<Vec<u8>, JsValue>;
+    #[wasm_bindgen(catch, js_name = writeOpfsFile)]
+    fn write_opfs_file(handle: &JsValue, snapshot: &[u8]) -> Result<(), JsValue>;
+    #[wasm_bindgen(catch, js_name = closeOpfsFile)]
+    fn close_opfs_file(handle: &JsValue) -> Result<(), JsValue>;
+    #[wasm_bindgen(js_name = startTimer)]
+    fn start_timer(ms: u32, callback: &Closure<dyn FnMut()>) -> JsValue;
+    #[wasm_bindgen(js_name = stopTimer)]
+    fn stop_timer(id: &JsValue);
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageKind {
+    // sled's files, at the database name.
+    File,
+    IndexedDb,
+    // A file in the Origin Private File System; dedicated workers only.
+    Opfs,
+    // Nothing kept; see `Database.newInMemory`.
+    Memory,
+}
+
+// The options of `Database.open`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpenOptions {
+    // IndexedDb where the runtime has it, File otherwise.
+    pub storage: Option<StorageKind>,
+    // How often changes are saved to IndexedDB or OPFS; 0 only saves on `persist`.
+    pub snapshot_interval_ms: Option<u32>,
+    pub log_level: Option<LogLevel>,
+}
+
+impl OpenOptions {
+    pub fn storage(&self) -> StorageKind {
+        self.storage.unwrap_or(if has_indexed_db() { StorageKind::IndexedDb } else { StorageKind::File })
+    }
+
+    pub fn snapshot_interval_ms(&self) -> u32 {
+        self.snapshot_interval_ms.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_MS)
+    }
+}
+
+enum Backend {
+    IndexedDb,
+    // Open FileSystemSyncAccessHandles of the two slot files, closed when the
+    // Persister drops.
+    Opfs([JsValue; 2]),
+}
+
+impl Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::IndexedDb => "IndexedDB",
+            Backend::Opfs(_) => "OPFS",
+        }
+    }
+}
+
+// Saves snapshots of a database under its name in IndexedDB or OPFS.
+pub struct Persister {
+    db: Arc<Db>,
+    name: String,
+    backend: Backend,
+    // Hash of the last snapshot saved or loaded, so unchanged data isn't saved again.
+    saved: Cell<u64>,
+    saving: Cell<bool>,
+    // Sequence number of the newest OPFS slot; the next save goes to the other.
+    sequence: Cell<u64>,
+}
+
+impl Persister {
+    pub fn indexed_db(db: Arc<Db>, name: String) -> Rc<Self> {
+        Persister::new(db, name, Backend::IndexedDb)
+    }
+
+    // Opens, and locks, the database's two slot files in OPFS.
+    pub async fn opfs(db: Arc<Db>, name: String) -> Result<Rc<Self>, WasmDbError> {
+        if !has_opfs() {
+            return Err(WasmDbError::new("OPFS storage needs sync access handles, which only dedicated workers have".to_string(), Some(400)));
+        }
+        let first = open_opfs_file(&name).await.map_err(|e| storage_error("OPFS", "open", e))?;
+        let second = match open_opfs_file(&format!("{}.1", name)).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                let _ = close_opfs_file(&first);
+                return Err(storage_error("OPFS", "open", e));
+            }
+        };
+        Ok(Persister::new(db, name, Backend::Opfs([first, second])))
+    }
+
+    fn new(db: Arc<Db>, name: String, backend: Backend) -> Rc<Self> {
+        Rc::new(Persister { db, name, backend, saved: Cell::new(0), saving: Cell::new(false), sequence: Cell::new(0) })
+    }
+
+    // Restores the last snapshot saved, if any. Returns the number of entries restored.
+    pub async fn load(&self) -> Result<usize, WasmDbError> {
+        let snapshot = match &self.backend {
+            Backend::IndexedDb => {
+                let stored = load_snapshot(&self.name).await.map_err(|e| storage_error("IndexedDB", "load", e))?;
+                if stored.is_undefined() || stored.is_null() {
+                    return Ok(0);
+                }
+                Uint8Array::new(&stored).to_vec()
+            }
+            Backend::Opfs(handles) => {
+                let mut newest: Option<(u64, Vec<u8>)> = None;
+                for (slot, handle) in handles.iter().enumerate() {
+                    let contents = read_opfs_file(handle).map_err(|e| storage_error("OPFS", "load", e))?;
+                    match decode_slot(&contents) {
+                        Some((sequence, snapshot)) if newest.as_ref().is_none_or(|(newest, _)| sequence > *newest) => {
+                            newest = Some((sequence, snapshot.to_vec()));
+                        }
+                        Some(_) => {}
+                        None if contents.is_empty() => {}
+                        None => warn!("Ignoring the torn OPFS slot {} of {}", slot, self.name),
+                    }
+                }
+                let Some((sequence, snapshot)) = newest else { return Ok(0) };
+                self.sequence.set(sequence);
+                snapshot
+            }
+        };
+        if snapshot.is_empty() {
+            return Ok(0);
+        }
+        let report = logic::restore_snapshot(&self.db, &snapshot).map_err(map_logic_error)?;
+        self.saved.set(logic::fnv1a(&snapshot));
+        info!("Restored {} entries in {} trees from {}", report.entries, report.trees, self.backend.name());
+        Ok(report.entries)
+    }
+
+    // Saves a snapshot unless nothing changed since the last one. Returns
+    // whether one was saved.
+    pub async fn save(&self) -> Result<bool, WasmDbError> {
+        let snapshot = logic::snapshot(&self.db).map_err(map_logic_error)?;
+        let hash = logic::fnv1a(&snapshot);
+        if hash == self.saved.get() {
+            return Ok(false);
+        }
+        match &self.backend {
+            Backend::IndexedDb => {
+                self.saving.set(true);
+                let saved = save_snapshot(&self.name, Uint8Array::from(&snapshot[..])).await;
+                self.saving.set(false);
+                saved.map_err(|e| storage_error("IndexedDB", "save", e))?;
+            }
+            Backend::Opfs(handles) => {
+                // Overwrite the older slot, keeping the newest one intact.
+                let sequence = self.sequence.get() + 1;
+                let handle = &handles[(sequence % 2) as usize];
+                write_opfs_file(handle, &encode_slot(sequence, &snapshot)).map_err(|e| storage_error("OPFS", "save", e))?;
+                self.sequence.set(sequence);
+            }
+        }
+        self.saved.set(hash);
+        Ok(true)
+    }
+}
+
+impl Drop for Persister {
+    fn drop(&mut self) {
+        if let Backend::Opfs(handles) = &self.backend {
+            for handle in handles {
+                if let Err(e) = close_opfs_file(handle) {
+                    error!("Failed to close an OPFS file of {}: {:?}", self.name, e);
+                }
+            }
+        }
+    }
+}
+
+// Saves a snapshot every interval until dropped.
+pub struct Snapshots {
+    timer: JsValue,
+    _callback: Closure<dyn FnMut()>,
+}
+
+impl Snapshots {
+    pub fn start(persister: Rc<Persister>, interval_ms: u32) -> Self {
+        let callback = Closure::<dyn FnMut()>::new(move || {
+            // A slow save is still running; the next tick catches up.
+            if persister.saving.get() {
+                return;
+            }
+            let persister = Rc::clone(&persister);
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Err(e) = persister.save().await {
+                    error!("Failed to save a snapshot of {}: {}", persister.name, e.message());
+                }
+            });
+        });
+        Snapshots { timer: start_timer(interval_ms, &callback), _callback: callback }
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        stop_timer(&self.timer);
+    }
+}
+
+fn encode_slot(sequence: u64, snapshot: &[u8]) -> Vec<u8> {
+    let mut slot = Vec::with_capacity(SLOT_HEADER_LEN + snapshot.len());
+    slot.extend_from_slice(&sequence.to_le_bytes());
+    slot.extend_from_slice(&(snapshot.len() as u64).to_le_bytes());
+    slot.extend_from_slice(&logic::fnv1a(snapshot).to_le_bytes());
+    slot.extend_from_slice(snapshot);
+    slot
+}
+
+// The sequence number and snapshot of a slot, or None if it is empty or torn.
+fn decode_slot(slot: &[u8]) -> Option<(u64, &[u8])> {
+    let header = |i: usize| u64::from_le_bytes(slot[i * 8..(i + 1) * 8].try_into().unwrap_or_default());
+    if slot.len() < SLOT_HEADER_LEN {
+        return None;
+    }
+    let snapshot = &slot[SLOT_HEADER_LEN..];
+    (header(1) == snapshot.len() as u64 && header(2) == logic::fnv1a(snapshot)).then(|| (header(0), snapshot))
+}
+
+fn storage_error(backend: &str, action: &str, err: JsValue) -> WasmDbError {
+    error!("{} error: {:?}", backend, err);
+    WasmDbError::new(format!("Failed to {} the {} snapshot: {:?}", action, backend, err), Some(500))
+}
@@ -0,0 +1,103 @@
+// Change callbacks registered with `Database.subscribe`. sled's watch_prefix
+// can't back them here: its subscribers are bounded channels that block the
+// writer once 1024 events are pending, and with no other thread to drain
+// them, a batch that large would hang the page. Instead, every Database
+// method that writes reports the keys it touched, and each subscriber whose
+// prefix matches is called with the document as it now is.
+use std::cell::{Cell, RefCell};
+
+use js_sys::Function;
+use rust_db_logic::{self as logic, ChangeEvent, DbError};
+use sled::Db;
+use tracing::error;
+use wasm_bindgen::prelude::*;
+
+#[derive(Default)]
+pub struct Subscriptions {
+    next_id: Cell<u32>,
+    subscribers: RefCell<Vec<Subscriber>>,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    id: u32,
+    prefix: String,
+    callback: Function,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&self, prefix: String, callback: Function) -> u32 {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        self.subscribers.borrow_mut().push(Subscriber { id, prefix, callback });
+        id
+    }
+
+    // Returns whether the subscription existed.
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        let mut subscribers = self.subscribers.borrow_mut();
+        let before = subscribers.len();
+        subscribers.retain(|subscriber| subscriber.id != id);
+        subscribers.len() != before
+    }
+
+    // The subscribed keys a write under `prefix` may delete, to notify after
+    // it; empty without subscribers, so unwatched databases skip the scan.
+    pub fn watched_keys(&self, db: &Db, prefix: &str) -> Vec<String> {
+        let subscribers = self.subscribers.borrow();
+        let mut keys = Vec::new();
+        for subscriber in subscribers.iter() {
+            // Scan the narrower of the two prefixes; keys must start with both.
+            let scan = if subscriber.prefix.len() > prefix.len() { &subscriber.prefix } else { prefix };
+            if !scan.starts_with(prefix) || !scan.starts_with(subscriber.prefix.as_str()) {
+                continue;
+            }
+            for entry in db.scan_prefix(scan.as_bytes()).keys() {
+                match entry.map_err(DbError::from).and_then(|key| Ok(String::from_utf8(key.to_vec())?)) {
+                    Ok(key) => keys.push(key),
+                    Err(e) => error!("Failed to read subscribed keys: {}", e),
+                }
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    // Calls the subscribers of each key with {type: "set", key, value}, or
+    // {type: "delete", key} if the document is gone.
+    pub fn notify<K: AsRef<str>>(&self, db: &Db, keys: impl IntoIterator<Item = K>) {
+        // Cloned so callbacks can subscribe, unsubscribe and write themselves.
+        let subscribers = self.subscribers.borrow().clone();
+        if subscribers.is_empty() {
+            return;
+        }
+        for key in keys {
+            let key = key.as_ref();
+            let matching: Vec<&Subscriber> = subscribers.iter().filter(|subscriber| key.starts_with(subscriber.prefix.as_str())).collect();
+            if matching.is_empty() {
+                continue;
+            }
+            let event = match logic::get_key(db, key) {
+                Ok(value) => ChangeEvent::Set { key: key.to_string(), value },
+                Err(DbError::NotFound) => ChangeEvent::Delete { key: key.to_string() },
+                Err(e) => {
+                    error!("Failed to read changed key {}: {}", key, e);
+                    continue;
+                }
+            };
+            let event_js = match serde::Serialize::serialize(&event, &serde_wasm_bindgen::Serializer::json_compatible()) {
+                Ok(event_js) => event_js,
+                Err(e) => {
+                    error!("Failed to serialize change of {}: {}", key, e);
+                    continue;
+                }
+            };
+            for subscriber in matching {
+                if let Err(e) = subscriber.callback.call1(&JsValue::NULL, &event_js) {
+                    error!("Subscriber {} threw on change of {}: {:?}", subscriber.id, key, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// A builder for `Database.transaction`, so JS needn't assemble the tagged
+// TransactionOperation array by hand:
+//
+//     const tx = db.begin();
+//     tx.set("a", {count: 1}).delete("b").check("c", "count", ">", 0);
+//     const results = await tx.commit();
+//
+// Each method adds an operation and returns the same transaction, so calls
+// chain or not alike. Nothing is written until `commit`, which runs the
+// operations atomically and empties the transaction for reuse.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use js_sys::Promise;
+use rust_db_logic::{ArrayOperation, DbConfig as LogicDbConfig, Expiry, TransactionOperation};
+use sled::Db;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::subscriptions::Subscriptions;
+use crate::{run_transaction, yield_to_event_loop, WasmDbError};
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct Transaction {
+    db: Arc<Db>,
+    db_config: Arc<Mutex<LogicDbConfig>>,
+    subscriptions: Rc<Subscriptions>,
+    // Shared by the handles the methods return.
+    operations: Rc<RefCell<Vec<TransactionOperation>>>,
+}
+
+#[wasm_bindgen]
+impl Transaction {
+    pub fn set(&self, key: String, value: JsValue, #[wasm_bindgen(unchecked_optional_param_type = "Expiry")] expiry: Option<JsValue>) -> Result<Transaction, WasmDbError> {
+        let value = from_js(value, "value")?;
+        let expiry: Expiry = match expiry {
+            Some(expiry_js) if !expiry_js.is_undefined() => from_js(expiry_js, "expiry")?,
+            _ => Expiry::default(),
+        };
+        Ok(self.push(TransactionOperation::Set { key, value, expiry }))
+    }
+
+    pub fn delete(&self, key: String) -> Transaction {
+        self.push(TransactionOperation::Delete { key })
+    }
+
+    // Aborts the transaction unless the value at `path` of the document
+    // compares to `value` as asked. `operator` is Eq, Ne, Gt, Gte, Lt, Lte or
+    // Includes, or one of "==", "!=", ">", ">=", "<" and "<=".
+    pub fn check(&self, key: String, path: String, operator: String, value: JsValue) -> Result<Transaction, WasmDbError> {
+        let operator = match operator.as_str() {
+            "=" | "==" | "===" => "Eq",
+            "!=" | "!==" => "Ne",
+            ">" => "Gt",
+            ">=" => "Gte",
+            "<" => "Lt",
+            "<=" => "Lte",
+            other => other,
+        }
+        .to_string();
+        let value = from_js(value, "value")?;
+        Ok(self.push(TransactionOperation::Check { key, path, operator, value }))
+    }
+
+    // Adds the document, as left by the operations before it, to the results.
+    pub fn get(&self, key: String) -> Transaction {
+        self.push(TransactionOperation::Get { key })
+    }
+
+    // Changes the array at `path` of the document.
+    pub fn array(&self, key: String, path: String, #[wasm_bindgen(unchecked_param_type = "ArrayOperation")] operation: JsValue) -> Result<Transaction, WasmDbError> {
+        let operation: ArrayOperation = from_js(operation, "array operation")?;
+        Ok(self.push(TransactionOperation::Array { key, path, operation }))
+    }
+
+    // The number of operations added since the last commit.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.operations.borrow().len()
+    }
+
+    // Resolves to the results of `Database.transaction`, one per operation.
+    #[wasm_bindgen(unchecked_return_type = "Promise<TransactionOpResult[]>")]
+    pub fn commit(&self) -> Promise {
+        let operations = std::mem::take(&mut *self.operations.borrow_mut());
+        let (db, db_config, subscriptions) = (Arc::clone(&self.db), Arc::clone(&self.db_config), Rc::clone(&self.subscriptions));
+        future_to_promise(async move {
+            yield_to_event_loop().await;
+            Ok(run_transaction(&db, &db_config, &subscriptions, &operations)?)
+        })
+    }
+}
+
+impl Transaction {
+    pub fn new(db: Arc<Db>, db_config: Arc<Mutex<LogicDbConfig>>, subscriptions: Rc<Subscriptions>) -> Transaction {
+        Transaction { db, db_config, subscriptions, operations: Rc::default() }
+    }
+
+    fn push(&self, operation: TransactionOperation) -> Transaction {
+        self.operations.borrow_mut().push(operation);
+        self.clone()
+    }
+}
+
+fn from_js<T: serde::de::DeserializeOwned>(value: JsValue, what: &str) -> Result<T, WasmDbError> {
+    serde_wasm_bindgen::from_value(value).map_err(|e| WasmDbError::new(format!("Failed to deserialize {}: {}", what, e), Some(400)))
+}
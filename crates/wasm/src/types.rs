@@ -0,0 +1,178 @@
+// TypeScript definitions of the values the Database methods take and return,
+// added to the generated .d.ts. The methods name them with
+// `unchecked_param_type`/`unchecked_return_type`, so a malformed query AST or
+// batch item fails to compile instead of failing serde at runtime. They
+// mirror the serde shapes of the logic crate's types and must change with them.
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &str = r#"
+export type DataType = "String" | "Number" | "Bool" | "DateTime";
+
+export type DistanceMetric = "Haversine" | "Geodesic" | "Planar";
+
+export interface GeoPoint {
+    lat: number;
+    lon: number;
+}
+
+export type QueryNode =
+    | { Eq: [string, any, DataType] }
+    | { Includes: [string, any, DataType] }
+    | { Gt: [string, any, DataType] }
+    | { Lt: [string, any, DataType] }
+    | { Gte: [string, any, DataType] }
+    | { Lte: [string, any, DataType] }
+    | { Ne: [string, any, DataType] }
+    | { And: [QueryNode, QueryNode] }
+    | { Or: [QueryNode, QueryNode] }
+    | { Not: QueryNode }
+    | { GeoWithinRadius: { field: string; lat: number; lon: number; radius: number; metric?: DistanceMetric } }
+    | { GeoInBox: { field: string; min_lat: number; min_lon: number; max_lat: number; max_lon: number } }
+    | { GeoNearRoute: { field: string; route: GeoPoint[]; distance: number } }
+    | { GeoIntersects: { field: string; geometry: any } }
+    | { Exists: string }
+    | { KeyEq: string }
+    | { KeyPrefix: string }
+    | { KeyRange: { start?: string | null; end?: string | null } };
+
+export interface QueryOptions {
+    projection?: string[];
+    limit?: number;
+    offset?: number;
+    sort?: { field: string; descending?: boolean };
+    sample?: number;
+    coerce_types?: boolean;
+    include_key?: boolean;
+}
+
+export interface QueryPage {
+    results: any[];
+    total: number;
+    offset: number;
+    limit: number | null;
+}
+
+export type Expiry = { expires_at?: string | number; ttl_seconds?: number };
+
+export type BatchSetItem = { key: string; value: any } & Expiry;
+
+export type ArrayOperation =
+    | { op: "push"; value: any }
+    | { op: "push_unique"; value: any }
+    | { op: "pop" }
+    | { op: "remove"; value: any }
+    | { op: "insert"; index: number; value: any };
+
+export type PatchOperation =
+    | { op: "add"; path: string; value: any }
+    | { op: "remove"; path: string }
+    | { op: "replace"; path: string; value: any }
+    | { op: "move"; from: string; path: string }
+    | { op: "copy"; from: string; path: string }
+    | { op: "test"; path: string; value: any };
+
+export type TransactionOperation =
+    | ({ type: "set"; key: string; value: any } & Expiry)
+    | { type: "delete"; key: string }
+    | { type: "check"; key: string; path: string; operator: "Eq" | "Ne" | "Gt" | "Gte" | "Lt" | "Lte" | "Includes"; value: any }
+    | { type: "get"; key: string }
+    | ({ type: "array"; key: string; path: string } & ArrayOperation);
+
+export interface TransactionOpResult {
+    ok: boolean;
+    value?: any;
+}
+
+export interface GetManyResult {
+    found: Record<string, any>;
+    missing: string[];
+}
+
+export type ProgressCallback = (processed: number, total?: number) => void;
+
+export type ChangeEvent =
+    | { type: "set"; key: string; value: any }
+    | { type: "delete"; key: string };
+
+export type IndexKind = "Hash" | "Sorted" | "Geo" | "Unique";
+
+export interface Collation {
+    case_insensitive?: boolean;
+    numeric?: boolean;
+    locale?: boolean;
+}
+
+export interface DbConfig {
+    hash_indexed_fields: string[];
+    sorted_indexed_fields: string[];
+    geo_indexed_fields: string[];
+    unique_fields: string[];
+    ttl_fields: Record<string, number>;
+    geohash_precision: Record<string, number>;
+    building_indexes: [string, IndexKind][];
+    sparse_fields: string[];
+    collations: Record<string, Collation>;
+    datetime_fields: string[];
+    collections: string[];
+}
+
+export interface IndexConfigChanges {
+    created: { field: string; kind: IndexKind }[];
+    dropped: { field: string; kind: IndexKind }[];
+}
+
+export interface IndexBuild {
+    field: string;
+    kind: IndexKind;
+    state: "Building" | "Ready" | "Failed";
+    processed: number;
+    resume_after: string | null;
+    error: string | null;
+}
+
+export interface IndexStats {
+    field: string;
+    kind: IndexKind;
+    entries: number;
+    approximate_size_bytes: number;
+    distinct_values: number;
+}
+
+export interface IndexGcReport {
+    entries_scanned: number;
+    entries_removed: number;
+}
+
+export interface IndexVerification {
+    documents_scanned: number;
+    missing_entries: number;
+    orphaned_entries: number;
+    repaired: boolean;
+    missing_samples: string[];
+    orphaned_samples: string[];
+}
+
+export type LogLevel = "off" | "error" | "warn" | "info" | "debug" | "trace";
+
+export interface LogOptions {
+    logLevel?: LogLevel;
+}
+
+export interface OpenOptions extends LogOptions {
+    storage?: "file" | "indexedDb" | "opfs" | "memory";
+    snapshotIntervalMs?: number;
+}
+
+export interface DatabaseError extends Error {
+    name: "DatabaseError" | "ValidationError" | "NotFoundError" | "ConflictError";
+    code?: number;
+}
+
+export interface ErrorClasses {
+    DatabaseError: new (message: string, code?: number) => DatabaseError;
+    ValidationError: new (message: string, code?: number) => DatabaseError;
+    NotFoundError: new (message: string, code?: number) => DatabaseError;
+    ConflictError: new (message: string, code?: number) => DatabaseError;
+}
+"#;
@@ -0,0 +1,99 @@
+// Glue for running databases in a dedicated Web Worker, behind the `worker`
+// feature, so queries and imports don't block the page. The worker script
+// loads the module and calls `serveWorker()`; the page then posts
+//
+//     {id, db, method, args, options}
+//
+// and gets {id, result} or {id, error} back. `db` names the database, opened
+// with `Database.open(db, options)` on its first message; `method` is any
+// Database method, called with `args`, which like the results must survive
+// structured cloning. Callbacks can't be posted, so for "subscribe" the
+// worker posts {id, event} for each change, and for "queryAstStream" (args
+// [query, options, batchSize]) {id, batch} for each batch; the methods
+// taking a progress callback post {id, progress: {processed, total}}.
+// "close" flushes and closes the database. Errors lose their class in the
+// post, so {id, error} carries the class as `name` beside `message` and
+// `code`.
+//
+// Memory: WebAssembly memory grows in 64 KiB pages and is never given back,
+// so a worker's footprint stays at the peak of what it held: its databases
+// (all in memory with IndexedDB or OPFS storage), their sled caches, and the largest
+// result or export it built. `wasmMemoryBytes` reports the current size;
+// terminating the worker is the only way to release it.
+use js_sys::{Function, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::Database;
+
+#[wasm_bindgen(inline_js = r#"
+// The index of the progress callback among each method's arguments.
+const PROGRESS_ARG = {
+    exportData: 0,
+    exportDataAsync: 0,
+    importData: 1,
+    importDataAsync: 1,
+    clearPrefix: 1,
+    dropDatabase: 0,
+};
+
+export function serve(open) {
+    const databases = new Map();
+    self.onmessage = async ({ data }) => {
+        const { id, db: name, method, args = [], options } = data;
+        try {
+            if (!databases.has(name)) {
+                databases.set(name, open(name, options));
+            }
+            const db = await databases.get(name);
+            if (method === "close") {
+                databases.delete(name);
+                await db.close();
+                self.postMessage({ id, result: true });
+                return;
+            }
+            if (typeof db[method] !== "function" || method === "free" || method.startsWith("__")) {
+                throw { message: `Unknown method: ${method}`, code: 400 };
+            }
+            if (method === "subscribe") {
+                args[1] = (event) => self.postMessage({ id, event });
+            } else if (method === "queryAstStream") {
+                args.length = Math.max(args.length, 2);
+                args.splice(2, 0, (batch) => self.postMessage({ id, batch }));
+            } else if (method in PROGRESS_ARG) {
+                args[PROGRESS_ARG[method]] = (processed, total) => self.postMessage({ id, progress: { processed, total } });
+            }
+            const result = await db[method](...args);
+            self.postMessage({ id, result });
+        } catch (error) {
+            self.postMessage({ id, error: error instanceof Error ? { name: error.name, message: error.message, code: error.code } : error });
+        }
+    };
+}
+"#)]
+extern "C" {
+    fn serve(open: &Function);
+}
+
+// Answers the page's messages as described above; call once from the worker.
+#[wasm_bindgen(js_name = serveWorker)]
+pub fn serve_worker() {
+    let open = Closure::<dyn FnMut(String, JsValue) -> Promise>::new(|name: String, options: JsValue| {
+        future_to_promise(async move {
+            Database::open(name, options).await.map(JsValue::from).map_err(JsValue::from)
+        })
+    });
+    serve(open.as_ref().unchecked_ref());
+    // Called for every database the worker opens, for as long as it runs.
+    open.forget();
+}
+
+// The size of this module's WebAssembly memory in bytes.
+#[wasm_bindgen(js_name = wasmMemoryBytes)]
+pub fn wasm_memory_bytes() -> f64 {
+    Reflect::get(&wasm_bindgen::memory(), &JsValue::from_str("buffer"))
+        .and_then(|buffer| Reflect::get(&buffer, &JsValue::from_str("byteLength")))
+        .ok()
+        .and_then(|length| length.as_f64())
+        .unwrap_or(0.0)
+}
@@ -5,6 +5,12 @@ export interface GeoPoint {
   lon: number;
 }
 
+// GeoJSON geometries accepted in geo-indexed fields; positions are [lon, lat].
+export type GeoJsonGeometry =
+  | { type: "Point"; coordinates: [number, number] }
+  | { type: "LineString"; coordinates: [number, number][] }
+  | { type: "Polygon"; coordinates: [number, number][][] };
+
 export interface ImportItem {
   key: string;
   value: any;
@@ -13,17 +19,144 @@ export interface ImportItem {
 export interface BatchSetItem {
     key: string;
     value: any;
+    expires_at?: string | number;
+    ttl_seconds?: number;
+}
+
+// When a document set with it expires: an RFC3339 string or Unix seconds,
+// or a number of seconds from now.
+export interface KeyPage {
+  keys: string[];
+  // Pass as `after` for the next page; null once the keys are exhausted.
+  next: string | null;
+}
+
+export type IndexKind = 'Hash' | 'Sorted' | 'Geo' | 'Unique';
+
+// Indexed fields by kind; kinds left out of setIndexedFields keep theirs.
+export interface IndexedFields {
+  hash?: string[];
+  sorted?: string[];
+  geo?: string[];
+  unique?: string[];
 }
 
+export interface IndexConfigChanges {
+  created: { field: string; kind: IndexKind }[];
+  dropped: { field: string; kind: IndexKind }[];
+}
+
+export interface SizeReport {
+  bytes_before: number;
+  bytes_after: number;
+}
+
+// A query that took longer than the server's --slow-query-ms.
+export interface SlowQuery {
+  at_ms: number;
+  duration_ms: number;
+  query: any;
+  total: number;
+  // The indexes and scans used, in the order they ran.
+  plan: string[];
+  keys_scanned: number;
+  documents_fetched: number;
+}
+
+// Where a webhook is in the change log; see ChangeBatch.
+export interface ChangelogPosition {
+  log_id: string;
+  seq: number;
+}
+
+// The server POSTs the changes to documents matching the target to `url`,
+// oldest first, each shaped like an entry of ChangeBatch.changes.
+export type Webhook = { id: string; url: string; position: ChangelogPosition } & WatchTarget;
+
+// A change a webhook gave up delivering after retrying.
+export interface DeadLetter {
+  at_ms: number;
+  attempts: number;
+  error: string;
+  change: ChangeEvent & { seq: number };
+}
+
+export interface BackupReport {
+  trees: number;
+  entries: number;
+}
+
+export type Expiry = { expires_at?: string | number; ttl_seconds?: number };
+
 export type TransactionOperation =
+    | ({ type: 'set'; key: string; value: any } & Expiry)
+    | { type: 'delete'; key: string }
+    | { type: 'check'; key: string; path: string; operator: 'Eq' | 'Ne' | 'Gt' | 'Gte' | 'Lt' | 'Lte' | 'Includes'; value: any }
+    | { type: 'get'; key: string }
+    | ({ type: 'array'; key: string; path: string } & ArrayOperation);
+
+// What one transaction operation did. `ok` is false when a 'get' or 'delete'
+// found no document; `value` holds the document read by a 'get', the
+// document stored by a 'set' or the result of an 'array' operation.
+export interface TransactionOpResult {
+    ok: boolean;
+    value?: any;
+}
+
+// A step of batch(). Unlike a transaction, each runs on its own, so one that
+// fails neither stops nor undoes the others.
+export type BatchOperation =
+    | { type: 'get'; key: string }
+    | ({ type: 'set'; key: string; value: any } & Expiry)
+    | { type: 'delete'; key: string }
+    | { type: 'query'; ast: AstNode; projection?: string[]; limit?: number; offset?: number };
+
+// `status` is the HTTP status the step would have had as a request of its
+// own; `value` holds the document a 'get' read or the documents a 'query'
+// matched.
+export interface BatchResult {
+    status: number;
+    value?: any;
+    error?: string;
+}
+
+export type WatchTarget = { key: string } | { prefix: string } | { query: AstNode };
+
+export type ChangeEvent =
     | { type: 'set'; key: string; value: any }
     | { type: 'delete'; key: string };
 
+// Changes from GET /changes, oldest first, each with its sequence number.
+export interface ChangeBatch {
+  // Changes to a new log_id start over: the log was reset by a restore.
+  log_id: string;
+  changes: (ChangeEvent & { seq: number })[];
+  // Pass as `since` for the changes that follow.
+  last_seq: number;
+}
+
+export type ArrayOperation =
+    | { op: 'push'; value: any }
+    | { op: 'push_unique'; value: any }
+    | { op: 'pop' }
+    | { op: 'remove'; value: any }
+    | { op: 'insert'; index: number; value: any };
+
+export type PatchOperation =
+    | { op: 'add'; path: string; value: any }
+    | { op: 'remove'; path: string }
+    | { op: 'replace'; path: string; value: any }
+    | { op: 'move'; from: string; path: string }
+    | { op: 'copy'; from: string; path: string }
+    | { op: 'test'; path: string; value: any };
+
 export interface CountResponse {
     count: number;
 }
 
-export type DataType = 'String' | 'Number' | 'Bool';
+export type DataType = 'String' | 'Number' | 'Bool' | 'DateTime';
+
+export type DistanceMetric = 'Haversine' | 'Geodesic' | 'Planar';
 
 export type AstNode =
   | { Eq: [string, any, DataType] }
@@ -36,8 +169,14 @@ export type AstNode =
   | { And: [AstNode, AstNode] }
   | { Or: [AstNode, AstNode] }
   | { Not: AstNode }
-  | { GeoWithinRadius: { field: string; lat: number; lon: number; radius: number } }
-  | { GeoInBox: { field: string; min_lat: number; min_lon: number; max_lat: number; max_lon: number } };
+  | { GeoWithinRadius: { field: string; lat: number; lon: number; radius: number; metric?: DistanceMetric } }
+  | { GeoInBox: { field: string; min_lat: number; min_lon: number; max_lat: number; max_lon: number } }
+  | { GeoNearRoute: { field: string; route: GeoPoint[]; distance: number } }
+  | { GeoIntersects: { field: string; geometry: GeoPoint | GeoJsonGeometry } }
+  | { Exists: string }
+  | { KeyEq: string }
+  | { KeyPrefix: string }
+  | { KeyRange: { start?: string | null; end?: string | null } };
 
 interface QueryAstPayload {
     ast: AstNode;
@@ -65,6 +204,23 @@ interface QueryRadiusPayload {
     lat: number;
     lon: number;
     radius: number;
+    limit?: number;
+    metric?: DistanceMetric;
+}
+
+interface QueryNearestPayload {
+    field: string;
+    lat: number;
+    lon: number;
+    k: number;
+    metric?: DistanceMetric;
+}
+
+interface QueryRoutePayload {
+    field: string;
+    route: GeoPoint[];
+    distance: number;
+    limit?: number;
 }
 
 interface QueryBoxPayload {
@@ -85,6 +241,8 @@ export interface DatabaseConfig {
     protocol?: 'http' | 'https';
     cacheTTL?: number;
     apiKey?: string; // Added API key
+    token?: string; // Bearer JWT, sent instead when no apiKey is set
+    database?: string; // Named database on the server; the server's default if unset
 }
 
 class DatabaseError extends Error {
@@ -208,9 +366,13 @@ const fieldProxyHandler: ProxyHandler<{ db: Database; path: string[] }> = {
             case 'includes':
                 return (value: any) => new Condition(target.db, { Includes: [currentPath, value, inferType(value)] });
             case 'withinRadius':
-                return (lat: number, lon: number, radius: number) => new Condition(target.db, { GeoWithinRadius: { field: currentPath, lat, lon, radius } });
+                return (lat: number, lon: number, radius: number, metric?: DistanceMetric) => new Condition(target.db, { GeoWithinRadius: { field: currentPath, lat, lon, radius, metric } });
             case 'inBox':
                 return (min_lat: number, min_lon: number, max_lat: number, max_lon: number) => new Condition(target.db, { GeoInBox: { field: currentPath, min_lat, min_lon, max_lat, max_lon } });
+            case 'nearRoute':
+                return (route: GeoPoint[], distance: number) => new Condition(target.db, { GeoNearRoute: { field: currentPath, route, distance } });
+            case 'intersects':
+                return (geometry: GeoPoint | GeoJsonGeometry) => new Condition(target.db, { GeoIntersects: { field: currentPath, geometry } });
             case 'then':
             case 'catch':
             case 'finally':
@@ -308,18 +470,21 @@ type ObjectQueryBuilder<T> = {
 };
 
 type GeoQueryBuilder = {
-    withinRadius(lat: number, lon: number, radius: number): Condition;
+    withinRadius(lat: number, lon: number, radius: number, metric?: DistanceMetric): Condition;
     inBox(minLat: number, minLon: number, maxLat: number, maxLon: number): Condition;
+    nearRoute(route: GeoPoint[], distance: number): Condition;
+    intersects(geometry: GeoPoint | GeoJsonGeometry): Condition;
 };
 
 
 export class Database {
   private baseURL: string;
-  private cache: Map<string, { value: any; timestamp: number }>;
+  private cache: Map<string, { value: any; timestamp: number; etag: string | null }>;
   private cacheTTL: number;
   private subscriptions: { [key: string]: Array<() => void> };
   private eventSource?: EventSource;
   private apiKey?: string; // Store API Key
+  private token?: string;
 
   constructor(config?: Partial<DatabaseConfig>) {
     const conf: DatabaseConfig = {
@@ -328,6 +493,8 @@ export class Database {
         protocol: config?.protocol ?? 'http',
         cacheTTL: config?.cacheTTL ?? 5000,
         apiKey: config?.apiKey, // Store API Key
+        token: config?.token,
+        database: config?.database,
     };
 
     if (!conf.host) {
@@ -338,10 +505,14 @@ export class Database {
     }
 
     this.baseURL = `${conf.protocol}://${conf.host}:${conf.port}`;
+    if (conf.database) {
+      this.baseURL += `/db/${encodeURIComponent(conf.database)}`;
+    }
     this.cache = new Map();
     this.cacheTTL = conf.cacheTTL ?? 5000;
     this.subscriptions = {};
     this.apiKey = conf.apiKey; // Store API Key
+    this.token = conf.token;
     console.info(`Database SDK initialized for server at: ${this.baseURL}`);
     this.initializeEventSource(); // Uncommented this line
   }
@@ -380,28 +551,47 @@ export class Database {
       }
   }
 
-  private async _request<T>(endpoint: string, body: any, method: 'POST' | 'GET' = 'POST'): Promise<T> {
+  // The API key, or else the bearer token; the server accepts either.
+  private authHeaders(): Record<string, string> {
+    if (this.apiKey) {
+      return { 'X-API-Key': this.apiKey };
+    }
+    return this.token ? { 'Authorization': `Bearer ${this.token}` } : {};
+  }
+
+  private async _request<T>(
+    endpoint: string,
+    body: any,
+    method: 'POST' | 'GET' | 'PUT' | 'DELETE' = 'POST',
+    extraHeaders: Record<string, string> = {},
+    onResponse?: (response: Response) => void,
+  ): Promise<T> {
     const url = `${this.baseURL}/${endpoint}`;
     const start = performance.now();
-    console.debug(`Sending ${method} request to ${url}`, method === 'POST' ? body : '');
+    const hasBody = method !== 'GET' && method !== 'DELETE';
+    console.debug(`Sending ${method} request to ${url}`, hasBody ? body : '');
     try {
       const headers: HeadersInit = {
         'Content-Type': 'application/json',
       };
 
-      if (this.apiKey) {
-        headers['X-API-Key'] = this.apiKey; // Add API Key header
-      }
+      Object.assign(headers, this.authHeaders(), extraHeaders);
 
       const response = await fetch(url, {
         method: method,
         headers: headers,
-        body: method === 'POST' ? JSON.stringify(body) : undefined,
+        body: hasBody ? JSON.stringify(body) : undefined,
       });
 
       const duration = performance.now() - start;
       console.log(`Request to ${endpoint} took ${duration.toFixed(2)}ms`);
       console.debug(`Received response ${response.status} from ${url}`);
+      onResponse?.(response);
+
+      // Not modified since the ETag sent in If-None-Match; the caller keeps its copy
+      if (response.status === 304) {
+          return undefined as T;
+      }
 
       // Special handling for /get 404: return undefined instead of throwing
       if (endpoint === 'get' && response.status === 404) {
@@ -454,11 +644,67 @@ export class Database {
     }
   }
 
-  async set(key: string, value: any): Promise<void> {
-    await this._request<void>('set', { key, value });
+  // A retry sent with the same idempotency key gets the first response back
+  // instead of writing again.
+  private idempotencyHeaders(idempotencyKey?: string): Record<string, string> {
+    return idempotencyKey ? { 'Idempotency-Key': idempotencyKey } : {};
+  }
+
+  // With `ifMatch` (an ETag from getWithEtag, or '*'), rejects with a 412
+  // DatabaseError if the document changed since it was read.
+  async set(key: string, value: any, ifMatch?: string, expiry: Expiry = {}, idempotencyKey?: string): Promise<void> {
+    const headers = { ...(ifMatch ? { 'If-Match': ifMatch } : {}), ...this.idempotencyHeaders(idempotencyKey) };
+    await this._request<void>('set', { key, value, ...expiry }, 'POST', headers);
+    this.cache.delete(key);
+  }
+
+  // Rejects with a 409 DatabaseError if the key already exists.
+  async setNx(key: string, value: any, expiry: Expiry = {}): Promise<void> {
+    await this._request<void>('set_nx', { key, value, ...expiry });
     this.cache.delete(key);
   }
 
+  async setPath(key: string, path: string, value: any): Promise<void> {
+    try {
+      await this._request<void>('set_path', { key, path, value });
+    } finally {
+      this.cache.delete(key);
+    }
+  }
+
+  // Resolves to the popped element for 'pop' and to the resulting array otherwise.
+  async arrayOp(key: string, path: string, operation: ArrayOperation): Promise<any> {
+    try {
+      return await this._request<any>('array', { key, path, ...operation });
+    } finally {
+      this.cache.delete(key);
+    }
+  }
+
+  async merge(key: string, patch: any): Promise<any> {
+    try {
+      return await this._request<any>('merge', { key, patch });
+    } finally {
+      this.cache.delete(key);
+    }
+  }
+
+  async patch(key: string, operations: PatchOperation[]): Promise<any> {
+    try {
+      return await this._request<any>('patch', { key, operations });
+    } finally {
+      this.cache.delete(key);
+    }
+  }
+
+  async compareAndSet(key: string, expected: any, value: any, path?: string): Promise<void> {
+    try {
+      await this._request<void>('cas', { key, path, expected, value });
+    } finally {
+      this.cache.delete(key);
+    }
+  }
+
   async get(key: string): Promise<any | undefined> {
     const cached = this.cache.get(key);
     if (cached && Date.now() - cached.timestamp < this.cacheTTL) {
@@ -466,13 +712,24 @@ export class Database {
       return cached.value;
     }
     console.debug(`Cache miss for key: ${key}`);
-    // _request now returns undefined for 404 on /get
-    const value = await this._request<any | undefined>('get', { key });
+    // A stale entry is revalidated by its ETag, so an unchanged document isn't sent again
+    const conditional: Record<string, string> = cached?.etag ? { 'If-None-Match': cached.etag } : {};
+    let status = 0;
+    let etag: string | null = null;
+    // _request now returns undefined for 404 and 304 on /get
+    const value = await this._request<any | undefined>('get', { key }, 'POST', conditional, response => {
+      status = response.status;
+      etag = response.headers.get('ETag');
+    });
+    if (status === 304 && cached) {
+        this.cache.set(key, { ...cached, timestamp: Date.now() });
+        return cached.value;
+    }
     if (value === undefined) {
         // Explicitly throw the expected error type for the tests
         throw new DatabaseError(`Database Error (404): Key not found`, 404);
     }
-    this.cache.set(key, { value, timestamp: Date.now() });
+    this.cache.set(key, { value, timestamp: Date.now(), etag });
     return value;
   }
 
@@ -482,24 +739,177 @@ export class Database {
     return this._request<any>('get_partial', { key, fields });
   }
 
-  async delete(key: string): Promise<void> {
-    await this._request<void>('delete', { key });
+  // With `ifMatch` (an ETag from getWithEtag, or '*'), rejects with a 412
+  // DatabaseError if the document changed since it was read.
+  // Reads the document along with its ETag, bypassing the cache.
+  async getWithEtag(key: string): Promise<{ value: any; etag: string | null }> {
+    let etag: string | null = null;
+    const value = await this._request<any | undefined>('get', { key }, 'POST', {}, response => {
+      etag = response.headers.get('ETag');
+    });
+    if (value === undefined) {
+        throw new DatabaseError(`Database Error (404): Key not found`, 404);
+    }
+    return { value, etag };
+  }
+
+  async delete(key: string, ifMatch?: string): Promise<void> {
+    await this._request<void>('delete', { key }, 'POST', ifMatch ? { 'If-Match': ifMatch } : {});
     this.cache.delete(key);
   }
 
-  async batchSet(items: BatchSetItem[]): Promise<void> {
-      await this._request<void>('batch_set', items);
+  // Rejects with a 409 DatabaseError if `to` exists, unless `overwrite` is set.
+  async rename(from: string, to: string, overwrite = false): Promise<void> {
+    try {
+      await this._request<void>('rename', { from, to, overwrite });
+    } finally {
+      this.cache.delete(from);
+      this.cache.delete(to);
+    }
+  }
+
+  // Rejects with a 409 DatabaseError if `to` exists, unless `overwrite` is set.
+  async copy(from: string, to: string, overwrite = false): Promise<void> {
+    try {
+      await this._request<void>('copy', { from, to, overwrite });
+    } finally {
+      this.cache.delete(to);
+    }
+  }
+
+  // Server-side collections: documents under the key prefix `${name}:`,
+  // addressed by id, with their own indexes.
+  async createCollection(name: string): Promise<void> {
+    await this._request<void>('collections', { name });
+  }
+
+  async listCollections(): Promise<string[]> {
+    return this._request<string[]>('collections', null, 'GET');
+  }
+
+  async dropCollection(name: string): Promise<number> {
+    const response = await this._request<CountResponse>(`collections/${encodeURIComponent(name)}/drop`, null);
+    this.cache.clear();
+    return response.count;
+  }
+
+  // Resolves to the document's id, generated unless given; rejects with a 409 DatabaseError if it is taken.
+  async insertDoc(collection: string, value: any, id?: string, expiry: Expiry = {}): Promise<string> {
+    const response = await this._request<{ id: string }>(`collections/${encodeURIComponent(collection)}/docs`, { id, value, ...expiry });
+    return response.id;
+  }
+
+  async getDoc(collection: string, id: string): Promise<any> {
+    return this._request<any>(`collections/${encodeURIComponent(collection)}/get`, { id });
+  }
+
+  async setDoc(collection: string, id: string, value: any, expiry: Expiry = {}): Promise<void> {
+    try {
+      await this._request<void>(`collections/${encodeURIComponent(collection)}/set`, { id, value, ...expiry });
+    } finally {
+      this.cache.delete(`${collection}:${id}`);
+    }
+  }
+
+  async deleteDoc(collection: string, id: string): Promise<void> {
+    try {
+      await this._request<void>(`collections/${encodeURIComponent(collection)}/delete`, { id });
+    } finally {
+      this.cache.delete(`${collection}:${id}`);
+    }
+  }
+
+  // Fields, sort fields and key conditions are relative to the collection's documents.
+  async queryCollection(collection: string, ast: AstNode, options: Omit<QueryAstPayload, 'ast'> = {}): Promise<any[]> {
+    return this._request<any[]>(`collections/${encodeURIComponent(collection)}/query`, { ast, ...options });
+  }
+
+  async createCollectionIndex(collection: string, field: string, kind: 'Hash' | 'Sorted' | 'Geo' | 'Unique'): Promise<void> {
+    await this._request<void>(`collections/${encodeURIComponent(collection)}/indexes`, { field, kind });
+  }
+
+  async exists(key: string): Promise<boolean> {
+    const response = await this._request<{ exists: boolean }>('exists', { key });
+    return response.exists;
+  }
+
+  async listKeys(options: { prefix?: string; limit?: number; after?: string } = {}): Promise<KeyPage> {
+    const params = new URLSearchParams();
+    for (const [name, value] of Object.entries(options)) {
+      if (value !== undefined) params.set(name, String(value));
+    }
+    return this._request<KeyPage>(`keys?${params}`, null, 'GET');
+  }
+
+  async batchSet(items: BatchSetItem[], idempotencyKey?: string): Promise<void> {
+      await this._request<void>('batch_set', items, 'POST', this.idempotencyHeaders(idempotencyKey));
       items.forEach(item => this.cache.delete(item.key));
   }
 
-  async transaction(operations: TransactionOperation[]): Promise<void> {
-      await this._request<void>('transaction', operations);
+  // Streams changes to the target over a WebSocket. Resolves once the server
+  // confirms the subscription, to a function that closes it. Query watchers
+  // get a 'delete' when a document stops matching.
+  watch(target: WatchTarget, onEvent: (event: ChangeEvent) => void): Promise<() => void> {
+    const url = `${this.baseURL.replace(/^http/, 'ws')}/watch`;
+    // Bun's WebSocket accepts headers, which carry the credentials.
+    const socket = new WebSocket(url, { headers: this.authHeaders() } as any);
+    return new Promise((resolve, reject) => {
+      socket.onopen = () => socket.send(JSON.stringify(target));
+      socket.onmessage = message => {
+        const data = JSON.parse(String(message.data));
+        if (data.type === 'subscribed') {
+          resolve(() => socket.close());
+        } else if (data.type === 'error') {
+          reject(new DatabaseError(`Watch Error: ${data.error}`, 400));
+          socket.close();
+        } else {
+          this.cache.delete(data.key);
+          onEvent(data as ChangeEvent);
+        }
+      };
+      socket.onerror = () => reject(new DatabaseError(`WebSocket error on /watch`, 0));
+    });
+  }
+
+  // The changes after sequence number `since`; needs a server started with
+  // --changelog-size. `waitMs` waits that long for a change when there is none.
+  async changes(since = 0, options: { limit?: number; waitMs?: number } = {}): Promise<ChangeBatch> {
+    const params = new URLSearchParams({ since: String(since) });
+    if (options.limit !== undefined) params.set('limit', String(options.limit));
+    if (options.waitMs !== undefined) params.set('wait_ms', String(options.waitMs));
+    return this._request<ChangeBatch>(`changes?${params}`, null, 'GET');
+  }
+
+  // High-throughput writes: the server groups them with other ingest requests
+  // into shared transactions. `ack` chooses when this resolves: once queued,
+  // once committed (the default) or once flushed to disk.
+  async ingest(items: BatchSetItem[], ack: 'queued' | 'committed' | 'durable' = 'committed'): Promise<number> {
+      const response = await this._request<CountResponse>('ingest', { items, ack });
+      items.forEach(item => this.cache.delete(item.key));
+      return response.count;
+  }
 
+  // Resolves to one result per operation, in order.
+  async transaction(operations: TransactionOperation[], idempotencyKey?: string): Promise<TransactionOpResult[]> {
+      const response = await this._request<{ results: TransactionOpResult[] }>('transaction', operations, 'POST', this.idempotencyHeaders(idempotencyKey));
+
+      operations.forEach(op => {
+          if (op.type === 'set' || op.type === 'delete' || op.type === 'array') {
+              this.cache.delete(op.key);
+          }
+      });
+      return response.results;
+  }
+
+  // Runs the operations in order in one round trip.
+  async batch(operations: BatchOperation[], idempotencyKey?: string): Promise<BatchResult[]> {
+      const response = await this._request<{ results: BatchResult[] }>('batch', operations, 'POST', this.idempotencyHeaders(idempotencyKey));
       operations.forEach(op => {
           if (op.type === 'set' || op.type === 'delete') {
               this.cache.delete(op.key);
           }
       });
+      return response.results;
   }
 
   async clearPrefix(prefix: string): Promise<number> {
@@ -524,6 +934,58 @@ export class Database {
      return this._request<any[]>('query/radius', payload);
   }
 
+  async queryNearest(payload: QueryNearestPayload): Promise<any[]> {
+      return this._request<any[]>('query/nearest', payload);
+  }
+
+  async queryRoute(payload: QueryRoutePayload): Promise<any[]> {
+      return this._request<any[]>('query/route', payload);
+  }
+
+  // The live index configuration of the database.
+  async getConfig(): Promise<any> {
+      return this._request<any>('admin/config', null, 'GET');
+  }
+
+  // Newest first; empty unless the server runs with --slow-query-ms.
+  async slowQueries(): Promise<SlowQuery[]> {
+      return this._request<SlowQuery[]>('admin/slow_queries', null, 'GET');
+  }
+
+  // Needs a server started with --changelog-size. Only changes made after
+  // this are delivered.
+  async createWebhook(url: string, target: WatchTarget): Promise<Webhook> {
+      return this._request<Webhook>('admin/webhooks', { url, ...target });
+  }
+
+  async listWebhooks(): Promise<Webhook[]> {
+      return this._request<Webhook[]>('admin/webhooks', null, 'GET');
+  }
+
+  async deleteWebhook(id: string): Promise<void> {
+      await this._request<void>(`admin/webhooks/${encodeURIComponent(id)}`, null, 'DELETE');
+  }
+
+  // Oldest first.
+  async deadLetters(webhookId: string): Promise<DeadLetter[]> {
+      return this._request<DeadLetter[]>(`admin/webhooks/${encodeURIComponent(webhookId)}/dead_letters`, null, 'GET');
+  }
+
+  async clearDeadLetters(webhookId: string): Promise<number> {
+      const response = await this._request<CountResponse>(`admin/webhooks/${encodeURIComponent(webhookId)}/dead_letters`, null, 'DELETE');
+      return response.count;
+  }
+
+  // Drops the indexes of fields no longer listed and builds those of new ones.
+  async setIndexedFields(fields: IndexedFields, background = false): Promise<IndexConfigChanges> {
+      return this._request<IndexConfigChanges>('admin/config', { ...fields, background }, 'PUT');
+  }
+
+  async loadGeoRTree(field: string): Promise<number> {
+      const response = await this._request<CountResponse>('index/rtree', { field });
+      return response.count;
+  }
+
   async queryBox(payload: QueryBoxPayload): Promise<any[]> {
       return this._request<any[]>('query/box', payload);
   }
@@ -551,6 +1013,30 @@ export class Database {
      return dataString;
   }
 
+  // Writes a consistent backup to a directory on the server, which must be
+  // missing or empty. POST /admin/backup with {} downloads it as .tar.gz instead.
+  async backup(path: string): Promise<BackupReport> {
+    return this._request<BackupReport>('admin/backup', { path });
+  }
+
+  // Replaces the database with a backup directory or .tar.gz on the server,
+  // rebuilding its indexes per the current configuration.
+  async restore(path: string): Promise<BackupReport> {
+    const report = await this._request<BackupReport>('admin/restore', { path });
+    this.cache.clear();
+    return report;
+  }
+
+  // Writes pending changes to disk now.
+  async flush(): Promise<SizeReport> {
+    return this._request<SizeReport>('admin/flush', {});
+  }
+
+  // Rewrites the database to reclaim the space left by large deletions.
+  async compact(): Promise<SizeReport> {
+    return this._request<SizeReport>('admin/compact', {});
+  }
+
   async importData(data: ImportItem[]): Promise<void> {
     await this._request<void>('import', data);
     this.cache.clear();